@@ -0,0 +1,203 @@
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A block of addresses such as "10.0.0.0/8" or "::1/128". A bare address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// The block of `prefix_len` bits (at most the address's length) that `ip` is in.
+    pub fn covering(ip: IpAddr, prefix_len: u8) -> Cidr {
+        match ip {
+            IpAddr::V4(ip) => {
+                let prefix_len = prefix_len.min(32);
+                let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+                let network = IpAddr::V4((u32::from(ip) & mask).into());
+                Cidr { network, prefix_len }
+            }
+            IpAddr::V6(ip) => {
+                let prefix_len = prefix_len.min(128);
+                let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+                let network = IpAddr::V6((u128::from(ip) & mask).into());
+                Cidr { network, prefix_len }
+            }
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s, None),
+        };
+        let network: IpAddr = address
+            .parse()
+            .map_err(|_| format!("{:?} is not an IP address", address))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => match prefix_len.parse::<u8>() {
+                Ok(len) if len <= max_len => len,
+                _ => return Err(format!("prefix length must be from 0 to {}", max_len)),
+            },
+            None => max_len,
+        };
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Who may use an internal listener (the admin API or the metrics endpoint): only peers in the
+/// allow list, and only with the bearer token if one is configured. With no allow list, only
+/// loopback peers are let in, so binding to a public address by mistake exposes nothing.
+#[derive(Debug)]
+pub struct AccessPolicy {
+    allow: Vec<Cidr>,
+    /// SHA-256 of the token. Comparing digests keeps the comparison constant time whatever the
+    /// length of the token presented.
+    token_digest: Option<[u8; 32]>,
+}
+
+impl AccessPolicy {
+    pub fn new(allow: Vec<Cidr>, token: Option<&str>) -> AccessPolicy {
+        let allow = if allow.is_empty() {
+            vec![
+                Cidr::from_str("127.0.0.0/8").unwrap(),
+                Cidr::from_str("::1").unwrap(),
+            ]
+        } else {
+            allow
+        };
+        AccessPolicy {
+            allow,
+            token_digest: token.map(digest),
+        }
+    }
+
+    /// Whether `peer` is in the allow list. Connections from anyone else are closed unread.
+    pub fn allows(&self, peer: IpAddr) -> bool {
+        self.allow.iter().any(|cidr| cidr.contains(peer))
+    }
+
+    /// Checks a request from `peer`. Returns the status to refuse it with: 403 for a peer outside
+    /// the allow list, or 401 for a missing or wrong token.
+    pub fn check(
+        &self,
+        peer: IpAddr,
+        request: &http::Request<Vec<u8>>,
+    ) -> Result<(), http::StatusCode> {
+        if !self.allows(peer) {
+            return Err(http::StatusCode::FORBIDDEN);
+        }
+        if let Some(expected) = &self.token_digest {
+            let presented = request
+                .headers()
+                .get(http::header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map(|token| digest(token.trim()));
+            match presented {
+                Some(presented) if constant_time_eq(&presented, expected) => {}
+                _ => return Err(http::StatusCode::UNAUTHORIZED),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// SHA-256 of a secret, for comparing with `constant_time_eq`.
+pub fn digest(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+pub fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let private: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(private.contains(ip("10.1.2.3")));
+        assert!(!private.contains(ip("11.0.0.1")));
+        // IPv4 peers that arrive as IPv4-mapped IPv6 addresses still match
+        assert!(private.contains(ip("::ffff:10.0.0.1")));
+        assert!(!private.contains(ip("::1")));
+
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("192.0.2.1")));
+        assert!("fd00::/8".parse::<Cidr>().unwrap().contains(ip("fd12::1")));
+        assert_eq!("::1".parse::<Cidr>().unwrap().to_string(), "::1/128");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+
+        let block = Cidr::covering(ip("2001:db8:1:2:3:4:5:6"), 64);
+        assert_eq!(block.to_string(), "2001:db8:1:2::/64");
+        assert!(block.contains(ip("2001:db8:1:2::ffff")));
+        assert_eq!(Cidr::covering(ip("192.0.2.77"), 64).to_string(), "192.0.2.77/32");
+    }
+
+    #[test]
+    fn test_access_policy() {
+        let request = |authorization: Option<&str>| {
+            let mut request = http::Request::builder().uri("/status");
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            request.body(Vec::new()).unwrap()
+        };
+        let loopback_only = AccessPolicy::new(Vec::new(), None);
+        assert!(loopback_only.allows(ip("127.0.0.1")) && !loopback_only.allows(ip("192.0.2.1")));
+        assert_eq!(loopback_only.check(ip("127.0.0.1"), &request(None)), Ok(()));
+        assert_eq!(loopback_only.check(ip("::1"), &request(None)), Ok(()));
+        assert_eq!(
+            loopback_only.check(ip("192.0.2.1"), &request(None)),
+            Err(http::StatusCode::FORBIDDEN)
+        );
+
+        let with_token = AccessPolicy::new(vec!["0.0.0.0/0".parse().unwrap()], Some("s3cret"));
+        let peer = ip("192.0.2.1");
+        assert_eq!(with_token.check(peer, &request(Some("Bearer s3cret"))), Ok(()));
+        for authorization in &[None, Some("Bearer s3cre"), Some("Basic s3cret")] {
+            assert_eq!(
+                with_token.check(peer, &request(*authorization)),
+                Err(http::StatusCode::UNAUTHORIZED)
+            );
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use crate::histogram::Histogram;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::Duration;
+
+/// Bounds on an adaptive weight, as a multiple of the upstream's configured weight
+pub const MIN_FACTOR: f64 = 0.1;
+pub const MAX_FACTOR: f64 = 2.0;
+/// How much of each new measurement goes into the moving averages
+const SMOOTHING: f64 = 0.5;
+/// Length of the window RecentWindow summarizes, in seconds
+const RECENT_SECS: u64 = 60;
+
+/// Per-upstream counters, updated with relaxed atomics from the request path and read by the
+/// weight adjustment task.
+#[derive(Debug, Default)]
+pub struct UpstreamStats {
+    responses: AtomicU64,
+    errors: AtomicU64,
+    latency_micros: AtomicU64,
+    in_flight: AtomicU64,
+    /// Errors since the last successful response
+    consecutive_errors: AtomicU64,
+    /// The same outcomes over the last minute, for reporting
+    recent: Mutex<RecentWindow>,
+}
+
+/// Counts a request as in flight to an upstream until it is dropped.
+pub struct InFlight(Arc<UpstreamStats>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl UpstreamStats {
+    /// Marks a request as sent to this upstream and awaiting its response.
+    pub fn begin_request(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self.clone())
+    }
+
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn record_response(&self, latency: Duration) {
+        self.responses.fetch_add(1, Ordering::Relaxed);
+        self.consecutive_errors.store(0, Ordering::Relaxed);
+        self.latency_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        self.recent.lock().unwrap().record(current_second(), Some(latency));
+    }
+
+    /// Records a failed connection, a timeout, an unparseable response or a 5xx.
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        self.consecutive_errors.fetch_add(1, Ordering::Relaxed);
+        self.recent.lock().unwrap().record(current_second(), None);
+    }
+
+    /// Number of errors in a row, up to now, since the upstream last answered successfully.
+    pub fn consecutive_errors(&self) -> u64 {
+        self.consecutive_errors.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests that failed, since balancebeam started.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests that got a response or failed, since balancebeam started.
+    pub fn total_requests(&self) -> u64 {
+        self.responses.load(Ordering::Relaxed) + self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Outcomes over the last minute.
+    pub fn last_minute(&self) -> RecentSummary {
+        self.recent.lock().unwrap().summary(current_second())
+    }
+
+    fn snapshot(&self) -> Counts {
+        Counts {
+            responses: self.responses.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            latency_micros: self.latency_micros.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn current_second() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// One second's worth of outcomes.
+#[derive(Debug, Clone, Default)]
+struct Second {
+    /// Which second (since the epoch) this slot currently holds
+    second: u64,
+    errors: u64,
+    latency: Histogram,
+}
+
+/// Outcomes of the last RECENT_SECS seconds, kept as a ring of per-second slots so that memory
+/// doesn't grow with traffic.
+#[derive(Debug, Default)]
+struct RecentWindow {
+    seconds: Vec<Second>,
+}
+
+/// What an upstream did over the last minute.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecentSummary {
+    /// Successful (non-5xx) responses
+    pub responses: u64,
+    pub errors: u64,
+    /// Latency histogram of the successful responses
+    pub latency: Histogram,
+}
+
+impl RecentSummary {
+    /// Fraction of requests that failed, or None if there were none.
+    pub fn error_rate(&self) -> Option<f64> {
+        let total = self.responses + self.errors;
+        if total == 0 {
+            None
+        } else {
+            Some(self.errors as f64 / total as f64)
+        }
+    }
+}
+
+impl RecentWindow {
+    /// Records a response that took `latency`, or an error if None.
+    fn record(&mut self, now: u64, latency: Option<Duration>) {
+        if self.seconds.is_empty() {
+            self.seconds = vec![Second::default(); RECENT_SECS as usize];
+        }
+        let slot = &mut self.seconds[(now % RECENT_SECS) as usize];
+        if slot.second != now {
+            *slot = Second { second: now, ..Second::default() };
+        }
+        match latency {
+            Some(latency) => slot.latency.record(latency),
+            None => slot.errors += 1,
+        }
+    }
+
+    fn summary(&self, now: u64) -> RecentSummary {
+        let mut summary = RecentSummary::default();
+        for slot in &self.seconds {
+            if slot.second + RECENT_SECS > now {
+                summary.errors += slot.errors;
+                summary.latency.merge(&slot.latency);
+            }
+        }
+        summary.responses = summary.latency.count();
+        summary
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Counts {
+    responses: u64,
+    errors: u64,
+    latency_micros: u64,
+}
+
+/// Moving averages of one upstream's latency and error rate.
+#[derive(Debug, Clone, Copy, Default)]
+struct Ewma {
+    /// Mean response latency in microseconds, once there has been a response
+    latency: Option<f64>,
+    error_rate: f64,
+    /// Counters as of the previous update
+    seen: Counts,
+}
+
+impl Ewma {
+    fn update(&mut self, now: Counts) {
+        let responses = now.responses - self.seen.responses;
+        let errors = now.errors - self.seen.errors;
+        let latency = now.latency_micros - self.seen.latency_micros;
+        self.seen = now;
+        if responses + errors > 0 {
+            let error_rate = errors as f64 / (responses + errors) as f64;
+            self.error_rate += SMOOTHING * (error_rate - self.error_rate);
+        }
+        if responses > 0 {
+            let mean = latency as f64 / responses as f64;
+            self.latency = Some(match self.latency {
+                Some(previous) => previous + SMOOTHING * (mean - previous),
+                None => mean,
+            });
+        }
+    }
+
+    /// Higher is better: the rate at which this upstream completes requests successfully.
+    fn score(&self) -> Option<f64> {
+        match self.latency {
+            Some(latency) => Some((1.0 - self.error_rate) / latency.max(1.0)),
+            // Never answered: nothing to go on unless it has been failing
+            None if self.error_rate > 0.0 => Some(0.0),
+            None => None,
+        }
+    }
+}
+
+/// Turns the counters of a pool's upstreams into weights. Upstreams that are faster and fail less
+/// than the pool's average get more traffic, within [MIN_FACTOR, MAX_FACTOR] of their configured
+/// weight.
+#[derive(Debug)]
+pub struct WeightCalculator {
+    upstreams: Vec<Ewma>,
+}
+
+impl WeightCalculator {
+    pub fn new(num_upstreams: usize) -> WeightCalculator {
+        WeightCalculator {
+            upstreams: vec![Ewma::default(); num_upstreams],
+        }
+    }
+
+    /// Folds in everything recorded since the last call and returns each upstream's weight factor.
+    /// Upstreams added since the last call start out with no history.
+    pub fn update<'a>(&mut self, stats: impl IntoIterator<Item = &'a UpstreamStats>) -> Vec<f64> {
+        let stats: Vec<&UpstreamStats> = stats.into_iter().collect();
+        self.upstreams.resize(stats.len().max(self.upstreams.len()), Ewma::default());
+        for (ewma, stats) in self.upstreams.iter_mut().zip(stats) {
+            ewma.update(stats.snapshot());
+        }
+        let scores: Vec<Option<f64>> = self.upstreams.iter().map(Ewma::score).collect();
+        let known: Vec<f64> = scores.iter().filter_map(|score| *score).collect();
+        let mean = known.iter().sum::<f64>() / known.len().max(1) as f64;
+        scores
+            .iter()
+            .map(|score| match score {
+                Some(score) if mean > 0.0 => (score / mean).clamp(MIN_FACTOR, MAX_FACTOR),
+                _ => 1.0,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_upstreams_keep_their_weight() {
+        let stats: Vec<UpstreamStats> = (0..3).map(|_| UpstreamStats::default()).collect();
+        let mut calculator = WeightCalculator::new(3);
+        assert_eq!(calculator.update(&stats), vec![1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_failing_upstream_gets_minimum_weight() {
+        let stats: Vec<UpstreamStats> = (0..2).map(|_| UpstreamStats::default()).collect();
+        stats[0].record_response(Duration::from_millis(10));
+        stats[1].record_error();
+        let weights = WeightCalculator::new(2).update(&stats);
+        assert_eq!(weights, vec![MAX_FACTOR, MIN_FACTOR]);
+    }
+
+    #[test]
+    fn test_recent_window_forgets_old_seconds() {
+        let mut window = RecentWindow::default();
+        window.record(1000, Some(Duration::from_millis(4)));
+        window.record(1000, None);
+        window.record(1030, Some(Duration::from_millis(40)));
+        let summary = window.summary(1059);
+        assert_eq!((summary.responses, summary.errors), (2, 1));
+        assert_eq!(summary.latency.quantile(0.99), Some(Duration::from_millis(50)));
+
+        // A minute later the first second has dropped out, even though its slot wasn't reused
+        let summary = window.summary(1060);
+        assert_eq!((summary.responses, summary.errors), (1, 0));
+        assert_eq!(summary.error_rate(), Some(0.0));
+        // ...and reusing the slot replaces it
+        window.record(1060, None);
+        assert_eq!(window.summary(1060).errors, 1);
+        assert_eq!(RecentWindow::default().summary(1060).error_rate(), None);
+    }
+
+    /// Routes traffic in proportion to the weights, as the proxy would, and checks that a slow
+    /// upstream's share shrinks within a few adjustment cycles.
+    #[test]
+    fn test_slow_upstream_loses_traffic() {
+        let latencies = [10, 10, 100];
+        let stats: Vec<UpstreamStats> = (0..3).map(|_| UpstreamStats::default()).collect();
+        let mut calculator = WeightCalculator::new(3);
+        let mut weights = vec![1.0; 3];
+        let mut slow_share = Vec::new();
+        for _cycle in 0..4 {
+            let total: f64 = weights.iter().sum();
+            slow_share.push(weights[2] / total);
+            for (idx, weight) in weights.iter().enumerate() {
+                let requests = (300.0 * weight / total).round() as usize;
+                for _ in 0..requests {
+                    stats[idx].record_response(Duration::from_millis(latencies[idx]));
+                }
+            }
+            weights = calculator.update(&stats);
+        }
+        assert!((slow_share[0] - 1.0 / 3.0).abs() < 1e-9);
+        assert!(slow_share[3] < 0.1, "slow upstream's share: {:?}", slow_share);
+        assert!(weights[0] > 1.0 && weights[1] > 1.0);
+    }
+}
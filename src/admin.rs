@@ -0,0 +1,452 @@
+use crate::access::AccessPolicy;
+use crate::connections::ConnectionInfo;
+use crate::rate_limiter::RateLimitMode;
+use crate::registry::Upstream;
+use crate::top_talkers::{Ranking, TopTalkers};
+use crate::{config, metrics, priority, request, response, upgrade};
+use crate::{ProxyState, UpstreamPool, UpstreamsState};
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::net::{TcpListener, TcpStream};
+
+/// How many clients `/status` lists as the worst rate limit offenders
+const TOP_OFFENDERS: usize = 10;
+/// How many clients and path prefixes `/status` lists as moving the most bytes
+const TOP_TALKERS: usize = 10;
+
+/// What an internal listener serves.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Api {
+    /// The whole admin API, /metrics included (--admin-bind)
+    Admin,
+    /// Only /metrics (--metrics-bind), so that scrapers needn't be trusted with the admin API
+    Metrics,
+}
+
+impl Api {
+    pub fn name(self) -> &'static str {
+        match self {
+            Api::Admin => "admin",
+            Api::Metrics => "metrics",
+        }
+    }
+}
+
+/// Accepts connections on an internal listener. The admin API is served on its own port so that it
+/// is never reachable through the proxy itself, and only to peers that `access` lets in.
+pub async fn serve(
+    mut listener: TcpListener,
+    state: Arc<ProxyState>,
+    api: Api,
+    access: AccessPolicy,
+) {
+    let access = Arc::new(access);
+    while let Ok((stream, peer)) = listener.accept().await {
+        let state = state.clone();
+        let access = access.clone();
+        tokio::spawn(async move {
+            handle_admin_connection(stream, peer.ip(), state, api, &access).await
+        });
+    }
+}
+
+async fn handle_admin_connection(
+    mut conn: TcpStream,
+    peer: IpAddr,
+    state: Arc<ProxyState>,
+    api: Api,
+    access: &AccessPolicy,
+) {
+    // Peers outside the allow list don't get to send us anything
+    if !access.allows(peer) {
+        log::warn!("{}: refused connection from {}", api.name(), peer);
+        return;
+    }
+    loop {
+        let request = match request::read_from_stream(&mut conn).await {
+            Ok(request) => request,
+            Err(request::Error::IncompleteRequest(0)) => return,
+            Err(error) => {
+                log::debug!("Error reading admin request: {}", error);
+                return;
+            }
+        };
+        let response = match access.check(peer, &request) {
+            Ok(()) if api == Api::Metrics && request.uri().path() != "/metrics" => {
+                response::make_http_error(http::StatusCode::NOT_FOUND)
+            }
+            Ok(()) => route(&request, &state).await,
+            Err(status) => {
+                log::warn!(
+                    "{}: refused {} from {} with {}",
+                    api.name(),
+                    request::format_request_line(&request),
+                    peer,
+                    status
+                );
+                let mut response = response::make_http_error(status);
+                if status == http::StatusCode::UNAUTHORIZED {
+                    response.headers_mut().insert(
+                        http::header::WWW_AUTHENTICATE,
+                        http::HeaderValue::from_static("Bearer"),
+                    );
+                }
+                response
+            }
+        };
+        log::debug!(
+            "{}: {} <- {}",
+            api.name(),
+            request::format_request_line(&request),
+            response::format_response_line(&response)
+        );
+        if let Err(error) = response::write_to_stream(&response, &mut conn).await {
+            log::warn!("Failed to send admin response: {}", error);
+            return;
+        }
+    }
+}
+
+async fn route(request: &http::Request<Vec<u8>>, state: &ProxyState) -> http::Response<Vec<u8>> {
+    if let Some(address) = request.uri().path().strip_prefix("/upstreams/") {
+        let address = &config::normalize_address(address);
+        return match *request.method() {
+            http::Method::GET => json_or_not_found(upstream(state, address).await),
+            http::Method::PUT => add_upstream(state, address, request.uri().query()).await,
+            http::Method::DELETE => json_or_not_found(remove_upstream(state, address).await),
+            _ => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+        };
+    }
+    if let Some(id) = request.uri().path().strip_prefix("/connections/") {
+        return match *request.method() {
+            http::Method::DELETE => close_connection(state, id),
+            _ => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+        };
+    }
+    if request.uri().path() == "/rate-limit-mode" && request.method() == http::Method::PUT {
+        return set_rate_limit_mode(state, request.body());
+    }
+    if request.uri().path() == "/upgrade" && request.method() == http::Method::POST {
+        return upgrade(state).await;
+    }
+    if request.method() != http::Method::GET {
+        return response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED);
+    }
+    match request.uri().path() {
+        "/status" => json_response(&status(state).await),
+        "/upstreams" => json_response(&upstreams(state).await),
+        "/connections" => json_response(&connections(state)),
+        "/config" => json_response(&state.effective_config),
+        "/metrics" => response::make_response(
+            http::StatusCode::OK,
+            metrics::CONTENT_TYPE,
+            metrics::render(state).await.into_bytes(),
+        ),
+        _ => response::make_http_error(http::StatusCode::NOT_FOUND),
+    }
+}
+
+/// Hands the listener sockets to a new binary (--upgrade-binary) and, once it is serving, shuts
+/// this process down.
+async fn upgrade(state: &ProxyState) -> http::Response<Vec<u8>> {
+    let binary = match &state.upgrade_binary {
+        Some(binary) => binary,
+        None => return response::make_http_error(http::StatusCode::NOT_FOUND),
+    };
+    if state.upgrading.swap(true, Ordering::Relaxed) {
+        return response::make_http_error(http::StatusCode::CONFLICT);
+    }
+    let listeners = state.upgrade_listeners.lock().clone();
+    match upgrade::spawn(binary, &listeners).await {
+        Ok(pid) => {
+            log::info!("Upgrade: pid {} is serving; shutting down", pid);
+            state.upgraded.notify();
+            json_response(&serde_json::json!({ "pid": pid }))
+        }
+        Err(err) => {
+            log::error!("Upgrade failed: {}", err);
+            state.upgrading.store(false, Ordering::Relaxed);
+            response::make_response(
+                http::StatusCode::INTERNAL_SERVER_ERROR,
+                "text/plain",
+                format!("Upgrade failed: {}\n", err).into_bytes(),
+            )
+        }
+    }
+}
+
+fn json_response(value: &serde_json::Value) -> http::Response<Vec<u8>> {
+    response::make_response(
+        http::StatusCode::OK,
+        "application/json",
+        value.to_string().into_bytes(),
+    )
+}
+
+/// Responds with the entries for an upstream, or 404 if there are none (no pool has it).
+fn json_or_not_found(entries: Vec<serde_json::Value>) -> http::Response<Vec<u8>> {
+    if entries.is_empty() {
+        response::make_http_error(http::StatusCode::NOT_FOUND)
+    } else {
+        json_response(&serde_json::Value::Array(entries))
+    }
+}
+
+async fn status(state: &ProxyState) -> serde_json::Value {
+    let now = Instant::now();
+    let mut upstreams = Vec::new();
+    for pool in &state.pools {
+        let upstreams_state = pool.upstreams_state.read().await;
+        for upstream in pool.upstreams.snapshot() {
+            let alive = upstreams_state.is_alive(upstream.id);
+            let phase = if alive && state.health_check_disabled.contains(&upstream.address) {
+                "health_check_disabled"
+            } else {
+                upstreams_state.phase(upstream.id, now)
+            };
+            upstreams.push(serde_json::json!({
+                "pool": pool.name,
+                "address": upstream.address,
+                "alive": alive,
+                "draining": upstreams_state.is_draining(upstream.id),
+                "state": phase,
+            }));
+        }
+    }
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_secs": state.started_at.elapsed().as_secs(),
+        "upstreams": upstreams,
+        "rate_limit": rate_limit_json(state),
+        "top_talkers": state.top_talkers.as_ref().map(top_talkers_json),
+        "traffic": {
+            "request_body_bytes": metrics::percentiles_json(&state.metrics.request_body_bytes()),
+            "response_body_bytes": metrics::percentiles_json(&state.metrics.response_body_bytes()),
+            "connection_lifetime_ms": metrics::percentiles_json(&state.connections.lifetimes()),
+        },
+    })
+}
+
+/// The rate limit mode, the scheduled profile in force if any, and the clients with the most
+/// requests over the limit in the last hour (whether or not they were rejected).
+fn rate_limit_json(state: &ProxyState) -> serde_json::Value {
+    let mode = if state.rate_limit_observe.load(Ordering::Relaxed) {
+        RateLimitMode::Observe
+    } else {
+        RateLimitMode::Enforce
+    };
+    let offenders: Vec<serde_json::Value> = state
+        .rate_limit_offenders
+        .top(TOP_OFFENDERS)
+        .into_iter()
+        .map(|(client, over_limit)| serde_json::json!({"client": client, "over_limit": over_limit}))
+        .collect();
+    let profile = state.rate_limit_schedule.active().map(|window| {
+        serde_json::json!({"name": window.name, "limit": window.limit})
+    });
+    serde_json::json!({"mode": mode.as_str(), "profile": profile, "top_offenders": offenders})
+}
+
+/// The clients and path prefixes with the most request and response body bytes over
+/// --top-k-retention. The counts can be over by up to `max_overcount_bytes`.
+fn top_talkers_json(top_talkers: &TopTalkers) -> serde_json::Value {
+    let entries = |ranked: Ranking, kind: &str| -> Vec<serde_json::Value> {
+        ranked
+            .into_iter()
+            .map(|(key, counter)| {
+                serde_json::json!({
+                    kind: key,
+                    "bytes": counter.bytes,
+                    "requests": counter.requests,
+                    "max_overcount_bytes": counter.error,
+                })
+            })
+            .collect()
+    };
+    let (clients, paths) = top_talkers.top(TOP_TALKERS);
+    serde_json::json!({"clients": entries(clients, "client"), "paths": entries(paths, "path")})
+}
+
+/// Switches between enforcing and observing the rate limit, given "enforce" or "observe". The
+/// limiters' counts carry on either way, so switching to enforce takes effect immediately for
+/// clients that are already over the limit.
+fn set_rate_limit_mode(state: &ProxyState, body: &[u8]) -> http::Response<Vec<u8>> {
+    let observe = match std::str::from_utf8(body).map(str::trim) {
+        Ok("enforce") => false,
+        Ok("observe") => true,
+        _ => return response::make_http_error(http::StatusCode::BAD_REQUEST),
+    };
+    if state.rate_limit_observe.swap(observe, Ordering::Relaxed) != observe {
+        log::info!(
+            "Rate limit is now {}",
+            if observe { "only observed" } else { "enforced" }
+        );
+    }
+    json_response(&rate_limit_json(state))
+}
+
+/// Everything we know about each upstream, for scripts that make decisions about the pool.
+async fn upstreams(state: &ProxyState) -> serde_json::Value {
+    let now = Instant::now();
+    let mut upstreams = Vec::new();
+    for pool in &state.pools {
+        let upstreams_state = pool.upstreams_state.read().await;
+        for upstream in pool.upstreams.snapshot() {
+            upstreams.push(upstream_json(pool, &upstreams_state, &upstream, now));
+        }
+    }
+    serde_json::Value::Array(upstreams)
+}
+
+/// The `GET /upstreams` entries for one address (one per pool that has it).
+async fn upstream(state: &ProxyState, address: &str) -> Vec<serde_json::Value> {
+    let now = Instant::now();
+    let mut entries = Vec::new();
+    for pool in &state.pools {
+        let upstreams_state = pool.upstreams_state.read().await;
+        if let Some(upstream) = pool.upstreams.find(address).and_then(|id| pool.upstreams.get(id)) {
+            entries.push(upstream_json(pool, &upstreams_state, &upstream, now));
+        }
+    }
+    entries
+}
+
+/// Takes an upstream out of every pool that has it. Requests it is already handling may finish
+/// within the removal grace period, and then their connections are closed; the returned entries
+/// show how many there are. After that the upstream is gone, and its slot is free.
+async fn remove_upstream(state: &ProxyState, address: &str) -> Vec<serde_json::Value> {
+    let now = Instant::now();
+    let mut entries = Vec::new();
+    for pool in &state.pools {
+        let mut upstreams_state = pool.upstreams_state.write().await;
+        if let Some(upstream) = pool.upstreams.find(address).and_then(|id| pool.upstreams.get(id)) {
+            if upstreams_state.set_removing(upstream.id) {
+                log::info!("Removing upstream {} from pool {}", address, pool.name);
+                let grace_period = state.removal_grace_period;
+                tokio::spawn(crate::drain_upstream(pool.clone(), upstream.id, grace_period));
+            }
+            entries.push(upstream_json(pool, &upstreams_state, &upstream, now));
+        }
+    }
+    entries
+}
+
+/// Adds an upstream to the pool named by the `pool` query parameter, which may be left out if there
+/// is only one. Adding an upstream the pool already has (and isn't removing) changes nothing. An
+/// upstream that was removed comes back with a new id (maybe in its old slot) and fresh counters.
+async fn add_upstream(
+    state: &ProxyState,
+    address: &str,
+    query: Option<&str>,
+) -> http::Response<Vec<u8>> {
+    // Form-encoded, as a pool called "api v2" is sent as pool=api+v2 or pool=api%20v2
+    let pool_name = query
+        .unwrap_or("")
+        .split('&')
+        .find_map(|param| param.strip_prefix("pool="))
+        .map(|name| request::percent_decode(&name.replace('+', " ")));
+    let pool = match pool_name {
+        Some(name) => state.pools.iter().find(|pool| pool.name.as_bytes() == name.as_slice()),
+        None if state.pools.len() == 1 => state.pools.first(),
+        None => return response::make_http_error(http::StatusCode::BAD_REQUEST),
+    };
+    let pool = match pool {
+        Some(pool) => pool,
+        None => return response::make_http_error(http::StatusCode::NOT_FOUND),
+    };
+    let (id, added) = pool
+        .add_upstream(
+            address,
+            priority::of(address, &state.upstream_priorities),
+            state.timeout_policy.initial(),
+        )
+        .await;
+    if added {
+        log::info!("Added upstream {} to pool {} as #{}", address, pool.name, id);
+    }
+    let upstreams_state = pool.upstreams_state.read().await;
+    match pool.upstreams.get(id) {
+        Some(upstream) => {
+            let entry = upstream_json(pool, &upstreams_state, &upstream, Instant::now());
+            json_response(&serde_json::Value::Array(vec![entry]))
+        }
+        // Removed again in the meantime
+        None => response::make_http_error(http::StatusCode::NOT_FOUND),
+    }
+}
+
+/// The open client connections, oldest first.
+fn connections(state: &ProxyState) -> serde_json::Value {
+    let now = Instant::now();
+    let connections = state.connections.snapshot();
+    serde_json::Value::Array(connections.iter().map(|c| connection_json(c, now)).collect())
+}
+
+/// Asks a client connection to close once it has sent the response it is working on (or straight
+/// away if it is idle). Responds with the connection, or 404 if it isn't open.
+fn close_connection(state: &ProxyState, id: &str) -> http::Response<Vec<u8>> {
+    let connection = id.parse().ok().and_then(|id| state.connections.close(id));
+    match connection {
+        Some(connection) => {
+            log::info!(
+                "Closing connection {} from {} as asked through the admin API",
+                connection.id,
+                connection.peer
+            );
+            json_response(&connection_json(&connection, Instant::now()))
+        }
+        None => response::make_http_error(http::StatusCode::NOT_FOUND),
+    }
+}
+
+fn connection_json(connection: &ConnectionInfo, now: Instant) -> serde_json::Value {
+    serde_json::json!({
+        "id": connection.id,
+        "peer": connection.peer.to_string(),
+        "listener": connection.listener,
+        "connected_secs": now.duration_since(connection.started_at).as_secs_f64(),
+        "idle_secs": now.duration_since(connection.last_activity).as_secs_f64(),
+        "requests": connection.requests,
+        "body_bytes_received": connection.body_bytes_received,
+        "body_bytes_sent": connection.body_bytes_sent,
+        // One of idle, reading, waiting-upstream or writing
+        "state": connection.state.as_str(),
+        "upstream": connection.upstream,
+        // Only kept with --debug-connection-tracking
+        "last_request": connection.last_request,
+        "closing": connection.closing,
+    })
+}
+
+fn upstream_json(
+    pool: &UpstreamPool,
+    upstreams_state: &UpstreamsState,
+    upstream: &Upstream,
+    now: Instant,
+) -> serde_json::Value {
+    let id = upstream.id;
+    let stats = &upstream.stats;
+    let last_minute = stats.last_minute();
+    let weight = upstreams_state.weights[id.index()];
+    let selectable = upstreams_state.is_alive(id) && !upstreams_state.is_ejected(id, now);
+    serde_json::json!({
+        "pool": pool.name,
+        "address": upstream.address,
+        "id": id.index(),
+        "alive": upstreams_state.is_alive(id),
+        // One of alive, ejected, draining, dead, removing or removed
+        "circuit_state": upstreams_state.phase(id, now),
+        "in_flight": stats.in_flight(),
+        "total_requests": stats.total_requests(),
+        "error_rate_1m": last_minute.error_rate(),
+        "p99_latency_ms": last_minute
+            .latency
+            .quantile(0.99)
+            .map(|latency| latency.as_millis() as u64),
+        "weight": weight,
+        // The weight upstream selection actually uses: nothing while the upstream is unavailable
+        "health_weight": if selectable { weight } else { 0.0 },
+    })
+}
@@ -0,0 +1,119 @@
+use crate::reuseport;
+use std::io;
+use std::str::FromStr;
+use tokio::net::TcpListener;
+use tokio::time::{delay_for, Duration};
+
+/// Longest wait between two attempts, however many there have been
+const MAX_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A `--bind-retry "attempts=10,interval=0.5"` option: how many more times to try binding a
+/// listener whose address is still in use (typically by the process this one replaces), and how
+/// many seconds to wait before the first retry. The wait doubles after each attempt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BindRetry {
+    pub attempts: u32,
+    pub interval: Duration,
+}
+
+impl FromStr for BindRetry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut attempts = None;
+        let mut interval = None;
+        for part in s.split(',') {
+            match part.trim().split_once('=') {
+                Some(("attempts", value)) => {
+                    let value = value.trim();
+                    let parsed = value.parse().map_err(|_| format!("bad attempts {:?}", value))?;
+                    attempts = Some(parsed);
+                }
+                Some(("interval", value)) => {
+                    let value = value.trim();
+                    let secs: f64 = value.parse().map_err(|_| format!("bad interval {:?}", value))?;
+                    if !secs.is_finite() || secs < 0.0 {
+                        return Err(format!("bad interval {:?}", value));
+                    }
+                    interval = Some(Duration::from_secs_f64(secs));
+                }
+                _ => return Err(format!("unrecognized bind retry component {:?}", part)),
+            }
+        }
+        match attempts {
+            Some(attempts) => Ok(BindRetry {
+                attempts,
+                interval: interval.unwrap_or(Duration::from_secs(1)),
+            }),
+            None => Err("bind retries need an attempts=".to_string()),
+        }
+    }
+}
+
+/// Binds a listener to `address`, with SO_REUSEPORT set if `reuse_port`. While the address is in
+/// use, tries again as often as `retry` allows; any other error is returned straight away. Tokio
+/// sets SO_REUSEADDR on the socket, so connections of an earlier process lingering in TIME_WAIT
+/// don't keep the address in use, but a process still listening on it does.
+pub async fn bind(
+    address: &str,
+    reuse_port: bool,
+    retry: Option<&BindRetry>,
+) -> io::Result<TcpListener> {
+    let attempts = retry.map_or(0, |retry| retry.attempts);
+    let mut interval = retry.map_or(Duration::default(), |retry| retry.interval);
+    let mut attempt = 0;
+    loop {
+        let bound = if reuse_port {
+            reuseport::bind(address).await
+        } else {
+            TcpListener::bind(address).await
+        };
+        match bound {
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse && attempt < attempts => {
+                attempt += 1;
+                log::warn!(
+                    "{} is in use; trying again in {:?} (retry {} of {})",
+                    address,
+                    interval,
+                    attempt,
+                    attempts
+                );
+                delay_for(interval).await;
+                interval = (interval * 2).min(MAX_INTERVAL);
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let retry: BindRetry = "attempts=5, interval=0.25".parse().unwrap();
+        assert_eq!(retry, BindRetry { attempts: 5, interval: Duration::from_millis(250) });
+        assert_eq!("attempts=3".parse::<BindRetry>().map(|retry| retry.interval.as_secs()), Ok(1));
+        assert!("interval=1".parse::<BindRetry>().is_err());
+        assert!("attempts=-1".parse::<BindRetry>().is_err());
+        assert!("attempts=3,interval=-1".parse::<BindRetry>().is_err());
+        assert!("attempts=3,backoff=2".parse::<BindRetry>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retries_until_the_address_is_free() {
+        let held = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = held.local_addr().unwrap().to_string();
+        assert_eq!(bind(&address, false, None).await.unwrap_err().kind(), io::ErrorKind::AddrInUse);
+
+        let retry = BindRetry { attempts: 10, interval: Duration::from_millis(50) };
+        let release = tokio::spawn(async move {
+            delay_for(Duration::from_millis(120)).await;
+            drop(held);
+        });
+        let listener = bind(&address, false, Some(&retry)).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap().to_string(), address);
+        release.await.unwrap();
+    }
+}
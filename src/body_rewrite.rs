@@ -0,0 +1,231 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A literal substitution made in upstream response bodies, parsed from
+/// `--rewrite-body "from=http://internal-host:8080,to=https://www.example.com"`. For backends that
+/// put their own absolute URLs in the pages they serve.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BodyRewrite {
+    from: String,
+    to: String,
+}
+
+impl FromStr for BodyRewrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut from = None;
+        let mut to = None;
+        for part in s.split(',') {
+            match part.trim().split_once('=') {
+                Some(("from", value)) => from = Some(value.trim().to_string()),
+                Some(("to", value)) => to = Some(value.trim().to_string()),
+                _ => return Err(format!("unrecognized body rewrite component \"{}\"", part)),
+            }
+        }
+        match (from, to) {
+            (Some(from), _) if from.is_empty() => Err("from= can't be empty".to_string()),
+            (Some(from), Some(to)) => Ok(BodyRewrite { from, to }),
+            _ => Err("a body rewrite needs both from= and to=".to_string()),
+        }
+    }
+}
+
+/// Why a response's body was left alone even though it might have contained something to rewrite.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Skipped {
+    /// The body is bigger than `--rewrite-body-max-bytes`
+    TooLarge,
+    /// The body is compressed, so the text can't be found in it
+    Compressed,
+    /// The body is framed by a transfer coding we relay as-is, so changing it would break the
+    /// framing
+    TransferEncoded,
+    /// The response is part of a larger body (206), which rewriting would make inconsistent
+    Partial,
+}
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Skipped::TooLarge => write!(f, "it is too large"),
+            Skipped::Compressed => write!(f, "it is compressed"),
+            Skipped::TransferEncoded => write!(f, "it has a Transfer-Encoding"),
+            Skipped::Partial => write!(f, "it is a partial response"),
+        }
+    }
+}
+
+/// Rewrites the bodies of responses with the configured content types. Off unless `--rewrite-body`
+/// is given: it changes what the backend sent, so it only touches responses it can rewrite safely.
+#[derive(Debug)]
+pub struct BodyRewriter {
+    rules: Vec<BodyRewrite>,
+    /// Media types (e.g. "text/html") whose bodies are rewritten, in lowercase
+    content_types: Vec<String>,
+    max_bytes: usize,
+    /// Whether a compressed response has been warned about, so the log isn't flooded
+    compressed_warned: AtomicBool,
+}
+
+impl BodyRewriter {
+    pub fn new(rules: Vec<BodyRewrite>, content_types: &[String], max_bytes: usize) -> Self {
+        BodyRewriter {
+            rules,
+            content_types: content_types
+                .iter()
+                .map(|content_type| content_type.trim().to_ascii_lowercase())
+                .collect(),
+            max_bytes,
+            compressed_warned: AtomicBool::new(false),
+        }
+    }
+
+    /// Logs what will be rewritten, so that nobody is surprised by it.
+    pub fn log_rules(&self) {
+        for rule in &self.rules {
+            log::warn!(
+                "Rewriting \"{}\" to \"{}\" in {} response bodies of up to {} bytes",
+                rule.from,
+                rule.to,
+                self.content_types.join(", "),
+                self.max_bytes
+            );
+        }
+    }
+
+    /// Makes every substitution in the response's body if it has one of the configured content
+    /// types, and fixes up its Content-Length. Returns how many substitutions were made, or why a
+    /// response that might have needed some was left alone.
+    pub fn rewrite(&self, response: &mut http::Response<Vec<u8>>) -> Result<usize, Skipped> {
+        if self.rules.is_empty() || response.body().is_empty() || !self.applies_to(response) {
+            return Ok(0);
+        }
+        if response.status() == http::StatusCode::PARTIAL_CONTENT {
+            return Err(Skipped::Partial);
+        }
+        if response.headers().contains_key(http::header::TRANSFER_ENCODING) {
+            return Err(Skipped::TransferEncoded);
+        }
+        let encoding = response.headers().get(http::header::CONTENT_ENCODING);
+        if encoding.is_some_and(|encoding| encoding != "identity") {
+            if !self.compressed_warned.swap(true, Ordering::Relaxed) {
+                log::warn!(
+                    "Not rewriting a compressed response body (further ones are not logged)"
+                );
+            }
+            return Err(Skipped::Compressed);
+        }
+        if response.body().len() > self.max_bytes {
+            return Err(Skipped::TooLarge);
+        }
+        let mut replaced = 0;
+        for rule in &self.rules {
+            let (from, to) = (rule.from.as_bytes(), rule.to.as_bytes());
+            let (body, count) = replace_all(response.body(), from, to);
+            if count > 0 {
+                *response.body_mut() = body;
+                replaced += count;
+            }
+        }
+        if replaced > 0 {
+            let length = http::HeaderValue::from(response.body().len());
+            response.headers_mut().insert(http::header::CONTENT_LENGTH, length);
+        }
+        Ok(replaced)
+    }
+
+    fn applies_to(&self, response: &http::Response<Vec<u8>>) -> bool {
+        let content_type = match response.headers().get(http::header::CONTENT_TYPE) {
+            Some(value) => value.to_str().unwrap_or(""),
+            None => return false,
+        };
+        let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        self.content_types.contains(&media_type)
+    }
+}
+
+/// Replaces every non-overlapping occurrence of `from` in `haystack`, returning the result and the
+/// number of occurrences.
+fn replace_all(haystack: &[u8], from: &[u8], to: &[u8]) -> (Vec<u8>, usize) {
+    let mut result = Vec::with_capacity(haystack.len());
+    let mut count = 0;
+    let mut rest = haystack;
+    while let Some(position) = rest.windows(from.len()).position(|window| window == from) {
+        result.extend_from_slice(&rest[..position]);
+        result.extend_from_slice(to);
+        rest = &rest[position + from.len()..];
+        count += 1;
+    }
+    result.extend_from_slice(rest);
+    (result, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewriter(max_bytes: usize) -> BodyRewriter {
+        let rule = "from=http://internal-host:8080,to=https://www.example.com".parse().unwrap();
+        BodyRewriter::new(vec![rule], &["text/html".to_string()], max_bytes)
+    }
+
+    fn response(content_type: &str, body: &str) -> http::Response<Vec<u8>> {
+        http::Response::builder()
+            .header("content-type", content_type)
+            .header("content-length", body.len())
+            .body(body.as_bytes().to_vec())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!("from=http://a:1,to=".parse::<BodyRewrite>().is_ok());
+        assert!("from=http://a:1".parse::<BodyRewrite>().is_err());
+        assert!("from=,to=http://b".parse::<BodyRewrite>().is_err());
+        assert!("from=a,to=b,also=c".parse::<BodyRewrite>().is_err());
+    }
+
+    #[test]
+    fn test_rewrite_occurrences() {
+        let rewriter = rewriter(1024);
+        let mut page = response(
+            "text/html; charset=utf-8",
+            r#"<a href="http://internal-host:8080/a"><img src="http://internal-host:8080/b">"#,
+        );
+        assert_eq!(rewriter.rewrite(&mut page), Ok(2));
+        let body = r#"<a href="https://www.example.com/a"><img src="https://www.example.com/b">"#;
+        assert_eq!(page.body(), body.as_bytes());
+        assert_eq!(page.headers()["content-length"], body.len().to_string().as_str());
+
+        let mut untouched = response("text/html", "<p>nothing to see</p>");
+        assert_eq!(rewriter.rewrite(&mut untouched), Ok(0));
+        assert_eq!(untouched.body(), b"<p>nothing to see</p>");
+    }
+
+    #[test]
+    fn test_rewrite_gates() {
+        let rewriter = rewriter(64);
+        let link = "http://internal-host:8080/";
+
+        let mut json = response("application/json", link);
+        assert_eq!(rewriter.rewrite(&mut json), Ok(0));
+        assert_eq!(json.body(), link.as_bytes());
+
+        let mut large = response("text/html", &link.repeat(4));
+        assert_eq!(rewriter.rewrite(&mut large), Err(Skipped::TooLarge));
+
+        let mut compressed = response("text/html", link);
+        compressed.headers_mut().insert("content-encoding", "gzip".parse().unwrap());
+        assert_eq!(rewriter.rewrite(&mut compressed), Err(Skipped::Compressed));
+
+        let mut chunked = response("text/html", link);
+        chunked.headers_mut().insert("transfer-encoding", "chunked".parse().unwrap());
+        assert_eq!(rewriter.rewrite(&mut chunked), Err(Skipped::TransferEncoded));
+
+        let mut partial = response("text/html", link);
+        *partial.status_mut() = http::StatusCode::PARTIAL_CONTENT;
+        assert_eq!(rewriter.rewrite(&mut partial), Err(Skipped::Partial));
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Keeps count of the bytes held in request and response bodies across all connections, and
+/// refuses to let the total go over `--max-buffered-bytes`. Running out of budget costs one request
+/// a 503; running out of memory would cost every connection.
+#[derive(Debug, Default)]
+pub struct BufferBudget {
+    /// Most bytes that may be held at once, if there is a limit
+    limit: Option<usize>,
+    held: AtomicUsize,
+    /// Most bytes that have been held at once since startup
+    peak: AtomicUsize,
+}
+
+impl BufferBudget {
+    pub fn new(limit: Option<usize>) -> BufferBudget {
+        BufferBudget {
+            limit,
+            ..Default::default()
+        }
+    }
+
+    /// Starts accounting for a request's buffers. The guard holds nothing until it is grown.
+    pub fn guard(&self) -> BufferGuard<'_> {
+        BufferGuard {
+            budget: self,
+            bytes: 0,
+        }
+    }
+
+    /// Bytes currently held in buffers.
+    pub fn held(&self) -> usize {
+        self.held.load(Ordering::Relaxed)
+    }
+
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    fn try_take(&self, bytes: usize) -> bool {
+        let taken = self
+            .held
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |held| {
+                let total = held.checked_add(bytes)?;
+                match self.limit {
+                    Some(limit) if total > limit => None,
+                    _ => Some(total),
+                }
+            });
+        match taken {
+            Ok(held) => {
+                self.peak.fetch_max(held + bytes, Ordering::Relaxed);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Bytes taken from a `BufferBudget` for one request's buffers, given back when the guard is
+/// dropped, so that no error path can leak them.
+#[derive(Debug)]
+pub struct BufferGuard<'a> {
+    budget: &'a BufferBudget,
+    bytes: usize,
+}
+
+impl BufferGuard<'_> {
+    /// Takes `bytes` more from the budget before a buffer grows by that much. Returns false, taking
+    /// nothing, if that would go over the limit.
+    pub fn grow(&mut self, bytes: usize) -> bool {
+        if !self.budget.try_take(bytes) {
+            return false;
+        }
+        self.bytes += bytes;
+        true
+    }
+}
+
+impl Drop for BufferGuard<'_> {
+    fn drop(&mut self) {
+        self.budget.held.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guards_stay_under_limit() {
+        let budget = BufferBudget::new(Some(100));
+        let mut first = budget.guard();
+        assert!(first.grow(60));
+        let mut second = budget.guard();
+        assert!(!second.grow(50));
+        assert!(second.grow(40));
+        assert!(!first.grow(1));
+        assert_eq!(budget.held(), 100);
+
+        drop(first);
+        assert_eq!(budget.held(), 40);
+        assert!(second.grow(50));
+        drop(second);
+        assert_eq!(budget.held(), 0);
+        assert_eq!(budget.peak(), 100);
+    }
+
+    #[test]
+    fn test_unlimited_budget_still_counts() {
+        let budget = BufferBudget::new(None);
+        let mut guard = budget.guard();
+        assert!(guard.grow(1 << 40));
+        assert_eq!(budget.held(), 1 << 40);
+        drop(guard);
+        assert_eq!((budget.held(), budget.peak()), (0, 1 << 40));
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Headers whose values are replaced with "[REDACTED]" in capture files unless redaction is
+/// turned off.
+const REDACTED_HEADERS: [&str; 4] = ["authorization", "proxy-authorization", "cookie", "set-cookie"];
+
+/// Suffix identifying files written by DebugCapture, so that files already in the directory from
+/// a previous run count towards the file limit.
+const CAPTURE_SUFFIX: &str = ".capture";
+
+/// Which exchanges get written to disk.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq)]
+pub enum CaptureTrigger {
+    /// Requests that couldn't be parsed
+    ParseError,
+    /// Exchanges where the client received a 5xx response
+    #[clap(name = "5xx")]
+    ServerError,
+    /// Every exchange
+    All,
+}
+
+/// Dumps the raw bytes of problematic exchanges into a directory, for reproducing parser bugs.
+/// Disk usage is bounded: at most `max_files` capture files of at most `max_bytes` per direction
+/// are kept, and the oldest files are deleted to make room for new ones.
+pub struct DebugCapture {
+    dir: PathBuf,
+    trigger: CaptureTrigger,
+    max_bytes: usize,
+    max_files: usize,
+    redact: bool,
+    /// Capture files kept, oldest first
+    files: Arc<Mutex<VecDeque<PathBuf>>>,
+}
+
+impl DebugCapture {
+    pub fn new(
+        dir: &Path,
+        trigger: CaptureTrigger,
+        max_bytes: usize,
+        max_files: usize,
+        redact: bool,
+    ) -> std::io::Result<DebugCapture> {
+        std::fs::create_dir_all(dir)?;
+        // File names start with a timestamp, so sorting them puts the oldest first
+        let mut existing: Vec<PathBuf> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.to_string_lossy().ends_with(CAPTURE_SUFFIX))
+            .collect();
+        existing.sort();
+        let mut files = existing.into();
+        enforce_file_limit(&mut files, max_files, 0);
+        Ok(DebugCapture {
+            dir: dir.to_path_buf(),
+            trigger,
+            max_bytes,
+            max_files,
+            redact,
+            files: Arc::new(Mutex::new(files)),
+        })
+    }
+
+    /// Returns true if an exchange with this outcome should be captured.
+    pub fn should_capture(&self, parse_error: bool, status: http::StatusCode) -> bool {
+        match self.trigger {
+            CaptureTrigger::ParseError => parse_error,
+            CaptureTrigger::ServerError => status.is_server_error(),
+            CaptureTrigger::All => true,
+        }
+    }
+
+    /// Writes a capture file for one exchange. Failures are logged rather than returned, since
+    /// capturing must never interfere with serving the request. The file system is only touched
+    /// on the blocking thread pool.
+    pub async fn write(&self, request_id: u64, raw_request: &[u8], raw_response: &[u8]) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = self
+            .dir
+            .join(format!("{:013}-req{}{}", timestamp, request_id, CAPTURE_SUFFIX));
+
+        let mut contents = b"=== request ===\n".to_vec();
+        contents.extend(self.prepare(raw_request));
+        contents.extend_from_slice(b"\n=== response ===\n");
+        contents.extend(self.prepare(raw_response));
+
+        let files = self.files.clone();
+        let max_files = self.max_files;
+        let written = tokio::task::spawn_blocking(move || {
+            let mut files = files.lock().unwrap();
+            enforce_file_limit(&mut files, max_files, 1);
+            let written = std::fs::write(&path, contents);
+            if written.is_ok() {
+                files.push_back(path.clone());
+            }
+            (path, written)
+        })
+        .await;
+        match written {
+            Ok((path, Ok(()))) => {
+                log::debug!("Captured exchange for request {} to {:?}", request_id, path)
+            }
+            Ok((path, Err(err))) => log::warn!("Failed to write debug capture {:?}: {}", path, err),
+            Err(err) => log::warn!("Failed to write debug capture: {}", err),
+        }
+    }
+
+    /// Truncates raw bytes to the size limit and redacts secrets.
+    fn prepare(&self, raw: &[u8]) -> Vec<u8> {
+        let raw = &raw[..raw.len().min(self.max_bytes)];
+        if self.redact {
+            redact_headers(raw)
+        } else {
+            raw.to_vec()
+        }
+    }
+}
+
+/// Deletes the oldest capture files until there is room for `incoming` new ones.
+fn enforce_file_limit(files: &mut VecDeque<PathBuf>, max_files: usize, incoming: usize) {
+    while !files.is_empty() && files.len() + incoming > max_files {
+        let oldest = files.pop_front().unwrap();
+        if let Err(err) = std::fs::remove_file(&oldest) {
+            log::warn!("Failed to remove old debug capture {:?}: {}", oldest, err);
+        }
+    }
+}
+
+/// Replaces the values of secret-bearing headers in a raw HTTP message. Only the header section
+/// (up to the first blank line) is touched; the body is passed through unchanged.
+fn redact_headers(raw: &[u8]) -> Vec<u8> {
+    let headers_end = raw
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|pos| pos + 2)
+        .unwrap_or(raw.len());
+    let mut out = Vec::with_capacity(raw.len());
+    for line in raw[..headers_end].split_inclusive(|&b| b == b'\n') {
+        let name_end = line.iter().position(|&b| b == b':');
+        let is_secret = name_end
+            .map(|end| {
+                let name = String::from_utf8_lossy(&line[..end]);
+                REDACTED_HEADERS.contains(&name.trim().to_ascii_lowercase().as_str())
+            })
+            .unwrap_or(false);
+        if is_secret {
+            out.extend_from_slice(&line[..=name_end.unwrap()]);
+            out.extend_from_slice(b" [REDACTED]");
+            if line.ends_with(b"\r\n") {
+                out.extend_from_slice(b"\r\n");
+            } else if line.ends_with(b"\n") {
+                out.extend_from_slice(b"\n");
+            }
+        } else {
+            out.extend_from_slice(line);
+        }
+    }
+    out.extend_from_slice(&raw[headers_end..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_headers() {
+        let raw = b"GET / HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer hunter2\r\n\
+                    cookie: a=b\r\n\r\nAuthorization: in the body";
+        let redacted = String::from_utf8(redact_headers(raw)).unwrap();
+        assert_eq!(
+            redacted,
+            "GET / HTTP/1.1\r\nHost: x\r\nAuthorization: [REDACTED]\r\ncookie: [REDACTED]\r\n\
+             \r\nAuthorization: in the body"
+        );
+    }
+
+    #[test]
+    fn test_redact_incomplete_headers() {
+        let redacted = redact_headers(b"GET / HTTP/1.1\r\nCookie: secret");
+        assert_eq!(redacted, b"GET / HTTP/1.1\r\nCookie: [REDACTED]".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_file_limit() {
+        let dir = std::env::temp_dir().join(format!("balancebeam-capture-{}", std::process::id()));
+        let capture = DebugCapture::new(&dir, CaptureTrigger::All, 10, 3, true).unwrap();
+        for request_id in 0..5 {
+            capture.write(request_id, b"0123456789abcdef", b"").await;
+        }
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 3);
+        assert!(names[2].ends_with("req4.capture"));
+        let contents = std::fs::read(dir.join(&names[2])).unwrap();
+        assert!(String::from_utf8_lossy(&contents).contains("0123456789\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
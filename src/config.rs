@@ -0,0 +1,336 @@
+use crate::rate_limiter::ArgRateLimiter;
+use clap::ArgEnum;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// A named set of upstreams. Each pool is health checked once, however many listeners use it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolConfig {
+    pub name: String,
+    pub upstreams: Vec<String>,
+    pub active_health_check_interval: usize,
+    pub active_health_check_path: String,
+    /// Statuses that count as healthy in an active health check
+    pub active_health_check_success_codes: HashSet<u16>,
+    /// Host header to send upstreams in place of the client's
+    pub host_override: Option<String>,
+}
+
+/// An address to accept client connections on, with its own rate limit and upstream pool.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenerConfig {
+    pub bind: String,
+    /// Index into `Config::pools`
+    pub pool: usize,
+    pub max_requests_per_minute: usize,
+    pub rate_limiter: ArgRateLimiter,
+}
+
+/// The listeners and upstream pools balancebeam serves. This comes either from a --config file or
+/// from the single-listener command-line options.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub pools: Vec<PoolConfig>,
+    pub listeners: Vec<ListenerConfig>,
+}
+
+/// Values for settings a config file leaves out, taken from the command line.
+pub struct Defaults<'a> {
+    pub active_health_check_interval: usize,
+    pub active_health_check_path: &'a str,
+    pub active_health_check_success_codes: &'a HashSet<u16>,
+    pub rate_limiter: ArgRateLimiter,
+    pub host_override: Option<&'a str>,
+}
+
+/// Parses a comma-separated list of HTTP status codes, e.g. "200,204,301".
+pub fn parse_status_codes(value: &str) -> Result<HashSet<u16>, String> {
+    value
+        .split(',')
+        .map(|code| match code.trim().parse::<u16>() {
+            Ok(code) if (100..600).contains(&code) => Ok(code),
+            _ => Err(format!("\"{}\" is not an HTTP status code", code.trim())),
+        })
+        .collect()
+}
+
+/// The form upstream addresses are kept and compared in, whichever option or API named them: host
+/// names are case-insensitive, so they are lowercased.
+pub fn normalize_address(address: &str) -> String {
+    address.trim().to_ascii_lowercase()
+}
+
+/// Normalizes upstream addresses and drops repeats, keeping the first of each in order. Listing an
+/// upstream twice would otherwise give it twice the traffic. Each repeat is logged.
+pub fn dedup_upstreams(pool: &str, upstreams: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut unique = Vec::with_capacity(upstreams.len());
+    for upstream in upstreams {
+        let normalized = normalize_address(&upstream);
+        if seen.insert(normalized.clone()) {
+            unique.push(normalized);
+        } else {
+            log::warn!("Ignoring duplicate upstream {} in pool {}", upstream, pool);
+        }
+    }
+    unique
+}
+
+impl Config {
+    /// The configuration given by --bind and --upstream: one listener and one pool.
+    pub fn single_listener(
+        bind: &str,
+        upstreams: Vec<String>,
+        max_requests_per_minute: usize,
+        defaults: &Defaults,
+    ) -> Config {
+        Config {
+            pools: vec![PoolConfig {
+                name: "default".to_string(),
+                upstreams: dedup_upstreams("default", upstreams),
+                active_health_check_interval: defaults.active_health_check_interval,
+                active_health_check_path: defaults.active_health_check_path.to_string(),
+                active_health_check_success_codes: defaults
+                    .active_health_check_success_codes
+                    .clone(),
+                host_override: defaults.host_override.map(str::to_string),
+            }],
+            listeners: vec![ListenerConfig {
+                bind: bind.to_string(),
+                pool: 0,
+                max_requests_per_minute,
+                rate_limiter: defaults.rate_limiter,
+            }],
+        }
+    }
+
+    pub fn load(path: &Path, defaults: &Defaults) -> Result<Config, String> {
+        let text = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+        Config::parse(&text, defaults)
+    }
+
+    /// Parses a JSON config file of the form
+    ///
+    /// ```text
+    /// {
+    ///   "pools": {
+    ///     "web": {
+    ///       "upstreams": ["10.0.0.1:80"],
+    ///       "active_health_check_path": "/health",
+    ///       "active_health_check_success_codes": [200, 204],
+    ///       "upstream_http_host_override": "web.internal"
+    ///     }
+    ///   },
+    ///   "listeners": [
+    ///     {"bind": "0.0.0.0:80", "pool": "web", "max_requests_per_minute": 60}
+    ///   ]
+    /// }
+    /// ```
+    pub fn parse(text: &str, defaults: &Defaults) -> Result<Config, String> {
+        let root: Value = serde_json::from_str(text).map_err(|err| err.to_string())?;
+
+        let mut pools = Vec::new();
+        let pool_values = root
+            .get("pools")
+            .and_then(Value::as_object)
+            .ok_or("\"pools\" must be an object")?;
+        for (name, pool) in pool_values {
+            let upstreams: Vec<String> = pool
+                .get("upstreams")
+                .and_then(Value::as_array)
+                .map(|upstreams| {
+                    upstreams.iter().filter_map(Value::as_str).map(str::to_string).collect()
+                })
+                .unwrap_or_default();
+            if upstreams.is_empty() {
+                return Err(format!("pool \"{}\" has no upstreams", name));
+            }
+            pools.push(PoolConfig {
+                name: name.clone(),
+                upstreams: dedup_upstreams(name, upstreams),
+                active_health_check_interval: get_usize(pool, "active_health_check_interval")?
+                    .unwrap_or(defaults.active_health_check_interval),
+                active_health_check_path: pool
+                    .get("active_health_check_path")
+                    .and_then(Value::as_str)
+                    .unwrap_or(defaults.active_health_check_path)
+                    .to_string(),
+                active_health_check_success_codes: match pool
+                    .get("active_health_check_success_codes")
+                {
+                    Some(codes) => get_status_codes(codes)
+                        .ok_or_else(|| format!("pool \"{}\": invalid success codes", name))?,
+                    None => defaults.active_health_check_success_codes.clone(),
+                },
+                host_override: pool
+                    .get("upstream_http_host_override")
+                    .and_then(Value::as_str)
+                    .or(defaults.host_override)
+                    .map(str::to_string),
+            });
+        }
+
+        let mut listeners = Vec::new();
+        let listener_values = root
+            .get("listeners")
+            .and_then(Value::as_array)
+            .ok_or("\"listeners\" must be an array")?;
+        for listener in listener_values {
+            let bind = listener
+                .get("bind")
+                .and_then(Value::as_str)
+                .ok_or("every listener needs a \"bind\" address")?;
+            if listener.get("tls").is_some() {
+                return Err(format!("listener {}: TLS is not supported", bind));
+            }
+            let pool_name = listener
+                .get("pool")
+                .and_then(Value::as_str)
+                .ok_or_else(|| format!("listener {} has no \"pool\"", bind))?;
+            let pool = pools
+                .iter()
+                .position(|pool| pool.name == pool_name)
+                .ok_or_else(|| format!("listener {} uses unknown pool \"{}\"", bind, pool_name))?;
+            let rate_limiter = match listener.get("rate_limiter").and_then(Value::as_str) {
+                Some(name) => ArgRateLimiter::from_str(name, false)
+                    .map_err(|err| format!("listener {}: {}", bind, err))?,
+                None => defaults.rate_limiter,
+            };
+            listeners.push(ListenerConfig {
+                bind: bind.to_string(),
+                pool,
+                max_requests_per_minute: get_usize(listener, "max_requests_per_minute")?
+                    .unwrap_or(0),
+                rate_limiter,
+            });
+        }
+        if listeners.is_empty() {
+            return Err("at least one listener is required".to_string());
+        }
+        Ok(Config { pools, listeners })
+    }
+}
+
+fn get_usize(object: &Value, key: &str) -> Result<Option<usize>, String> {
+    match object.get(key) {
+        None => Ok(None),
+        Some(value) => value
+            .as_u64()
+            .map(|n| Some(n as usize))
+            .ok_or_else(|| format!("\"{}\" must be a non-negative integer", key)),
+    }
+}
+
+fn get_status_codes(value: &Value) -> Option<HashSet<u16>> {
+    value
+        .as_array()?
+        .iter()
+        .map(|code| match code.as_u64() {
+            Some(code) if (100..600).contains(&code) => Some(code as u16),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config, String> {
+        let success_codes = [200].iter().copied().collect();
+        let defaults = Defaults {
+            active_health_check_interval: 10,
+            active_health_check_path: "/",
+            active_health_check_success_codes: &success_codes,
+            rate_limiter: ArgRateLimiter::FixedWindow,
+            host_override: None,
+        };
+        Config::parse(text, &defaults)
+    }
+
+    #[test]
+    fn test_parse_two_listeners() {
+        let config = parse(
+            r#"{
+                "pools": {
+                    "internal": {"upstreams": ["10.0.0.1:80", "10.0.0.2:80"]},
+                    "public": {
+                        "upstreams": ["10.0.1.1:80"],
+                        "active_health_check_path": "/up",
+                        "active_health_check_success_codes": [204, 301],
+                        "upstream_http_host_override": "public.internal"
+                    }
+                },
+                "listeners": [
+                    {"bind": "10.1.1.1:80", "pool": "internal"},
+                    {"bind": "0.0.0.0:80", "pool": "public", "max_requests_per_minute": 60}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.pools.len(), 2);
+        let public = &config.listeners[1];
+        assert_eq!(config.pools[public.pool].name, "public");
+        assert_eq!(config.pools[public.pool].active_health_check_path, "/up");
+        assert_eq!(config.pools[public.pool].active_health_check_interval, 10);
+        assert_eq!(
+            config.pools[public.pool].active_health_check_success_codes,
+            [204, 301].iter().copied().collect()
+        );
+        let internal = &config.pools[config.listeners[0].pool];
+        assert_eq!(internal.active_health_check_success_codes, [200].iter().copied().collect());
+        assert_eq!(config.pools[public.pool].host_override.as_deref(), Some("public.internal"));
+        assert_eq!(internal.host_override, None);
+        assert_eq!(public.max_requests_per_minute, 60);
+        assert_eq!(config.listeners[0].max_requests_per_minute, 0);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let cases = [
+            r#"{"pools": {}, "listeners": []}"#,
+            r#"{"pools": {"a": {"upstreams": []}}, "listeners": [{"bind": "x", "pool": "a"}]}"#,
+            r#"{"pools": {"a": {"upstreams": ["u"]}}, "listeners": [{"bind": "x", "pool": "b"}]}"#,
+            r#"{"pools": {"a": {"upstreams": ["u"]}},
+                "listeners": [{"bind": "x", "pool": "a", "tls": {"cert": "c.pem"}}]}"#,
+            r#"{"pools": {"a": {"upstreams": ["u"]}},
+                "listeners": [{"bind": "x", "pool": "a", "max_requests_per_minute": -1}]}"#,
+            r#"{"pools": {"a": {"upstreams": ["u"], "active_health_check_success_codes": [99]}},
+                "listeners": [{"bind": "x", "pool": "a"}]}"#,
+            "not json",
+        ];
+        for case in cases.iter() {
+            assert!(parse(case).is_err(), "{} should fail", case);
+        }
+    }
+
+    #[test]
+    fn test_dedup_upstreams() {
+        let upstreams = ["host1:8080", "10.0.0.1:80", "HOST1:8080", "host1:8080", "Host2:80"];
+        assert_eq!(
+            dedup_upstreams("default", upstreams.iter().map(|u| u.to_string()).collect()),
+            vec!["host1:8080", "10.0.0.1:80", "host2:80"]
+        );
+
+        let config = parse(
+            r#"{
+                "pools": {"a": {"upstreams": ["u:1", "U:1"]}},
+                "listeners": [{"bind": "x", "pool": "a"}]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.pools[0].upstreams, vec!["u:1"]);
+    }
+
+    #[test]
+    fn test_parse_status_codes() {
+        assert_eq!(
+            parse_status_codes("200, 204,301"),
+            Ok([200, 204, 301].iter().copied().collect())
+        );
+        assert!(parse_status_codes("200,").is_err());
+        assert!(parse_status_codes("600").is_err());
+        assert!(parse_status_codes("ok").is_err());
+    }
+}
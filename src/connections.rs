@@ -0,0 +1,320 @@
+use crate::histogram::{Histogram, LIFETIME_BOUNDS_MS};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::Notify;
+
+/// How many locks the registry spreads connections over, so that busy connections updating their
+/// own entries don't all queue on one lock.
+const SHARDS: usize = 16;
+
+/// What a client connection is doing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    /// Waiting for the client to send another request
+    Idle,
+    /// Reading a request from the client
+    Reading,
+    /// Waiting on an upstream to take the request and answer it
+    WaitingUpstream,
+    /// Sending a response to the client
+    Writing,
+}
+
+impl ConnectionState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConnectionState::Idle => "idle",
+            ConnectionState::Reading => "reading",
+            ConnectionState::WaitingUpstream => "waiting-upstream",
+            ConnectionState::Writing => "writing",
+        }
+    }
+}
+
+/// A client connection that is currently open.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub peer: SocketAddr,
+    /// Bind address of the listener that accepted the connection
+    pub listener: String,
+    pub started_at: Instant,
+    /// When a request was last read from or a response last written to this connection
+    pub last_activity: Instant,
+    /// Request line of the latest request, if the registry records them
+    pub last_request: Option<String>,
+    /// Requests read from the connection
+    pub requests: u64,
+    /// Bytes of request bodies read from the connection
+    pub body_bytes_received: u64,
+    /// Bytes of response bodies written to the connection
+    pub body_bytes_sent: u64,
+    pub state: ConnectionState,
+    /// Address of the upstream the connection's requests are going to, while it holds one
+    pub upstream: Option<String>,
+    /// Whether the connection has been asked to close
+    pub closing: bool,
+}
+
+/// A registered connection, and the signal that asks it to close.
+#[derive(Debug)]
+struct Entry {
+    info: ConnectionInfo,
+    close: Arc<CloseSignal>,
+}
+
+/// Asks a connection's task to close the connection once it has finished its current response.
+#[derive(Debug, Default)]
+struct CloseSignal {
+    requested: AtomicBool,
+    notify: Notify,
+}
+
+/// Keeps track of every open client connection, so that diagnostics and the admin API can list
+/// them, and the admin API can close them.
+#[derive(Debug)]
+pub struct ConnectionRegistry {
+    next_id: AtomicU64,
+    /// Connections by id, in shard `id % SHARDS`
+    shards: Vec<Mutex<HashMap<u64, Entry>>>,
+    /// Whether to keep each connection's latest request line (--debug-connection-tracking)
+    record_requests: bool,
+    /// How long closed connections were open, in milliseconds
+    lifetimes: Mutex<Histogram>,
+    /// Connections open now, and the most that have been open at once
+    open: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl Default for ConnectionRegistry {
+    fn default() -> ConnectionRegistry {
+        ConnectionRegistry::new(false)
+    }
+}
+
+impl ConnectionRegistry {
+    pub fn new(record_requests: bool) -> ConnectionRegistry {
+        ConnectionRegistry {
+            next_id: AtomicU64::new(0),
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            record_requests,
+            lifetimes: Mutex::new(Histogram::new(&LIFETIME_BOUNDS_MS)),
+            open: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    /// Lifetimes of the connections closed since startup, in milliseconds.
+    pub fn lifetimes(&self) -> Histogram {
+        self.lifetimes.lock().unwrap().clone()
+    }
+
+    /// The most connections that have been open at once since startup.
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap().len()).sum()
+    }
+
+    fn shard(&self, id: u64) -> &Mutex<HashMap<u64, Entry>> {
+        &self.shards[id as usize % SHARDS]
+    }
+
+    /// Adds a connection to the registry. It stays there until the returned handle is dropped.
+    pub fn register(&self, peer: SocketAddr, listener: &str) -> ConnectionHandle<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let close = Arc::new(CloseSignal::default());
+        let info = ConnectionInfo {
+            id,
+            peer,
+            listener: listener.to_string(),
+            started_at: now,
+            last_activity: now,
+            last_request: None,
+            requests: 0,
+            body_bytes_received: 0,
+            body_bytes_sent: 0,
+            state: ConnectionState::Idle,
+            upstream: None,
+            closing: false,
+        };
+        let entry = Entry {
+            info,
+            close: close.clone(),
+        };
+        self.shard(id).lock().unwrap().insert(id, entry);
+        let open = self.open.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak.fetch_max(open, Ordering::Relaxed);
+        ConnectionHandle {
+            registry: self,
+            id,
+            close,
+        }
+    }
+
+    /// Returns the open connections, oldest first.
+    pub fn snapshot(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<ConnectionInfo> = Vec::new();
+        for shard in &self.shards {
+            connections.extend(shard.lock().unwrap().values().map(|entry| entry.info.clone()));
+        }
+        connections.sort_by_key(|connection| connection.id);
+        connections
+    }
+
+    /// Asks a connection to close once it has sent the response it is working on, if any. Returns
+    /// the connection, or None if there is no open connection with that id.
+    pub fn close(&self, id: u64) -> Option<ConnectionInfo> {
+        let mut shard = self.shard(id).lock().unwrap();
+        let entry = shard.get_mut(&id)?;
+        entry.info.closing = true;
+        entry.close.requested.store(true, Ordering::Relaxed);
+        // Wakes the connection if it is idle, or leaves a permit for its next wait if not
+        entry.close.notify.notify();
+        Some(entry.info.clone())
+    }
+}
+
+/// Registration of one connection; dropping it removes the connection from the registry.
+pub struct ConnectionHandle<'a> {
+    registry: &'a ConnectionRegistry,
+    id: u64,
+    close: Arc<CloseSignal>,
+}
+
+impl ConnectionHandle<'_> {
+    fn update<F: FnOnce(&mut ConnectionInfo)>(&self, f: F) {
+        if let Some(entry) = self.registry.shard(self.id).lock().unwrap().get_mut(&self.id) {
+            f(&mut entry.info);
+        }
+    }
+
+    pub fn set_state(&self, state: ConnectionState) {
+        self.update(|connection| connection.state = state);
+    }
+
+    /// Records which upstream the connection's requests are going to, if any.
+    pub fn set_upstream(&self, upstream: Option<&str>) {
+        self.update(|connection| connection.upstream = upstream.map(str::to_string));
+    }
+
+    /// Records a request read from the connection. The request line is only built if the
+    /// registry keeps it.
+    pub fn record_request<F: FnOnce() -> String>(&self, body_bytes: usize, request_line: F) {
+        let request_line = if self.registry.record_requests { Some(request_line()) } else { None };
+        self.update(|connection| {
+            connection.last_activity = Instant::now();
+            connection.requests += 1;
+            connection.body_bytes_received += body_bytes as u64;
+            if request_line.is_some() {
+                connection.last_request = request_line;
+            }
+        });
+    }
+
+    /// Records a response written to the connection.
+    pub fn record_response(&self, body_bytes: usize) {
+        self.update(|connection| {
+            connection.last_activity = Instant::now();
+            connection.body_bytes_sent += body_bytes as u64;
+        });
+    }
+
+    /// Whether the connection has been asked to close.
+    pub fn close_requested(&self) -> bool {
+        self.close.requested.load(Ordering::Relaxed)
+    }
+
+    /// Waits until the connection is asked to close.
+    pub async fn closed(&self) {
+        if !self.close_requested() {
+            self.close.notify.notified().await;
+        }
+    }
+}
+
+impl Drop for ConnectionHandle<'_> {
+    fn drop(&mut self) {
+        self.registry.open.fetch_sub(1, Ordering::Relaxed);
+        let removed = self.registry.shard(self.id).lock().unwrap().remove(&self.id);
+        if let Some(entry) = removed {
+            self.registry.lifetimes.lock().unwrap().record(entry.info.started_at.elapsed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connections_are_removed_when_dropped() {
+        let registry = ConnectionRegistry::default();
+        let first = registry.register("10.0.0.1:5000".parse().unwrap(), "0.0.0.0:80");
+        let second = registry.register("10.0.0.2:5000".parse().unwrap(), "0.0.0.0:80");
+        let peers: Vec<SocketAddr> = registry.snapshot().iter().map(|c| c.peer).collect();
+        assert_eq!(
+            peers,
+            vec!["10.0.0.1:5000".parse().unwrap(), "10.0.0.2:5000".parse().unwrap()]
+        );
+
+        let before = registry.snapshot()[1].last_activity;
+        second.record_response(10);
+        assert!(registry.snapshot()[1].last_activity >= before);
+        assert_eq!(registry.snapshot()[1].body_bytes_sent, 10);
+
+        drop(first);
+        let peers: Vec<SocketAddr> = registry.snapshot().iter().map(|c| c.peer).collect();
+        assert_eq!(peers, vec!["10.0.0.2:5000".parse().unwrap()]);
+        let third = registry.register("10.0.0.3:5000".parse().unwrap(), "0.0.0.0:80");
+        drop(second);
+        drop(third);
+        assert!(registry.snapshot().is_empty());
+        assert_eq!(registry.lifetimes().count(), 3);
+        assert_eq!(registry.peak(), 2);
+    }
+
+    #[test]
+    fn test_request_lines_are_only_kept_if_enabled() {
+        let registry = ConnectionRegistry::default();
+        let connection = registry.register("10.0.0.1:5000".parse().unwrap(), "0.0.0.0:80");
+        connection.record_request(0, || panic!("request line built while not recording"));
+        assert_eq!(registry.snapshot()[0].last_request, None);
+
+        let registry = ConnectionRegistry::new(true);
+        let connection = registry.register("10.0.0.1:5000".parse().unwrap(), "0.0.0.0:80");
+        connection.record_request(5, || "GET / HTTP/1.1".to_string());
+        assert_eq!(registry.len(), 1);
+        assert_eq!(registry.snapshot()[0].last_request.as_deref(), Some("GET / HTTP/1.1"));
+        let connection = &registry.snapshot()[0];
+        assert_eq!((connection.requests, connection.body_bytes_received), (1, 5));
+    }
+
+    #[tokio::test]
+    async fn test_close_wakes_the_connection() {
+        let registry = ConnectionRegistry::default();
+        let connections: Vec<ConnectionHandle> = (0..SHARDS + 1)
+            .map(|i| registry.register(format!("10.0.0.1:{}", 5000 + i).parse().unwrap(), "x"))
+            .collect();
+        assert_eq!(registry.len(), SHARDS + 1);
+        let last = connections.last().unwrap();
+        assert!(!last.close_requested());
+
+        let closed = registry.close(last.id).unwrap();
+        assert!(closed.closing && last.close_requested());
+        // A close asked for before the connection waits on it isn't lost
+        tokio::time::timeout(std::time::Duration::from_secs(1), last.closed())
+            .await
+            .expect("close request not seen");
+        assert!(!connections[0].close_requested());
+        assert!(registry.close(1000).is_none());
+        drop(connections);
+        assert_eq!(registry.len(), 0);
+    }
+}
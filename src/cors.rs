@@ -0,0 +1,141 @@
+/// How long browsers may cache a preflight answer, in seconds
+const PREFLIGHT_MAX_AGE_SECS: &str = "600";
+
+/// Origins that browsers may call the proxied sites from (`--cors-allowed-origin`). Preflight
+/// requests are answered by balancebeam so that upstreams only ever see the actual requests.
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    /// Allowed origins, e.g. "https://app.example.com"; "*" allows any
+    allowed_origins: Vec<String>,
+}
+
+impl CorsPolicy {
+    pub fn new(allowed_origins: Vec<String>) -> CorsPolicy {
+        CorsPolicy { allowed_origins }
+    }
+
+    /// Returns the value of Access-Control-Allow-Origin to send to `origin`, if it is allowed.
+    fn allow_origin<'a>(&self, origin: &'a str) -> Option<&'a str> {
+        if self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            Some("*")
+        } else if self.allowed_origins.iter().any(|allowed| allowed == origin) {
+            Some(origin)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether a request is a CORS preflight: an OPTIONS request from a browser asking whether it may
+/// send the real request.
+pub fn is_preflight(request: &http::Request<Vec<u8>>) -> bool {
+    request.method() == http::Method::OPTIONS
+        && request.headers().contains_key(http::header::ORIGIN)
+        && request
+            .headers()
+            .contains_key(http::header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+/// Answers `OPTIONS *` or a preflight request with 204 and the methods we accept. The request's
+/// origin is only granted access if `policy` allows it.
+pub fn preflight_response(
+    policy: Option<&CorsPolicy>,
+    request: &http::Request<Vec<u8>>,
+    allowed_methods: &'static str,
+) -> http::Response<Vec<u8>> {
+    let mut response = http::Response::builder()
+        .status(http::StatusCode::NO_CONTENT)
+        .version(http::Version::HTTP_11)
+        .header(http::header::ALLOW, allowed_methods)
+        .header(http::header::ACCESS_CONTROL_ALLOW_METHODS, allowed_methods)
+        .body(Vec::new())
+        .unwrap();
+    let origin = request
+        .headers()
+        .get(http::header::ORIGIN)
+        .and_then(|value| value.to_str().ok());
+    let allow_origin = match (policy, origin) {
+        (Some(policy), Some(origin)) => policy.allow_origin(origin),
+        _ => None,
+    };
+    let headers = response.headers_mut();
+    if policy.is_some() {
+        // The answer depends on the origin, so caches mustn't hand it to other origins
+        headers.insert(http::header::VARY, http::HeaderValue::from_static("Origin"));
+    }
+    if let Some(Ok(allow_origin)) = allow_origin.map(http::HeaderValue::from_str) {
+        headers.insert(http::header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        if let Some(requested) = request
+            .headers()
+            .get(http::header::ACCESS_CONTROL_REQUEST_HEADERS)
+        {
+            headers.insert(http::header::ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+        }
+        headers.insert(
+            http::header::ACCESS_CONTROL_MAX_AGE,
+            http::HeaderValue::from_static(PREFLIGHT_MAX_AGE_SECS),
+        );
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METHODS: &str = "GET, OPTIONS";
+
+    fn preflight(origin: &str) -> http::Request<Vec<u8>> {
+        http::Request::builder()
+            .method(http::Method::OPTIONS)
+            .uri("/api/items")
+            .header("Origin", origin)
+            .header("Access-Control-Request-Method", "PUT")
+            .header("Access-Control-Request-Headers", "content-type")
+            .body(Vec::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_is_preflight() {
+        assert!(is_preflight(&preflight("https://app.example.com")));
+        let plain_options = http::Request::builder()
+            .method(http::Method::OPTIONS)
+            .uri("/api/items")
+            .header("Origin", "https://app.example.com")
+            .body(Vec::new())
+            .unwrap();
+        assert!(!is_preflight(&plain_options));
+    }
+
+    #[test]
+    fn test_allowed_origin() {
+        let policy = CorsPolicy::new(vec!["https://app.example.com".to_string()]);
+        let request = preflight("https://app.example.com");
+        let response = preflight_response(Some(&policy), &request, METHODS);
+        assert_eq!(response.status(), http::StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(headers["access-control-allow-origin"], "https://app.example.com");
+        assert_eq!(headers["access-control-allow-methods"], METHODS);
+        assert_eq!(headers["access-control-allow-headers"], "content-type");
+        assert_eq!(headers["vary"], "Origin");
+        assert!(!headers.contains_key("content-length"));
+    }
+
+    #[test]
+    fn test_other_origins() {
+        let policy = CorsPolicy::new(vec!["https://app.example.com".to_string()]);
+        let request = preflight("https://evil.example.com");
+        let response = preflight_response(Some(&policy), &request, METHODS);
+        assert_eq!(response.status(), http::StatusCode::NO_CONTENT);
+        assert!(!response.headers().contains_key("access-control-allow-origin"));
+        assert!(!response.headers().contains_key("access-control-allow-headers"));
+
+        let any = CorsPolicy::new(vec!["*".to_string()]);
+        let response = preflight_response(Some(&any), &request, METHODS);
+        assert_eq!(response.headers()["access-control-allow-origin"], "*");
+        // Without a policy no origin is granted anything
+        let response = preflight_response(None, &request, METHODS);
+        assert!(!response.headers().contains_key("access-control-allow-origin"));
+    }
+}
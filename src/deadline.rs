@@ -0,0 +1,95 @@
+use std::time::Instant;
+use tokio::time::Duration;
+
+/// Header through which a trusted proxy in front of us can pass along its own timeout, in seconds
+pub const REQUEST_TIMEOUT_HEADER: &str = "x-request-timeout";
+/// Header telling upstreams how many milliseconds the caller is still willing to wait
+pub const DEADLINE_HEADER: &str = "x-deadline-ms";
+
+/// The point in time after which nobody is waiting for a request's response any more.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Deadline {
+    expires_at: Instant,
+}
+
+impl Deadline {
+    /// Works out the deadline for a request received at `received_at`. A timeout requested via
+    /// header is clamped to the configured maximum; either one alone is used as is.
+    pub fn for_request(
+        received_at: Instant,
+        max: Option<Duration>,
+        requested: Option<Duration>,
+    ) -> Option<Deadline> {
+        let timeout = match (max, requested) {
+            (Some(max), Some(requested)) => max.min(requested),
+            (max, requested) => max.or(requested)?,
+        };
+        Some(Deadline {
+            expires_at: received_at + timeout,
+        })
+    }
+
+    /// Time left as of `now` (zero once the deadline has passed).
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.expires_at
+            .checked_duration_since(now)
+            .unwrap_or_else(|| Duration::from_secs(0))
+    }
+
+    /// The value to send in the deadline header as of `now`. This is recomputed for each attempt,
+    /// so a retry carries whatever budget the earlier attempts left.
+    pub fn header_value(&self, now: Instant) -> http::HeaderValue {
+        http::HeaderValue::from(self.remaining(now).as_millis() as u64)
+    }
+}
+
+/// Parses a timeout header value given in (possibly fractional) seconds.
+pub fn parse_timeout(value: &str) -> Option<Duration> {
+    let secs: f64 = value.trim().parse().ok()?;
+    if secs.is_finite() && secs >= 0.0 {
+        Some(Duration::from_secs_f64(secs))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_requested_timeout_is_clamped() {
+        let now = Instant::now();
+        let deadline = Deadline::for_request(now, Some(secs(5)), Some(secs(60))).unwrap();
+        assert_eq!(deadline.remaining(now), secs(5));
+        let deadline = Deadline::for_request(now, Some(secs(5)), Some(secs(2))).unwrap();
+        assert_eq!(deadline.remaining(now), secs(2));
+        let deadline = Deadline::for_request(now, None, Some(secs(2))).unwrap();
+        assert_eq!(deadline.remaining(now), secs(2));
+        assert_eq!(Deadline::for_request(now, None, None), None);
+    }
+
+    #[test]
+    fn test_retries_carry_less_budget() {
+        let received_at = Instant::now();
+        let deadline = Deadline::for_request(received_at, Some(secs(5)), None).unwrap();
+        assert_eq!(deadline.header_value(received_at), "5000");
+        let retry_at = received_at + Duration::from_millis(1250);
+        assert_eq!(deadline.header_value(retry_at), "3750");
+        // Past the deadline, upstreams are told there's no time left rather than a negative value
+        assert_eq!(deadline.header_value(received_at + secs(6)), "0");
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!(parse_timeout("1.5"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_timeout(" 3 "), Some(secs(3)));
+        assert_eq!(parse_timeout("-1"), None);
+        assert_eq!(parse_timeout("soon"), None);
+        assert_eq!(parse_timeout("inf"), None);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::access;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::Duration;
+
+/// Names the upstream ("host:port", as given to --upstream) a request should be sent to.
+pub const UPSTREAM_HEADER: &str = "x-balancebeam-upstream";
+/// Hex-encoded HMAC-SHA256 of the X-Balancebeam-Upstream value, keyed with --debug-routing-secret.
+pub const TOKEN_HEADER: &str = "x-balancebeam-token";
+/// Carries the --expose-upstream-header-token secret, asking for the response headers below.
+pub const DEBUG_HEADER: &str = "x-balancebeam-debug";
+/// The address of the upstream that served a response.
+pub const SERVED_BY_HEADER: &str = "x-served-by";
+/// Milliseconds from starting to send the request upstream to having the response headers.
+pub const LATENCY_HEADER: &str = "x-upstream-latency-ms";
+
+/// Lets internal tooling pin a request to a particular upstream for debugging. Only requests
+/// carrying a token signed with the shared secret are honored, so clients can't pick upstreams
+/// themselves.
+pub struct DebugRouting {
+    secret: Vec<u8>,
+}
+
+impl DebugRouting {
+    pub fn new(secret: &str) -> DebugRouting {
+        DebugRouting {
+            secret: secret.as_bytes().to_vec(),
+        }
+    }
+
+    fn mac(&self, upstream: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(upstream.as_bytes());
+        mac
+    }
+
+    /// The token that authorizes routing to `upstream`.
+    #[cfg(test)]
+    pub fn sign(&self, upstream: &str) -> String {
+        hex::encode(self.mac(upstream).finalize().into_bytes())
+    }
+
+    /// Returns true if `token` authorizes routing to `upstream`. The comparison is constant time.
+    pub fn verify(&self, upstream: &str, token: &str) -> bool {
+        match hex::decode(token.trim()) {
+            Ok(token) => self.mac(upstream).verify_slice(&token).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Removes the override headers from the request, returning the requested upstream if the override
+/// is authorized. The headers are always removed so that they never reach an upstream, whether or
+/// not debug routing is enabled.
+pub fn take_override(
+    routing: Option<&DebugRouting>,
+    request: &mut http::Request<Vec<u8>>,
+) -> Option<String> {
+    let upstream = request.headers_mut().remove(UPSTREAM_HEADER);
+    let token = request.headers_mut().remove(TOKEN_HEADER);
+    let upstream = upstream?.to_str().ok()?.trim().to_string();
+    let token = token?;
+    if routing?.verify(&upstream, token.to_str().ok()?) {
+        Some(upstream)
+    } else {
+        None
+    }
+}
+
+/// Which responses say which upstream served them and how long it took
+/// (--expose-upstream-header): all of them, or only those to requests carrying the secret in
+/// X-Balancebeam-Debug.
+pub struct ExposeUpstream {
+    /// SHA-256 of the secret, if there is one
+    token_digest: Option<[u8; 32]>,
+}
+
+impl ExposeUpstream {
+    pub fn new(token: Option<&str>) -> ExposeUpstream {
+        ExposeUpstream {
+            token_digest: token.map(access::digest),
+        }
+    }
+}
+
+/// Removes X-Balancebeam-Debug from the request, returning whether its response should get the
+/// X-Served-By and X-Upstream-Latency-Ms headers. The header is always removed, so that the secret
+/// never reaches an upstream.
+pub fn take_expose(expose: Option<&ExposeUpstream>, request: &mut http::Request<Vec<u8>>) -> bool {
+    let presented = request.headers_mut().remove(DEBUG_HEADER);
+    let expected = match expose {
+        Some(ExposeUpstream { token_digest }) => token_digest,
+        None => return false,
+    };
+    match (expected, presented) {
+        (None, _) => true,
+        (Some(expected), Some(presented)) => match presented.to_str() {
+            Ok(presented) => access::constant_time_eq(&access::digest(presented.trim()), expected),
+            Err(_) => false,
+        },
+        (Some(_), None) => false,
+    }
+}
+
+/// Says which upstream served the response, and how long it took to answer. These are added next
+/// to any the upstream sent itself, never in place of them.
+pub fn add_served_by(response: &mut http::Response<Vec<u8>>, upstream: &str, latency: Duration) {
+    let headers = response.headers_mut();
+    if let Ok(value) = http::HeaderValue::from_str(upstream) {
+        headers.append(SERVED_BY_HEADER, value);
+    }
+    headers.append(LATENCY_HEADER, http::HeaderValue::from(latency.as_millis() as u64));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_with(headers: &[(&str, &str)]) -> http::Request<Vec<u8>> {
+        let mut builder = http::Request::builder().uri("/");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn test_take_override() {
+        let routing = DebugRouting::new("s3cret");
+        let token = routing.sign("10.0.0.2:80");
+
+        let mut request =
+            request_with(&[(UPSTREAM_HEADER, "10.0.0.2:80"), (TOKEN_HEADER, token.as_str())]);
+        assert_eq!(
+            take_override(Some(&routing), &mut request),
+            Some("10.0.0.2:80".to_string())
+        );
+        assert!(request.headers().is_empty());
+
+        // A token for a different upstream, or signed with a different secret, is ignored
+        let mut request =
+            request_with(&[(UPSTREAM_HEADER, "10.0.0.3:80"), (TOKEN_HEADER, token.as_str())]);
+        assert_eq!(take_override(Some(&routing), &mut request), None);
+        assert!(request.headers().is_empty());
+        let other = DebugRouting::new("other").sign("10.0.0.2:80");
+        let mut request =
+            request_with(&[(UPSTREAM_HEADER, "10.0.0.2:80"), (TOKEN_HEADER, other.as_str())]);
+        assert_eq!(take_override(Some(&routing), &mut request), None);
+
+        // Without a secret nothing is honored, but the headers are still stripped
+        let mut request =
+            request_with(&[(UPSTREAM_HEADER, "10.0.0.2:80"), (TOKEN_HEADER, token.as_str())]);
+        assert_eq!(take_override(None, &mut request), None);
+        assert!(request.headers().is_empty());
+    }
+
+    #[test]
+    fn test_take_expose() {
+        let everyone = ExposeUpstream::new(None);
+        let with_token = ExposeUpstream::new(Some("s3cret"));
+        for (expose, debug, expected) in &[
+            (None, Some("s3cret"), false),
+            (Some(&everyone), None, true),
+            (Some(&with_token), None, false),
+            (Some(&with_token), Some("wrong"), false),
+            (Some(&with_token), Some(" s3cret "), true),
+        ] {
+            let headers: Vec<(&str, &str)> = debug.iter().map(|d| (DEBUG_HEADER, *d)).collect();
+            let mut request = request_with(&headers);
+            assert_eq!(take_expose(*expose, &mut request), *expected, "{:?}", debug);
+            assert!(request.headers().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_verify_rejects_malformed_tokens() {
+        let routing = DebugRouting::new("s3cret");
+        assert!(!routing.verify("10.0.0.2:80", "not hex"));
+        assert!(!routing.verify("10.0.0.2:80", ""));
+        assert!(!routing.verify("10.0.0.2:80", "abcd"));
+    }
+}
@@ -0,0 +1,293 @@
+use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+use std::fmt;
+use std::io::Read;
+
+/// Why a compressed body couldn't be decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The body isn't valid data in the encoding it claims
+    Malformed(&'static str),
+    /// The decompressed body would be bigger than the limit
+    TooLarge,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Malformed(why) => write!(f, "malformed compressed body: {}", why),
+            Error::TooLarge => write!(f, "decompressed body is too large"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A content coding we can undo.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Coding {
+    Gzip,
+    Deflate,
+}
+
+impl Coding {
+    /// The coding named by a Content-Encoding header, if it is a single one we can undo.
+    pub fn from_header(value: &http::HeaderValue) -> Option<Coding> {
+        match value.to_str().ok()?.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Coding::Gzip),
+            "deflate" => Some(Coding::Deflate),
+            _ => None,
+        }
+    }
+
+    fn names(&self) -> &'static [&'static str] {
+        match self {
+            Coding::Gzip => &["gzip", "x-gzip"],
+            Coding::Deflate => &["deflate"],
+        }
+    }
+}
+
+/// Whether an Accept-Encoding header (if the client sent one) allows `coding`. A client that sent
+/// none is taken not to understand any coding, as that is what the clients this is for mean by it.
+pub fn accepts(accept_encoding: Option<&http::HeaderValue>, coding: Coding) -> bool {
+    let value = match accept_encoding.and_then(|value| value.to_str().ok()) {
+        Some(value) => value,
+        None => return false,
+    };
+    let mut wildcard = false;
+    for item in value.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let allowed = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .all(|q| q.trim().parse::<f32>().is_ok_and(|q| q > 0.0));
+        if coding.names().contains(&name.as_str()) {
+            // An explicit entry wins over the wildcard, either way
+            return allowed;
+        }
+        if name == "*" {
+            wildcard = allowed;
+        }
+    }
+    wildcard
+}
+
+/// Decodes the body of `response` for a client that sent `accept_encoding`, if the response has a
+/// single Content-Encoding the client doesn't accept and we can undo, and changing the body won't
+/// break its framing. The Content-Encoding goes, the Content-Length is fixed up, and Vary gains
+/// Accept-Encoding. Returns the coding that was undone, if any.
+///
+/// `limit` applies to the decoded body, so that a small compressed body can't expand into more
+/// memory than an uncompressed one would be allowed. Decoding runs on the blocking thread pool,
+/// as a large body takes long enough to hold up the other requests on this thread.
+pub async fn decode_response(
+    response: &mut http::Response<Vec<u8>>,
+    accept_encoding: Option<&http::HeaderValue>,
+    limit: usize,
+) -> Result<Option<Coding>, Error> {
+    let mut encodings = response.headers().get_all(http::header::CONTENT_ENCODING).iter();
+    let coding = match (encodings.next().and_then(Coding::from_header), encodings.next()) {
+        (Some(coding), None) => coding,
+        _ => return Ok(None),
+    };
+    if accepts(accept_encoding, coding)
+        || response.body().is_empty()
+        || response.status() == http::StatusCode::PARTIAL_CONTENT
+        || response.headers().contains_key(http::header::TRANSFER_ENCODING)
+    {
+        return Ok(None);
+    }
+    let body = std::mem::take(response.body_mut());
+    let (body, decoded) = tokio::task::spawn_blocking(move || {
+        let decoded = decode(coding, &body, limit);
+        (body, decoded)
+    })
+    .await
+    .unwrap_or_else(|_| (Vec::new(), Err(Error::Malformed("the decoder crashed"))));
+    match decoded {
+        Ok(decoded) => *response.body_mut() = decoded,
+        Err(error) => {
+            *response.body_mut() = body;
+            return Err(error);
+        }
+    }
+    let length = response.body().len();
+    let headers = response.headers_mut();
+    headers.remove(http::header::CONTENT_ENCODING);
+    headers.insert(http::header::CONTENT_LENGTH, length.into());
+    let varies = headers.get_all(http::header::VARY).iter().any(|value| {
+        value.to_str().unwrap_or("").split(',').any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case("accept-encoding")
+        })
+    });
+    if !varies {
+        headers.append(http::header::VARY, http::HeaderValue::from_static("Accept-Encoding"));
+    }
+    Ok(Some(coding))
+}
+
+/// Decodes `body`, failing if the result would be longer than `limit` bytes.
+pub fn decode(coding: Coding, body: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+    match coding {
+        // A gzip body may be several members one after another
+        Coding::Gzip => read_limited(MultiGzDecoder::new(body), limit, "invalid gzip data"),
+        Coding::Deflate => {
+            // "deflate" is meant to be zlib-wrapped, but some servers send raw deflate data
+            if is_zlib_header(body) {
+                read_limited(ZlibDecoder::new(body), limit, "invalid zlib data")
+            } else {
+                read_limited(DeflateDecoder::new(body), limit, "invalid deflate data")
+            }
+        }
+    }
+}
+
+/// Reads `decoder` to the end, stopping one byte past `limit` so a decompression bomb can't get
+/// any further than that.
+fn read_limited(decoder: impl Read, limit: usize, why: &'static str) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::new();
+    decoder
+        .take(limit as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(|_| Error::Malformed(why))?;
+    if out.len() > limit {
+        return Err(Error::TooLarge);
+    }
+    Ok(out)
+}
+
+fn is_zlib_header(data: &[u8]) -> bool {
+    data.len() >= 2
+        && data[0] & 0x0f == 8
+        && data[0] >> 4 <= 7
+        && (u16::from(data[0]) << 8 | u16::from(data[1])) % 31 == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const TEXT: &[u8] = b"hello hello hello hello, balancebeam! hello hello hello hello\n";
+
+    /// `TEXT` as gzip compressed it (one block with the fixed Huffman codes)
+    const TEXT_GZIP: &str = "1f8b0800000000000203cb48cdc9c957c84027751492127312f39253935213731531\
+                             e521241700613420383e000000";
+
+    /// `SENTENCES` as zlib compressed it at level 9 (one block with dynamic Huffman codes)
+    const SENTENCES: &[u8] = b"The quick brown fox jumps over the lazy dog. Balancebeam relays \
+        requests to upstream servers and relays their responses back to clients.\n";
+    const SENTENCES_ZLIB: &str = "78daed4ebb15c2300cec99e226c810cc9005644780c1911cc906c2f4888286\
+                                  19a8eeddbbef7c616ca3e41b92e94370d227ae636d0ebdb3a1875ce9b563d1\
+                                  f384235592cc89698571a5dd03b6c1de1d5d319a77fb68ce166107c9f2f545\
+                                  51b120de549c1d89623232b91696eed361fe1ff939f2065c659802";
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn gzip(data: &[u8], level: Compression) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), level);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_gunzip() {
+        assert_eq!(decode(Coding::Gzip, &unhex(TEXT_GZIP), 1000).unwrap(), TEXT);
+        assert_eq!(decode(Coding::Gzip, &gzip(TEXT, Compression::none()), 1000).unwrap(), TEXT);
+
+        // Members are concatenated
+        let mut twice = gzip(TEXT, Compression::best());
+        twice.extend(unhex(TEXT_GZIP));
+        assert_eq!(decode(Coding::Gzip, &twice, 1000).unwrap(), [TEXT, TEXT].concat());
+
+        let mut corrupt = unhex(TEXT_GZIP);
+        let crc_position = corrupt.len() - 8;
+        corrupt[crc_position] ^= 1;
+        assert!(matches!(decode(Coding::Gzip, &corrupt, 1000), Err(Error::Malformed(_))));
+        let truncated = &unhex(TEXT_GZIP)[..20];
+        assert!(matches!(decode(Coding::Gzip, truncated, 1000), Err(Error::Malformed(_))));
+        assert!(matches!(decode(Coding::Gzip, TEXT, 1000), Err(Error::Malformed(_))));
+    }
+
+    #[test]
+    fn test_zlib_and_dynamic_codes() {
+        let text = SENTENCES.repeat(3);
+        let zlib = unhex(SENTENCES_ZLIB);
+        assert_eq!(decode(Coding::Deflate, &zlib, 1000).unwrap(), text);
+        // Raw deflate data, as some servers send for "deflate"
+        assert_eq!(decode(Coding::Deflate, &zlib[2..zlib.len() - 4], 1000).unwrap(), text);
+
+        let mut corrupt = zlib.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        assert!(matches!(decode(Coding::Deflate, &corrupt, 1000), Err(Error::Malformed(_))));
+    }
+
+    #[test]
+    fn test_output_limit() {
+        // 100 KB of zeros compresses to a couple of hundred bytes
+        let zeros = vec![0_u8; 100_000];
+        let compressed = gzip(&zeros, Compression::default());
+        assert!(compressed.len() < 1000);
+        assert_eq!(decode(Coding::Gzip, &compressed, 100_000).unwrap().len(), 100_000);
+        assert_eq!(decode(Coding::Gzip, &compressed, 99_999), Err(Error::TooLarge));
+        assert_eq!(decode(Coding::Gzip, &unhex(TEXT_GZIP), 10), Err(Error::TooLarge));
+    }
+
+    #[tokio::test]
+    async fn test_decode_response() {
+        let gzipped = || {
+            http::Response::builder()
+                .header("content-encoding", "gzip")
+                .header("content-length", TEXT_GZIP.len() / 2)
+                .body(unhex(TEXT_GZIP))
+                .unwrap()
+        };
+        let accept_gzip = http::HeaderValue::from_static("gzip");
+
+        let mut response = gzipped();
+        assert_eq!(decode_response(&mut response, None, 1000).await, Ok(Some(Coding::Gzip)));
+        assert_eq!(response.body(), TEXT);
+        assert!(!response.headers().contains_key("content-encoding"));
+        assert_eq!(response.headers()["content-length"], TEXT.len().to_string().as_str());
+        assert_eq!(response.headers()["vary"], "Accept-Encoding");
+
+        let mut response = gzipped();
+        assert_eq!(decode_response(&mut response, Some(&accept_gzip), 1000).await, Ok(None));
+        assert_eq!(response.body(), &unhex(TEXT_GZIP));
+
+        let mut response = gzipped();
+        response.headers_mut().append("content-encoding", "br".parse().unwrap());
+        assert_eq!(decode_response(&mut response, None, 1000).await, Ok(None));
+
+        let mut response = gzipped();
+        response.headers_mut().insert("vary", "accept-encoding".parse().unwrap());
+        assert_eq!(decode_response(&mut response, None, 1000).await, Ok(Some(Coding::Gzip)));
+        assert_eq!(response.headers().get_all("vary").iter().count(), 1);
+
+        let mut response = gzipped();
+        assert_eq!(decode_response(&mut response, None, 10).await, Err(Error::TooLarge));
+    }
+
+    #[test]
+    fn test_accepts() {
+        let header = |value: &'static str| http::HeaderValue::from_static(value);
+        assert!(!accepts(None, Coding::Gzip));
+        assert!(accepts(Some(&header("gzip, deflate, br")), Coding::Gzip));
+        assert!(accepts(Some(&header("br;q=1.0, GZIP;q=0.5")), Coding::Gzip));
+        assert!(!accepts(Some(&header("br")), Coding::Gzip));
+        assert!(!accepts(Some(&header("identity")), Coding::Deflate));
+        assert!(!accepts(Some(&header("gzip;q=0")), Coding::Gzip));
+        assert!(accepts(Some(&header("*")), Coding::Deflate));
+        assert!(!accepts(Some(&header("*, gzip;q=0")), Coding::Gzip));
+        assert!(accepts(Some(&header("x-gzip")), Coding::Gzip));
+    }
+}
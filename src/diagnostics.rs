@@ -0,0 +1,117 @@
+use crate::{Listener, ProxyState};
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Writes a diagnostics report every time balancebeam receives SIGUSR2, to `diag_file` if given
+/// (appending) or to stderr otherwise.
+pub async fn run(state: Arc<ProxyState>, listeners: Vec<Arc<Listener>>, diag_file: Option<PathBuf>) {
+    let mut signals = match signal(SignalKind::user_defined2()) {
+        Ok(signals) => signals,
+        Err(err) => {
+            log::warn!("Could not listen for SIGUSR2; diagnostics dumps are disabled: {}", err);
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        let report = report(&state, &listeners).await;
+        match &diag_file {
+            Some(path) => {
+                let written = match tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                {
+                    Ok(mut file) => file.write_all(report.as_bytes()).await,
+                    Err(err) => Err(err),
+                };
+                match written {
+                    Ok(()) => log::info!("Wrote diagnostics to {:?}", path),
+                    Err(err) => log::error!("Could not write diagnostics to {:?}: {}", path, err),
+                }
+            }
+            None => eprint!("{}", report),
+        }
+    }
+}
+
+/// Builds a plain-text snapshot of balancebeam's configuration and current state.
+async fn report(state: &ProxyState, listeners: &[Arc<Listener>]) -> String {
+    let now = Instant::now();
+    let mut out = String::new();
+    // Writing to a String can't fail, so the fmt::Results below are ignored
+    let _ = writeln!(out, "=== balancebeam diagnostics ===");
+    let _ = writeln!(
+        out,
+        "version {}, uptime {:.1}s, rss {}, tokio tasks unavailable",
+        env!("CARGO_PKG_VERSION"),
+        state.started_at.elapsed().as_secs_f64(),
+        resident_set_size().await.unwrap_or_else(|| "unknown".to_string())
+    );
+
+    let _ = writeln!(out, "--- listeners ---");
+    for listener in listeners {
+        let _ = writeln!(
+            out,
+            "{} -> pool {}, max {} requests/minute, rate limiter tracking {} clients",
+            listener.bind,
+            listener.pool.name,
+            listener.max_requests_per_minute,
+            listener.rate_limiter.tracked_clients().await
+        );
+    }
+
+    let _ = writeln!(out, "--- upstreams ---");
+    for pool in &state.pools {
+        let upstreams_state = pool.upstreams_state.read().await;
+        for upstream in pool.upstreams.snapshot() {
+            let _ = writeln!(
+                out,
+                "{} {}: {}, {} in flight, weight {:.2}, response timeout {:?}",
+                pool.name,
+                upstream.address,
+                upstreams_state.phase(upstream.id, now),
+                upstream.stats.in_flight(),
+                upstreams_state.weights[upstream.id.index()],
+                upstreams_state.response_timeout(upstream.id)
+            );
+        }
+    }
+
+    let connections = state.connections.snapshot();
+    let _ = writeln!(out, "--- connections ({}) ---", connections.len());
+    for connection in connections {
+        let _ = write!(
+            out,
+            "#{} {} on {}: open {:.1}s, idle {:.1}s",
+            connection.id,
+            connection.peer,
+            connection.listener,
+            now.duration_since(connection.started_at).as_secs_f64(),
+            now.duration_since(connection.last_activity).as_secs_f64()
+        );
+        match &connection.last_request {
+            Some(request_line) => {
+                let _ = writeln!(out, ", last request {}", request_line);
+            }
+            None => {
+                let _ = writeln!(out);
+            }
+        }
+    }
+    let _ = writeln!(out, "=== end of diagnostics ===");
+    out
+}
+
+/// Returns the process's resident set size as reported by /proc (e.g. "10240 kB"), if available.
+async fn resident_set_size() -> Option<String> {
+    let status = tokio::fs::read_to_string("/proc/self/status").await.ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .map(|value| value.trim().to_string())
+}
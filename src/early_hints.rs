@@ -0,0 +1,68 @@
+use crate::rewrite;
+use std::str::FromStr;
+
+/// Link headers to send in a 103 Early Hints response for GET requests under a path prefix, parsed
+/// from an `--early-hints "prefix=/app,link=</static/app.css>; rel=preload; as=style"` option.
+/// Everything after `link=` is the header value, commas included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EarlyHint {
+    prefix: String,
+    link: http::HeaderValue,
+}
+
+impl FromStr for EarlyHint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, link) = s
+            .trim()
+            .strip_prefix("prefix=")
+            .and_then(|rest| rest.split_once(",link="))
+            .ok_or("expected \"prefix=<path>,link=<Link header value>\"")?;
+        let prefix = prefix.trim();
+        if !prefix.starts_with('/') {
+            return Err("early hint prefixes must start with '/'".to_string());
+        }
+        let link = http::HeaderValue::from_str(link.trim())
+            .map_err(|_| "the link is not a valid header value".to_string())?;
+        Ok(EarlyHint {
+            prefix: prefix.to_string(),
+            link,
+        })
+    }
+}
+
+/// The Link values of every hint whose prefix `path` falls under, in the order they were given.
+pub fn links_for<'a>(hints: &'a [EarlyHint], path: &str) -> Vec<&'a http::HeaderValue> {
+    hints
+        .iter()
+        .filter(|hint| rewrite::strip_segment_prefix(path, &hint.prefix).is_some())
+        .map(|hint| &hint.link)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_early_hint() {
+        let hint: EarlyHint = "prefix=/app,link=</a.css>; rel=preload; as=style, </b.js>; rel=preload"
+            .parse()
+            .unwrap();
+        assert_eq!(hint.prefix, "/app");
+        assert_eq!(hint.link, "</a.css>; rel=preload; as=style, </b.js>; rel=preload");
+        assert!("link=</a.css>".parse::<EarlyHint>().is_err());
+        assert!("prefix=app,link=</a.css>".parse::<EarlyHint>().is_err());
+    }
+
+    #[test]
+    fn test_links_for() {
+        let hints: Vec<EarlyHint> = ["prefix=/app,link=</app.css>", "prefix=/,link=</all.css>"]
+            .iter()
+            .map(|hint| hint.parse().unwrap())
+            .collect();
+        assert_eq!(links_for(&hints, "/app/page"), vec!["</app.css>", "</all.css>"]);
+        assert_eq!(links_for(&hints, "/apple"), vec!["</all.css>"]);
+    }
+}
@@ -0,0 +1,229 @@
+use crate::config::Config;
+use crate::{priority, ProxyConfig};
+use clap::{ArgAction, ArgMatches, CommandFactory, ValueSource};
+use hmac::{Hmac, Mac};
+use serde_json::{json, Map, Value};
+use sha2::Sha256;
+use std::sync::OnceLock;
+
+/// Options whose values are secrets, shown only as a fingerprint: enough to tell whether one has
+/// changed, or two are the same, without the secret itself reaching logs or the admin API
+const SECRET_OPTIONS: [&str; 6] = [
+    "admin-token",
+    "metrics-token",
+    "debug-routing-secret",
+    "expose-upstream-header-token",
+    // May hold the Redis password
+    "redis-url",
+    // May hold basic auth credentials for the Pushgateway
+    "pushgateway-url",
+];
+/// Options of `<name>: <value>` headers, whose values may be credentials (an upstream's
+/// Authorization, say). Their names are shown as given.
+const SECRET_HEADER_OPTIONS: [&str; 1] = ["upstream-add-request-header"];
+
+/// Key for fingerprints, made up afresh by every process. Without it, anyone who can read a
+/// fingerprint could check guesses at the secret against it.
+static FINGERPRINT_KEY: OnceLock<[u8; 32]> = OnceLock::new();
+
+/// Stands in for a secret: the start of its HMAC-SHA256 under this process's fingerprint key.
+/// Fingerprints can only be compared with others from the same process.
+pub fn fingerprint(secret: &str) -> String {
+    let key = FINGERPRINT_KEY.get_or_init(rand::random);
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(secret.as_bytes());
+    format!("<redacted hmac:{}>", hex::encode(&mac.finalize().into_bytes()[..8]))
+}
+
+/// Everything balancebeam is running with, as JSON: each option's value and where it came from,
+/// the pools and listeners being served, and how many of each kind of rule there are. Secrets are
+/// replaced by their fingerprints.
+pub fn render(options: &ProxyConfig, config: &Config) -> Value {
+    let config_source = if options.config.is_some() {
+        "config file"
+    } else {
+        "command line"
+    };
+    let pools: Vec<Value> = config
+        .pools
+        .iter()
+        .map(|pool| {
+            let mut success_codes: Vec<u16> =
+                pool.active_health_check_success_codes.iter().copied().collect();
+            success_codes.sort_unstable();
+            let upstreams: Vec<Value> = pool
+                .upstreams
+                .iter()
+                .map(|address| {
+                    json!({
+                        "address": address,
+                        // Every upstream starts out with the same weight; only --adaptive-weights
+                        // changes them, which /upstreams shows
+                        "weight": 1.0,
+                        "priority": priority::of(address, &options.upstream_priority),
+                    })
+                })
+                .collect();
+            json!({
+                "name": pool.name,
+                "source": config_source,
+                "upstreams": upstreams,
+                "active_health_check_interval": pool.active_health_check_interval,
+                "active_health_check_path": pool.active_health_check_path,
+                "active_health_check_success_codes": success_codes,
+                "host_override": pool.host_override,
+            })
+        })
+        .collect();
+    let listeners: Vec<Value> = config
+        .listeners
+        .iter()
+        .map(|listener| {
+            json!({
+                "bind": listener.bind,
+                "source": config_source,
+                "pool": config.pools[listener.pool].name,
+                "max_requests_per_minute": listener.max_requests_per_minute,
+                "rate_limiter": format!("{:?}", listener.rate_limiter).to_lowercase(),
+            })
+        })
+        .collect();
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "options": options_json(&options.matches),
+        "pools": pools,
+        "listeners": listeners,
+        "rules": {
+            "path_rewrites": options.rewrite_path.len(),
+            "body_rewrites": options.rewrite_body.len(),
+            "early_hints": options.early_hints.len(),
+            "access_log_exclusions": options.access_log_exclude_path.len(),
+            "rate_limit_schedule": options.rate_limit_schedule.len(),
+            "stripped_request_headers": options.strip_request_header.len(),
+            "request_header_edits": options.upstream_remove_request_header.len()
+                + options.upstream_add_request_header.len(),
+            "response_header_edits": options.upstream_remove_response_header.len()
+                + options.upstream_add_response_header.len(),
+            "upstream_priorities": options.upstream_priority.len(),
+            "cors_allowed_origins": options.cors_allowed_origin.len(),
+        },
+    })
+}
+
+/// Every command-line option, by name, with its value and whether it was given or defaulted.
+/// Options left unset (with no default) have a null value.
+fn options_json(matches: &ArgMatches) -> Value {
+    let command = ProxyConfig::command();
+    let mut options = Map::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id();
+        if id == "help" || id == "version" {
+            continue;
+        }
+        let value = if !arg.is_takes_value_set() {
+            Value::Bool(matches.is_present(id))
+        } else {
+            let values: Vec<String> = matches
+                .get_raw(id)
+                .into_iter()
+                .flatten()
+                .map(|value| redact(id, &value.to_string_lossy()))
+                .collect();
+            // Repeatable options are lists, however many times they were given
+            if arg.is_multiple_occurrences_set() || matches!(arg.get_action(), ArgAction::Append) {
+                json!(values)
+            } else {
+                values.into_iter().next().map_or(Value::Null, Value::String)
+            }
+        };
+        let source = match matches.value_source(id) {
+            Some(ValueSource::CommandLine) => "command line",
+            Some(ValueSource::EnvVariable) => "environment",
+            _ => "default",
+        };
+        options.insert(id.to_string(), json!({ "value": value, "source": source }));
+    }
+    Value::Object(options)
+}
+
+fn redact(id: &str, value: &str) -> String {
+    if SECRET_OPTIONS.contains(&id) {
+        return fingerprint(value);
+    }
+    if SECRET_HEADER_OPTIONS.contains(&id) {
+        if let Some((name, secret)) = value.split_once(':') {
+            return format!("{}: {}", name, fingerprint(secret.trim()));
+        }
+        return fingerprint(value);
+    }
+    value.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A new option whose name says it holds a secret (or a URL, which may carry a password)
+    /// must be redacted too.
+    #[test]
+    fn test_secret_looking_options_are_redacted() {
+        for arg in ProxyConfig::command().get_arguments() {
+            let id = arg.get_id();
+            let secret_looking = ["token", "secret", "password", "url"]
+                .iter()
+                .any(|word| id.to_ascii_lowercase().contains(word));
+            if secret_looking {
+                assert!(
+                    SECRET_OPTIONS.contains(&id) || SECRET_HEADER_OPTIONS.contains(&id),
+                    "--{} isn't redacted",
+                    id
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_secrets_are_redacted() {
+        let options = ProxyConfig::parse_args([
+            "balancebeam",
+            "--upstream",
+            "10.0.0.1:80",
+            "--upstream",
+            "10.0.0.2:80",
+            "--upstream-priority",
+            "10.0.0.2:80=3",
+            "--admin-token",
+            "hunter2",
+            "--debug-routing-secret",
+            "hmac-secret",
+            "--upstream-add-request-header",
+            "Authorization: Bearer upstream-credential",
+            "--rewrite-path",
+            "match=/old/,replace=/new/",
+        ]);
+        let rendered = render(&options, &options.load_config().unwrap());
+        let text = rendered.to_string();
+        for secret in &["hunter2", "hmac-secret", "upstream-credential"] {
+            assert!(!text.contains(secret), "{} leaked into {}", secret, text);
+        }
+
+        let option = |name: &str| &rendered["options"][name];
+        assert_eq!(option("admin-token")["value"], json!(fingerprint("hunter2")));
+        assert_ne!(fingerprint("hunter2"), fingerprint("hunter3"));
+        assert_eq!(
+            option("upstream-add-request-header")["value"][0],
+            json!(format!("Authorization: {}", fingerprint("Bearer upstream-credential")))
+        );
+        assert_eq!(
+            option("upstream"),
+            &json!({ "value": ["10.0.0.1:80", "10.0.0.2:80"], "source": "command line" })
+        );
+        let defaulted = json!({ "value": "10", "source": "default" });
+        assert_eq!(option("active-health-check-interval"), &defaulted);
+        assert_eq!(option("metrics-token"), &json!({ "value": null, "source": "default" }));
+        assert_eq!(option("preflight-check")["value"], json!(false));
+        assert_eq!(rendered["pools"][0]["upstreams"][1]["priority"], json!(3));
+        assert_eq!(rendered["listeners"][0]["source"], json!("command line"));
+        assert_eq!(rendered["rules"]["path_rewrites"], json!(1));
+    }
+}
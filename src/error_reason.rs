@@ -0,0 +1,167 @@
+use crate::{request, response};
+use std::fmt;
+
+/// Why balancebeam generated an error response itself instead of relaying one from an upstream.
+/// Each reason maps to exactly one HTTP status, and is recorded in the access log and metrics so
+/// that e.g. a spike of 502s can be traced back to refused connections vs. broken upstreams.
+///
+/// The 5xx statuses tell overload from breakage: 503 means there is nothing to serve the request
+/// right now (no live upstreams, or balancebeam itself is full) and it is worth retrying later,
+/// 502 means an upstream was tried and misbehaved, and 504 means an upstream was too slow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorReason {
+    /// The client sent something we couldn't parse as an HTTP request
+    ClientParse,
+    /// The client's request body is bigger than we're willing to buffer
+    BodyTooLarge,
+    /// The client exceeded its rate limit
+    RateLimited,
+    /// The request method is refused by policy (e.g. TRACE)
+    MethodNotAllowed,
+    /// Every upstream was already marked dead when the request arrived
+    AllDead,
+    /// We tried to connect to upstreams for this request, and every attempt failed
+    UpstreamConnect,
+    /// Writing the request to the upstream failed
+    UpstreamWrite,
+    /// The upstream didn't accept the request in time, and there was no other upstream to try
+    UpstreamWriteTimeout,
+    /// The upstream hung up or the connection failed while we were reading its response
+    UpstreamRead,
+    /// The upstream sent something we couldn't parse as an HTTP response
+    UpstreamParse,
+    /// The upstream didn't finish its response within its timeout
+    UpstreamTimeout,
+    /// `--fault-inject-error-rate` picked this request to fail
+    FaultInjected,
+    /// Buffering the request or response body would go over `--max-buffered-bytes`
+    BufferLimit,
+    /// The client speaks an HTTP version other than 1.0 or 1.1
+    UnsupportedVersion,
+    /// The client asked for the upstreams' health check path (`--block-health-path-from-clients`)
+    HealthPathBlocked,
+}
+
+impl ErrorReason {
+    pub const ALL: [ErrorReason; 15] = [
+        ErrorReason::ClientParse,
+        ErrorReason::BodyTooLarge,
+        ErrorReason::RateLimited,
+        ErrorReason::MethodNotAllowed,
+        ErrorReason::AllDead,
+        ErrorReason::UpstreamConnect,
+        ErrorReason::UpstreamWrite,
+        ErrorReason::UpstreamWriteTimeout,
+        ErrorReason::UpstreamRead,
+        ErrorReason::UpstreamParse,
+        ErrorReason::UpstreamTimeout,
+        ErrorReason::FaultInjected,
+        ErrorReason::BufferLimit,
+        ErrorReason::UnsupportedVersion,
+        ErrorReason::HealthPathBlocked,
+    ];
+
+    /// The label used for this reason in logs and metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorReason::ClientParse => "client_parse",
+            ErrorReason::BodyTooLarge => "body_too_large",
+            ErrorReason::RateLimited => "rate_limited",
+            ErrorReason::MethodNotAllowed => "method_not_allowed",
+            ErrorReason::AllDead => "all_dead",
+            ErrorReason::UpstreamConnect => "upstream_connect",
+            ErrorReason::UpstreamWrite => "upstream_write",
+            ErrorReason::UpstreamWriteTimeout => "upstream_write_timeout",
+            ErrorReason::UpstreamRead => "upstream_read",
+            ErrorReason::UpstreamParse => "upstream_parse",
+            ErrorReason::UpstreamTimeout => "upstream_timeout",
+            ErrorReason::FaultInjected => "fault_injected",
+            ErrorReason::BufferLimit => "buffer_limit",
+            ErrorReason::UnsupportedVersion => "unsupported_version",
+            ErrorReason::HealthPathBlocked => "health_path_blocked",
+        }
+    }
+
+    /// The HTTP status sent to the client for this reason.
+    pub fn status(&self) -> http::StatusCode {
+        match self {
+            ErrorReason::ClientParse => http::StatusCode::BAD_REQUEST,
+            ErrorReason::BodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorReason::RateLimited => http::StatusCode::TOO_MANY_REQUESTS,
+            ErrorReason::MethodNotAllowed => http::StatusCode::METHOD_NOT_ALLOWED,
+            ErrorReason::HealthPathBlocked => http::StatusCode::NOT_FOUND,
+            ErrorReason::UpstreamConnect
+            | ErrorReason::UpstreamWrite
+            | ErrorReason::UpstreamRead
+            | ErrorReason::UpstreamParse => http::StatusCode::BAD_GATEWAY,
+            ErrorReason::UpstreamWriteTimeout | ErrorReason::UpstreamTimeout => {
+                http::StatusCode::GATEWAY_TIMEOUT
+            }
+            ErrorReason::FaultInjected => http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorReason::AllDead | ErrorReason::BufferLimit => {
+                http::StatusCode::SERVICE_UNAVAILABLE
+            }
+            ErrorReason::UnsupportedVersion => http::StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+        }
+    }
+
+    /// Whether the client connection has to be closed after the error response, because whatever
+    /// the client sends next can't be read as the next request: the rest of a body left unread, or
+    /// a protocol other than HTTP/1.x.
+    pub fn closes_connection(&self) -> bool {
+        matches!(
+            self,
+            ErrorReason::BufferLimit | ErrorReason::BodyTooLarge | ErrorReason::UnsupportedVersion
+        )
+    }
+
+    /// Position of this reason in `ALL`, used to index per-reason counters.
+    pub fn index(&self) -> usize {
+        ErrorReason::ALL.iter().position(|reason| reason == self).unwrap()
+    }
+}
+
+impl fmt::Display for ErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<&request::Error> for ErrorReason {
+    fn from(error: &request::Error) -> ErrorReason {
+        match error {
+            request::Error::RequestBodyTooLarge => ErrorReason::BodyTooLarge,
+            request::Error::BufferLimit => ErrorReason::BufferLimit,
+            request::Error::UnsupportedVersion(_) | request::Error::Http2Preface => {
+                ErrorReason::UnsupportedVersion
+            }
+            request::Error::IncompleteRequest(_)
+            | request::Error::MalformedRequest(_)
+            | request::Error::InvalidContentLength
+            | request::Error::ContentLengthMismatch
+            | request::Error::UnsupportedTransferEncoding
+            | request::Error::InvalidChunkedBody
+            | request::Error::MissingHost
+            | request::Error::DuplicateHost
+            | request::Error::ConnectionError(_) => ErrorReason::ClientParse,
+        }
+    }
+}
+
+impl From<&response::Error> for ErrorReason {
+    fn from(error: &response::Error) -> ErrorReason {
+        match error {
+            response::Error::IncompleteResponse | response::Error::ConnectionError(_) => {
+                ErrorReason::UpstreamRead
+            }
+            response::Error::BufferLimit => ErrorReason::BufferLimit,
+            response::Error::MalformedResponse(_)
+            | response::Error::InvalidContentLength
+            | response::Error::ContentLengthMismatch
+            | response::Error::ResponseBodyTooLarge
+            | response::Error::HeadersTooLarge
+            | response::Error::InvalidStatusCode(_) => ErrorReason::UpstreamParse,
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::time::Duration;
+
+/// Parses a `--fault-inject-*-rate` option, a fraction of requests from 0 to 1.
+pub fn parse_rate(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err("expected a fraction from 0 to 1".to_string()),
+    }
+}
+
+/// Something going wrong on purpose, to see how clients cope.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// Answer with a 500 without contacting an upstream
+    Error,
+    /// Answer as if no upstream could be connected to
+    UpstreamFailure,
+    /// Hold the request this long before forwarding it
+    Delay(Duration),
+}
+
+impl Fault {
+    const KINDS: [&'static str; 3] = ["error", "upstream_failure", "delay"];
+
+    /// The label used for this fault in logs and metrics.
+    pub fn as_str(&self) -> &'static str {
+        Fault::KINDS[self.index()]
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Fault::Error => 0,
+            Fault::UpstreamFailure => 1,
+            Fault::Delay(_) => 2,
+        }
+    }
+}
+
+/// Picks requests to fail or slow down (`--fault-inject-*`), for chaos testing. Each request gets
+/// at most one fault: the rates are laid end to end over [0, 1), so e.g. error and delay rates of
+/// 0.05 fail 5% of requests and delay a different 5%.
+#[derive(Debug)]
+pub struct FaultInjector {
+    error_rate: f64,
+    upstream_failure_rate: f64,
+    delay_rate: f64,
+    delay: Duration,
+    /// Faults injected since startup, indexed by `Fault::index`
+    injected: [AtomicU64; Fault::KINDS.len()],
+}
+
+impl FaultInjector {
+    /// Returns None if every rate is zero, so that nothing is drawn per request.
+    pub fn new(
+        error_rate: f64,
+        upstream_failure_rate: f64,
+        delay_rate: f64,
+        delay: Duration,
+    ) -> Option<FaultInjector> {
+        let delay_rate = if delay > Duration::from_secs(0) { delay_rate } else { 0.0 };
+        if error_rate == 0.0 && upstream_failure_rate == 0.0 && delay_rate == 0.0 {
+            return None;
+        }
+        Some(FaultInjector {
+            error_rate,
+            upstream_failure_rate,
+            delay_rate,
+            delay,
+            injected: Default::default(),
+        })
+    }
+
+    /// Decides the fault for a request, given `point` drawn uniformly from [0, 1), and counts it.
+    pub fn choose(&self, point: f64) -> Option<Fault> {
+        let fault = if point < self.error_rate {
+            Fault::Error
+        } else if point < self.error_rate + self.upstream_failure_rate {
+            Fault::UpstreamFailure
+        } else if point < self.error_rate + self.upstream_failure_rate + self.delay_rate {
+            Fault::Delay(self.delay)
+        } else {
+            return None;
+        };
+        self.injected[fault.index()].fetch_add(1, Ordering::Relaxed);
+        Some(fault)
+    }
+
+    /// (label, count) of the faults injected since startup, for metrics.
+    pub fn counts(&self) -> Vec<(&'static str, u64)> {
+        Fault::KINDS
+            .iter()
+            .zip(self.injected.iter())
+            .map(|(kind, count)| (*kind, count.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("0.05"), Ok(0.05));
+        assert!(parse_rate("5%").is_err());
+        assert!(parse_rate("-0.1").is_err());
+    }
+
+    #[test]
+    fn test_disabled_without_rates() {
+        assert!(FaultInjector::new(0.0, 0.0, 0.0, Duration::from_millis(200)).is_none());
+        // A delay rate means nothing without a delay
+        assert!(FaultInjector::new(0.0, 0.0, 0.5, Duration::from_secs(0)).is_none());
+    }
+
+    #[test]
+    fn test_faults_do_not_overlap() {
+        let delay = Duration::from_millis(200);
+        let injector = FaultInjector::new(0.1, 0.2, 0.3, delay).unwrap();
+        let points = [0.0, 0.09, 0.1, 0.29, 0.31, 0.59, 0.61, 0.99];
+        let faults: Vec<Option<Fault>> = points.iter().map(|p| injector.choose(*p)).collect();
+        assert_eq!(
+            faults,
+            vec![
+                Some(Fault::Error),
+                Some(Fault::Error),
+                Some(Fault::UpstreamFailure),
+                Some(Fault::UpstreamFailure),
+                Some(Fault::Delay(delay)),
+                Some(Fault::Delay(delay)),
+                None,
+                None,
+            ]
+        );
+        assert_eq!(injector.counts(), vec![("error", 2), ("upstream_failure", 2), ("delay", 2)]);
+    }
+}
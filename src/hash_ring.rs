@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+
+/// A consistent hash ring for `--load-balancing consistent-hashing`. Every upstream gets a number
+/// of virtual nodes at pseudo-random points on the ring, and a key goes to the first usable
+/// upstream at or after its own point. When an upstream stops being usable, only the keys that
+/// went to it move; everything else stays put.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    /// Virtual nodes per upstream
+    replicas: usize,
+    /// Virtual node positions, mapped to the index of the upstream they belong to
+    ring: BTreeMap<u64, usize>,
+}
+
+impl HashRing {
+    pub fn new(replicas: usize) -> HashRing {
+        HashRing {
+            replicas,
+            ring: BTreeMap::new(),
+        }
+    }
+
+    /// Places the upstream with index `index` on the ring. Its virtual nodes' positions depend
+    /// only on its address, so it lands in the same places every time, in every process and every
+    /// build.
+    pub fn add(&mut self, index: usize, address: &str) {
+        for replica in 0..self.replicas {
+            self.ring.insert(hash(format!("{}#{}", address, replica).as_bytes()), index);
+        }
+    }
+
+    /// Takes the upstream with index `index` off the ring. Its keys go to the upstreams after it.
+    pub fn remove(&mut self, index: usize) {
+        self.ring.retain(|_, node| *node != index);
+    }
+
+    /// The index of the upstream `key` maps to: the first one at or after the key's position, going
+    /// round the ring, for which `usable` is true. None if no upstream is usable.
+    pub fn lookup(&self, key: &[u8], usable: impl Fn(usize) -> bool) -> Option<usize> {
+        let point = hash(key);
+        self.ring
+            .range(point..)
+            .chain(self.ring.range(..point))
+            .map(|(_, index)| *index)
+            .find(|index| usable(*index))
+    }
+}
+
+/// 64-bit FNV-1a, with MurmurHash3's finalizer to spread the virtual nodes of similar addresses
+/// around the ring. Unlike std's `DefaultHasher`, whose algorithm may change between Rust releases,
+/// this gives the same ring (and so sends keys to the same upstreams) in every build, which matters
+/// when several balancebeams share the upstreams or one replaces another.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = fnv1a(bytes);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_is_fixed() {
+        // Published FNV-1a test vectors
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+        // Changing this moves keys between upstreams on upgrade
+        assert_eq!(hash(b"10.0.0.1:80#0"), 0xd988_28fa_0576_6dd3);
+    }
+
+    #[test]
+    fn test_removing_an_upstream_only_moves_its_keys() {
+        let mut ring = HashRing::new(100);
+        for index in 0..5 {
+            ring.add(index, &format!("10.0.0.{}:80", index));
+        }
+        let keys: Vec<String> = (0..10000).map(|i| format!("client-{}", i)).collect();
+        let lookup_all = |ring: &HashRing, usable: &dyn Fn(usize) -> bool| -> Vec<usize> {
+            keys.iter().map(|key| ring.lookup(key.as_bytes(), usable).unwrap()).collect()
+        };
+        let before = lookup_all(&ring, &|_| true);
+        let after = lookup_all(&ring, &|index| index != 2);
+
+        let mut counts = [0; 5];
+        for (before, after) in before.iter().zip(&after) {
+            counts[*before] += 1;
+            if *before != 2 {
+                assert_eq!(before, after, "a key moved off an upstream that is still usable");
+            } else {
+                assert_ne!(*after, 2);
+            }
+        }
+        // With 100 virtual nodes each, every upstream has a fair share of the keys
+        for count in counts {
+            assert!((1200..2800).contains(&count), "uneven: {:?}", counts);
+        }
+        assert_eq!(ring.lookup(b"client-0", |_| false), None);
+
+        // Taking it off the ring moves the same keys as passing it over
+        ring.remove(2);
+        assert_eq!(lookup_all(&ring, &|_| true), after);
+    }
+}
@@ -0,0 +1,283 @@
+use crate::upstream_bind::{self, UpstreamBindAddr};
+use crate::{request, response};
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use tokio::time::{timeout, Duration};
+
+/// Why an upstream failed an active health check.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Failure {
+    /// The TCP connection was refused (or failed some other way)
+    ConnectRefused(String),
+    /// No complete response arrived within the health check timeout
+    Timeout,
+    /// The upstream answered with a status that isn't one of the success codes
+    BadStatus(u16),
+    /// The response body didn't contain the expected text
+    BodyMismatch,
+    /// The request couldn't be sent, or what came back wasn't an HTTP response
+    BadResponse(String),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::ConnectRefused(err) => write!(f, "connect refused: {}", err),
+            Failure::Timeout => write!(f, "timed out"),
+            Failure::BadStatus(status) => write!(f, "bad status {}", status),
+            Failure::BodyMismatch => write!(f, "body mismatch"),
+            Failure::BadResponse(err) => write!(f, "bad response: {}", err),
+        }
+    }
+}
+
+/// Requests sent to an upstream that passes a health check after being dead, before it gets
+/// traffic again, so that it can fill its caches. Parsed from
+/// `--warmup-requests "path=/warm,count=5"`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warmup {
+    pub path: String,
+    pub count: usize,
+}
+
+impl FromStr for Warmup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut path = None;
+        let mut count = None;
+        for part in s.split(',') {
+            match part.trim().split_once('=') {
+                Some(("path", value)) => path = Some(value.trim().to_string()),
+                Some(("count", value)) => {
+                    count = Some(
+                        value
+                            .trim()
+                            .parse::<usize>()
+                            .map_err(|_| format!("\"{}\" is not a request count", value))?,
+                    )
+                }
+                _ => return Err(format!("unrecognized warm-up component \"{}\"", part)),
+            }
+        }
+        let path = match path {
+            Some(path) if path.starts_with('/') => path,
+            Some(_) => return Err("the warm-up path must start with '/'".to_string()),
+            None => return Err("warm-up requests need a path=".to_string()),
+        };
+        Ok(Warmup {
+            path,
+            count: count.unwrap_or(1),
+        })
+    }
+}
+
+/// What an active health check asks of each upstream in a pool.
+#[derive(Debug)]
+pub struct HealthCheck<'a> {
+    pub path: &'a str,
+    pub success_codes: &'a HashSet<u16>,
+    /// Text the response body must contain, if any
+    pub expect_body: Option<&'a str>,
+    /// How long a check may take, connecting included
+    pub timeout: Duration,
+    pub limits: &'a response::HeaderLimits,
+    /// Requests to send after the upstream recovers, if any
+    pub warmup: Option<&'a Warmup>,
+    /// Local addresses to connect from
+    pub bind_addrs: &'a [UpstreamBindAddr],
+}
+
+impl HealthCheck<'_> {
+    /// Sends a health check request to the upstream at `address` and judges the response.
+    pub async fn probe(&self, address: &str) -> Result<(), Failure> {
+        match timeout(self.timeout, self.exchange(address, self.path, self.expect_body)).await {
+            Ok(result) => result,
+            Err(_) => Err(Failure::Timeout),
+        }
+    }
+
+    /// Sends the warm-up requests to the upstream at `address` one after another, each with the
+    /// health check timeout. Stops at the first one that fails, which fails the warm-up. The body
+    /// isn't checked, only the status.
+    pub async fn warm_up(&self, address: &str) -> Result<(), Failure> {
+        let warmup = match self.warmup {
+            Some(warmup) => warmup,
+            None => return Ok(()),
+        };
+        for _ in 0..warmup.count {
+            match timeout(self.timeout, self.exchange(address, &warmup.path, None)).await {
+                Ok(result) => result?,
+                Err(_) => return Err(Failure::Timeout),
+            }
+        }
+        Ok(())
+    }
+
+    async fn exchange(
+        &self,
+        address: &str,
+        path: &str,
+        expect_body: Option<&str>,
+    ) -> Result<(), Failure> {
+        let source = upstream_bind::of(address, self.bind_addrs);
+        let mut stream = upstream_bind::connect(address, source)
+            .await
+            .map_err(|err| Failure::ConnectRefused(err.to_string()))?;
+        let request = http::Request::builder()
+            .method(http::Method::GET)
+            .uri(path)
+            .header("Host", address)
+            .body(Vec::new())
+            .unwrap();
+        request::write_to_stream(&request, &mut stream)
+            .await
+            .map_err(|err| Failure::BadResponse(err.to_string()))?;
+        let response = response::read_from_stream(&mut stream, &http::Method::GET, self.limits)
+            .await
+            .map_err(|err| Failure::BadResponse(err.to_string()))?;
+        let status = response.status().as_u16();
+        if !self.success_codes.contains(&status) {
+            return Err(Failure::BadStatus(status));
+        }
+        if let Some(expected) = expect_body {
+            let body = String::from_utf8_lossy(response.body());
+            if !body.contains(expected) {
+                return Err(Failure::BodyMismatch);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether a request for `path` would reach the health check endpoint at `health_path`, which
+/// upstreams often answer without their usual authentication. Paths are compared the way an
+/// upstream might read them: percent-decoded, with dot segments resolved (RFC 3986 §5.2.4), empty
+/// segments from repeated or trailing slashes dropped, and ignoring ASCII case. A health path of
+/// "/" never matches, as that would take in the whole site.
+pub fn is_health_path(health_path: &str, path: &str) -> bool {
+    let normalize = |path: &str| {
+        let decoded = request::percent_decode(path.split('?').next().unwrap_or_default());
+        let mut segments: Vec<&[u8]> = Vec::new();
+        for segment in decoded.split(|byte| *byte == b'/') {
+            match segment {
+                b"" | b"." => {}
+                b".." => {
+                    segments.pop();
+                }
+                segment => segments.push(segment),
+            }
+        }
+        segments.join(&b'/')
+    };
+    let health_path = normalize(health_path);
+    !health_path.is_empty() && health_path.eq_ignore_ascii_case(&normalize(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Starts an upstream that answers every request with `response`, or never answers if it is
+    /// None. Returns its address.
+    async fn fake_upstream(response: Option<&'static [u8]>) -> String {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buffer = [0_u8; 1024];
+                    let _ = stream.read(&mut buffer).await;
+                    match response {
+                        Some(response) => {
+                            let _ = stream.write_all(response).await;
+                        }
+                        None => tokio::time::delay_for(Duration::from_secs(10)).await,
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    async fn probe(address: &str, expect_body: Option<&str>) -> Result<(), Failure> {
+        let success_codes: HashSet<u16> = [200].iter().copied().collect();
+        let limits = response::HeaderLimits {
+            max_bytes: 8192,
+            max_count: 100,
+        };
+        let check = HealthCheck {
+            path: "/health",
+            success_codes: &success_codes,
+            expect_body,
+            timeout: Duration::from_millis(200),
+            limits: &limits,
+            warmup: None,
+            bind_addrs: &[],
+        };
+        check.probe(address).await
+    }
+
+    #[tokio::test]
+    async fn test_probe_failures() {
+        let ready = fake_upstream(Some(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nready")).await;
+        assert_eq!(probe(&ready, None).await, Ok(()));
+        assert_eq!(probe(&ready, Some("ready")).await, Ok(()));
+        assert_eq!(probe(&ready, Some("steady")).await, Err(Failure::BodyMismatch));
+
+        let failing = fake_upstream(Some(b"HTTP/1.1 503 Service Unavailable\r\n\r\n")).await;
+        assert_eq!(probe(&failing, None).await, Err(Failure::BadStatus(503)));
+
+        let silent = fake_upstream(None).await;
+        assert_eq!(probe(&silent, None).await, Err(Failure::Timeout));
+
+        let garbled = fake_upstream(Some(b"nonsense\r\n\r\n")).await;
+        assert!(matches!(probe(&garbled, None).await, Err(Failure::BadResponse(_))));
+
+        // Nothing listens on a port we just released
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        assert!(matches!(
+            probe(&closed.to_string(), None).await,
+            Err(Failure::ConnectRefused(_))
+        ));
+    }
+
+    #[test]
+    fn test_is_health_path() {
+        for path in &[
+            "/healthz",
+            "/healthz/",
+            "/HealthZ",
+            "/%68ealthz",
+            "/healthz?x=1",
+            "//healthz",
+            "/./healthz",
+            "/x/../healthz",
+            "/../healthz",
+            "/x/%2e%2e/healthz",
+            "/healthz//.",
+            "/healthz/x/..",
+        ] {
+            assert!(is_health_path("/healthz", path), "{} got through", path);
+        }
+        assert!(is_health_path("/internal//health/", "/internal/./health"));
+        assert!(is_health_path("/internal/health?full=1", "/internal/health"));
+        for path in &["/healthz2", "/api/healthz", "/health%", "/%zzealthz", "/x/../api/healthz"] {
+            assert!(!is_health_path("/healthz", path), "{} was blocked", path);
+        }
+        assert!(!is_health_path("/", "/"));
+    }
+
+    #[test]
+    fn test_parse_warmup() {
+        let warmup: Warmup = "path=/warm?all=1, count=5".parse().unwrap();
+        assert_eq!(warmup, Warmup { path: "/warm?all=1".to_string(), count: 5 });
+        assert_eq!("path=/warm".parse::<Warmup>().map(|warmup| warmup.count), Ok(1));
+        assert!("count=5".parse::<Warmup>().is_err());
+        assert!("path=warm".parse::<Warmup>().is_err());
+        assert!("path=/warm,count=many".parse::<Warmup>().is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use std::convert::TryFrom;
+use tokio::time::Duration;
+
+/// Upper bounds of the latency buckets, in milliseconds. Anything slower lands in an extra
+/// overflow bucket.
+pub const LATENCY_BOUNDS_MS: [u64; 15] = [
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000, 10000, 30000, 60000,
+];
+
+/// Upper bounds of the buckets for body sizes, in bytes: powers of 4 from 64 bytes to 16 MiB.
+pub const SIZE_BOUNDS_BYTES: [u64; 10] = [
+    64, 256, 1024, 4096, 16384, 65536, 262144, 1048576, 4194304, 16777216,
+];
+
+/// Upper bounds of the buckets for client connection lifetimes, in milliseconds (up to an hour).
+pub const LIFETIME_BOUNDS_MS: [u64; 12] = [
+    10, 100, 500, 1000, 5000, 10000, 30000, 60000, 300000, 600000, 1800000, 3600000,
+];
+
+/// Counts of values in fixed buckets, from which quantiles can be estimated without keeping every
+/// sample. Latencies (the default) are recorded in milliseconds; other histograms pick their own
+/// bounds and unit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    bounds: &'static [u64],
+    /// One count per bound, plus one for values beyond the last bound
+    counts: Vec<u64>,
+    sum: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new(&LATENCY_BOUNDS_MS)
+    }
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [u64]) -> Histogram {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0,
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        self.record_value(u64::try_from(latency.as_millis()).unwrap_or(u64::MAX));
+    }
+
+    pub fn record_value(&mut self, value: u64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum = self.sum.saturating_add(value);
+    }
+
+    /// Adds `other`'s counts to this histogram's. Both must have the same bounds.
+    pub fn merge(&mut self, other: &Histogram) {
+        debug_assert_eq!(self.bounds, other.bounds);
+        for (count, other) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other;
+        }
+        self.sum = self.sum.saturating_add(other.sum);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Total of the recorded values.
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    /// Estimates the `q` quantile (0 to 1) of a latency histogram. See `quantile_value`.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        self.quantile_value(q).map(Duration::from_millis)
+    }
+
+    /// Estimates the `q` quantile (0 to 1) as the upper bound of the bucket it falls in. Values
+    /// beyond the last bucket are reported as its bound. Returns None if nothing was recorded.
+    pub fn quantile_value(&self, q: f64) -> Option<u64> {
+        let total = self.count();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((q * total as f64).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+        let bucket = self
+            .counts
+            .iter()
+            .position(|count| {
+                seen += count;
+                seen >= rank
+            })
+            .unwrap_or(self.bounds.len());
+        Some(self.bounds[bucket.min(self.bounds.len() - 1)])
+    }
+
+    /// (upper bound, number of values up to that bound) for each bucket, as Prometheus histograms
+    /// expose them. The overflow bucket is left out; `count` covers it.
+    pub fn cumulative_buckets(&self) -> Vec<(u64, u64)> {
+        let mut seen = 0;
+        self.bounds
+            .iter()
+            .zip(self.counts.iter())
+            .map(|(bound, count)| {
+                seen += count;
+                (*bound, seen)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantiles() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.99), None);
+        for _ in 0..98 {
+            histogram.record(Duration::from_millis(3));
+        }
+        histogram.record(Duration::from_millis(150));
+        histogram.record(Duration::from_secs(90));
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_millis(5)));
+        assert_eq!(histogram.quantile(0.99), Some(Duration::from_millis(200)));
+        assert_eq!(histogram.quantile(1.0), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = Histogram::default();
+        a.record(Duration::from_millis(1));
+        let mut b = Histogram::default();
+        b.record(Duration::from_millis(1000));
+        b.record(Duration::from_millis(1000));
+        a.merge(&b);
+        assert_eq!(a.count(), 3);
+        assert_eq!(a.quantile(0.34), Some(Duration::from_millis(1000)));
+        assert_eq!(a.quantile(0.33), Some(Duration::from_millis(1)));
+        assert_eq!(a.sum(), 2001);
+    }
+
+    #[test]
+    fn test_size_buckets() {
+        let mut sizes = Histogram::new(&SIZE_BOUNDS_BYTES);
+        for _ in 0..8 {
+            sizes.record_value(100);
+        }
+        sizes.record_value(5000);
+        sizes.record_value(50_000_000);
+        assert_eq!(sizes.quantile_value(0.5), Some(256));
+        assert_eq!(sizes.quantile_value(0.9), Some(16384));
+        assert_eq!(sizes.quantile_value(0.99), Some(16777216));
+        let buckets = sizes.cumulative_buckets();
+        assert_eq!(&buckets[..5], &[(64, 0), (256, 8), (1024, 8), (4096, 8), (16384, 9)]);
+        assert_eq!(buckets.last(), Some(&(16777216, 9)));
+        assert_eq!(sizes.count(), 10);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::{request, rewrite};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Paths that self-served endpoints answered on before they moved under `--internal-prefix`, and
+/// the endpoints they are aliases for. Only these exact paths are taken from upstreams; the rest of
+/// /balancebeam/ is forwarded as usual.
+const LEGACY_PATHS: [(&str, Endpoint); 1] = [("/balancebeam/healthz", Endpoint::Healthz)];
+
+/// Parses `--internal-prefix`: a path of at least one segment, e.g. "/_balancebeam".
+pub fn parse_prefix(value: &str) -> Result<String, String> {
+    let prefix = value.trim();
+    if !prefix.starts_with('/') {
+        return Err("the internal prefix must start with '/'".to_string());
+    }
+    let prefix = prefix.trim_end_matches('/');
+    if prefix.is_empty() {
+        return Err("the internal prefix can't be the whole site".to_string());
+    }
+    if prefix.contains(&['?', '#'][..]) {
+        return Err("the internal prefix must be a plain path".to_string());
+    }
+    Ok(prefix.to_string())
+}
+
+/// Something balancebeam answers itself on the proxy listener.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Endpoint {
+    /// 200 if the listener's pool has an upstream to send requests to, 503 if not (or if
+    /// balancebeam is still starting up)
+    Healthz,
+    /// Any other path under the prefix, which gets a 404 rather than going to an upstream
+    NotFound,
+}
+
+/// The path namespace reserved for self-served endpoints. Requests in it are answered by
+/// balancebeam and never reach an upstream, so a backend can't shadow them and they can't shadow
+/// a backend (once the legacy aliases are turned off).
+#[derive(Debug)]
+pub struct InternalPaths {
+    prefix: String,
+    /// Whether the pre-namespace paths in `LEGACY_PATHS` are still answered
+    legacy: bool,
+    /// Whether a client has been warned about, so the log isn't flooded
+    legacy_warned: AtomicBool,
+}
+
+impl InternalPaths {
+    pub fn new(prefix: String, legacy: bool) -> InternalPaths {
+        InternalPaths {
+            prefix,
+            legacy,
+            legacy_warned: AtomicBool::new(false),
+        }
+    }
+
+    /// The endpoint that `path` names, or None if the request is for an upstream. Escapes are
+    /// decoded first (as an upstream would), so "/%5Fbalancebeam/healthz" can't slip past.
+    pub fn route(&self, path: &str) -> Option<Endpoint> {
+        let decoded = String::from_utf8_lossy(&request::percent_decode(path)).into_owned();
+        let path = decoded.as_str();
+        if let Some(rest) = rewrite::strip_segment_prefix(path, &self.prefix) {
+            return Some(match rest {
+                "/healthz" => Endpoint::Healthz,
+                _ => Endpoint::NotFound,
+            });
+        }
+        if !self.legacy {
+            return None;
+        }
+        let (_, endpoint) = LEGACY_PATHS.iter().find(|(legacy, _)| *legacy == path)?;
+        if !self.legacy_warned.swap(true, Ordering::Relaxed) {
+            log::warn!(
+                "{} is deprecated; use {}{} instead (further uses are not logged)",
+                path,
+                self.prefix,
+                &path[path.rfind('/').unwrap_or(0)..]
+            );
+        }
+        Some(*endpoint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_prefix() {
+        assert_eq!(parse_prefix("/_balancebeam/"), Ok("/_balancebeam".to_string()));
+        assert!(parse_prefix("_balancebeam").is_err());
+        assert!(parse_prefix("/").is_err());
+        assert!(parse_prefix("/x?y").is_err());
+    }
+
+    #[test]
+    fn test_route() {
+        let paths = InternalPaths::new("/_balancebeam".to_string(), true);
+        assert_eq!(paths.route("/_balancebeam/healthz"), Some(Endpoint::Healthz));
+        assert_eq!(paths.route("/_balancebeam/nope"), Some(Endpoint::NotFound));
+        assert_eq!(paths.route("/_balancebeam"), Some(Endpoint::NotFound));
+        assert_eq!(paths.route("/_balancebeamx/healthz"), None);
+        assert_eq!(paths.route("/%5Fbalancebeam/healthz"), Some(Endpoint::Healthz));
+        assert_eq!(paths.route("/_balancebeam%2Fsecret"), Some(Endpoint::NotFound));
+        assert_eq!(paths.route("/balancebeam/health%7A"), Some(Endpoint::Healthz));
+        assert_eq!(paths.route("/balancebeam/healthz"), Some(Endpoint::Healthz));
+        assert_eq!(paths.route("/balancebeam/other"), None);
+
+        let paths = InternalPaths::new("/_balancebeam".to_string(), false);
+        assert_eq!(paths.route("/balancebeam/healthz"), None);
+        assert_eq!(paths.route("/_balancebeam/healthz"), Some(Endpoint::Healthz));
+    }
+}
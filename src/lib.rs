@@ -0,0 +1,3882 @@
+//! balancebeam, an HTTP load balancer. The binary parses a `ProxyConfig` from its command line and
+//! hands it to `run`; embedders can do the same, or build a `ProxyState` and its `Listener`s and
+//! drive `handle_connection` and `active_health_check` themselves.
+
+mod access;
+mod adaptive_weights;
+mod admin;
+mod bind_retry;
+mod body_rewrite;
+mod buffer_budget;
+mod capture;
+mod config;
+mod connections;
+mod cors;
+mod deadline;
+mod diagnostics;
+mod debug_routing;
+mod decompress;
+mod early_hints;
+mod effective_config;
+mod error_reason;
+mod fault_injection;
+mod hash_ring;
+mod health_check;
+mod histogram;
+mod internal_paths;
+mod metrics;
+mod outlier;
+mod phase_timer;
+mod priority;
+mod pushgateway;
+mod request;
+mod response;
+mod retry_budget;
+mod reuseport;
+mod rate_limiter;
+mod registry;
+mod rewrite;
+mod sampling;
+mod self_test;
+mod shutdown;
+mod timeout_backoff;
+mod top_talkers;
+mod upgrade;
+mod upstream_bind;
+
+use clap::{CommandFactory, FromArgMatches, Parser};
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::io::AsyncReadExt;
+use rand::{Rng, SeedableRng};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, Notify, RwLock, Semaphore};
+use tokio::time::{delay_for, timeout, Duration};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use crate::rate_limiter::fixed_window::FixedWindow;
+use crate::rate_limiter::redis::{RedisRateLimiter, RedisUrl};
+use crate::rate_limiter::offenders::Offenders;
+use crate::rate_limiter::schedule::{Schedule, ScheduledLimit};
+use crate::rate_limiter::RateLimitMode;
+use crate::phase_timer::{Phase, PhaseTimer};
+use crate::rewrite::{PathRewrite, PathRewriter};
+use crate::top_talkers::TopTalkers;
+use crate::body_rewrite::{BodyRewrite, BodyRewriter, Skipped};
+use crate::metrics::Metrics;
+use crate::adaptive_weights::WeightCalculator;
+use crate::capture::{CaptureTrigger, DebugCapture};
+use crate::sampling::{ExcludedPath, LogSampler};
+use crate::retry_budget::RetryBudget;
+use crate::timeout_backoff::{AdaptiveTimeout, TimeoutPolicy};
+use crate::deadline::Deadline;
+use crate::debug_routing::{DebugRouting, ExposeUpstream};
+use crate::connections::{ConnectionRegistry, ConnectionState};
+use crate::cors::CorsPolicy;
+use crate::access::{AccessPolicy, Cidr};
+use crate::bind_retry::BindRetry;
+use crate::admin::Api;
+use crate::buffer_budget::BufferBudget;
+use crate::early_hints::EarlyHint;
+use crate::fault_injection::{Fault, FaultInjector};
+use crate::hash_ring::HashRing;
+use crate::health_check::{HealthCheck, Warmup};
+use crate::internal_paths::{Endpoint, InternalPaths};
+use crate::outlier::{OutlierPolicy, OutlierState};
+use crate::priority::UpstreamPriority;
+use crate::registry::UpstreamRegistry;
+use crate::upgrade::InheritedFd;
+use crate::upstream_bind::UpstreamBindAddr;
+use crate::pushgateway::PushgatewayUrl;
+
+pub use crate::config::{Config, ListenerConfig};
+pub use crate::error_reason::ErrorReason;
+pub use crate::rate_limiter::{ArgRateLimiter, RateLimiterAlgorithm};
+pub use crate::registry::{Upstream, UpstreamId};
+pub use crate::shutdown::{shutdown, ShutdownResult};
+
+/// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
+/// provide a fancy way to automatically construct a command-line argument parser.
+#[derive(Parser, Debug)]
+#[clap(about = "Fun with load balancing")]
+pub struct ProxyConfig {
+    #[clap(
+        short,
+        long,
+        help = "IP/port to bind to",
+        default_value = "0.0.0.0:1100"
+    )]
+    bind: String,
+    #[clap(short, long, help = "Upstream host to forward requests to")]
+    upstream: Vec<String>,
+    #[clap(
+        long,
+        conflicts_with = "upstream",
+        help = "JSON file defining listeners and upstream pools (replaces --bind and --upstream)"
+    )]
+    config: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Keep trying to bind listeners (including the admin and metrics ones) whose \
+                address is in use, e.g. \"attempts=10,interval=0.5\" (seconds before the first \
+                retry, doubling after each)"
+    )]
+    bind_retry: Option<BindRetry>,
+    #[clap(
+        long,
+        conflicts_with = "config",
+        help = "Address to listen on instead if --bind can't be bound (after any --bind-retry \
+                attempts)"
+    )]
+    bind_fallback: Option<String>,
+    #[clap(
+        long,
+        value_parser = parse_acceptor_count,
+        help = "Accept connections on this many sockets per listener, bound to the same address \
+                with SO_REUSEPORT so that the kernel shares connections out among them (Linux \
+                only)",
+        default_value = "1"
+    )]
+    reuseport_acceptors: usize,
+    #[clap(
+        long,
+        help = "Perform active health checks on this interval (in seconds)",
+        default_value = "10"
+    )]
+    active_health_check_interval: usize,
+    #[clap(
+        long,
+        help = "Path to send request to for active health checks",
+        default_value = "/"
+    )]
+    active_health_check_path: String,
+    #[clap(
+        long,
+        alias = "upstream-healthcheck-success-codes",
+        value_parser = config::parse_status_codes,
+        help = "Comma-separated HTTP statuses that count as healthy in active health checks",
+        default_value = "200"
+    )]
+    active_health_check_success_codes: HashSet<u16>,
+    #[clap(
+        long,
+        help = "How long an active health check may take, connecting included, in milliseconds",
+        default_value = "5000"
+    )]
+    active_health_check_timeout_ms: u64,
+    #[clap(long, help = "Text that active health check response bodies must contain")]
+    active_health_check_expect_body: Option<String>,
+    #[clap(
+        long,
+        help = "Never send active health checks to this upstream, e.g. one that only speaks \
+                WebSocket; failed connections still take it out of rotation until the next \
+                check interval (repeatable)"
+    )]
+    upstream_healthcheck_disable_for: Vec<String>,
+    #[clap(
+        long,
+        action = clap::ArgAction::Set,
+        help = "Answer client requests for the active health check path with 404 instead of \
+                forwarding them, as upstreams often serve it without authentication (a path of \
+                \"/\" is never blocked)",
+        default_value = "true"
+    )]
+    block_health_path_from_clients: bool,
+    #[clap(
+        long,
+        help = "Clients allowed to request the health check path despite \
+                --block-health-path-from-clients, e.g. 10.0.0.0/8 (repeatable)"
+    )]
+    health_path_allow_cidr: Vec<Cidr>,
+    #[clap(
+        long,
+        value_parser = parse_positive,
+        help = "Most active health checks in flight at once, across all pools",
+        default_value = "16"
+    )]
+    health_check_concurrency: usize,
+    #[clap(
+        long,
+        help = "Requests to send to an upstream that passes a health check after being dead, \
+                before it gets traffic again, e.g. \"path=/warm,count=5\""
+    )]
+    warmup_requests: Option<Warmup>,
+    #[clap(
+        long,
+        help = "Log a summary of upstream health on this interval, in seconds (0 = never)",
+        default_value = "0"
+    )]
+    health_log_summary_interval: u64,
+    #[clap(
+        long,
+        help = "Maximum number of requests to accept per IP per minute (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_requests_per_minute: usize,
+    #[clap(
+        long,
+        value_parser = parse_ipv6_prefix_len,
+        help = "Rate limit IPv6 clients by the block of this many bits their address is in, since \
+                a client can have a whole /64 (128 = each address on its own)",
+        default_value = "64"
+    )]
+    rate_limit_ipv6_prefix: u8,
+    #[clap(
+        long,
+        arg_enum,
+        help = "The rate limit algorithm to apply (if max number of requests > 0)",
+        default_value = "fixed-window",
+    )]
+    rate_limiter: ArgRateLimiter,
+    #[clap(long, help = "Redis server for --rate-limiter redis, e.g. redis://localhost:6379")]
+    redis_url: Option<RedisUrl>,
+    #[clap(
+        long,
+        action = clap::ArgAction::Set,
+        help = "Rate limit locally while Redis is unreachable (otherwise requests are let through)",
+        default_value = "true"
+    )]
+    rate_limiter_redis_fallback_local: bool,
+    #[clap(
+        long,
+        arg_enum,
+        help = "Whether requests over the rate limit are rejected, or only logged and counted \
+                (can be changed through the admin API)",
+        default_value = "enforce"
+    )]
+    rate_limit_mode: RateLimitMode,
+    #[clap(
+        long,
+        help = "Replace the rate limit during a daily window (UTC), e.g. \"name=nightly,\
+                start=02:00,end=04:00,limit=6000\" or \"start=02:00,duration=2h,limit=6000\" (repeatable; \
+                overlapping windows take the highest limit; listeners without a limit stay \
+                unlimited)"
+    )]
+    rate_limit_schedule: Vec<ScheduledLimit>,
+    #[clap(
+        long,
+        help = "Replace a path prefix before forwarding, e.g. \"match=/api/v1,replace=/\" \
+                (repeatable, longest match wins)"
+    )]
+    rewrite_path: Vec<PathRewrite>,
+    #[clap(
+        long,
+        help = "Replace text in upstream response bodies, e.g. \"from=http://internal-host:8080,\
+                to=https://www.example.com\" (repeatable; off by default, and only for \
+                uncompressed responses with a --rewrite-body-content-type)"
+    )]
+    rewrite_body: Vec<BodyRewrite>,
+    #[clap(
+        long,
+        help = "Content type whose bodies --rewrite-body applies to (repeatable)",
+        default_value = "text/html"
+    )]
+    rewrite_body_content_type: Vec<String>,
+    #[clap(
+        long,
+        help = "Largest response body --rewrite-body applies to, in bytes",
+        default_value = "1048576"
+    )]
+    rewrite_body_max_bytes: usize,
+    #[clap(
+        long,
+        help = "Decompress gzip and deflate response bodies for clients whose Accept-Encoding \
+                doesn't allow them (bounded by the response body size limit)"
+    )]
+    decompress_upstream: bool,
+    #[clap(
+        long,
+        help = "Add X-Content-Type-Options: nosniff to upstream responses, and give those without \
+                a Content-Type the --default-content-type, so that browsers don't guess the type \
+                from the body"
+    )]
+    security_headers: bool,
+    #[clap(
+        long,
+        value_parser = response::parse_content_type,
+        help = "Content-Type given by --security-headers to upstream responses without one",
+        default_value = "application/octet-stream"
+    )]
+    default_content_type: http::HeaderValue,
+    #[clap(
+        long,
+        help = "Send a 103 Early Hints response with this Link header to HTTP/1.1 GETs under a \
+                path prefix before contacting an upstream, e.g. \"prefix=/app,link=</app.css>; \
+                rel=preload; as=style\" (repeatable)"
+    )]
+    early_hints: Vec<EarlyHint>,
+    #[clap(long, help = "IP/port to serve the admin API (/status, /metrics) on")]
+    admin_bind: Option<String>,
+    #[clap(
+        long,
+        help = "Network allowed to use the admin API, e.g. 10.0.0.0/8 (repeatable; default \
+                loopback only)"
+    )]
+    admin_allow_cidr: Vec<Cidr>,
+    #[clap(long, help = "Require this bearer token on admin API requests")]
+    admin_token: Option<String>,
+    #[clap(long, help = "IP/port to serve only /metrics on, for scrapers")]
+    metrics_bind: Option<String>,
+    #[clap(
+        long,
+        help = "Network allowed to scrape --metrics-bind (repeatable; default loopback only)"
+    )]
+    metrics_allow_cidr: Vec<Cidr>,
+    #[clap(long, help = "Require this bearer token on --metrics-bind requests")]
+    metrics_token: Option<String>,
+    #[clap(long, help = "Periodically push metrics to this Prometheus Pushgateway (http:// only)")]
+    pushgateway_url: Option<PushgatewayUrl>,
+    #[clap(long, help = "How often to push metrics, in seconds", default_value = "15")]
+    pushgateway_interval_secs: u64,
+    #[clap(
+        long,
+        help = "Track the clients and path prefixes sending and receiving the most bytes, keeping \
+                counts for about this many of each per five minutes, and list them in the admin \
+                API's /status"
+    )]
+    top_k_entries: Option<usize>,
+    #[clap(
+        long,
+        help = "How long --top-k-entries counts are kept, in minutes",
+        default_value = "60"
+    )]
+    top_k_retention: u64,
+    #[clap(long, help = "Check that every upstream accepts TCP connections before serving")]
+    preflight_check: bool,
+    #[clap(
+        long,
+        conflicts_with = "dry-upstream-connect",
+        help = "Before accepting connections, health check every upstream and send a request \
+                through each listener, exiting if one doesn't come back 2xx"
+    )]
+    self_test: bool,
+    #[clap(
+        long,
+        help = "Path --self-test requests (default: the pool's active health check path)"
+    )]
+    self_test_path: Option<String>,
+    #[clap(
+        long,
+        requires = "preflight-check",
+        help = "Exit if the preflight check finds an unreachable upstream"
+    )]
+    require_all_upstreams_reachable: bool,
+    #[clap(
+        long,
+        conflicts_with = "preflight-check",
+        help = "For testing balancebeam itself: never contact upstreams, and forward requests to a \
+                local stand-in that hangs up instead (every forwarded request gets a 502)"
+    )]
+    dry_upstream_connect: bool,
+    #[clap(long, help = "Directory to dump raw bytes of problematic exchanges into")]
+    debug_capture_dir: Option<PathBuf>,
+    #[clap(
+        long,
+        arg_enum,
+        help = "Which exchanges to capture (with --debug-capture-dir)",
+        default_value = "parse-error"
+    )]
+    debug_capture_on: CaptureTrigger,
+    #[clap(
+        long,
+        help = "Maximum number of bytes captured per request and per response",
+        default_value = "65536"
+    )]
+    debug_capture_max_bytes: usize,
+    #[clap(
+        long,
+        help = "Maximum number of capture files to keep (oldest are deleted first)",
+        default_value = "100"
+    )]
+    debug_capture_max_files: usize,
+    #[clap(long, help = "Don't redact Authorization and Cookie headers in capture files")]
+    debug_capture_unredacted: bool,
+    #[clap(
+        long,
+        arg_enum,
+        help = "HTTP version to speak to upstreams (1.0 opens a new connection per request)",
+        default_value = "1.1"
+    )]
+    upstream_http_version: UpstreamHttpVersion,
+    #[clap(
+        long,
+        help = "Speak a particular HTTP version to one upstream, e.g. \"10.0.0.1:80=http1.0\" \
+                (repeatable; other upstreams get --upstream-http-version)"
+    )]
+    upstream_proto: Vec<UpstreamProtocol>,
+    #[clap(
+        long,
+        help = "Send this Host header to upstreams instead of the client's (a pool in --config can \
+                set its own with \"upstream_http_host_override\")"
+    )]
+    upstream_http_host_override: Option<String>,
+    #[clap(
+        long,
+        value_parser = sampling::parse_sample_rate,
+        help = "Log headers and timings for a fraction (0.01) or one in N (100) of requests; \
+                with --log-upstream-errors-only, also their access log lines [default: 1, or 0 \
+                with --log-upstream-errors-only]"
+    )]
+    log_sample_rate: Option<u64>,
+    #[clap(long, help = "Only log requests that fail, plus upstream health changes")]
+    log_upstream_errors_only: bool,
+    #[clap(
+        long,
+        help = "Leave requests for this path out of the access log; a trailing * matches any path \
+                with that prefix (repeatable)"
+    )]
+    access_log_exclude_path: Vec<ExcludedPath>,
+    #[clap(
+        long,
+        help = "Maximum size of upstream response headers, in bytes",
+        default_value = "8000"
+    )]
+    max_response_header_bytes: usize,
+    #[clap(
+        long,
+        help = "Maximum number of headers in an upstream response",
+        default_value = "32"
+    )]
+    max_response_headers: usize,
+    #[clap(
+        long,
+        help = "Most bytes of request and response bodies to hold in memory at once, across all \
+                connections; requests that would go over get a 503 (default unlimited)"
+    )]
+    max_buffered_bytes: Option<usize>,
+    #[clap(
+        long,
+        help = "Largest request body to accept, in bytes; bigger ones get a 413, chunked ones as \
+                soon as they cross it",
+        default_value = "10000000"
+    )]
+    max_request_body_bytes: usize,
+    #[clap(long, help = "Redirect plain HTTP requests to https:// instead of proxying them")]
+    redirect_http_to_https: bool,
+    #[clap(long, help = "Forward TRACE requests instead of refusing them with 405")]
+    allow_trace: bool,
+    #[clap(
+        long,
+        value_parser = internal_paths::parse_prefix,
+        help = "Path prefix that balancebeam's own endpoints (such as <prefix>/healthz) are served \
+                under; requests under it never reach upstreams",
+        default_value = "/_balancebeam"
+    )]
+    internal_prefix: String,
+    #[clap(
+        long,
+        help = "Forward /balancebeam/healthz to upstreams instead of answering it as a deprecated \
+                alias for <internal prefix>/healthz"
+    )]
+    no_legacy_internal_paths: bool,
+    #[clap(long, help = "Count requests for balancebeam's own endpoints towards the rate limit")]
+    rate_limit_internal_paths: bool,
+    #[clap(
+        long,
+        help = "Answer CORS preflight requests ourselves, allowing this origin (repeatable; * \
+                allows any)"
+    )]
+    cors_allowed_origin: Vec<String>,
+    #[clap(
+        long,
+        help = "Maximum retries, as a percentage of requests over the last 10 seconds",
+        default_value = "20"
+    )]
+    retry_budget_percent: u64,
+    #[clap(
+        long,
+        help = "Retries per second always allowed on top of --retry-budget-percent, so a quiet \
+                proxy can still fail over",
+        default_value = "10"
+    )]
+    retry_budget_min_per_sec: u64,
+    #[clap(
+        long,
+        alias = "upstream-timeout-ms",
+        help = "How long to wait for an upstream's response headers after sending the request, in \
+                milliseconds (0 = no limit)",
+        default_value = "0"
+    )]
+    upstream_response_header_timeout_ms: u64,
+    #[clap(
+        long,
+        help = "How long to wait for the rest of an upstream's response body once its headers have \
+                arrived, in milliseconds (0 = no limit)",
+        default_value = "0"
+    )]
+    upstream_response_body_timeout_ms: u64,
+    #[clap(
+        long,
+        alias = "upstream-write-timeout",
+        help = "How long an upstream may take to accept a request, in seconds; slower upstreams \
+                are marked dead and the request is sent to another one",
+        default_value = "10"
+    )]
+    upstream_write_timeout_secs: f64,
+    #[clap(
+        long,
+        value_parser = fault_injection::parse_rate,
+        help = "For testing clients: answer this fraction of requests (e.g. 0.05) with a 500 \
+                without contacting an upstream",
+        default_value = "0"
+    )]
+    fault_inject_error_rate: f64,
+    #[clap(
+        long,
+        value_parser = fault_injection::parse_rate,
+        help = "For testing clients: answer this fraction of requests with a 502, as if no \
+                upstream could be connected to",
+        default_value = "0"
+    )]
+    fault_inject_upstream_failure_rate: f64,
+    #[clap(
+        long,
+        help = "For testing clients: delay some requests (see --fault-inject-delay-rate) by this \
+                many milliseconds before forwarding them",
+        default_value = "0"
+    )]
+    fault_inject_delay_ms: u64,
+    #[clap(
+        long,
+        value_parser = fault_injection::parse_rate,
+        help = "Fraction of requests delayed by --fault-inject-delay-ms",
+        default_value = "0.05"
+    )]
+    fault_inject_delay_rate: f64,
+    #[clap(
+        long,
+        help = "Multiply an upstream's timeout by this after a response close to the timeout \
+                (1.0 = no backoff)",
+        default_value = "1.0"
+    )]
+    upstream_timeout_backoff_factor: f64,
+    #[clap(
+        long,
+        help = "Upper bound for backed-off upstream timeouts, in milliseconds",
+        default_value = "120000"
+    )]
+    upstream_timeout_max_ms: u64,
+    #[clap(
+        long,
+        help = "Consecutive fast responses before a backed-off timeout is reset",
+        default_value = "10"
+    )]
+    upstream_timeout_recovery_requests: usize,
+    #[clap(
+        long,
+        help = "Deadline for each request, in seconds; also caps X-Request-Timeout from clients"
+    )]
+    request_deadline_secs: Option<f64>,
+    #[clap(
+        long,
+        help = "Longest timeout clients may ask for with X-Request-Timeout, in seconds",
+        default_value = "60"
+    )]
+    max_request_timeout_secs: f64,
+    #[clap(
+        long,
+        help = "Honor X-Balancebeam-Upstream overrides whose X-Balancebeam-Token is a hex \
+                HMAC-SHA256 of the upstream, keyed with this secret"
+    )]
+    debug_routing_secret: Option<String>,
+    #[clap(
+        long,
+        help = "Add X-Served-By (the upstream's address) and X-Upstream-Latency-Ms to responses"
+    )]
+    expose_upstream_header: bool,
+    #[clap(
+        long,
+        requires = "expose-upstream-header",
+        help = "Only add the --expose-upstream-header headers for requests with this secret in \
+                X-Balancebeam-Debug"
+    )]
+    expose_upstream_header_token: Option<String>,
+    #[clap(
+        long,
+        value_parser = parse_thread_count,
+        help = "Number of threads handling connections (defaults to the number of CPUs)"
+    )]
+    worker_threads: Option<usize>,
+    #[clap(
+        long,
+        value_parser = parse_thread_count,
+        help = "Maximum number of threads for blocking work",
+        default_value = "512"
+    )]
+    blocking_threads: usize,
+    #[clap(
+        long,
+        arg_enum,
+        help = "How upstreams are picked for each request",
+        default_value = "random"
+    )]
+    load_balancing: LoadBalancingStrategy,
+    #[clap(
+        long,
+        value_parser = parse_positive,
+        help = "Virtual nodes per upstream on the consistent hash ring",
+        default_value = "100"
+    )]
+    consistent_hash_replicas: usize,
+    #[clap(
+        long,
+        help = "Hash this request header rather than the client's IP for consistent hashing \
+                (requests without it fall back to the IP)"
+    )]
+    consistent_hash_by_header: Option<http::header::HeaderName>,
+    #[clap(
+        long,
+        help = "Periodically shift traffic towards upstreams with lower latency and fewer errors"
+    )]
+    adaptive_weights: bool,
+    #[clap(
+        long,
+        help = "How often to recompute adaptive weights, in seconds",
+        default_value = "5"
+    )]
+    adaptive_weights_interval_secs: u64,
+    #[clap(
+        long,
+        help = "Give an upstream a priority, e.g. \"10.0.0.1:80=2\" (repeatable; default 0). Lower \
+                priorities only get traffic while every higher-priority upstream is dead"
+    )]
+    upstream_priority: Vec<UpstreamPriority>,
+    #[clap(
+        long,
+        help = "Connect to upstreams (health checks included) from this local address, for \
+                backends that only accept one interface; \"<upstream>=<ip>\" applies to a single \
+                upstream and takes precedence (repeatable)"
+    )]
+    upstream_bind_addr: Vec<UpstreamBindAddr>,
+    #[clap(long, help = "Append SIGUSR2 diagnostics dumps to this file instead of stderr")]
+    diag_file: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Append the JSON summary written at shutdown to this file instead of the log"
+    )]
+    shutdown_report_file: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "How long to wait on SIGTERM or SIGINT for open connections to finish, in seconds",
+        default_value = "30"
+    )]
+    shutdown_timeout_secs: u64,
+    #[clap(
+        long,
+        help = "Serve on this already-bound listener socket instead of binding one: a bare fd \
+                (or comma-separated SO_REUSEPORT fds) stands for the next listener in order, \
+                admin=<fd> and metrics=<fd> for the internal ones (repeatable; without it, \
+                sockets passed by systemd in LISTEN_FDS are used)"
+    )]
+    inherit_listener_fd: Vec<InheritedFd>,
+    #[clap(
+        long,
+        help = "Binary that POST /upgrade on the admin API starts with the same arguments, \
+                handing it the listener sockets; once it is ready, this process drains and exits"
+    )]
+    upgrade_binary: Option<PathBuf>,
+    /// Set by an upgrade: the pipe to tell the old process on once we are serving
+    #[clap(long, hide = true)]
+    upgrade_ready_fd: Option<RawFd>,
+    #[clap(
+        long,
+        help = "Export the number of open client connections and remember each one's latest \
+                request, to help find leaked connections"
+    )]
+    debug_connection_tracking: bool,
+    #[clap(
+        long,
+        requires = "debug-connection-tracking",
+        help = "Warn about client connections open longer than this, in seconds (0 = never)",
+        default_value = "0"
+    )]
+    max_connection_age_secs: u64,
+    #[clap(
+        long,
+        help = "Longest X-Forwarded-For value to forward, in bytes; the oldest entries are dropped \
+                to fit",
+        default_value = "1024"
+    )]
+    max_xff_bytes: usize,
+    #[clap(
+        long,
+        help = "Most X-Forwarded-For entries to forward; the oldest are dropped to fit",
+        default_value = "20"
+    )]
+    max_xff_entries: usize,
+    #[clap(
+        long,
+        arg_enum,
+        help = "How to tell upstreams who the client is: X-Forwarded-For, the standard Forwarded \
+                header (which replaces every X-Forwarded-* header), or both",
+        default_value = "x-forwarded-for"
+    )]
+    forwarded_header_style: ForwardedHeaderStyle,
+    #[clap(
+        long,
+        action = clap::ArgAction::Set,
+        help = "Forward the request headers balancebeam doesn't change in the order and case the \
+                client sent them (otherwise they are lowercased and grouped by name)",
+        default_value = "true"
+    )]
+    preserve_header_order: bool,
+    #[clap(
+        long,
+        value_parser = request::parse_header_name,
+        help = "Strip this header from client requests as they arrive, so that clients can't \
+                spoof headers that backends trust, e.g. X-Internal-Auth (repeatable; headers \
+                balancebeam sets itself, such as X-Deadline-Ms, always are)"
+    )]
+    strip_request_header: Vec<http::header::HeaderName>,
+    #[clap(
+        long,
+        value_parser = request::parse_header_name,
+        help = "Strip this header from requests just before forwarding them, after balancebeam \
+                has added its own (repeatable)"
+    )]
+    upstream_remove_request_header: Vec<http::header::HeaderName>,
+    #[clap(
+        long,
+        value_parser = request::parse_header,
+        help = "Send this header to upstreams with every request, e.g. \"X-Internal-Token: \
+                secret\" (repeatable; replaces any the client sent, and applies after the \
+                removals)"
+    )]
+    upstream_add_request_header: Vec<(http::header::HeaderName, http::HeaderValue)>,
+    #[clap(
+        long,
+        value_parser = request::parse_header_name,
+        help = "Strip this header from upstream responses, e.g. Server or X-Powered-By (repeatable)"
+    )]
+    upstream_remove_response_header: Vec<http::header::HeaderName>,
+    #[clap(
+        long,
+        value_parser = request::parse_header,
+        help = "Add this header to every upstream response, e.g. \"X-Served-By: balancebeam\" \
+                (repeatable; replaces any the upstream sent, and applies after the removals)"
+    )]
+    upstream_add_response_header: Vec<(http::header::HeaderName, http::HeaderValue)>,
+    #[clap(
+        long,
+        value_parser = outlier::parse_threshold,
+        help = "Eject upstreams from rotation when more than this fraction (0 to 1) of their \
+                recent responses are 5xx"
+    )]
+    outlier_5xx_threshold: Option<f64>,
+    #[clap(
+        long,
+        help = "Responses an upstream must have sent recently before it can be ejected",
+        default_value = "20"
+    )]
+    outlier_min_requests: usize,
+    #[clap(
+        long,
+        help = "How far back responses count towards --outlier-5xx-threshold, in seconds",
+        default_value = "30"
+    )]
+    outlier_window_secs: u64,
+    #[clap(
+        long,
+        alias = "outlier-ejection-duration",
+        help = "How long an ejected upstream stays out of rotation, in seconds",
+        default_value = "30"
+    )]
+    outlier_ejection_duration_secs: u64,
+    #[clap(
+        long,
+        help = "Most upstreams of a pool that may be ejected at once, as a percentage (at least \
+                one, never all)",
+        default_value = "10"
+    )]
+    outlier_max_ejected_percent: u64,
+    #[clap(
+        long,
+        help = "Log an error when every upstream in a pool has failed this many requests in a row \
+                (0 = never)",
+        default_value = "5"
+    )]
+    failure_streak_alert: u64,
+    #[clap(
+        long,
+        help = "How long requests already sent to an upstream that failed its health check may \
+                take to finish before it is marked dead, in seconds",
+        default_value = "30"
+    )]
+    upstream_drain_timeout_secs: u64,
+    #[clap(
+        long,
+        alias = "removal-grace-period",
+        help = "How long requests already sent to an upstream removed through the admin API may \
+                take to finish, in seconds, before their connections are closed",
+        default_value = "30"
+    )]
+    removal_grace_period_secs: u64,
+    /// What the command line was parsed into, which knows which options were given and which were
+    /// defaulted. Kept for the effective configuration logged at startup and served at /config.
+    #[clap(skip)]
+    matches: clap::ArgMatches,
+}
+
+impl Default for ProxyConfig {
+    /// The options balancebeam runs with given only `--bind 127.0.0.1:0` (a port the OS picks), so
+    /// that tests can build a `ProxyState` without a command line. Set `upstream` before use.
+    fn default() -> Self {
+        ProxyConfig::parse_args(["balancebeam", "--bind", "127.0.0.1:0"])
+    }
+}
+
+impl ProxyConfig {
+    /// Parses a command line, as `Parser::parse_from` does (exiting with usage on errors), but also
+    /// remembers where each option's value came from.
+    pub fn parse_args<I, T>(args: I) -> ProxyConfig
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let matches = ProxyConfig::command().get_matches_from(args);
+        let mut options =
+            ProxyConfig::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+        options.matches = matches;
+        options
+    }
+
+    /// The listeners and pools to serve: the --config file's if there is one, or else the single
+    /// listener given by --bind and --upstream.
+    pub fn load_config(&self) -> Result<Config, String> {
+        let defaults = config::Defaults {
+            active_health_check_interval: self.active_health_check_interval,
+            active_health_check_path: &self.active_health_check_path,
+            active_health_check_success_codes: &self.active_health_check_success_codes,
+            rate_limiter: self.rate_limiter,
+            host_override: self.upstream_http_host_override.as_deref(),
+        };
+        match &self.config {
+            Some(path) => Config::load(path, &defaults)
+                .map_err(|err| format!("Could not load config file {:?}: {}", path, err)),
+            None if self.upstream.is_empty() => Err(
+                "At least one upstream server must be specified using the --upstream option."
+                    .to_string(),
+            ),
+            None => Ok(Config::single_listener(
+                &self.bind,
+                self.upstream.clone(),
+                self.max_requests_per_minute,
+                &defaults,
+            )),
+        }
+    }
+
+    /// A tokio runtime with the --worker-threads and --blocking-threads asked for, to `run` these
+    /// options on.
+    pub fn runtime(&self) -> tokio::runtime::Runtime {
+        let worker_threads = self.worker_threads.unwrap_or_else(|| {
+            std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+        });
+        log::info!(
+            "Using {} worker threads and up to {} blocking threads",
+            worker_threads,
+            self.blocking_threads
+        );
+        // tokio's max_threads counts the worker threads as well as the blocking ones
+        tokio::runtime::Builder::new()
+            .threaded_scheduler()
+            .enable_all()
+            .core_threads(worker_threads)
+            .max_threads(worker_threads + self.blocking_threads)
+            .build()
+            .expect("Could not start the tokio runtime")
+    }
+}
+
+fn parse_positive(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err("expected a positive number".to_string()),
+    }
+}
+
+fn parse_ipv6_prefix_len(value: &str) -> Result<u8, String> {
+    match value.parse::<u8>() {
+        Ok(len) if (1..=128).contains(&len) => Ok(len),
+        _ => Err("expected a prefix length from 1 to 128".to_string()),
+    }
+}
+
+fn parse_thread_count(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err("expected a positive number of threads".to_string()),
+    }
+}
+
+fn parse_acceptor_count(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err("expected a positive number of acceptors".to_string()),
+    }
+}
+
+/// The HTTP version balancebeam uses when talking to upstreams, regardless of what clients use.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq)]
+enum UpstreamHttpVersion {
+    #[clap(name = "1.0")]
+    Http10,
+    #[clap(name = "1.1")]
+    Http11,
+}
+
+/// An `--upstream-proto` option, for pools that mix upstreams that speak HTTP/1.1 with ones that
+/// only speak HTTP/1.0.
+#[derive(Debug, Clone, PartialEq)]
+struct UpstreamProtocol {
+    address: String,
+    version: UpstreamHttpVersion,
+}
+
+impl FromStr for UpstreamProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, version) = s
+            .rsplit_once('=')
+            .ok_or_else(|| "expected <address>=http1.0 or <address>=http1.1".to_string())?;
+        let version = match version.trim() {
+            "http1.0" => UpstreamHttpVersion::Http10,
+            "http1.1" => UpstreamHttpVersion::Http11,
+            other => return Err(format!("\"{}\" is not http1.0 or http1.1", other)),
+        };
+        Ok(UpstreamProtocol {
+            address: config::normalize_address(address),
+            version,
+        })
+    }
+}
+
+/// How an upstream is picked from the alive ones in a tier. Both respect upstream weights.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq)]
+enum LoadBalancingStrategy {
+    /// One upstream drawn at random
+    Random,
+    /// Two upstreams drawn at random, and whichever has fewer requests in flight
+    PowerOfTwoChoices,
+    /// The upstream the client's IP (or --consistent-hash-by-header) maps to on a hash ring, for
+    /// as long as it is alive
+    ConsistentHashing,
+}
+
+/// Which headers tell upstreams where a request came from.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq)]
+enum ForwardedHeaderStyle {
+    #[clap(name = "x-forwarded-for")]
+    XForwardedFor,
+    #[clap(name = "rfc7239")]
+    Rfc7239,
+    Both,
+}
+
+/// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
+/// to, what servers have failed, rate limiting counts, etc.)
+///
+/// You should add fields to this struct in later milestones. Settings that differ between listeners
+/// live in `Listener` instead.
+pub struct ProxyState {
+    /// Upstream pools, shared by the listeners that route to them
+    pools: Vec<Arc<UpstreamPool>>,
+    /// Path prefix substitutions applied to forwarded requests
+    path_rewriter: PathRewriter,
+    /// Text substitutions applied to response bodies
+    body_rewriter: BodyRewriter,
+    /// Whether compressed responses are decoded for clients that can't accept them
+    decompress_upstream: bool,
+    /// Whether responses get X-Content-Type-Options and, if they lack one, `default_content_type`
+    security_headers: bool,
+    default_content_type: http::HeaderValue,
+    /// Link headers sent ahead of responses to GETs under configured prefixes
+    early_hints: Vec<EarlyHint>,
+    /// Tells the accept loops and background tasks to stop, when shutting down
+    shutdown: broadcast::Sender<()>,
+    /// How long open client connections get to finish when shutting down
+    shutdown_timeout: Duration,
+    /// Binary that POST /upgrade hands the listeners to, if upgrades are allowed
+    upgrade_binary: Option<PathBuf>,
+    /// The sockets handed over in an upgrade, filled in once the listeners are bound
+    upgrade_listeners: parking_lot::Mutex<Vec<InheritedFd>>,
+    /// Whether an upgrade is under way, so that only one runs at a time
+    upgrading: AtomicBool,
+    /// Tells balancebeam to shut down because a new binary has taken over
+    upgraded: Notify,
+    /// Whether startup (including any --self-test) has finished; healthz answers 503 until then
+    ready: AtomicBool,
+    /// Counters exported for monitoring
+    metrics: Metrics,
+    /// When balancebeam started, for reporting uptime
+    started_at: Instant,
+    /// Every setting balancebeam was started with, secrets redacted, for /config
+    effective_config: serde_json::Value,
+    /// ID assigned to the next request read from a client
+    next_request_id: AtomicU64,
+    /// Whether the rate limit is only being observed (`RateLimitMode::Observe`) rather than
+    /// enforced. The admin API can flip this at runtime.
+    rate_limit_observe: AtomicBool,
+    /// Clients with the most requests over the rate limit lately, rejected or not
+    rate_limit_offenders: Offenders,
+    /// Bits of an IPv6 client's address that identify it for rate limiting
+    rate_limit_ipv6_prefix: u8,
+    /// Whether the metrics are exported anywhere, so that proxied requests' phases are worth
+    /// timing into them
+    phase_metrics: bool,
+    /// Bytes by client and by path prefix (None unless --top-k-entries is given)
+    top_talkers: Option<TopTalkers>,
+    /// Daily windows in which the listeners' rate limits are replaced
+    rate_limit_schedule: Schedule,
+    /// Raw exchange capture for debugging (None unless --debug-capture-dir is given)
+    debug_capture: Option<DebugCapture>,
+    /// HTTP version used for upstream requests, unless `upstream_protocols` says otherwise
+    upstream_http_version: UpstreamHttpVersion,
+    /// HTTP versions for particular upstreams, by address
+    upstream_protocols: HashMap<String, UpstreamHttpVersion>,
+    /// Picks the requests that get verbose logging
+    log_sampler: LogSampler,
+    /// Whether to skip access log lines for successful requests that weren't sampled
+    log_upstream_errors_only: bool,
+    /// Paths whose successful requests never get access log lines (e.g. health checks)
+    access_log_exclude_paths: Vec<ExcludedPath>,
+    /// Limits on upstream response headers
+    response_limits: response::HeaderLimits,
+    /// How long an active health check may take
+    health_check_timeout: Duration,
+    /// Text that active health check responses must contain, if any
+    health_check_expect_body: Option<String>,
+    /// Upstreams that are never sent active health checks
+    health_check_disabled: HashSet<String>,
+    /// One permit per health check that may be in flight, shared by all the pools
+    health_check_permits: Semaphore,
+    /// The number of permits in `health_check_permits`, for logging
+    health_check_concurrency: usize,
+    /// Requests that prime an upstream's caches before it is let back in after being dead
+    warmup: Option<Warmup>,
+    /// Bytes held in request and response bodies, and the most that may be
+    buffer_budget: BufferBudget,
+    /// Largest request body accepted
+    max_request_body_bytes: usize,
+    /// Whether to answer plain HTTP requests with a redirect to HTTPS
+    redirect_http_to_https: bool,
+    /// Whether TRACE requests are forwarded. TRACE reflects the request back (including cookies
+    /// and auth headers), so it is refused by default.
+    allow_trace: bool,
+    /// Where balancebeam's own endpoints are served on the proxy listeners
+    internal_paths: InternalPaths,
+    /// Whether requests for our own endpoints count towards the rate limit
+    rate_limit_internal_paths: bool,
+    /// The clients that may request the upstreams' health check path, if any are kept from it
+    health_path_allowed: Option<Vec<Cidr>>,
+    /// Origins allowed by the CORS preflight requests we answer, if we answer them
+    cors: Option<CorsPolicy>,
+    /// Faults to inject into requests for testing, if any are enabled
+    fault_injector: Option<FaultInjector>,
+    /// Shared limit on retries across all retry sites
+    retry_budget: RetryBudget,
+    /// How upstream response header timeouts adapt to slow upstreams
+    timeout_policy: TimeoutPolicy,
+    /// How long an upstream may take to send a response body once its headers have arrived
+    body_timeout: Option<Duration>,
+    /// How long an upstream may take to accept a request
+    upstream_write_timeout: Duration,
+    /// Longest deadline a request may have, if any
+    request_deadline: Option<Duration>,
+    /// Longest timeout a client may ask for
+    max_request_timeout: Duration,
+    /// Verifies requests that ask to be sent to a particular upstream, if that's enabled
+    debug_routing: Option<DebugRouting>,
+    /// Which responses say which upstream served them, if any
+    expose_upstream: Option<ExposeUpstream>,
+    /// Client connections that are currently open
+    connections: ConnectionRegistry,
+    /// Whether connection counts are exported (--debug-connection-tracking)
+    debug_connection_tracking: bool,
+    /// Caps on the X-Forwarded-For and Forwarded chains we forward
+    xff_limits: request::ListLimits,
+    /// Whether we add X-Forwarded-For, Forwarded or both
+    forwarded_header_style: ForwardedHeaderStyle,
+    /// Whether requests keep the order and case of the client's header fields for forwarding
+    preserve_header_order: bool,
+    /// Headers stripped from client requests as they arrive, ours included
+    strip_request_headers: Vec<http::header::HeaderName>,
+    /// Headers stripped from requests before they are forwarded
+    upstream_remove_request_headers: Vec<http::header::HeaderName>,
+    /// Headers added to every forwarded request, after the removals (their values are kept out of
+    /// the logs, as they are often credentials)
+    upstream_add_request_headers: Vec<(http::header::HeaderName, http::HeaderValue)>,
+    /// Headers stripped from upstream responses before they are relayed
+    upstream_remove_response_headers: Vec<http::header::HeaderName>,
+    /// Headers added to every upstream response, after the removals
+    upstream_add_response_headers: Vec<(http::header::HeaderName, http::HeaderValue)>,
+    /// When to eject upstreams that answer with too many 5xx, if that's enabled
+    outlier_policy: Option<OutlierPolicy>,
+    /// Consecutive failures of every upstream in a pool that set off the all-failing alert
+    failure_streak_alert: u64,
+    /// How long in-flight requests to a failed upstream may take before it is marked dead
+    upstream_drain_timeout: Duration,
+    /// How long in-flight requests to a removed upstream may take before it is gone for good
+    removal_grace_period: Duration,
+    /// Priorities for upstreams, including ones added through the admin API
+    upstream_priorities: Vec<UpstreamPriority>,
+    /// Local addresses connections to upstreams are made from
+    upstream_bind_addrs: Vec<UpstreamBindAddr>,
+    load_balancing: LoadBalancingStrategy,
+    /// Request header whose value is the consistent hashing key, instead of the client's IP
+    consistent_hash_header: Option<http::header::HeaderName>,
+    /// Address of the stand-in that every upstream connection goes to instead of the real
+    /// upstream, with --dry-upstream-connect
+    dry_upstream: Option<String>,
+}
+
+/// A summary for error messages and tests. Upstream addresses are left out, as they are often
+/// internal IPs that shouldn't end up in logs; see the `UpstreamPool` impl.
+impl fmt::Debug for ProxyState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = if self.rate_limit_observe.load(Ordering::Relaxed) {
+            RateLimitMode::Observe
+        } else {
+            RateLimitMode::Enforce
+        };
+        f.debug_struct("ProxyState")
+            .field("pools", &self.pools)
+            .field("rate_limit_mode", &mode.as_str())
+            .field(
+                "rate_limit_profile",
+                &self.rate_limit_schedule.active().map(|window| &window.name),
+            )
+            .field("upstream_http_version", &self.upstream_http_version)
+            .field("health_check_timeout", &self.health_check_timeout)
+            .field("connections", &self.connections.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A named set of upstreams, health checked once no matter how many listeners use it.
+pub struct UpstreamPool {
+    name: String,
+    /// How frequently we check whether upstream servers are alive (Milestone 4)
+    active_health_check_interval: usize,
+    /// Where we should send requests when doing active health checks (Milestone 4)
+    active_health_check_path: String,
+    /// Statuses that count as healthy in an active health check
+    active_health_check_success_codes: HashSet<u16>,
+    upstreams_state: RwLock<UpstreamsState>,
+    /// Servers that we are proxying to, with their latency and error counts
+    upstreams: UpstreamRegistry,
+    /// Host header sent to this pool's upstreams in place of the client's, if any
+    host_override: Option<http::HeaderValue>,
+}
+
+impl UpstreamPool {
+    /// Adds an upstream while requests are being served, unless the pool already has one with
+    /// this address that isn't being removed. It may take the slot of one that was removed. Its
+    /// state is in place before anything else can use its id. Returns the upstream's id and
+    /// whether it was added.
+    async fn add_upstream(
+        &self,
+        address: &str,
+        priority: u8,
+        timeout: AdaptiveTimeout,
+    ) -> (UpstreamId, bool) {
+        let mut upstreams_state = self.upstreams_state.write().await;
+        match self.upstreams.find(address) {
+            Some(id) if !upstreams_state.is_removed(id) => (id, false),
+            _ => {
+                let id = self.upstreams.add(address.to_string(), priority);
+                upstreams_state.add(id, timeout);
+                if let Some(ring) = upstreams_state.ring.as_mut() {
+                    ring.add(id.index(), address);
+                }
+                (id, true)
+            }
+        }
+    }
+}
+
+/// Upstreams are shown by position ("upstream_0", ...) rather than address. How many are alive
+/// isn't shown: the upstreams' state is behind an async lock, which can't be taken here.
+impl fmt::Debug for UpstreamPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Ids are slots, which removals leave gaps in and additions reuse
+        let upstreams: Vec<String> = (0..self.upstreams.snapshot().len())
+            .map(|position| format!("upstream_{}", position))
+            .collect();
+        f.debug_struct("UpstreamPool")
+            .field("name", &self.name)
+            .field("upstreams", &upstreams)
+            .field("active_health_check_interval", &self.active_health_check_interval)
+            .field("active_health_check_path", &self.active_health_check_path)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Settings for one address we accept client connections on.
+pub struct Listener {
+    /// Address the listener is bound to
+    bind: String,
+    /// Pool that requests from this listener are forwarded to
+    pool: Arc<UpstreamPool>,
+    /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
+    max_requests_per_minute: usize,
+    /// Rate limiter
+    rate_limiter: Box<dyn RateLimiterAlgorithm>,
+}
+
+impl Listener {
+    /// The listener `config` describes, forwarding to one of `state`'s pools.
+    pub fn new(options: &ProxyConfig, state: &ProxyState, config: &ListenerConfig) -> Listener {
+        Listener {
+            bind: config.bind.clone(),
+            pool: state.pools[config.pool].clone(),
+            max_requests_per_minute: config.max_requests_per_minute,
+            rate_limiter: create_rate_limiter(config.rate_limiter, options),
+        }
+    }
+}
+
+impl fmt::Debug for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Listener")
+            .field("bind", &self.bind)
+            .field("pool", &self.pool.name)
+            .field("max_requests_per_minute", &self.max_requests_per_minute)
+            .field("rate_limiter", &self.rate_limiter.name())
+            .finish()
+    }
+}
+
+/// Whether an upstream gets traffic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UpstreamStatus {
+    Alive,
+    /// Failed a health check but still finishing requests it was already sent. It gets no new
+    /// requests, not even on client connections that were using it.
+    Draining,
+    Dead,
+    /// Passed a health check after being dead, and is being sent --warmup-requests. It gets no
+    /// traffic, and health checks leave it alone, until the warm-up is over.
+    WarmingUp,
+    /// Removed through the admin API but still finishing requests it was already sent, like
+    /// Draining. Health checks can't bring it back.
+    Removing,
+    /// Removed through the admin API, and its slot is free for the next upstream added. Ids held
+    /// for it are stale, and are treated as this.
+    Removed,
+}
+
+/// Health and selection state of a pool's upstreams. The vectors are indexed by `UpstreamId`
+/// and have an entry for every slot in the pool's registry.
+pub struct UpstreamsState {
+    /// Number of upstreams that are alive
+    num_upstreams: usize,
+    /// Which upstream each slot belongs to now
+    ids: Vec<UpstreamId>,
+    status: Vec<UpstreamStatus>,
+    /// Current response timeout of each upstream
+    timeouts: Vec<AdaptiveTimeout>,
+    /// Relative share of traffic each upstream gets while alive. Upstreams have no configured
+    /// weight, so these stay at 1 unless --adaptive-weights adjusts them.
+    weights: Vec<f64>,
+    /// Recent 5xx rates and ejections of each upstream. Ejected upstreams are alive but only get
+    /// traffic when no other upstream can take it.
+    outliers: Vec<OutlierState>,
+    /// Longest run of consecutive failures any upstream in the pool has had
+    max_consecutive_failures: u64,
+    /// Whether the all-upstreams-failing alert has been logged for the current run of failures
+    failing_alert_logged: bool,
+    /// Every upstream ever added, with --load-balancing consistent-hashing. Upstreams that aren't
+    /// alive are passed over rather than taken off.
+    ring: Option<HashRing>,
+}
+
+impl UpstreamsState {
+    fn new(ids: Vec<UpstreamId>, timeout: AdaptiveTimeout) -> UpstreamsState {
+        let num_upstreams = ids.len();
+        UpstreamsState {
+            num_upstreams,
+            ids,
+            status: vec![UpstreamStatus::Alive; num_upstreams],
+            timeouts: vec![timeout; num_upstreams],
+            weights: vec![1.0; num_upstreams],
+            outliers: vec![OutlierState::default(); num_upstreams],
+            max_consecutive_failures: 0,
+            failing_alert_logged: false,
+            ring: None,
+        }
+    }
+
+    /// Sets up an upstream just added to the pool's registry, in a new slot or one freed by a
+    /// removed upstream. It starts out alive, and health checks will tell if it isn't.
+    fn add(&mut self, id: UpstreamId, timeout: AdaptiveTimeout) {
+        self.num_upstreams += 1;
+        let slot = id.index();
+        if slot == self.ids.len() {
+            self.ids.push(id);
+            self.status.push(UpstreamStatus::Alive);
+            self.timeouts.push(timeout);
+            self.weights.push(1.0);
+            self.outliers.push(OutlierState::default());
+        } else {
+            self.ids[slot] = id;
+            self.status[slot] = UpstreamStatus::Alive;
+            self.timeouts[slot] = timeout;
+            self.weights[slot] = 1.0;
+            self.outliers[slot] = OutlierState::default();
+        }
+    }
+
+    /// Whether `id` still names the upstream in its slot, rather than one removed since.
+    fn holds(&self, id: UpstreamId) -> bool {
+        self.ids[id.index()] == id
+    }
+
+    fn status_of(&self, id: UpstreamId) -> UpstreamStatus {
+        if self.holds(id) {
+            self.status[id.index()]
+        } else {
+            UpstreamStatus::Removed
+        }
+    }
+
+    /// Picks an alive upstream from `candidates` with probability proportional to its weight, given
+    /// `point` drawn uniformly from [0, 1). Ejected upstreams are passed over unless
+    /// `include_ejected` is set. Returns None if no candidate qualifies.
+    fn choose(
+        &self,
+        candidates: &[UpstreamId],
+        point: f64,
+        include_ejected: bool,
+    ) -> Option<UpstreamId> {
+        let now = Instant::now();
+        let alive = || {
+            candidates.iter().copied().filter(move |id| {
+                self.is_alive(*id) && (include_ejected || !self.is_ejected(*id, now))
+            })
+        };
+        let total: f64 = alive().map(|id| self.weights[id.index()]).sum();
+        let mut target = point * total;
+        let mut chosen = None;
+        for id in alive() {
+            chosen = Some(id);
+            target -= self.weights[id.index()];
+            if target < 0.0 {
+                break;
+            }
+        }
+        chosen
+    }
+
+    /// The upstream `key` maps to on the consistent hash ring, from the highest priority tier with
+    /// one that is alive and not ejected. None if there is no ring.
+    fn hashed(&self, tiers: &BTreeMap<u8, Vec<UpstreamId>>, key: &[u8]) -> Option<UpstreamId> {
+        let ring = self.ring.as_ref()?;
+        let now = Instant::now();
+        tiers.values().rev().find_map(|tier| {
+            let usable = |index| {
+                tier.iter().any(|id| {
+                    id.index() == index && self.is_alive(*id) && !self.is_ejected(*id, now)
+                })
+            };
+            let index = ring.lookup(key, usable)?;
+            tier.iter().copied().find(|id| id.index() == index)
+        })
+    }
+
+    /// The power of two choices: draws two different upstreams from `candidates` the way `choose`
+    /// draws one, and picks the one with the lower `load`, or either at random if they tie. Nearly
+    /// as even as always picking the least loaded upstream, without looking at every one.
+    fn choose_two(
+        &self,
+        candidates: &[UpstreamId],
+        rng: &mut impl Rng,
+        include_ejected: bool,
+        load: impl Fn(UpstreamId) -> u64,
+    ) -> Option<UpstreamId> {
+        let first = self.choose(candidates, rng.gen(), include_ejected)?;
+        let others: Vec<UpstreamId> =
+            candidates.iter().copied().filter(|id| *id != first).collect();
+        let second = match self.choose(&others, rng.gen(), include_ejected) {
+            Some(second) => second,
+            None => return Some(first),
+        };
+        Some(match load(first).cmp(&load(second)) {
+            std::cmp::Ordering::Less => first,
+            std::cmp::Ordering::Greater => second,
+            std::cmp::Ordering::Equal if rng.gen() => first,
+            std::cmp::Ordering::Equal => second,
+        })
+    }
+
+    /// Picks an upstream for a request from the highest priority tier that has one to offer,
+    /// leaving out the upstreams in `tried` (ones that already failed the request). Ejected
+    /// outliers are only picked if every other candidate is ejected. None if there is nothing left
+    /// to try.
+    fn select(
+        &self,
+        tiers: &BTreeMap<u8, Vec<UpstreamId>>,
+        strategy: LoadBalancingStrategy,
+        rng: &mut impl Rng,
+        load: impl Fn(UpstreamId) -> u64,
+        tried: &[UpstreamId],
+    ) -> Option<UpstreamId> {
+        let untried: Vec<Vec<UpstreamId>> = tiers
+            .values()
+            .rev()
+            .map(|tier| tier.iter().copied().filter(|id| !tried.contains(id)).collect())
+            .collect();
+        let mut choose = |include_ejected| {
+            untried.iter().find_map(|tier| match strategy {
+                // A consistent hashing pick comes in as the preferred upstream
+                LoadBalancingStrategy::Random | LoadBalancingStrategy::ConsistentHashing => {
+                    self.choose(tier, rng.gen(), include_ejected)
+                }
+                LoadBalancingStrategy::PowerOfTwoChoices => {
+                    self.choose_two(tier, rng, include_ejected, &load)
+                }
+            })
+        };
+        choose(false).or_else(|| choose(true))
+    }
+
+    /// This upstream's response header timeout, if there is one.
+    fn response_timeout(&self, id: UpstreamId) -> Option<Duration> {
+        let timeout = self.timeouts[id.index()].effective;
+        Some(timeout).filter(|timeout| *timeout > Duration::from_secs(0))
+    }
+
+    fn record_response_time(&mut self, id: UpstreamId, policy: &TimeoutPolicy, elapsed: Duration) {
+        if !self.holds(id) {
+            return;
+        }
+        let before = self.timeouts[id.index()].effective;
+        policy.record(&mut self.timeouts[id.index()], elapsed);
+        if self.timeouts[id.index()].effective != before {
+            log::info!(
+                "Response timeout for upstream {} is now {:?}",
+                id,
+                self.timeouts[id.index()].effective
+            );
+        }
+    }
+
+    fn is_alive(&self, id: UpstreamId) -> bool {
+        self.status_of(id) == UpstreamStatus::Alive
+    }
+
+    fn is_dead(&self, id: UpstreamId) -> bool {
+        self.status_of(id) == UpstreamStatus::Dead
+    }
+
+    /// Whether the upstream is finishing its in-flight requests before being dropped, whether
+    /// because it failed a health check or because it is being removed.
+    fn is_draining(&self, id: UpstreamId) -> bool {
+        matches!(self.status_of(id), UpstreamStatus::Draining | UpstreamStatus::Removing)
+    }
+
+    fn is_removed(&self, id: UpstreamId) -> bool {
+        matches!(self.status_of(id), UpstreamStatus::Removing | UpstreamStatus::Removed)
+    }
+
+    /// Describes what the upstream is up to, for the admin API and diagnostics.
+    fn phase(&self, id: UpstreamId, now: Instant) -> &'static str {
+        match self.status_of(id) {
+            UpstreamStatus::Alive if self.is_ejected(id, now) => "ejected",
+            UpstreamStatus::Alive => "alive",
+            UpstreamStatus::Draining => "draining",
+            UpstreamStatus::Dead => "dead",
+            UpstreamStatus::WarmingUp => "warming_up",
+            UpstreamStatus::Removing => "removing",
+            UpstreamStatus::Removed => "removed",
+        }
+    }
+
+    fn is_ejected(&self, id: UpstreamId, now: Instant) -> bool {
+        self.outliers[id.index()].is_ejected(now)
+    }
+
+    /// Records whether a response from an upstream was a 5xx, ejecting the upstream if it has sent
+    /// too many of them (and the pool can spare it). Returns true if it was ejected.
+    fn record_status(
+        &mut self,
+        id: UpstreamId,
+        policy: &OutlierPolicy,
+        server_error: bool,
+        now: Instant,
+    ) -> bool {
+        if !self.holds(id) {
+            return false;
+        }
+        if self.outliers[id.index()].readmit_if_due(now) {
+            log::info!("Upstream #{} readmitted after outlier ejection", id);
+        }
+        let (errors, total) = match self.outliers[id.index()].record(policy, now, server_error) {
+            Some(counts) => counts,
+            None => return false,
+        };
+        let ejected = self.outliers.iter().filter(|outlier| outlier.is_ejected(now)).count();
+        if ejected >= policy.max_ejected(self.status.len()) {
+            log::warn!(
+                "Upstream #{} is an outlier ({}/{} responses were 5xx) but {} upstream(s) are \
+                 already ejected",
+                id,
+                errors,
+                total,
+                ejected
+            );
+            return false;
+        }
+        log::warn!(
+            "Upstream #{} ejected for {:?} ({}/{} responses were 5xx)",
+            id,
+            policy.ejection_duration,
+            errors,
+            total
+        );
+        self.outliers[id.index()].eject(policy, now);
+        true
+    }
+
+    fn all_dead(&self) -> bool {
+        self.num_upstreams == 0
+    }
+
+    fn set_dead(&mut self, id: UpstreamId) {
+        if matches!(
+            self.status_of(id),
+            UpstreamStatus::Alive | UpstreamStatus::Draining | UpstreamStatus::WarmingUp
+        ) {
+            log::warn!("Upstream #{} is now dead", id);
+            if self.is_alive(id) {
+                self.num_upstreams -= 1;
+            }
+            self.status[id.index()] = UpstreamStatus::Dead;
+        }
+    }
+
+    /// Stops sending new requests to an alive upstream while letting the ones it already has
+    /// finish. Returns true if the upstream was alive; the caller should then wait for it to drain
+    /// with `drain_upstream`.
+    fn set_draining(&mut self, id: UpstreamId) -> bool {
+        if !self.is_alive(id) {
+            return false;
+        }
+        self.status[id.index()] = UpstreamStatus::Draining;
+        self.num_upstreams -= 1;
+        true
+    }
+
+    /// Starts removing an upstream: like `set_draining`, but for good. Returns true if the upstream
+    /// wasn't already being removed; the caller should then wait for it to drain with
+    /// `drain_upstream`.
+    fn set_removing(&mut self, id: UpstreamId) -> bool {
+        if self.is_removed(id) {
+            return false;
+        }
+        log::warn!("Upstream #{} is being removed", id);
+        if self.is_alive(id) {
+            self.num_upstreams -= 1;
+        }
+        self.status[id.index()] = UpstreamStatus::Removing;
+        true
+    }
+
+    /// Ends draining: upstreams that failed a health check are now dead, and ones being removed
+    /// are gone. Returns true if the upstream was removed, in which case the caller should free
+    /// its slot in the registry too.
+    fn finish_draining(&mut self, id: UpstreamId) -> bool {
+        match self.status_of(id) {
+            UpstreamStatus::Draining => self.set_dead(id),
+            UpstreamStatus::Removing => {
+                log::warn!("Upstream #{} is now removed", id);
+                self.status[id.index()] = UpstreamStatus::Removed;
+                if let Some(ring) = self.ring.as_mut() {
+                    ring.remove(id.index());
+                }
+                return true;
+            }
+            _ => {}
+        }
+        false
+    }
+
+    /// The longest run of failures among `upstreams` that haven't been removed, and whether every
+    /// one of them has failed at least `threshold` requests in a row.
+    fn failure_streaks(&self, upstreams: &[Arc<Upstream>], threshold: u64) -> (u64, bool) {
+        let mut longest = 0;
+        let mut remaining = 0;
+        let mut failing = 0;
+        for upstream in upstreams {
+            if self.is_removed(upstream.id) {
+                continue;
+            }
+            let streak = upstream.stats.consecutive_errors();
+            longest = longest.max(streak);
+            remaining += 1;
+            if streak >= threshold {
+                failing += 1;
+            }
+        }
+        (longest, threshold > 0 && remaining > 0 && failing == remaining)
+    }
+
+    /// Whether `record_failure_streaks` would change anything, which only needs a read lock to find
+    /// out.
+    fn failure_streaks_changed(&self, upstreams: &[Arc<Upstream>], threshold: u64) -> bool {
+        let (longest, all_failing) = self.failure_streaks(upstreams, threshold);
+        longest > self.max_consecutive_failures || all_failing != self.failing_alert_logged
+    }
+
+    /// Raises the high watermark of consecutive failures after a request to one of `upstreams`
+    /// failed. Returns true if every upstream that hasn't been removed has now failed at least
+    /// `threshold` requests in a row, the first time that is so since one of them last answered.
+    fn record_failure_streaks(&mut self, upstreams: &[Arc<Upstream>], threshold: u64) -> bool {
+        let (longest, all_failing) = self.failure_streaks(upstreams, threshold);
+        self.max_consecutive_failures = self.max_consecutive_failures.max(longest);
+        let alert = all_failing && !self.failing_alert_logged;
+        self.failing_alert_logged = all_failing;
+        alert
+    }
+
+    /// Holds back a dead upstream that passed a health check until `warm_up` has run. Returns
+    /// false if it isn't dead.
+    fn set_warming_up(&mut self, id: UpstreamId) -> bool {
+        if self.status_of(id) != UpstreamStatus::Dead {
+            return false;
+        }
+        self.status[id.index()] = UpstreamStatus::WarmingUp;
+        true
+    }
+
+    fn is_warming_up(&self, id: UpstreamId) -> bool {
+        self.status_of(id) == UpstreamStatus::WarmingUp
+    }
+
+    /// Returns true if the upstream wasn't already alive.
+    fn set_alive(&mut self, id: UpstreamId) -> bool {
+        if !matches!(
+            self.status_of(id),
+            UpstreamStatus::Draining | UpstreamStatus::Dead | UpstreamStatus::WarmingUp
+        ) {
+            return false;
+        }
+        self.status[id.index()] = UpstreamStatus::Alive;
+        self.num_upstreams += 1;
+        true
+    }
+}
+
+impl ProxyState {
+    /// Builds the state for serving `config` and starts the tasks that keep it up to date: the
+    /// stand-in upstream for dry runs, active health checks, and the periodic pool and rate limit
+    /// updates. Must be called from within the tokio runtime.
+    pub async fn new(options: &ProxyConfig, config: &Config) -> Arc<ProxyState> {
+        let mut state = ProxyState::from_options(options, config);
+        state.dry_upstream = if options.dry_upstream_connect {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap_or_else(|err| {
+                log::error!("Could not start the dry run upstream: {}", err);
+                std::process::exit(1);
+            });
+            let address = listener.local_addr().unwrap().to_string();
+            log::warn!("Dry run: upstreams won't be contacted; requests go to {} instead", address);
+            tokio::spawn(serve_dry_upstream(listener));
+            Some(address)
+        } else {
+            None
+        };
+        state.body_rewriter.log_rules();
+        let state = Arc::new(state);
+
+        for pool in &state.pools {
+            // Health checks would dial the real upstreams, which dry runs promise not to
+            if state.dry_upstream.is_none() {
+                tokio::spawn(active_health_check(state.clone(), pool.clone()));
+            }
+            if options.health_log_summary_interval > 0 {
+                let interval = Duration::from_secs(options.health_log_summary_interval);
+                tokio::spawn(health_log_summary(pool.clone(), interval));
+            }
+            if options.adaptive_weights {
+                let interval = Duration::from_secs(options.adaptive_weights_interval_secs);
+                tokio::spawn(adjust_weights(pool.clone(), interval));
+            }
+        }
+        if !state.rate_limit_schedule.is_empty() {
+            tokio::spawn(update_rate_limit_schedule(state.clone()));
+        }
+        state
+    }
+
+    /// Builds the state for serving `config` with the rest of the settings in `options`. Upstream
+    /// connections go to the real upstreams; `run` points them at the stand-in for dry runs.
+    ///
+    /// This is all that tests need; `ProxyState::new` also starts the state's background tasks.
+    fn from_options(options: &ProxyConfig, config: &Config) -> ProxyState {
+        let debug_capture = options.debug_capture_dir.as_ref().map(|dir| {
+            DebugCapture::new(
+                dir,
+                options.debug_capture_on,
+                options.debug_capture_max_bytes,
+                options.debug_capture_max_files,
+                !options.debug_capture_unredacted,
+            )
+            .unwrap_or_else(|err| {
+                log::error!("Could not use debug capture directory {:?}: {}", dir, err);
+                std::process::exit(1);
+            })
+        });
+
+        let timeout_policy = TimeoutPolicy {
+            base: Duration::from_millis(options.upstream_response_header_timeout_ms),
+            factor: options.upstream_timeout_backoff_factor,
+            max: Duration::from_millis(options.upstream_timeout_max_ms),
+            recovery_requests: options.upstream_timeout_recovery_requests,
+        };
+        let pools: Vec<Arc<UpstreamPool>> = config
+            .pools
+            .iter()
+            .map(|pool| {
+                let ring = match options.load_balancing {
+                    LoadBalancingStrategy::ConsistentHashing => {
+                        let mut ring = HashRing::new(options.consistent_hash_replicas);
+                        for (index, address) in pool.upstreams.iter().enumerate() {
+                            ring.add(index, address);
+                        }
+                        Some(ring)
+                    }
+                    _ => None,
+                };
+                let upstreams = UpstreamRegistry::new(pool.upstreams.iter().map(|address| {
+                    (address.clone(), priority::of(address, &options.upstream_priority))
+                }));
+                let ids = upstreams.snapshot().iter().map(|upstream| upstream.id).collect();
+                Arc::new(UpstreamPool {
+                    name: pool.name.clone(),
+                    active_health_check_interval: pool.active_health_check_interval,
+                    active_health_check_path: pool.active_health_check_path.clone(),
+                    active_health_check_success_codes: pool
+                        .active_health_check_success_codes
+                        .clone(),
+                    upstreams_state: RwLock::new(UpstreamsState {
+                        ring,
+                        ..UpstreamsState::new(ids, timeout_policy.initial())
+                    }),
+                    upstreams,
+                    host_override: pool.host_override.as_ref().map(|host| {
+                        http::HeaderValue::from_str(host).unwrap_or_else(|_| {
+                            log::error!("Invalid Host override {:?} for pool {}", host, pool.name);
+                            std::process::exit(1);
+                        })
+                    }),
+                })
+            })
+            .collect();
+
+        let outlier_policy = options.outlier_5xx_threshold.map(|threshold| OutlierPolicy {
+            threshold,
+            min_requests: options.outlier_min_requests,
+            window: Duration::from_secs(options.outlier_window_secs),
+            ejection_duration: Duration::from_secs(options.outlier_ejection_duration_secs),
+            max_ejected_percent: options.outlier_max_ejected_percent,
+        });
+
+        ProxyState {
+            pools,
+            path_rewriter: PathRewriter::new(options.rewrite_path.clone()),
+            body_rewriter: BodyRewriter::new(
+                options.rewrite_body.clone(),
+                &options.rewrite_body_content_type,
+                options.rewrite_body_max_bytes,
+            ),
+            decompress_upstream: options.decompress_upstream,
+            security_headers: options.security_headers,
+            default_content_type: options.default_content_type.clone(),
+            early_hints: options.early_hints.clone(),
+            shutdown: broadcast::channel(1).0,
+            shutdown_timeout: Duration::from_secs(options.shutdown_timeout_secs),
+            upgrade_binary: options.upgrade_binary.clone(),
+            upgrade_listeners: parking_lot::Mutex::new(Vec::new()),
+            upgrading: AtomicBool::new(false),
+            upgraded: Notify::new(),
+            ready: AtomicBool::new(!options.self_test),
+            metrics: Metrics::default(),
+            started_at: Instant::now(),
+            effective_config: effective_config::render(options, config),
+            next_request_id: AtomicU64::new(0),
+            rate_limit_observe: AtomicBool::new(options.rate_limit_mode == RateLimitMode::Observe),
+            rate_limit_offenders: Offenders::default(),
+            rate_limit_ipv6_prefix: options.rate_limit_ipv6_prefix,
+            phase_metrics: options.admin_bind.is_some()
+                || options.metrics_bind.is_some()
+                || options.pushgateway_url.is_some(),
+            top_talkers: options.top_k_entries.map(|entries| {
+                TopTalkers::new(entries, Duration::from_secs(options.top_k_retention * 60))
+            }),
+            rate_limit_schedule: Schedule::new(options.rate_limit_schedule.clone()),
+            debug_capture,
+            upstream_http_version: options.upstream_http_version,
+            upstream_protocols: options
+                .upstream_proto
+                .iter()
+                .map(|protocol| (protocol.address.clone(), protocol.version))
+                .collect(),
+            log_sampler: LogSampler::new(
+                options
+                    .log_sample_rate
+                    .unwrap_or(if options.log_upstream_errors_only { 0 } else { 1 }),
+            ),
+            log_upstream_errors_only: options.log_upstream_errors_only,
+            access_log_exclude_paths: options.access_log_exclude_path.clone(),
+            response_limits: response::HeaderLimits {
+                max_bytes: options.max_response_header_bytes,
+                max_count: options.max_response_headers,
+            },
+            health_check_timeout: Duration::from_millis(options.active_health_check_timeout_ms),
+            health_check_expect_body: options.active_health_check_expect_body.clone(),
+            health_check_disabled: options
+                .upstream_healthcheck_disable_for
+                .iter()
+                .map(|address| config::normalize_address(address))
+                .collect(),
+            health_check_permits: Semaphore::new(options.health_check_concurrency),
+            health_check_concurrency: options.health_check_concurrency,
+            warmup: options.warmup_requests.clone(),
+            buffer_budget: BufferBudget::new(options.max_buffered_bytes),
+            max_request_body_bytes: options.max_request_body_bytes,
+            redirect_http_to_https: options.redirect_http_to_https,
+            allow_trace: options.allow_trace,
+            internal_paths: InternalPaths::new(
+                options.internal_prefix.clone(),
+                !options.no_legacy_internal_paths,
+            ),
+            rate_limit_internal_paths: options.rate_limit_internal_paths,
+            health_path_allowed: if options.block_health_path_from_clients {
+                Some(options.health_path_allow_cidr.clone())
+            } else {
+                None
+            },
+            fault_injector: FaultInjector::new(
+                options.fault_inject_error_rate,
+                options.fault_inject_upstream_failure_rate,
+                options.fault_inject_delay_rate,
+                Duration::from_millis(options.fault_inject_delay_ms),
+            ),
+            cors: if options.cors_allowed_origin.is_empty() {
+                None
+            } else {
+                Some(CorsPolicy::new(options.cors_allowed_origin.clone()))
+            },
+            retry_budget: RetryBudget::new(
+                options.retry_budget_percent,
+                options.retry_budget_min_per_sec,
+            ),
+            timeout_policy,
+            body_timeout: Some(options.upstream_response_body_timeout_ms)
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            upstream_write_timeout: Duration::from_secs_f64(options.upstream_write_timeout_secs),
+            request_deadline: options.request_deadline_secs.map(Duration::from_secs_f64),
+            max_request_timeout: Duration::from_secs_f64(options.max_request_timeout_secs),
+            debug_routing: options.debug_routing_secret.as_deref().map(DebugRouting::new),
+            expose_upstream: if options.expose_upstream_header {
+                Some(ExposeUpstream::new(options.expose_upstream_header_token.as_deref()))
+            } else {
+                None
+            },
+            connections: ConnectionRegistry::new(options.debug_connection_tracking),
+            debug_connection_tracking: options.debug_connection_tracking,
+            xff_limits: request::ListLimits {
+                max_bytes: options.max_xff_bytes,
+                max_entries: options.max_xff_entries,
+            },
+            forwarded_header_style: options.forwarded_header_style,
+            preserve_header_order: options.preserve_header_order,
+            strip_request_headers: OWN_REQUEST_HEADERS
+                .iter()
+                .map(|name| http::header::HeaderName::from_static(name))
+                .chain(options.strip_request_header.iter().cloned())
+                .collect(),
+            upstream_remove_request_headers: options.upstream_remove_request_header.clone(),
+            upstream_add_request_headers: options.upstream_add_request_header.clone(),
+            upstream_remove_response_headers: options.upstream_remove_response_header.clone(),
+            upstream_add_response_headers: options.upstream_add_response_header.clone(),
+            outlier_policy,
+            failure_streak_alert: options.failure_streak_alert,
+            upstream_drain_timeout: Duration::from_secs(options.upstream_drain_timeout_secs),
+            removal_grace_period: Duration::from_secs(options.removal_grace_period_secs),
+            upstream_priorities: options.upstream_priority.clone(),
+            upstream_bind_addrs: options.upstream_bind_addr.clone(),
+            load_balancing: options.load_balancing,
+            consistent_hash_header: options.consistent_hash_by_header.clone(),
+            dry_upstream: None,
+        }
+    }
+
+    /// The HTTP version to speak to the upstream at `address`.
+    fn upstream_version(&self, address: &str) -> http::Version {
+        match self.upstream_protocols.get(address).unwrap_or(&self.upstream_http_version) {
+            UpstreamHttpVersion::Http10 => http::Version::HTTP_10,
+            UpstreamHttpVersion::Http11 => http::Version::HTTP_11,
+        }
+    }
+}
+
+/// Serves `options` until SIGTERM or SIGINT, or until a listener fails, and then shuts down. Exits
+/// the process if the configuration can't be served.
+pub async fn run(options: ProxyConfig) {
+    let mut config = options.load_config().unwrap_or_else(|err| {
+        log::error!("{}", err);
+        std::process::exit(1);
+    });
+    let uses_redis = config
+        .listeners
+        .iter()
+        .any(|listener| listener.rate_limiter == ArgRateLimiter::Redis);
+    if uses_redis && options.redis_url.is_none() {
+        log::error!("The redis rate limiter needs a server; specify one with --redis-url");
+        std::process::exit(1);
+    }
+    let reuse_port = options.reuseport_acceptors > 1;
+    if reuse_port && !reuseport::SUPPORTED {
+        log::error!(
+            "--reuseport-acceptors needs the kernel to share connections out among sockets \
+             bound with SO_REUSEPORT, which this platform doesn't"
+        );
+        std::process::exit(1);
+    }
+    for bind in &options.upstream_bind_addr {
+        if let Err(err) = upstream_bind::check_local(bind.ip) {
+            log::error!("Bad --upstream-bind-addr: {}", err);
+            std::process::exit(1);
+        }
+    }
+
+    let mut inherited = options.inherit_listener_fd.clone();
+    if inherited.is_empty() {
+        inherited = upgrade::systemd_listeners();
+    }
+    let inherited_proxy: Vec<&[RawFd]> =
+        inherited.iter().filter(|fd| fd.api.is_none()).map(|fd| &fd.fds[..]).collect();
+    if !inherited_proxy.is_empty() && inherited_proxy.len() != config.listeners.len() {
+        log::error!(
+            "Inherited {} listener socket(s) for {} listener(s)",
+            inherited_proxy.len(),
+            config.listeners.len()
+        );
+        std::process::exit(1);
+    }
+
+    // Start listening for connections
+    let mut tcp_listeners = Vec::new();
+    for (i, listener) in config.listeners.iter_mut().enumerate() {
+        let mut bound = match inherited_proxy.get(i) {
+            Some(fds) => fds.iter().map(|fd| upgrade::listener(*fd)).collect(),
+            None => {
+                let retry = options.bind_retry.as_ref();
+                bind_retry::bind(&listener.bind, reuse_port, retry).await.map(|bound| vec![bound])
+            }
+        };
+        if let (Err(err), Some(fallback)) = (&bound, &options.bind_fallback) {
+            // Logged as an error, as clients expecting the usual address won't reach us
+            log::error!(
+                "Could not bind to {} ({}); listening on the fallback address {} instead",
+                listener.bind,
+                err,
+                fallback
+            );
+            bound = bind_retry::bind(fallback, reuse_port, None).await.map(|bound| vec![bound]);
+            listener.bind = fallback.clone();
+        }
+        // Inherited sockets are all adopted before any more are bound alongside them, if they were
+        // bound with SO_REUSEPORT
+        let bound = match bound {
+            Ok(adopted) => reuseport::group(adopted, options.reuseport_acceptors).await,
+            Err(err) => Err(err),
+        };
+        match bound {
+            Ok(group) => tcp_listeners.push(group),
+            Err(err) => {
+                let reason = format!("Could not bind to {}: {}", listener.bind, err);
+                if options.self_test {
+                    let stage = self_test::Stage::Bind;
+                    log::error!("{}", self_test::Failure { stage, reason });
+                } else {
+                    log::error!("{}", reason);
+                }
+                std::process::exit(1);
+            }
+        };
+        match inherited_proxy.get(i) {
+            Some(fds) => log::info!("Listening for requests on {} (fd {:?})", listener.bind, fds),
+            None => log::info!("Listening for requests on {}", listener.bind),
+        }
+        let sockets = tcp_listeners.last().map_or(0, Vec::len);
+        if sockets > 1 {
+            log::info!("Accepting on {} sockets with SO_REUSEPORT", sockets);
+        }
+    }
+    // A new binary is handed every socket of each group, so that no connection queued on one of
+    // them is reset when this process closes it
+    let mut upgrade_listeners: Vec<InheritedFd> = tcp_listeners
+        .iter()
+        .map(|group| InheritedFd {
+            api: None,
+            fds: group.iter().map(AsRawFd::as_raw_fd).collect(),
+        })
+        .collect();
+
+    let shared_state = ProxyState::new(&options, &config).await;
+    log::info!("Effective configuration: {}", shared_state.effective_config);
+
+    let listeners: Vec<Arc<Listener>> = config
+        .listeners
+        .iter()
+        .map(|listener| Arc::new(Listener::new(&options, &shared_state, listener)))
+        .collect();
+
+    if options.preflight_check {
+        let unreachable = preflight_check(&shared_state).await;
+        if unreachable > 0 && options.require_all_upstreams_reachable {
+            log::error!("{} upstream(s) unreachable; exiting", unreachable);
+            std::process::exit(1);
+        }
+    }
+
+    tokio::spawn(diagnostics::run(
+        shared_state.clone(),
+        listeners.clone(),
+        options.diag_file.clone(),
+    ));
+
+    let internal_listeners = [
+        (
+            Api::Admin,
+            &options.admin_bind,
+            &options.admin_allow_cidr,
+            &options.admin_token,
+        ),
+        (
+            Api::Metrics,
+            &options.metrics_bind,
+            &options.metrics_allow_cidr,
+            &options.metrics_token,
+        ),
+    ];
+    for (api, bind, allow, token) in internal_listeners {
+        if let Some(bind) = bind {
+            let bound = match inherited.iter().find(|fd| fd.api == Some(api)) {
+                Some(fd) => upgrade::listener(fd.fds[0]),
+                // The process this one replaces may hold these ports as long as the proxy's
+                None => bind_retry::bind(bind, false, options.bind_retry.as_ref()).await,
+            };
+            match bound {
+                Ok(listener) => {
+                    log::info!("Serving {} API on {}", api.name(), bind);
+                    let fd = listener.as_raw_fd();
+                    upgrade_listeners.push(InheritedFd { api: Some(api), fds: vec![fd] });
+                    let access = AccessPolicy::new(allow.clone(), token.as_deref());
+                    tokio::spawn(admin::serve(listener, shared_state.clone(), api, access));
+                }
+                Err(err) => {
+                    log::error!("Could not bind {} API to {}: {}", api.name(), bind, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    if options.max_connection_age_secs > 0 {
+        let max_age = Duration::from_secs(options.max_connection_age_secs);
+        tokio::spawn(warn_about_old_connections(shared_state.clone(), max_age));
+    }
+
+    if let Some(url) = options.pushgateway_url {
+        let interval = Duration::from_secs(options.pushgateway_interval_secs);
+        tokio::spawn(pushgateway::run(shared_state.clone(), url, interval));
+    }
+
+    shutdown::install_panic_hook(shared_state.clone(), options.shutdown_report_file.clone());
+    // Clients wait in the listen queues until the self-test passes, rather than being served by a
+    // proxy that may turn out to be broken
+    if options.self_test {
+        let path = options.self_test_path.as_deref();
+        match self_test::run(&shared_state, &listeners, path).await {
+            Ok(slowest) => log::info!("Self-test passed; the slowest request took {:?}", slowest),
+            Err(failure) => {
+                log::error!("{}", failure);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut accept_loops = FuturesUnordered::new();
+    for (group, listener) in tcp_listeners.into_iter().zip(listeners) {
+        if listener.max_requests_per_minute > 0 {
+            let shutdown = shared_state.shutdown.subscribe();
+            tokio::spawn(update_rate_limiter(listener.clone(), shutdown));
+        }
+        for tcp_listener in group {
+            accept_loops.push(tokio::spawn(serve_listener(
+                tcp_listener,
+                shared_state.clone(),
+                listener.clone(),
+            )));
+        }
+    }
+    *shared_state.upgrade_listeners.lock() = upgrade_listeners;
+    shared_state.ready.store(true, Ordering::Relaxed);
+    log::info!("Ready");
+    if let Some(fd) = options.upgrade_ready_fd {
+        upgrade::signal_ready(fd);
+    }
+    // The first listener to stop accepting brings the rest down with it
+    let accepting = accept_loops.next();
+    let reason = tokio::select! {
+        _ = accepting => "listener failed",
+        signal = shutdown::signalled() => signal,
+        _ = shared_state.upgraded.notified() => "upgrade",
+    };
+
+    // Stop taking connections, let the open ones finish, and then say what we did
+    log::info!("Shutting down ({})", reason);
+    let result = shutdown::shutdown(shared_state.clone()).await;
+    if result.connections_abandoned > 0 {
+        log::warn!("Gave up waiting for {} connection(s) to finish", result.connections_abandoned);
+    }
+    shutdown::write_report(&shared_state, reason, options.shutdown_report_file.as_deref());
+}
+
+/// Accepts client connections on one listener until accepting fails or balancebeam shuts down.
+async fn serve_listener(
+    mut tcp_listener: TcpListener,
+    state: Arc<ProxyState>,
+    listener: Arc<Listener>,
+) {
+    let mut shutdown = state.shutdown.subscribe();
+    loop {
+        let stream = tokio::select! {
+            accepted = tcp_listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(_) => break,
+            },
+            _ = shutdown.recv() => break,
+        };
+        let shared_state_ref = state.clone();
+        let listener_ref = listener.clone();
+        // Handle the connection!
+        tokio::spawn(async move {
+            handle_connection(stream, shared_state_ref, listener_ref).await
+        });
+    }
+}
+
+fn create_rate_limiter(
+    limiter: ArgRateLimiter,
+    options: &ProxyConfig,
+) -> Box<dyn RateLimiterAlgorithm> {
+    match limiter {
+        ArgRateLimiter::FixedWindow => Box::new(FixedWindow::default()),
+        ArgRateLimiter::Redis => Box::new(RedisRateLimiter::new(
+            options.redis_url.clone().expect("--redis-url is checked at startup"),
+            options.rate_limiter_redis_fallback_local,
+        )),
+    }
+}
+
+/// Recomputes the pool's upstream weights from their recent latency and error rates.
+async fn adjust_weights(pool: Arc<UpstreamPool>, interval: Duration) {
+    let mut calculator = WeightCalculator::new(pool.upstreams.len());
+    loop {
+        delay_for(interval).await;
+        let upstreams = pool.upstreams.snapshot();
+        let weights = calculator.update(upstreams.iter().map(|upstream| &*upstream.stats));
+        let mut upstreams_state = pool.upstreams_state.write().await;
+        for (upstream, weight) in upstreams.iter().zip(weights) {
+            let old = upstreams_state.weights[upstream.id.index()];
+            if (old - weight).abs() >= 0.01 {
+                log::debug!(
+                    "Weight of upstream {} in pool {} changed from {:.2} to {:.2}",
+                    upstream.address,
+                    pool.name,
+                    old,
+                    weight
+                );
+            }
+            upstreams_state.weights[upstream.id.index()] = weight;
+        }
+    }
+}
+
+/// Periodically logs a warning about each client connection that has been open for longer than
+/// `max_age` (once per connection), to help find connections whose tasks never finish.
+async fn warn_about_old_connections(state: Arc<ProxyState>, max_age: Duration) {
+    let mut warned = HashSet::new();
+    loop {
+        delay_for(max_age.min(Duration::from_secs(10))).await;
+        let now = Instant::now();
+        let connections = state.connections.snapshot();
+        warned.retain(|id| connections.iter().any(|connection| connection.id == *id));
+        for connection in connections {
+            let age = now.duration_since(connection.started_at);
+            if age > max_age && warned.insert(connection.id) {
+                log::warn!(
+                    "Connection #{} from {} has been open for {:.0?} (idle for {:.0?}); last \
+                     request: {}",
+                    connection.id,
+                    connection.peer,
+                    age,
+                    now.duration_since(connection.last_activity),
+                    connection.last_request.as_deref().unwrap_or("none")
+                );
+            }
+        }
+    }
+}
+
+/// Resets the rate limiter's counters at the start of every window.
+async fn update_rate_limiter(listener: Arc<Listener>, mut shutdown: broadcast::Receiver<()>) {
+    loop {
+        tokio::select! {
+            _ = delay_for(Duration::from_secs(60)) => {}
+            _ = shutdown.recv() => return,
+        }
+        listener.rate_limiter.refresh().await;
+    }
+}
+
+/// Keeps the rate limit schedule's active profile current.
+async fn update_rate_limit_schedule(state: Arc<ProxyState>) {
+    let mut shutdown = state.shutdown.subscribe();
+    loop {
+        state.rate_limit_schedule.update();
+        tokio::select! {
+            _ = delay_for(Duration::from_secs(1)) => {}
+            _ = shutdown.recv() => return,
+        }
+    }
+}
+
+/// Tries to open a TCP connection to every upstream, logging the outcome of each and marking the
+/// unreachable ones dead so that the first client requests don't have to discover them. Returns
+/// the number of unreachable upstreams.
+async fn preflight_check(state: &ProxyState) -> usize {
+    let mut unreachable = 0;
+    for pool in &state.pools {
+        unreachable += preflight_check_pool(pool, &state.upstream_bind_addrs).await;
+    }
+    unreachable
+}
+
+async fn preflight_check_pool(pool: &UpstreamPool, binds: &[UpstreamBindAddr]) -> usize {
+    let mut unreachable = 0;
+    for upstream in pool.upstreams.snapshot() {
+        let address = &upstream.address;
+        let connecting = upstream_bind::connect(address, upstream_bind::of(address, binds));
+        let result = match timeout(Duration::from_secs(2), connecting).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(err)) => Err(match err.raw_os_error() {
+                Some(errno) => format!("{} (errno {})", err, errno),
+                None => err.to_string(),
+            }),
+            Err(_) => Err("timed out after 2s".to_string()),
+        };
+        match result {
+            Ok(()) => log::info!("Preflight check {}: SUCCESS", address),
+            Err(err) => {
+                log::warn!("Preflight check {}: FAILED: {}", address, err);
+                pool.upstreams_state.write().await.set_dead(upstream.id);
+                unreachable += 1;
+            }
+        }
+    }
+    unreachable
+}
+
+/// Checks every upstream in the pool on its health check interval. Each probe is only logged at
+/// trace level; upstreams going down or coming back are logged at info, with why and how long the
+/// probe took.
+///
+/// Rather than probing every upstream at once, which makes a burst of connections that big pools
+/// notice, the probes' start times are spread evenly over the interval (leaving time for the last
+/// one to finish), and no more than --health-check-concurrency run at once across all pools.
+pub async fn active_health_check(state: Arc<ProxyState>, pool: Arc<UpstreamPool>) {
+    let interval = Duration::from_secs(pool.active_health_check_interval as u64);
+    let spread = interval.checked_sub(state.health_check_timeout).unwrap_or_default();
+    let mut next_sweep = Instant::now() + interval;
+    let mut shutdown = state.shutdown.subscribe();
+    loop {
+        tokio::select! {
+            _ = delay_for(next_sweep.saturating_duration_since(Instant::now())) => {}
+            _ = shutdown.recv() => return,
+        }
+        let sweep_started = Instant::now();
+        next_sweep = sweep_started + interval;
+        let upstreams = pool.upstreams.snapshot();
+        let count = upstreams.len() as u32;
+        let probes: Vec<_> = upstreams
+            .into_iter()
+            .enumerate()
+            .map(|(index, upstream)| {
+                let offset = spread * index as u32 / count;
+                tokio::spawn(probe_upstream(state.clone(), pool.clone(), upstream, offset))
+            })
+            .collect();
+        for probe in probes {
+            let _ = probe.await;
+        }
+        if Instant::now() > next_sweep {
+            log::warn!(
+                "Health checks of pool {} took {:?}, longer than the {:?} interval; checking {} \
+                 upstreams {} at a time needs a longer --active-health-check-interval (or a \
+                 higher --health-check-concurrency)",
+                pool.name,
+                sweep_started.elapsed(),
+                interval,
+                count,
+                state.health_check_concurrency
+            );
+            next_sweep = Instant::now();
+        }
+    }
+}
+
+/// Health checks one upstream `offset` into a sweep of its pool, once there is a permit for it.
+async fn probe_upstream(
+    state: Arc<ProxyState>,
+    pool: Arc<UpstreamPool>,
+    upstream: Arc<Upstream>,
+    offset: Duration,
+) {
+    delay_for(offset).await;
+    let id = upstream.id;
+    {
+        let upstreams_state = pool.upstreams_state.read().await;
+        if upstreams_state.is_removed(id) || upstreams_state.is_warming_up(id) {
+            return;
+        }
+    }
+    let address = &upstream.address;
+    if state.health_check_disabled.contains(address) {
+        // Nothing to check, so an upstream that a failed connection took out comes straight back
+        let mut upstreams_state = pool.upstreams_state.write().await;
+        if upstreams_state.is_dead(id) && upstreams_state.set_alive(id) {
+            log::info!("Upstream {} (not health checked) is back in rotation", address);
+        }
+        return;
+    }
+    let (result, latency) = {
+        let _permit = state.health_check_permits.acquire().await;
+        let started = Instant::now();
+        let result = health_check(&state, &pool).probe(address).await;
+        (result, started.elapsed())
+    };
+    match &result {
+        Ok(()) => log::trace!("Health check {}: ok in {:?}", address, latency),
+        Err(failure) => log::trace!("Health check {}: {} after {:?}", address, failure, latency),
+    }
+    let mut upstream_status = pool.upstreams_state.write().await;
+    match result {
+        Ok(()) if state.warmup.is_some() && upstream_status.set_warming_up(id) => {
+            log::info!(
+                "Upstream {} passed its health check in {:?}; warming it up",
+                address,
+                latency
+            );
+            // In its own task, so that the next sweep doesn't wait for it
+            tokio::spawn(warm_up(state.clone(), pool.clone(), upstream.clone()));
+        }
+        Ok(()) => {
+            if upstream_status.set_alive(id) {
+                log::info!(
+                    "Upstream {} passed its health check in {:?} and is alive again",
+                    address,
+                    latency
+                );
+            }
+        }
+        Err(failure) => {
+            if upstream_status.set_draining(id) {
+                log::info!(
+                    "Upstream {} failed its health check ({}) after {:?} and is now draining",
+                    address,
+                    failure,
+                    latency
+                );
+                let drain_timeout = state.upstream_drain_timeout;
+                tokio::spawn(drain_upstream(pool.clone(), id, drain_timeout));
+            }
+        }
+    }
+}
+
+/// The active health check for `pool`'s upstreams.
+fn health_check<'a>(state: &'a ProxyState, pool: &'a UpstreamPool) -> HealthCheck<'a> {
+    HealthCheck {
+        path: &pool.active_health_check_path,
+        success_codes: &pool.active_health_check_success_codes,
+        expect_body: state.health_check_expect_body.as_deref(),
+        timeout: state.health_check_timeout,
+        limits: &state.response_limits,
+        warmup: state.warmup.as_ref(),
+        bind_addrs: &state.upstream_bind_addrs,
+    }
+}
+
+/// Sends the warm-up requests to an upstream that has recovered, and lets it back in once they
+/// have all succeeded. If one fails, the upstream goes back to being dead, and the next health
+/// check that it passes starts another warm-up.
+async fn warm_up(state: Arc<ProxyState>, pool: Arc<UpstreamPool>, upstream: Arc<Upstream>) {
+    let started = Instant::now();
+    let result = health_check(&state, &pool).warm_up(&upstream.address).await;
+    let mut upstreams_state = pool.upstreams_state.write().await;
+    match result {
+        Ok(()) => {
+            if upstreams_state.set_alive(upstream.id) {
+                log::info!(
+                    "Upstream {} warmed up in {:?} and is alive again",
+                    upstream.address,
+                    started.elapsed()
+                );
+            }
+        }
+        Err(failure) => {
+            log::info!("Upstream {} failed to warm up ({})", upstream.address, failure);
+            upstreams_state.set_dead(upstream.id);
+        }
+    }
+}
+
+/// Logs one line every `interval` saying how many of the pool's upstreams are healthy, and which
+/// ones aren't (--health-log-summary-interval).
+async fn health_log_summary(pool: Arc<UpstreamPool>, interval: Duration) {
+    loop {
+        delay_for(interval).await;
+        let upstreams_state = pool.upstreams_state.read().await;
+        let mut total = 0;
+        let mut down = Vec::new();
+        for upstream in pool.upstreams.snapshot() {
+            if upstreams_state.is_removed(upstream.id) {
+                continue;
+            }
+            total += 1;
+            if !upstreams_state.is_alive(upstream.id) {
+                down.push(upstream.address.clone());
+            }
+        }
+        if down.is_empty() {
+            log::info!("Pool {}: healthy={}/{}", pool.name, total, total);
+        } else {
+            log::info!(
+                "Pool {}: healthy={}/{} (down: {})",
+                pool.name,
+                total - down.len(),
+                total,
+                down.join(", ")
+            );
+        }
+    }
+}
+
+/// Waits for a draining upstream's in-flight requests to finish, or for `drain_timeout` to pass,
+/// and then marks it dead (or removed, if it is being removed). Nothing is done if a health check
+/// revives it in the meantime. An upstream being removed has any connections still open to it
+/// closed, and its slot freed.
+async fn drain_upstream(pool: Arc<UpstreamPool>, id: UpstreamId, drain_timeout: Duration) {
+    let started = Instant::now();
+    let upstream = match pool.upstreams.get(id) {
+        Some(upstream) => upstream,
+        None => return,
+    };
+    while upstream.stats.in_flight() > 0 && started.elapsed() < drain_timeout {
+        if !pool.upstreams_state.read().await.is_draining(id) {
+            return;
+        }
+        delay_for(Duration::from_millis(100)).await;
+    }
+    let mut upstreams_state = pool.upstreams_state.write().await;
+    if !upstreams_state.is_draining(id) {
+        return;
+    }
+    let in_flight = upstream.stats.in_flight();
+    let removing = upstreams_state.is_removed(id);
+    if in_flight > 0 && removing {
+        log::warn!(
+            "Upstream {} still has {} request(s) in flight after {:?}; closing their connections",
+            upstream.address,
+            in_flight,
+            drain_timeout
+        );
+        upstream.close_connections();
+    } else if in_flight > 0 {
+        log::warn!(
+            "Upstream {} still has {} request(s) in flight after {:?}; not waiting for them",
+            upstream.address,
+            in_flight,
+            drain_timeout
+        );
+    } else {
+        log::info!("Upstream {} drained in {:?}", upstream.address, started.elapsed());
+    }
+    if upstreams_state.finish_draining(id) {
+        pool.upstreams.remove(id);
+        log::info!(
+            "Upstream {} removed after serving {} request(s)",
+            upstream.address,
+            upstream.stats.total_requests()
+        );
+    }
+}
+
+/// Connects to a random live upstream, marking upstreams dead as connection attempts fail.
+/// Upstreams in `tried` have already failed this request and are passed over; every upstream
+/// attempted is added to it. Returns `AllDead` if there was nothing to try, or `UpstreamConnect` if
+/// every attempt failed, every alive upstream was already tried, or the retry budget ran out.
+pub async fn connect_to_upstream(
+    state: &ProxyState,
+    pool: &UpstreamPool,
+    mut preferred: Option<UpstreamId>,
+    tried: &mut Vec<UpstreamId>,
+) -> Result<(TcpStream, Arc<Upstream>), ErrorReason> {
+    let mut rng = rand::rngs::StdRng::from_entropy();
+    let mut attempted = false;
+    loop {
+        if pool.upstreams_state.read().await.all_dead() {
+            log::warn!("All upstream servers are dead");
+            return Err(if attempted { ErrorReason::UpstreamConnect } else { ErrorReason::AllDead });
+        }
+
+        // Try the preferred upstream first, if there is one; fall back to random ones (weighted
+        // by their share of traffic) from the highest priority tier that has any alive after that.
+        let upstream_id = {
+            let upstreams_state = pool.upstreams_state.read().await;
+            let in_flight =
+                |id| pool.upstreams.get(id).map_or(0, |upstream| upstream.stats.in_flight());
+            match preferred.take() {
+                Some(id) if upstreams_state.is_alive(id) && !tried.contains(&id) => Some(id),
+                _ => upstreams_state.select(
+                    &pool.upstreams.tiers(),
+                    state.load_balancing,
+                    &mut rng,
+                    in_flight,
+                    tried,
+                ),
+            }
+        };
+        let upstream = match upstream_id {
+            Some(id) => match pool.upstreams.get(id) {
+                Some(upstream) => upstream,
+                // Removed since it was picked
+                None => continue,
+            },
+            // Going back to an upstream that just failed would most likely fail again
+            None if !tried.is_empty() => {
+                log::warn!("Every alive upstream has already failed this request");
+                return Err(ErrorReason::UpstreamConnect);
+            }
+            // Everything died since we checked; the loop will notice
+            None => continue,
+        };
+        if attempted && !state.retry_budget.try_retry() {
+            log::warn!("Retry budget exhausted; not trying another upstream");
+            return Err(ErrorReason::UpstreamConnect);
+        }
+        attempted = true;
+        tried.push(upstream.id);
+
+        let address = state.dry_upstream.as_deref().unwrap_or(&upstream.address);
+        let source = upstream_bind::of(&upstream.address, &state.upstream_bind_addrs);
+        match upstream_bind::connect(address, source).await {
+            Err(err) => { log::warn!("Failed to connect to upstream: {}", err);
+                          record_upstream_error(state, pool, &upstream).await;
+                          let mut upstream_status = pool.upstreams_state.write().await;
+                          upstream_status.set_dead(upstream.id);
+                        },
+            Ok(s) => return Ok((s, upstream)),
+        }
+    }
+}
+
+/// Counts a failed request to `upstream`, and raises the alarm if every upstream in the pool keeps
+/// failing. That can come well before they are all dead, as only connection failures kill them.
+async fn record_upstream_error(state: &ProxyState, pool: &UpstreamPool, upstream: &Upstream) {
+    upstream.stats.record_error();
+    let upstreams = pool.upstreams.snapshot();
+    let threshold = state.failure_streak_alert;
+    // Most failures leave the watermark and the alert as they were; those needn't hold up requests
+    // waiting on the read lock
+    if !pool.upstreams_state.read().await.failure_streaks_changed(&upstreams, threshold) {
+        return;
+    }
+    if pool.upstreams_state.write().await.record_failure_streaks(&upstreams, threshold) {
+        log::error!(
+            "All upstreams may be failing — check backend health (every upstream in pool {} has \
+             failed at least {} requests in a row)",
+            pool.name,
+            threshold
+        );
+    }
+}
+
+/// The sooner of two optional time limits.
+fn earliest(a: Option<Duration>, b: Option<Duration>) -> Option<Duration> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Like `timeout`, but without a limit the future just runs to completion.
+async fn timeout_if_any<F: std::future::Future>(
+    limit: Option<Duration>,
+    future: F,
+) -> Result<F::Output, tokio::time::Elapsed> {
+    match limit {
+        Some(limit) => timeout(limit, future).await,
+        None => Ok(future.await),
+    }
+}
+
+/// Runs `io` on a connection to `upstream`, unless the upstream's connections are closed first (it
+/// was removed, and the removal grace period ran out). That fails it with an error made by `fail`.
+async fn unless_closed<T, E, F>(
+    upstream: &Upstream,
+    io: F,
+    fail: impl FnOnce(std::io::Error) -> E,
+) -> Result<T, E>
+where
+    F: std::future::Future<Output = Result<T, E>>,
+{
+    tokio::select! {
+        result = io => result,
+        _ = upstream.closed() => Err(fail(std::io::Error::new(
+            std::io::ErrorKind::ConnectionAborted,
+            "upstream was removed",
+        ))),
+    }
+}
+
+/// Stands in for every upstream with --dry-upstream-connect: reads the request headers and hangs
+/// up without answering, so that requests go through everything balancebeam does up to reading the
+/// response and then fail with a 502.
+async fn serve_dry_upstream(mut listener: TcpListener) {
+    while let Ok((mut stream, _)) = listener.accept().await {
+        tokio::spawn(async move {
+            let mut received = Vec::new();
+            let mut buffer = [0_u8; 4096];
+            while !received.windows(4).any(|window| window == b"\r\n\r\n") {
+                match stream.read(&mut buffer).await {
+                    Ok(n) if n > 0 => received.extend_from_slice(&buffer[..n]),
+                    _ => break,
+                }
+            }
+        });
+    }
+}
+
+/// Sends a response to the client. Upstream 5xx responses are always logged; other responses only
+/// if `log_access` is set. Returns whether the client took the whole response.
+async fn send_response(
+    client_conn: &mut TcpStream,
+    state: &ProxyState,
+    response: &http::Response<Vec<u8>>,
+    log_access: bool,
+) -> bool {
+    let client_ip = client_conn.peer_addr().unwrap().ip();
+    log_response(client_ip, response, log_access, None);
+    write_response(client_conn, state, response).await
+}
+
+/// Logs the access log line for a response: always for a 5xx, otherwise only if `log_access` is
+/// set. How long the request spent in each phase goes on the end of the line, if given.
+fn log_response(
+    client_ip: IpAddr,
+    response: &http::Response<Vec<u8>>,
+    log_access: bool,
+    phases: Option<&PhaseTimer>,
+) {
+    let status_line = response::format_response_line(response);
+    let phases = match phases {
+        Some(phases) if phases.recorded().next().is_some() => format!(" {}", phases),
+        _ => String::new(),
+    };
+    if response.status().is_server_error() {
+        log::warn!("{} <- {}{}", client_ip, status_line, phases);
+    } else if log_access {
+        log::info!("{} <- {}{}", client_ip, status_line, phases);
+    }
+}
+
+/// Writes a response to the client. It is counted by its status only if the client takes all of
+/// it; a client that hangs up partway is counted as having aborted instead. Returns whether the
+/// client took the whole response.
+async fn write_response(
+    client_conn: &mut TcpStream,
+    state: &ProxyState,
+    response: &http::Response<Vec<u8>>,
+) -> bool {
+    match response::write_to_stream(response, client_conn).await {
+        Ok(()) => {
+            state.metrics.record_response_status(response.status());
+            true
+        }
+        Err(error) => {
+            log::warn!("Failed to send response to client: {}", error);
+            state.metrics.record_client_abort();
+            false
+        }
+    }
+}
+
+/// Counts a request against the client's rate limit. Returns whether the request should be refused,
+/// which it never is while the limit is only observed.
+async fn rate_limit_refuses(state: &ProxyState, listener: &Listener, client_ip: IpAddr) -> bool {
+    if listener.max_requests_per_minute == 0 {
+        return false;
+    }
+    let limit = state.rate_limit_schedule.effective_limit(listener.max_requests_per_minute);
+    let key = rate_limiter::client_key(client_ip, state.rate_limit_ipv6_prefix);
+    if listener.rate_limiter.check_and_increment(&key, limit).await {
+        return false;
+    }
+    let this_minute = state.rate_limit_offenders.record(&key);
+    if !state.rate_limit_observe.load(Ordering::Relaxed) {
+        return true;
+    }
+    // Only the first request over the limit each minute is logged per client; the rest are counted
+    // in the offenders list
+    if this_minute == 1 {
+        log::info!("{} is over its rate limit; forwarding anyway (observe mode)", key);
+    }
+    false
+}
+
+/// Answers a request for one of balancebeam's own endpoints on a proxy listener.
+async fn serve_internal(
+    endpoint: Endpoint,
+    request: &http::Request<Vec<u8>>,
+    state: &ProxyState,
+    pool: &UpstreamPool,
+) -> http::Response<Vec<u8>> {
+    if request.method() != http::Method::GET {
+        return response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED);
+    }
+    match endpoint {
+        Endpoint::Healthz if !state.ready.load(Ordering::Relaxed) => response::make_response(
+            http::StatusCode::SERVICE_UNAVAILABLE,
+            "text/plain",
+            b"starting\n".to_vec(),
+        ),
+        Endpoint::Healthz if pool.upstreams_state.read().await.all_dead() => {
+            response::make_response(
+                http::StatusCode::SERVICE_UNAVAILABLE,
+                "text/plain",
+                b"no upstreams alive\n".to_vec(),
+            )
+        }
+        Endpoint::Healthz => {
+            response::make_response(http::StatusCode::OK, "text/plain", b"ok\n".to_vec())
+        }
+        Endpoint::NotFound => response::make_http_error(http::StatusCode::NOT_FOUND),
+    }
+}
+
+/// Headers balancebeam sets on forwarded requests, which clients therefore can never set
+/// themselves: they are stripped on arrival, with the --strip-request-header ones.
+/// X-Request-Timeout is read before it goes.
+const OWN_REQUEST_HEADERS: [&str; 2] =
+    [deadline::DEADLINE_HEADER, deadline::REQUEST_TIMEOUT_HEADER];
+
+/// Removes the headers named in `remove`, then adds those in `add`, replacing any already there
+/// under the same names.
+fn edit_headers(
+    headers: &mut http::HeaderMap,
+    remove: &[http::header::HeaderName],
+    add: &[(http::header::HeaderName, http::HeaderValue)],
+) {
+    for name in remove.iter().chain(add.iter().map(|(name, _)| name)) {
+        headers.remove(name);
+    }
+    for (name, value) in add {
+        headers.append(name, value.clone());
+    }
+}
+
+/// Sends a proxy-generated error to the client, recording why we generated it. Returns the
+/// response that was sent.
+async fn send_error(
+    client_conn: &mut TcpStream,
+    state: &ProxyState,
+    pool: &UpstreamPool,
+    reason: ErrorReason,
+) -> http::Response<Vec<u8>> {
+    state.metrics.record_error(reason);
+    let mut response = response::make_http_error(reason.status());
+    if reason == ErrorReason::MethodNotAllowed {
+        response.headers_mut().insert(
+            http::header::ALLOW,
+            http::HeaderValue::from_static(allowed_methods(state)),
+        );
+    }
+    if reason == ErrorReason::AllDead {
+        // The soonest a health check could bring one of this pool's upstreams back
+        let retry_after = pool.active_health_check_interval.max(1).to_string();
+        response.headers_mut().insert(
+            http::header::RETRY_AFTER,
+            http::HeaderValue::from_str(&retry_after).unwrap(),
+        );
+    }
+    if reason == ErrorReason::BufferLimit {
+        // Buffers are freed as soon as the requests holding them finish
+        response
+            .headers_mut()
+            .insert(http::header::RETRY_AFTER, http::HeaderValue::from_static("1"));
+    }
+    if reason.closes_connection() {
+        response
+            .headers_mut()
+            .insert(http::header::CONNECTION, http::HeaderValue::from_static("close"));
+    }
+    let client_ip = client_conn.peer_addr().unwrap().ip();
+    log::warn!(
+        "{} <- {} (reason={})",
+        client_ip,
+        response::format_response_line(&response),
+        reason
+    );
+    write_response(client_conn, state, &response).await;
+    response
+}
+
+/// The methods balancebeam will forward, for Allow headers.
+fn allowed_methods(state: &ProxyState) -> &'static str {
+    if state.allow_trace {
+        "GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS, TRACE"
+    } else {
+        "GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS"
+    }
+}
+
+/// Writes the raw bytes of an exchange to disk if debug capture is enabled and wants it.
+async fn capture_exchange(
+    state: &ProxyState,
+    request_id: u64,
+    parse_error: bool,
+    raw_request: &[u8],
+    response: &http::Response<Vec<u8>>,
+) {
+    if let Some(capture) = &state.debug_capture {
+        if capture.should_capture(parse_error, response.status()) {
+            capture.write(request_id, raw_request, &response::serialize(response)).await;
+        }
+    }
+}
+
+/// How long a connection asked to close before its first request still waits for that request
+const NEW_CONNECTION_GRACE: Duration = Duration::from_secs(1);
+
+/// Serves the requests a client sends on one connection, until it hangs up or the connection has
+/// to close.
+pub async fn handle_connection(
+    mut client_conn: TcpStream,
+    state: Arc<ProxyState>,
+    listener: Arc<Listener>,
+) {
+    let pool = &listener.pool;
+    let client_addr = client_conn.peer_addr().unwrap();
+    // IPv4 clients of a dual-stack listener arrive as IPv4-mapped IPv6 addresses
+    let client_ip = client_addr.ip().to_canonical();
+    let connection = state.connections.register(client_addr, &listener.bind);
+    if state.log_upstream_errors_only {
+        log::debug!("Connection received from {}", client_ip);
+    } else {
+        log::info!("Connection received from {}", client_ip);
+    }
+
+    // Connection to the upstream server (and its index and IP), reused across requests when
+    // possible
+    let mut upstream: Option<(TcpStream, Arc<Upstream>, String)> = None;
+
+    // The client may now send us one or more requests. Keep trying to read requests until the
+    // client hangs up or we get an error.
+    let mut first_request = true;
+    loop {
+        // Wait for the client to start on its next request, unless the connection is asked to
+        // close (through the admin API, or by a shutdown) first
+        connection.set_state(ConnectionState::Idle);
+        let mut first_byte = [0_u8; 1];
+        tokio::select! {
+            _ = connection.closed() => {
+                // A client that has only just connected hasn't had the chance to send the request
+                // it connected for; closing on it now would reset the connection under it
+                let grace = if first_request { NEW_CONNECTION_GRACE } else { Duration::ZERO };
+                if timeout(grace, client_conn.peek(&mut first_byte)).await.is_err() {
+                    log::info!("Closing connection from {} as asked", client_ip);
+                    return;
+                }
+            }
+            _ = client_conn.peek(&mut first_byte) => {}
+        }
+        first_request = false;
+        connection.set_state(ConnectionState::Reading);
+        let client_read_start = Instant::now();
+
+        // Read a request from the client, keeping a copy of the raw bytes if we might need to dump
+        // them for debugging
+        let mut raw_request = Vec::new();
+        let capture_buffer = state.debug_capture.as_ref().map(|_| &mut raw_request);
+        // Accounts for this request's buffers until the end of the iteration, however it ends
+        let mut buffers = state.buffer_budget.guard();
+        let result = request::read_from_stream_capturing(
+            &mut client_conn,
+            capture_buffer,
+            Some(&mut buffers),
+            state.max_request_body_bytes,
+        )
+        .await;
+        let received_at = Instant::now();
+        let request_id = state.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let verbose = state.log_sampler.is_verbose(request_id);
+        let mut request = match result {
+            Ok(request) => {
+                state.retry_budget.record_request();
+                state.metrics.record_request_body(request.body().len());
+                connection.record_request(request.body().len(), || {
+                    request::format_request_line(&request)
+                });
+                request
+            }
+            // Handle case where client closed connection and is no longer sending requests
+            Err(request::Error::IncompleteRequest(0)) => {
+                log::debug!("Client finished sending requests. Shutting down connection");
+                return;
+            }
+            // Handle I/O error in reading from the client
+            Err(request::Error::ConnectionError(io_err)) => {
+                log::info!("Error reading request from client stream: {}", io_err);
+                return;
+            }
+            Err(error) => {
+                log::debug!("Error parsing request: {}", error);
+                let reason = ErrorReason::from(&error);
+                let response = send_error(&mut client_conn, &state, pool, reason).await;
+                capture_exchange(&state, request_id, true, &raw_request, &response).await;
+                if reason.closes_connection() {
+                    return;
+                }
+                continue;
+            }
+        };
+        // The client's timeout is read before its header is stripped below, and capped so that
+        // a client can't have us wait on its behalf for as long as it likes
+        let requested_timeout = request
+            .headers()
+            .get(deadline::REQUEST_TIMEOUT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(deadline::parse_timeout)
+            .map(|requested| requested.min(state.max_request_timeout));
+        // Headers only we (or whoever is behind us) may set go before anything can read them. Every
+        // field with the name goes, however many the client sent.
+        for name in &state.strip_request_headers {
+            if request.headers_mut().remove(name).is_some() && verbose {
+                log::debug!("Request {}: stripped the client's {} header", request_id, name);
+            }
+        }
+        if !state.preserve_header_order {
+            request.extensions_mut().remove::<request::RawHeaders>();
+        }
+        // Upstreams are always spoken to in HTTP/1.1 (or 1.0 if configured for the upstream, which
+        // is applied as the request is written), whatever the client speaks
+        let client_version = request.version();
+        *request.version_mut() = http::Version::HTTP_11;
+        let excluded = state
+            .access_log_exclude_paths
+            .iter()
+            .any(|excluded| excluded.matches(request.uri().path()));
+        let log_access = (verbose || !state.log_upstream_errors_only) && !excluded;
+        // Only timed if the access log or the metrics will show it
+        let mut phases = PhaseTimer::new(log_access || state.phase_metrics);
+        if let Some(request::HeadersReadAt(headers_at)) = request.extensions().get().copied() {
+            phases.record(Phase::ClientHeaderRead, headers_at - client_read_start);
+            phases.record(Phase::ClientBodyRead, received_at - headers_at);
+        }
+
+        let deadline = Deadline::for_request(received_at, state.request_deadline, requested_timeout);
+
+        if request.method() == http::Method::TRACE && !state.allow_trace {
+            let response =
+                send_error(&mut client_conn, &state, pool, ErrorReason::MethodNotAllowed).await;
+            capture_exchange(&state, request_id, false, &raw_request, &response).await;
+            continue;
+        }
+
+        // Our own endpoints are answered before anything below can redirect the request or send it
+        // to an upstream
+        if let Some(endpoint) = state.internal_paths.route(request.uri().path()) {
+            if log_access {
+                log::info!(
+                    "{} -> internal: {}",
+                    client_ip,
+                    request::format_request_line(&request)
+                );
+            }
+            let response = if state.rate_limit_internal_paths
+                && rate_limit_refuses(&state, &listener, client_ip).await
+            {
+                send_error(&mut client_conn, &state, pool, ErrorReason::RateLimited).await
+            } else {
+                let response = serve_internal(endpoint, &request, &state, pool).await;
+                send_response(&mut client_conn, &state, &response, log_access).await;
+                response
+            };
+            capture_exchange(&state, request_id, false, &raw_request, &response).await;
+            continue;
+        }
+
+        // An asterisk-form OPTIONS asks about the server as a whole, so we answer it ourselves
+        // rather than forwarding a target the upstream may not be able to parse. CORS preflights
+        // are answered here too when CORS is configured, so upstreams needn't handle OPTIONS.
+        let asterisk_options = request.method() == http::Method::OPTIONS && request.uri() == "*";
+        if asterisk_options || (state.cors.is_some() && cors::is_preflight(&request)) {
+            let response =
+                cors::preflight_response(state.cors.as_ref(), &request, allowed_methods(&state));
+            send_response(&mut client_conn, &state, &response, log_access).await;
+            capture_exchange(&state, request_id, false, &raw_request, &response).await;
+            continue;
+        }
+
+        // balancebeam doesn't terminate TLS, so every client connection is plain HTTP and gets
+        // redirected without involving an upstream
+        if state.redirect_http_to_https {
+            let host = request.headers().get("host").and_then(|value| value.to_str().ok());
+            match host {
+                Some(host) => {
+                    let location = format!("https://{}{}", host, request::request_uri(&request));
+                    let response = response::make_redirect(&location, true);
+                    send_response(&mut client_conn, &state, &response, log_access).await;
+                    capture_exchange(&state, request_id, false, &raw_request, &response).await;
+                }
+                None => {
+                    let response =
+                        send_error(&mut client_conn, &state, pool, ErrorReason::ClientParse).await;
+                    capture_exchange(&state, request_id, false, &raw_request, &response).await;
+                }
+            }
+            continue;
+        }
+
+        // Upstreams may answer their health check path without authentication, so clients only
+        // get to it from allowed addresses. It is matched as the upstream would see it, after
+        // any path rewrite. Health checks themselves go to the upstreams directly.
+        if let Some(allowed) = &state.health_path_allowed {
+            let path = state.path_rewriter.upstream_path(request.uri().path());
+            if health_check::is_health_path(&pool.active_health_check_path, &path)
+                && !allowed.iter().any(|cidr| cidr.contains(client_ip))
+            {
+                let reason = ErrorReason::HealthPathBlocked;
+                let response = send_error(&mut client_conn, &state, pool, reason).await;
+                capture_exchange(&state, request_id, false, &raw_request, &response).await;
+                continue;
+            }
+        }
+
+        // Debugging may ask which upstream serves the request
+        let expose_upstream =
+            debug_routing::take_expose(state.expose_upstream.as_ref(), &mut request);
+
+        // Internal tooling may ask for a particular upstream. Overrides that aren't authorized, or
+        // that name an upstream that isn't in this pool or is dead, are ignored.
+        let pinned = match debug_routing::take_override(state.debug_routing.as_ref(), &mut request)
+        {
+            Some(address) => pool.upstreams.find(&address),
+            None => None,
+        };
+        let pinned = match pinned {
+            Some(id) if pool.upstreams_state.read().await.is_alive(id) => Some(id),
+            _ => None,
+        };
+        // Otherwise, with consistent hashing, requests with the same key keep going to the same
+        // upstream for as long as it is alive
+        let preferred = match pinned {
+            None if state.load_balancing == LoadBalancingStrategy::ConsistentHashing => {
+                let upstreams_state = pool.upstreams_state.read().await;
+                let tiers = pool.upstreams.tiers();
+                let header = state
+                    .consistent_hash_header
+                    .as_ref()
+                    .and_then(|name| request.headers().get(name));
+                match header {
+                    Some(value) => upstreams_state.hashed(&tiers, value.as_bytes()),
+                    None => upstreams_state.hashed(&tiers, client_ip.to_string().as_bytes()),
+                }
+            }
+            _ => pinned,
+        };
+        if let (Some(id), Some((_, current, _))) = (preferred, &upstream) {
+            if id != current.id {
+                upstream = None;
+            }
+        }
+        // Don't send anything more to an upstream that has failed since the previous request,
+        // even though its connection is still open
+        if let Some((_, current, _)) = &upstream {
+            if !pool.upstreams_state.read().await.is_alive(current.id) {
+                upstream = None;
+            }
+        }
+
+        // Fail or hold up the request on purpose, if fault injection picks it. Failures are
+        // answered before an upstream is touched.
+        let fault = match &state.fault_injector {
+            Some(injector) => injector.choose(rand::random()),
+            None => None,
+        };
+        if let Some(fault) = fault {
+            log::debug!(
+                "Request {}: injecting fault {} into {} {}",
+                request_id,
+                fault.as_str(),
+                request::request_method(&request),
+                request::request_uri(&request)
+            );
+        }
+        let injected_error = match fault {
+            Some(Fault::Error) => Some(ErrorReason::FaultInjected),
+            Some(Fault::UpstreamFailure) => Some(ErrorReason::UpstreamConnect),
+            Some(Fault::Delay(delay)) => {
+                delay_for(delay).await;
+                None
+            }
+            None => None,
+        };
+        if let Some(reason) = injected_error {
+            let response = send_error(&mut client_conn, &state, pool, reason).await;
+            capture_exchange(&state, request_id, false, &raw_request, &response).await;
+            continue;
+        }
+
+        // A refused request goes no further, so it costs no upstream a connection
+        if rate_limit_refuses(&state, &listener, client_ip).await {
+            let response =
+                send_error(&mut client_conn, &state, pool, ErrorReason::RateLimited).await;
+            capture_exchange(&state, request_id, false, &raw_request, &response).await;
+            continue;
+        }
+
+        // Let the client start on preloads while the upstream works on the page. HTTP/1.0 clients
+        // don't know about interim responses and would take the 103 for the real one.
+        if request.method() == http::Method::GET && client_version == http::Version::HTTP_11 {
+            let links = early_hints::links_for(&state.early_hints, request.uri().path());
+            if !links.is_empty() {
+                let hints = response::make_early_hints(&links);
+                if let Err(error) = response::write_to_stream(&hints, &mut client_conn).await {
+                    log::warn!("Failed to send early hints to client: {}", error);
+                    return;
+                }
+            }
+        }
+
+        // Open a connection to a random destination server, unless we can keep using the one from
+        // the previous request
+        connection.set_state(ConnectionState::WaitingUpstream);
+        let connect_start = Instant::now();
+        // Upstreams this request has been sent to, so that a retry never goes back to one
+        let mut tried = Vec::new();
+        if upstream.is_none() {
+            match connect_to_upstream(&state, pool, preferred, &mut tried).await {
+                Ok((stream, target)) => {
+                    let upstream_ip = stream.peer_addr().unwrap().ip().to_string();
+                    upstream = Some((stream, target, upstream_ip));
+                }
+                Err(reason) => {
+                    let response = send_error(&mut client_conn, &state, pool, reason).await;
+                    capture_exchange(&state, request_id, false, &raw_request, &response).await;
+                    return;
+                }
+            }
+        }
+        let connect_time = connect_start.elapsed();
+        phases.record(Phase::UpstreamConnect, connect_time);
+        let (_, target, upstream_ip) = upstream.as_ref().unwrap();
+        if pinned == Some(target.id) {
+            log::info!(
+                "Request {}: routed to upstream {} by debug override",
+                request_id,
+                target.address
+            );
+        }
+        if log_access {
+            log::info!(
+                "{} -> {}: {}",
+                client_ip,
+                upstream_ip,
+                request::format_request_line(&request)
+            );
+        }
+
+        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
+        // (We're the ones connecting directly to the upstream server, so without this header, the
+        // upstream server will only know our IP, not the client's.)
+        // Overlong chains (which clients can forge freely) are trimmed from the left.
+        let style = state.forwarded_header_style;
+        if style == ForwardedHeaderStyle::Rfc7239 {
+            // Forwarded says everything the X-Forwarded-* headers would, so upstreams get one
+            // account of where the request came from rather than two that may disagree
+            let names: Vec<http::header::HeaderName> = request
+                .headers()
+                .keys()
+                .filter(|name| name.as_str().starts_with("x-forwarded-"))
+                .cloned()
+                .collect();
+            for name in names {
+                request.headers_mut().remove(name);
+            }
+        } else {
+            let dropped = request::extend_header_value(
+                &mut request,
+                "x-forwarded-for",
+                &client_ip.to_string(),
+                &state.xff_limits,
+            );
+            if dropped > 0 {
+                log::debug!("Dropped {} X-Forwarded-For entries from {}", dropped, client_ip);
+            }
+        }
+        if style != ForwardedHeaderStyle::XForwardedFor {
+            // balancebeam doesn't terminate TLS, so clients always reach us over plain HTTP
+            let element = request::build_forwarded_header(
+                client_ip,
+                "http",
+                request.headers().get("host").and_then(|value| value.to_str().ok()),
+                client_conn.local_addr().unwrap().ip().to_canonical(),
+            );
+            let limits = &state.xff_limits;
+            let dropped = request::extend_header_value(&mut request, "forwarded", &element, limits);
+            if dropped > 0 {
+                log::debug!("Dropped {} Forwarded elements from {}", dropped, client_ip);
+            }
+        }
+
+        // Whether the client wants its connection closed after this request
+        let client_close = request::wants_close(request.headers());
+        // What the client can decode, should the response need decompressing for it
+        let accept_encoding = request.headers().get(http::header::ACCEPT_ENCODING).cloned();
+
+        // Headers the client shouldn't be able to set go, then ours (e.g. credentials for internal
+        // APIs) replace any the client sent under the same names
+        edit_headers(
+            request.headers_mut(),
+            &state.upstream_remove_request_headers,
+            &state.upstream_add_request_headers,
+        );
+
+        // Rewrite the path last, so that everything above sees the path the client asked for
+        let client_path = state.top_talkers.as_ref().map(|_| request.uri().path().to_string());
+        let host = request
+            .headers()
+            .get("host")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let rewrite = state.path_rewriter.rewrite_request(&mut request);
+
+        // Tell the upstream how much longer the client will wait (any value the client made up
+        // itself was stripped on arrival)
+        if let Some(deadline) = &deadline {
+            request
+                .headers_mut()
+                .insert(deadline::DEADLINE_HEADER, deadline.header_value(Instant::now()));
+        }
+
+        // Some upstreams only answer to one name, whatever the client asked for. This goes last so
+        // that nothing above sees the overridden Host.
+        if let Some(host_override) = &pool.host_override {
+            request.headers_mut().insert(http::header::HOST, host_override.clone());
+        }
+
+        // Forward the request to the server. An upstream that fails to take the request, or won't
+        // take it in time, is failed, and since the whole request is buffered we can send it to
+        // another one instead (if it is idempotent, or never reached the upstream).
+        let write_start = Instant::now();
+        let (in_flight, whole_request_sent) = loop {
+            let (upstream_conn, target, upstream_ip) = upstream.as_mut().unwrap();
+            let in_flight = target.stats.begin_request();
+            // Upstreams that can't cope with keep-alive get HTTP/1.0. This is decided per attempt,
+            // as a retry may go to an upstream that speaks another version.
+            let version = state.upstream_version(&target.address);
+            let result = timeout(
+                state.upstream_write_timeout,
+                unless_closed(
+                    target,
+                    request::write_to_stream_as(&request, version, upstream_conn),
+                    std::convert::identity,
+                ),
+            )
+            .await;
+            let failure = match result {
+                Ok(Ok(whole)) => break (in_flight, whole),
+                Ok(Err(error)) => {
+                    log::error!("Failed to send request to upstream {}: {}", upstream_ip, error);
+                    ErrorReason::UpstreamWrite
+                }
+                Err(_) => {
+                    log::error!(
+                        "Upstream {} didn't take the request within {:?}",
+                        upstream_ip,
+                        state.upstream_write_timeout
+                    );
+                    ErrorReason::UpstreamWriteTimeout
+                }
+            };
+            drop(in_flight);
+            record_upstream_error(&state, pool, target).await;
+            pool.upstreams_state.write().await.set_dead(target.id);
+            // It may have been kept from an earlier request rather than connected to now
+            let kept = !tried.contains(&target.id);
+            if kept {
+                tried.push(target.id);
+            }
+            // Part of the request may have reached the upstream, and it might act on that, so only
+            // requests that can safely be applied twice are sent again. The exception is a kept
+            // connection the upstream closed while it was idle, which never saw any of it.
+            let unsent = kept && failure == ErrorReason::UpstreamWrite;
+            let retry = if !request::is_idempotent(request.method()) && !unsent {
+                log::warn!(
+                    "Request {}: not retrying {} on another upstream, as it isn't idempotent",
+                    request_id,
+                    request.method()
+                );
+                Err(failure)
+            } else if state.retry_budget.try_retry() {
+                connect_to_upstream(&state, pool, None, &mut tried).await
+            } else {
+                log::warn!("Retry budget exhausted; not trying another upstream");
+                Err(failure)
+            };
+            match retry {
+                Ok((stream, target)) => {
+                    log::info!("Request {}: retrying on upstream {}", request_id, target.address);
+                    let upstream_ip = stream.peer_addr().unwrap().ip().to_string();
+                    upstream = Some((stream, target, upstream_ip));
+                }
+                Err(reason) => {
+                    let reason = match reason {
+                        // Nothing left to fail over to: the write is what went wrong
+                        ErrorReason::AllDead => failure,
+                        reason => reason,
+                    };
+                    let response = send_error(&mut client_conn, &state, pool, reason).await;
+                    capture_exchange(&state, request_id, false, &raw_request, &response).await;
+                    return;
+                }
+            }
+        };
+        let (upstream_conn, target, upstream_ip) = upstream.as_mut().unwrap();
+        let write_time = write_start.elapsed();
+        phases.record(Phase::UpstreamWrite, write_time);
+        connection.set_upstream(Some(&target.address));
+        if !whole_request_sent {
+            log::info!(
+                "Request {}: upstream {} answered before taking the whole request; relaying that",
+                request_id,
+                upstream_ip
+            );
+        }
+        if verbose {
+            let mut headers = request.headers().clone();
+            for (name, _) in &state.upstream_add_request_headers {
+                headers.insert(name, http::HeaderValue::from_static("[REDACTED]"));
+            }
+            log::debug!("Request {}: sent headers {:?}", request_id, headers);
+        }
+
+        // Read the server's response headers, giving up once this upstream's timeout or the
+        // request's deadline expires
+        let header_timeout = earliest(
+            pool.upstreams_state.read().await.response_timeout(target.id),
+            deadline.as_ref().map(|deadline| deadline.remaining(Instant::now())),
+        );
+        let read_start = Instant::now();
+        let result = timeout_if_any(
+            header_timeout,
+            unless_closed(
+                target,
+                response::read_headers(upstream_conn, &state.response_limits),
+                response::Error::ConnectionError,
+            ),
+        )
+        .await;
+        if state.timeout_policy.is_adaptive() {
+            pool.upstreams_state.write().await.record_response_time(
+                target.id,
+                &state.timeout_policy,
+                read_start.elapsed(),
+            );
+        }
+        let mut response = match result {
+            Ok(Ok(response)) => response,
+            Ok(Err(error)) => {
+                log::error!("Error reading response from server: {}", error);
+                record_upstream_error(&state, pool, target).await;
+                let reason = ErrorReason::from(&error);
+                let response = send_error(&mut client_conn, &state, pool, reason).await;
+                capture_exchange(&state, request_id, false, &raw_request, &response).await;
+                return;
+            }
+            Err(_) => {
+                log::error!(
+                    "Upstream {} didn't send response headers within {:?}",
+                    upstream_ip,
+                    header_timeout.unwrap_or_default()
+                );
+                record_upstream_error(&state, pool, target).await;
+                let response =
+                    send_error(&mut client_conn, &state, pool, ErrorReason::UpstreamTimeout).await;
+                capture_exchange(&state, request_id, false, &raw_request, &response).await;
+                return;
+            }
+        };
+        let upstream_latency = write_start.elapsed();
+        let first_byte_time = read_start.elapsed();
+
+        // Then the body, which gets its own timeout starting now that the headers are here
+        if response::has_body(request.method(), &response) {
+            let body_timeout = earliest(
+                state.body_timeout,
+                deadline.as_ref().map(|deadline| deadline.remaining(Instant::now())),
+            );
+            let result = timeout_if_any(
+                body_timeout,
+                unless_closed(
+                    target,
+                    response::read_body(upstream_conn, &mut response, Some(&mut buffers)),
+                    response::Error::ConnectionError,
+                ),
+            )
+            .await;
+            let reason = match result {
+                Ok(Ok(())) => None,
+                Ok(Err(error)) => {
+                    log::error!("Error reading response from server: {}", error);
+                    Some(ErrorReason::from(&error))
+                }
+                Err(_) => {
+                    log::error!(
+                        "Upstream {} didn't send the response body within {:?}",
+                        upstream_ip,
+                        body_timeout.unwrap_or_default()
+                    );
+                    Some(ErrorReason::UpstreamTimeout)
+                }
+            };
+            if let Some(reason) = reason {
+                // Running out of buffer space is our problem, not the upstream's
+                if reason != ErrorReason::BufferLimit {
+                    record_upstream_error(&state, pool, target).await;
+                }
+                let response = send_error(&mut client_conn, &state, pool, reason).await;
+                capture_exchange(&state, request_id, false, &raw_request, &response).await;
+                return;
+            }
+        }
+        let read_time = read_start.elapsed();
+        phases.record(Phase::UpstreamFirstByte, first_byte_time);
+        phases.record(Phase::UpstreamBodyRead, read_time - first_byte_time);
+        drop(in_flight);
+        state.metrics.record_response_body(response.body().len());
+        if response::is_cacheable(&request, &response) {
+            state.metrics.record_cacheable_response();
+        }
+        if response.status().is_server_error() {
+            record_upstream_error(&state, pool, target).await;
+        } else {
+            target.stats.record_response(read_time);
+        }
+        if let Some(policy) = &state.outlier_policy {
+            let ejected = pool.upstreams_state.write().await.record_status(
+                target.id,
+                policy,
+                response.status().is_server_error(),
+                Instant::now(),
+            );
+            if ejected {
+                state.metrics.record_ejection();
+            }
+        }
+        if let Some(rule) = rewrite {
+            rewrite::rewrite_response(rule, &mut response, host.as_deref());
+        }
+        if state.decompress_upstream {
+            let compressed_len = response.body().len();
+            // The decoded body is held to the same limit as any other response body
+            let decoded = decompress::decode_response(
+                &mut response,
+                accept_encoding.as_ref(),
+                response::MAX_BODY_SIZE,
+            )
+            .await;
+            let reason = match decoded {
+                Ok(None) => None,
+                Ok(Some(coding)) => {
+                    log::debug!("Request {}: decompressed a {:?} body", request_id, coding);
+                    let grown = response.body().len().saturating_sub(compressed_len);
+                    if buffers.grow(grown) {
+                        None
+                    } else {
+                        Some(ErrorReason::BufferLimit)
+                    }
+                }
+                Err(error) => {
+                    log::error!("Could not decompress a response from {}: {}", upstream_ip, error);
+                    record_upstream_error(&state, pool, target).await;
+                    Some(ErrorReason::UpstreamParse)
+                }
+            };
+            if let Some(reason) = reason {
+                let response = send_error(&mut client_conn, &state, pool, reason).await;
+                capture_exchange(&state, request_id, false, &raw_request, &response).await;
+                return;
+            }
+        }
+        match state.body_rewriter.rewrite(&mut response) {
+            Ok(0) => {}
+            Ok(count) => log::debug!("Request {}: made {} body rewrites", request_id, count),
+            // Already warned about, once
+            Err(Skipped::Compressed) => {}
+            Err(skipped) => {
+                log::debug!("Request {}: not rewriting the body, as {}", request_id, skipped)
+            }
+        }
+        edit_headers(
+            response.headers_mut(),
+            &state.upstream_remove_response_headers,
+            &state.upstream_add_response_headers,
+        );
+        if expose_upstream {
+            debug_routing::add_served_by(&mut response, &target.address, upstream_latency);
+        }
+        if state.security_headers {
+            if response::default_content_type(&mut response, &state.default_content_type) {
+                log::debug!(
+                    "Request {}: the upstream sent no Content-Type, so sending {:?}",
+                    request_id,
+                    state.default_content_type
+                );
+            }
+            response.headers_mut().insert(
+                http::header::X_CONTENT_TYPE_OPTIONS,
+                http::HeaderValue::from_static("nosniff"),
+            );
+        }
+        // If either side asked to close, neither connection is reused: the upstream's may already
+        // be closing, and the client is told to close its own. So is a client whose connection we
+        // were asked to close.
+        let close = client_close
+            || request::wants_close(response.headers())
+            || connection.close_requested();
+        if close {
+            response
+                .headers_mut()
+                .insert(http::header::CONNECTION, http::HeaderValue::from_static("close"));
+        }
+        if verbose {
+            log::debug!("Request {}: received headers {:?}", request_id, response.headers());
+        }
+        // Forward the response to the client
+        let send_start = Instant::now();
+        connection.set_state(ConnectionState::Writing);
+        let sent = write_response(&mut client_conn, &state, &response).await;
+        phases.record(Phase::ClientWrite, send_start.elapsed());
+        log_response(client_ip, &response, log_access, Some(&phases));
+        if !sent {
+            // The client hung up partway through. The upstream's side of the exchange is already
+            // over (the whole response was read before any of it was sent), and it was already
+            // counted as finished, so all that is left is to close both connections.
+            log::info!(
+                "{} <- request {} aborted by the client after {:?}",
+                client_ip,
+                request_id,
+                send_start.elapsed()
+            );
+            if state.phase_metrics {
+                state.metrics.record_phases(&phases);
+            }
+            capture_exchange(&state, request_id, false, &raw_request, &response).await;
+            return;
+        }
+        connection.record_response(response.body().len());
+        if let (Some(top_talkers), Some(path)) = (&state.top_talkers, &client_path) {
+            let client = rate_limiter::client_key(client_ip, state.rate_limit_ipv6_prefix);
+            let bytes = request.body().len() + response.body().len();
+            top_talkers.record(&client, path, bytes as u64);
+        }
+        if state.phase_metrics {
+            state.metrics.record_phases(&phases);
+        }
+        capture_exchange(&state, request_id, false, &raw_request, &response).await;
+        if verbose {
+            log::debug!(
+                "Request {}: timings: connect {:?}, upstream write {:?}, upstream response {:?}, \
+                 client write {:?}",
+                request_id,
+                connect_time,
+                write_time,
+                read_time,
+                send_start.elapsed()
+            );
+        }
+
+        if close {
+            return;
+        }
+        // An HTTP/1.0 upstream connection only carries a single request, and one we stopped
+        // writing a request to partway through can't carry another
+        if !whole_request_sent
+            || upstream.as_ref().is_some_and(|(_, target, _)| {
+                state.upstream_version(&target.address) == http::Version::HTTP_10
+            })
+        {
+            upstream = None;
+            connection.set_upstream(None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstreams(count: usize) -> (Vec<UpstreamId>, UpstreamsState) {
+        let registry = UpstreamRegistry::new((0..count).map(|i| (format!("upstream-{}:80", i), 0)));
+        let ids: Vec<UpstreamId> = registry.snapshot().iter().map(|upstream| upstream.id).collect();
+        let policy = TimeoutPolicy {
+            base: Duration::from_secs(10),
+            factor: 1.0,
+            max: Duration::from_secs(10),
+            recovery_requests: 10,
+        };
+        (ids.clone(), UpstreamsState::new(ids, policy.initial()))
+    }
+
+    /// Draws `draws` choices from `candidates` with a seeded RNG, counting picks per upstream.
+    fn pick_counts(state: &UpstreamsState, candidates: &[UpstreamId], draws: usize) -> Vec<usize> {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(177);
+        let mut counts = vec![0; candidates.len()];
+        for _ in 0..draws {
+            let id = state.choose(candidates, rng.gen(), false).expect("no upstream chosen");
+            counts[id.index()] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_choose_is_uniform_over_alive_upstreams() {
+        let (ids, mut state) = upstreams(4);
+        state.set_dead(ids[1]);
+        let counts = pick_counts(&state, &ids, 30000);
+        assert_eq!(counts[1], 0, "dead upstream chosen: {:?}", counts);
+        for count in [counts[0], counts[2], counts[3]] {
+            assert!((9000..11000).contains(&count), "not uniform: {:?}", counts);
+        }
+    }
+
+    #[test]
+    fn test_choose_finds_last_alive_upstream() {
+        let (ids, mut state) = upstreams(4);
+        for id in &ids[..3] {
+            state.set_dead(*id);
+        }
+        assert_eq!(pick_counts(&state, &ids, 1000), vec![0, 0, 0, 1000]);
+        // Even a point at the very end of [0, 1) lands on it
+        assert_eq!(state.choose(&ids, 0.999_999, false), Some(ids[3]));
+
+        state.set_dead(ids[3]);
+        assert_eq!(state.choose(&ids, 0.5, false), None);
+        assert_eq!(state.choose(&ids, 0.5, true), None);
+    }
+
+    #[test]
+    fn test_choose_two_evens_out_load() {
+        let (ids, state) = upstreams(4);
+        // The first upstream starts out busy; requests pile up and never finish
+        let mut loads = vec![400_u64, 0, 0, 0];
+        let mut rng = rand::rngs::StdRng::seed_from_u64(177);
+        for _ in 0..10000 {
+            let id = state
+                .choose_two(&ids, &mut rng, false, |id| loads[id.index()])
+                .expect("no upstream chosen");
+            loads[id.index()] += 1;
+        }
+        // Picking at random would leave the first one about 400 ahead
+        let (least, most) = (*loads.iter().min().unwrap(), *loads.iter().max().unwrap());
+        assert!(most - least <= least / 20, "uneven: {:?}", loads);
+
+        let (ids, mut state) = upstreams(2);
+        state.set_dead(ids[0]);
+        for _ in 0..10 {
+            assert_eq!(state.choose_two(&ids, &mut rng, false, |_| 0), Some(ids[1]));
+        }
+    }
+
+    #[test]
+    fn test_select_never_picks_a_tried_upstream() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(202);
+        let strategies = [
+            LoadBalancingStrategy::Random,
+            LoadBalancingStrategy::PowerOfTwoChoices,
+            LoadBalancingStrategy::ConsistentHashing,
+        ];
+        let (ids, state) = upstreams(2);
+        let tiers: BTreeMap<u8, Vec<UpstreamId>> = vec![(0, ids.clone())].into_iter().collect();
+        for strategy in strategies {
+            for _ in 0..100 {
+                let picked = state.select(&tiers, strategy, &mut rng, |_| 0, &ids[..1]);
+                assert_eq!(picked, Some(ids[1]));
+            }
+            assert_eq!(state.select(&tiers, strategy, &mut rng, |_| 0, &ids), None);
+        }
+
+        // Once the preferred tier has been tried, a lower priority one is used
+        let (ids, mut state) = upstreams(3);
+        let tiers: BTreeMap<u8, Vec<UpstreamId>> =
+            vec![(1, vec![ids[0]]), (0, vec![ids[1], ids[2]])].into_iter().collect();
+        assert_eq!(state.select(&tiers, strategies[0], &mut rng, |_| 0, &[]), Some(ids[0]));
+        state.set_dead(ids[2]);
+        for _ in 0..100 {
+            let picked = state.select(&tiers, strategies[1], &mut rng, |_| 0, &ids[..1]);
+            assert_eq!(picked, Some(ids[1]));
+        }
+        assert_eq!(state.select(&tiers, strategies[0], &mut rng, |_| 0, &ids[..2]), None);
+    }
+
+    #[test]
+    fn test_failure_streak_alert() {
+        let (ids, mut state) = upstreams(2);
+        let registry = UpstreamRegistry::new((0..2).map(|i| (format!("upstream-{}:80", i), 0)));
+        let upstreams = registry.snapshot();
+        let fail = |upstream: &Upstream, times: usize| {
+            (0..times).for_each(|_| upstream.stats.record_error())
+        };
+
+        fail(&upstreams[0], 3);
+        assert!(state.failure_streaks_changed(&upstreams, 2));
+        assert!(!state.record_failure_streaks(&upstreams, 2));
+        assert_eq!(state.max_consecutive_failures, 3);
+        fail(&upstreams[1], 2);
+        assert!(state.record_failure_streaks(&upstreams, 2));
+        // Only once per run of failures
+        fail(&upstreams[1], 1);
+        assert!(!state.failure_streaks_changed(&upstreams, 2));
+        assert!(!state.record_failure_streaks(&upstreams, 2));
+
+        // A success ends the run, though not the high watermark
+        upstreams[1].stats.record_response(Duration::from_millis(1));
+        assert!(!state.record_failure_streaks(&upstreams, 2));
+        assert_eq!(state.max_consecutive_failures, 3);
+        fail(&upstreams[1], 2);
+        assert!(state.record_failure_streaks(&upstreams, 2));
+
+        // Removed upstreams don't count, and a threshold of 0 never alerts
+        upstreams[1].stats.record_response(Duration::from_millis(1));
+        assert!(!state.record_failure_streaks(&upstreams, 2));
+        state.set_removing(ids[1]);
+        assert!(state.record_failure_streaks(&upstreams, 2));
+        assert!(!state.record_failure_streaks(&upstreams, 0));
+    }
+
+    #[test]
+    fn test_consistent_hash_replicas_must_be_positive() {
+        let parse = |replicas| {
+            ProxyConfig::command().try_get_matches_from([
+                "balancebeam",
+                "--upstream",
+                "10.0.0.1:80",
+                "--consistent-hash-replicas",
+                replicas,
+            ])
+        };
+        assert!(parse("0").is_err());
+        assert!(parse("1").is_ok());
+    }
+
+    #[test]
+    fn test_proxy_state_from_default_options() {
+        assert!(ProxyConfig::default().load_config().is_err(), "no upstreams should be an error");
+
+        let options = ProxyConfig {
+            upstream: vec!["127.0.0.1:8001".to_string(), "127.0.0.1:8002".to_string()],
+            ..Default::default()
+        };
+        let config = options.load_config().unwrap();
+        assert_eq!(config.listeners[0].bind, "127.0.0.1:0");
+        assert_eq!(config.listeners[0].max_requests_per_minute, 0);
+        let state = ProxyState::from_options(&options, &config);
+        let pool = &state.pools[0];
+        assert_eq!((pool.name.as_str(), pool.active_health_check_path.as_str()), ("default", "/"));
+        assert_eq!(pool.active_health_check_interval, 10);
+        assert_eq!(pool.upstreams.snapshot().len(), 2);
+        assert!(state.fault_injector.is_none() && state.dry_upstream.is_none());
+        assert!(!state.rate_limit_observe.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_state_new_starts_dry_upstream() {
+        let options = ProxyConfig {
+            upstream: vec!["127.0.0.1:8001".to_string()],
+            dry_upstream_connect: true,
+            ..Default::default()
+        };
+        let state = ProxyState::new(&options, &options.load_config().unwrap()).await;
+        let address = state.dry_upstream.clone().expect("dry runs have a stand-in upstream");
+        use tokio::io::AsyncWriteExt;
+        // It swallows the request and hangs up without answering
+        let mut stream = TcpStream::connect(&address).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert!(received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_summary() {
+        let options = ProxyConfig {
+            upstream: vec!["127.0.0.1:8001".to_string(), "127.0.0.1:8002".to_string()],
+            ..Default::default()
+        };
+        let state = ProxyState::new(&options, &options.load_config().unwrap()).await;
+        let second = state.pools[0].upstreams.snapshot()[1].id;
+        state.pools[0].upstreams_state.write().await.set_dead(second);
+        let result = shutdown::shutdown(state).await;
+        let expected = shutdown::ShutdownResult {
+            requests_processed: 0,
+            upstreams_alive: 1,
+            connections_abandoned: 0,
+        };
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_debug_masks_upstream_addresses() {
+        let options = ProxyConfig {
+            upstream: vec!["10.1.2.3:8001".to_string(), "10.1.2.4:8002".to_string()],
+            ..Default::default()
+        };
+        let config = options.load_config().unwrap();
+        let state = ProxyState::from_options(&options, &config);
+        let debug = format!("{:?}", state);
+        assert!(debug.contains(r#"upstreams: ["upstream_0", "upstream_1"]"#), "{}", debug);
+        assert!(debug.contains("active_health_check_interval: 10"), "{}", debug);
+        assert!(debug.contains(r#"rate_limit_mode: "enforce""#), "{}", debug);
+        assert!(!debug.contains("10.1.2"), "{}", debug);
+
+        let listener = Listener::new(&options, &state, &config.listeners[0]);
+        let debug = format!("{:?}", listener);
+        assert!(debug.starts_with(r#"Listener { bind: "127.0.0.1:0", pool: "default", "#));
+        assert!(debug.ends_with(r#"rate_limiter: "fixed-window" }"#), "{}", debug);
+    }
+}
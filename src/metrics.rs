@@ -0,0 +1,324 @@
+use crate::error_reason::ErrorReason;
+use crate::histogram::{Histogram, SIZE_BOUNDS_BYTES};
+use crate::phase_timer::{Phase, PhaseTimer};
+use crate::registry::Upstream;
+use crate::{ProxyState, UpstreamPool};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Content-Type of the text exposition format produced by `render`
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Counters describing what balancebeam has been doing. Counters are updated with relaxed atomics
+/// from the request path, and histograms are only locked long enough to bump a bucket, so reading a
+/// snapshot never holds up a connection for long.
+#[derive(Debug)]
+pub struct Metrics {
+    /// Number of proxy-generated error responses, indexed by `ErrorReason::index`
+    errors: [AtomicU64; ErrorReason::ALL.len()],
+    /// Number of times an upstream was ejected for sending too many 5xx responses
+    outlier_ejections: AtomicU64,
+    /// Number of upstream responses a shared cache could have stored
+    cacheable_responses: AtomicU64,
+    /// Number of responses the client went away in the middle of
+    client_aborts: AtomicU64,
+    /// Number of responses sent to clients, by status class (1xx to 5xx)
+    responses_by_class: [AtomicU64; 5],
+    /// Sizes of the request bodies read from clients
+    request_body_bytes: Mutex<Histogram>,
+    /// Sizes of the response bodies relayed from upstreams
+    response_body_bytes: Mutex<Histogram>,
+    /// Time proxied requests spent in each phase, indexed by `Phase::index`
+    phase_durations: [Mutex<Histogram>; Phase::ALL.len()],
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics {
+            errors: Default::default(),
+            outlier_ejections: AtomicU64::new(0),
+            cacheable_responses: AtomicU64::new(0),
+            client_aborts: AtomicU64::new(0),
+            responses_by_class: Default::default(),
+            request_body_bytes: Mutex::new(Histogram::new(&SIZE_BOUNDS_BYTES)),
+            response_body_bytes: Mutex::new(Histogram::new(&SIZE_BOUNDS_BYTES)),
+            phase_durations: Default::default(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record_error(&self, reason: ErrorReason) {
+        self.errors[reason.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn errors(&self, reason: ErrorReason) -> u64 {
+        self.errors[reason.index()].load(Ordering::Relaxed)
+    }
+
+    pub fn record_ejection(&self) {
+        self.outlier_ejections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn outlier_ejections(&self) -> u64 {
+        self.outlier_ejections.load(Ordering::Relaxed)
+    }
+
+    pub fn record_cacheable_response(&self) {
+        self.cacheable_responses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cacheable_responses(&self) -> u64 {
+        self.cacheable_responses.load(Ordering::Relaxed)
+    }
+
+    pub fn record_client_abort(&self) {
+        self.client_aborts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn client_aborts(&self) -> u64 {
+        self.client_aborts.load(Ordering::Relaxed)
+    }
+
+    /// Counts a response sent to a client, whether relayed from an upstream or our own.
+    pub fn record_response_status(&self, status: http::StatusCode) {
+        let class = (status.as_u16() / 100).clamp(1, 5) as usize;
+        self.responses_by_class[class - 1].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Responses sent to clients, indexed by status class less one (so 2xx is at 1).
+    pub fn responses_by_class(&self) -> [u64; 5] {
+        let mut counts = [0; 5];
+        for (count, counter) in counts.iter_mut().zip(&self.responses_by_class) {
+            *count = counter.load(Ordering::Relaxed);
+        }
+        counts
+    }
+
+    pub fn record_request_body(&self, bytes: usize) {
+        self.request_body_bytes.lock().unwrap().record_value(bytes as u64);
+    }
+
+    pub fn record_response_body(&self, bytes: usize) {
+        self.response_body_bytes.lock().unwrap().record_value(bytes as u64);
+    }
+
+    pub fn request_body_bytes(&self) -> Histogram {
+        self.request_body_bytes.lock().unwrap().clone()
+    }
+
+    pub fn response_body_bytes(&self) -> Histogram {
+        self.response_body_bytes.lock().unwrap().clone()
+    }
+
+    /// Adds a request's phase durations to the per-phase histograms.
+    pub fn record_phases(&self, timer: &PhaseTimer) {
+        for (phase, duration) in timer.recorded() {
+            self.phase_durations[phase.index()].lock().unwrap().record(duration);
+        }
+    }
+
+    pub fn phase_durations(&self, phase: Phase) -> Histogram {
+        self.phase_durations[phase.index()].lock().unwrap().clone()
+    }
+}
+
+/// Renders the current metrics in the Prometheus text exposition format.
+pub async fn render(state: &ProxyState) -> String {
+    let mut out = String::new();
+    // Writing to a String can't fail, so the fmt::Results below are ignored
+    let _ = writeln!(out, "# HELP balancebeam_build_info Version of the running binary");
+    let _ = writeln!(out, "# TYPE balancebeam_build_info gauge");
+    let _ = writeln!(
+        out,
+        "balancebeam_build_info{{version=\"{}\",rustc_version=\"{}\"}} 1",
+        env!("CARGO_PKG_VERSION"),
+        env!("BALANCEBEAM_RUSTC_VERSION")
+    );
+
+    let _ = writeln!(out, "# HELP balancebeam_uptime_seconds Seconds since balancebeam started");
+    let _ = writeln!(out, "# TYPE balancebeam_uptime_seconds counter");
+    let _ = writeln!(
+        out,
+        "balancebeam_uptime_seconds {}",
+        state.started_at.elapsed().as_secs_f64()
+    );
+
+    let _ = writeln!(out, "# HELP balancebeam_proxy_errors_total Error responses generated by balancebeam");
+    let _ = writeln!(out, "# TYPE balancebeam_proxy_errors_total counter");
+    for reason in ErrorReason::ALL.iter() {
+        let _ = writeln!(
+            out,
+            "balancebeam_proxy_errors_total{{reason=\"{}\"}} {}",
+            reason,
+            state.metrics.errors(*reason)
+        );
+    }
+
+    let _ = writeln!(out, "# HELP balancebeam_responses_total Responses sent to clients by status class");
+    let _ = writeln!(out, "# TYPE balancebeam_responses_total counter");
+    for (class, count) in state.metrics.responses_by_class().iter().enumerate() {
+        let _ = writeln!(out, "balancebeam_responses_total{{class=\"{}xx\"}} {}", class + 1, count);
+    }
+    let _ = writeln!(out, "# HELP balancebeam_client_aborts_total Responses the client hung up in the middle of");
+    let _ = writeln!(out, "# TYPE balancebeam_client_aborts_total counter");
+    let _ = writeln!(out, "balancebeam_client_aborts_total {}", state.metrics.client_aborts());
+
+    let budget = &state.retry_budget;
+    let _ = writeln!(out, "# HELP balancebeam_retries_total Retries allowed by the retry budget");
+    let _ = writeln!(out, "# TYPE balancebeam_retries_total counter");
+    let _ = writeln!(out, "balancebeam_retries_total {}", budget.retries());
+    let _ = writeln!(out, "# HELP balancebeam_retries_suppressed_total Retries refused by the retry budget");
+    let _ = writeln!(out, "# TYPE balancebeam_retries_suppressed_total counter");
+    let _ = writeln!(out, "balancebeam_retries_suppressed_total {}", budget.suppressed());
+    let _ = writeln!(out, "# HELP balancebeam_retry_budget_utilization Fraction of the retry budget in use");
+    let _ = writeln!(out, "# TYPE balancebeam_retry_budget_utilization gauge");
+    let _ = writeln!(out, "balancebeam_retry_budget_utilization {}", budget.utilization());
+
+    let buffers = &state.buffer_budget;
+    let _ = writeln!(out, "# HELP balancebeam_buffered_bytes Bytes held in request and response bodies");
+    let _ = writeln!(out, "# TYPE balancebeam_buffered_bytes gauge");
+    let _ = writeln!(out, "balancebeam_buffered_bytes {}", buffers.held());
+    let _ = writeln!(out, "# HELP balancebeam_buffered_bytes_peak Most bytes held in bodies at once since startup");
+    let _ = writeln!(out, "# TYPE balancebeam_buffered_bytes_peak gauge");
+    let _ = writeln!(out, "balancebeam_buffered_bytes_peak {}", buffers.peak());
+
+    write_histogram(
+        &mut out,
+        "balancebeam_request_body_bytes",
+        "Sizes of request bodies read from clients",
+        &state.metrics.request_body_bytes(),
+        1.0,
+    );
+    write_histogram(
+        &mut out,
+        "balancebeam_response_body_bytes",
+        "Sizes of response bodies relayed from upstreams",
+        &state.metrics.response_body_bytes(),
+        1.0,
+    );
+    // Lifetimes are kept in milliseconds, but Prometheus durations are in seconds
+    write_histogram(
+        &mut out,
+        "balancebeam_connection_lifetime_seconds",
+        "How long closed client connections were open",
+        &state.connections.lifetimes(),
+        1000.0,
+    );
+    let name = "balancebeam_request_phase_seconds";
+    let _ = writeln!(out, "# HELP {} Time proxied requests spent in each phase", name);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for phase in Phase::ALL.iter() {
+        let labels = format!("phase=\"{}\",", phase.as_str());
+        write_series(&mut out, name, &labels, &state.metrics.phase_durations(*phase), 1000.0);
+    }
+
+    if state.debug_connection_tracking {
+        let _ = writeln!(out, "# HELP balancebeam_active_connections Open client connections");
+        let _ = writeln!(out, "# TYPE balancebeam_active_connections gauge");
+        let _ = writeln!(out, "balancebeam_active_connections {}", state.connections.len());
+    }
+
+    if let Some(injector) = &state.fault_injector {
+        let _ = writeln!(out, "# HELP balancebeam_faults_injected_total Faults injected for testing");
+        let _ = writeln!(out, "# TYPE balancebeam_faults_injected_total counter");
+        for (fault, count) in injector.counts() {
+            let _ = writeln!(out, "balancebeam_faults_injected_total{{fault=\"{}\"}} {}", fault, count);
+        }
+    }
+
+    let _ = writeln!(out, "# HELP balancebeam_upstream_up Whether an upstream is considered alive");
+    let _ = writeln!(out, "# TYPE balancebeam_upstream_up gauge");
+    for pool in &state.pools {
+        let upstreams_state = pool.upstreams_state.read().await;
+        for upstream in current_upstreams(pool) {
+            let _ = writeln!(
+                out,
+                "balancebeam_upstream_up{{pool=\"{}\",upstream=\"{}\"}} {}",
+                pool.name,
+                upstream.address,
+                upstreams_state.is_alive(upstream.id) as u8
+            );
+        }
+    }
+
+    let mut max_streak = 0;
+    for pool in &state.pools {
+        max_streak = max_streak.max(pool.upstreams_state.read().await.max_consecutive_failures);
+    }
+    let _ = writeln!(out, "# HELP balancebeam_max_upstream_consecutive_failures Longest run of failed requests any upstream has had");
+    let _ = writeln!(out, "# TYPE balancebeam_max_upstream_consecutive_failures gauge");
+    let _ = writeln!(out, "balancebeam_max_upstream_consecutive_failures {}", max_streak);
+
+    let _ = writeln!(out, "# HELP balancebeam_outlier_ejections_total Upstreams ejected for sending too many 5xx");
+    let _ = writeln!(out, "# TYPE balancebeam_outlier_ejections_total counter");
+    let _ = writeln!(out, "balancebeam_outlier_ejections_total {}", state.metrics.outlier_ejections());
+    let _ = writeln!(out, "# HELP balancebeam_cacheable_responses_total Upstream responses that a shared cache could have stored");
+    let _ = writeln!(out, "# TYPE balancebeam_cacheable_responses_total counter");
+    let _ = writeln!(out, "balancebeam_cacheable_responses_total {}", state.metrics.cacheable_responses());
+    let _ = writeln!(out, "# HELP balancebeam_upstream_ejected Whether an upstream is ejected as an outlier");
+    let _ = writeln!(out, "# TYPE balancebeam_upstream_ejected gauge");
+    let now = Instant::now();
+    for pool in &state.pools {
+        let upstreams_state = pool.upstreams_state.read().await;
+        for upstream in current_upstreams(pool) {
+            let _ = writeln!(
+                out,
+                "balancebeam_upstream_ejected{{pool=\"{}\",upstream=\"{}\"}} {}",
+                pool.name,
+                upstream.address,
+                upstreams_state.is_ejected(upstream.id, now) as u8
+            );
+        }
+    }
+    out
+}
+
+/// Writes `histogram` as a Prometheus histogram, dividing its bounds and sum by `divisor` to
+/// convert them to the metric's unit.
+fn write_histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram, divisor: f64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    write_series(out, name, "", histogram, divisor);
+}
+
+/// Writes the samples of one histogram in a family, with `labels` (each followed by a comma) in
+/// front of the bucket bounds.
+fn write_series(out: &mut String, name: &str, labels: &str, histogram: &Histogram, divisor: f64) {
+    for (bound, count) in histogram.cumulative_buckets() {
+        let bound = bound as f64 / divisor;
+        let _ = writeln!(out, "{}_bucket{{{}le=\"{}\"}} {}", name, labels, bound, count);
+    }
+    let count = histogram.count();
+    let _ = writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, labels, count);
+    let labels = labels.trim_end_matches(',');
+    if labels.is_empty() {
+        let _ = writeln!(out, "{}_sum {}", name, histogram.sum() as f64 / divisor);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    } else {
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum() as f64 / divisor);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+/// The p50, p90 and p99 of a histogram, for the admin API. Values are null until something has
+/// been recorded.
+pub fn percentiles_json(histogram: &Histogram) -> serde_json::Value {
+    serde_json::json!({
+        "count": histogram.count(),
+        "p50": histogram.quantile_value(0.5),
+        "p90": histogram.quantile_value(0.9),
+        "p99": histogram.quantile_value(0.99),
+    })
+}
+
+/// The pool's upstreams, leaving out ones that were removed and added again since, so that every
+/// label set appears once.
+fn current_upstreams(pool: &UpstreamPool) -> impl Iterator<Item = Arc<Upstream>> + '_ {
+    pool.upstreams
+        .snapshot()
+        .into_iter()
+        .filter(move |upstream| pool.upstreams.find(&upstream.address) == Some(upstream.id))
+}
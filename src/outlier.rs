@@ -0,0 +1,178 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+use tokio::time::Duration;
+
+/// Parses `--outlier-5xx-threshold`, a fraction of responses from 0 to 1.
+pub fn parse_threshold(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(threshold) if (0.0..=1.0).contains(&threshold) => Ok(threshold),
+        _ => Err("expected a fraction from 0 to 1".to_string()),
+    }
+}
+
+/// When to take an upstream that keeps answering with 5xx out of rotation. An upstream like that
+/// still accepts connections and may pass health checks, so only its responses give it away.
+#[derive(Debug, Clone, Copy)]
+pub struct OutlierPolicy {
+    /// Fraction of responses (0 to 1) that must be 5xx for the upstream to be ejected
+    pub threshold: f64,
+    /// Responses needed in the window before the upstream is judged at all
+    pub min_requests: usize,
+    /// How far back responses are counted
+    pub window: Duration,
+    /// How long an ejected upstream is left out of selection
+    pub ejection_duration: Duration,
+    /// Most upstreams in a pool that may be ejected at once, as a percentage
+    pub max_ejected_percent: u64,
+}
+
+impl OutlierPolicy {
+    /// How many upstreams of a pool this size may be ejected at once. At least one may be (so that
+    /// small pools are protected too) but never all of them.
+    pub fn max_ejected(&self, pool_size: usize) -> usize {
+        let allowed = (pool_size as u64 * self.max_ejected_percent / 100) as usize;
+        allowed.max(1).min(pool_size.saturating_sub(1))
+    }
+}
+
+/// Per-upstream state for OutlierPolicy.
+#[derive(Debug, Clone, Default)]
+pub struct OutlierState {
+    /// When each recent response arrived and whether it was a 5xx
+    outcomes: VecDeque<(Instant, bool)>,
+    ejected_until: Option<Instant>,
+}
+
+impl OutlierState {
+    pub fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| now < until)
+    }
+
+    /// Records a response. Returns the number of 5xx and total responses in the window if the
+    /// upstream is now over the threshold and should be ejected.
+    pub fn record(
+        &mut self,
+        policy: &OutlierPolicy,
+        now: Instant,
+        server_error: bool,
+    ) -> Option<(usize, usize)> {
+        if self.is_ejected(now) {
+            // Stragglers from before the ejection don't count towards the next evaluation
+            return None;
+        }
+        while let Some((at, _)) = self.outcomes.front() {
+            if now.duration_since(*at) <= policy.window {
+                break;
+            }
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back((now, server_error));
+        let total = self.outcomes.len();
+        let errors = self.outcomes.iter().filter(|(_, error)| *error).count();
+        if total >= policy.min_requests && errors as f64 > policy.threshold * total as f64 {
+            Some((errors, total))
+        } else {
+            None
+        }
+    }
+
+    /// Takes the upstream out of selection. It is judged afresh once readmitted.
+    pub fn eject(&mut self, policy: &OutlierPolicy, now: Instant) {
+        self.ejected_until = Some(now + policy.ejection_duration);
+        self.outcomes.clear();
+    }
+
+    /// Clears an ejection that has run its course, returning true if there was one.
+    pub fn readmit_if_due(&mut self, now: Instant) -> bool {
+        match self.ejected_until {
+            Some(until) if now >= until => {
+                self.ejected_until = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: OutlierPolicy = OutlierPolicy {
+        threshold: 0.5,
+        min_requests: 4,
+        window: Duration::from_secs(10),
+        ejection_duration: Duration::from_secs(30),
+        max_ejected_percent: 10,
+    };
+
+    /// Feeds a script of outcomes ('5' for a 5xx, '2' for anything else) one second apart,
+    /// returning the index of the response that tripped the threshold.
+    fn run_script(state: &mut OutlierState, start: Instant, script: &str) -> Option<usize> {
+        script.chars().enumerate().find_map(|(i, outcome)| {
+            let now = start + Duration::from_secs(i as u64);
+            state.record(&POLICY, now, outcome == '5').map(|_| i)
+        })
+    }
+
+    #[test]
+    fn test_parse_threshold() {
+        assert_eq!(parse_threshold("0.25"), Ok(0.25));
+        assert_eq!(parse_threshold("1"), Ok(1.0));
+        assert!(parse_threshold("1.5").is_err());
+        assert!(parse_threshold("half").is_err());
+    }
+
+    #[test]
+    fn test_needs_minimum_requests() {
+        let mut state = OutlierState::default();
+        assert_eq!(run_script(&mut state, Instant::now(), "555"), None);
+        let mut state = OutlierState::default();
+        assert_eq!(run_script(&mut state, Instant::now(), "5555"), Some(3));
+    }
+
+    #[test]
+    fn test_threshold_is_a_rate() {
+        // Exactly half isn't over a 0.5 threshold; more than half is
+        let mut state = OutlierState::default();
+        assert_eq!(run_script(&mut state, Instant::now(), "2525"), None);
+        let mut state = OutlierState::default();
+        assert_eq!(run_script(&mut state, Instant::now(), "25255"), Some(4));
+    }
+
+    #[test]
+    fn test_old_responses_fall_out_of_the_window() {
+        // Old successes stop diluting the rate once they're more than 10s old, so six 5xx
+        // eventually make up more than half of the window
+        let mut state = OutlierState::default();
+        assert_eq!(run_script(&mut state, Instant::now(), "2222222222222222555555"), Some(21));
+    }
+
+    #[test]
+    fn test_ejection_and_readmission() {
+        let start = Instant::now();
+        let mut state = OutlierState::default();
+        assert_eq!(run_script(&mut state, start, "5555"), Some(3));
+        state.eject(&POLICY, start);
+        assert!(state.is_ejected(start + Duration::from_secs(29)));
+        assert!(!state.readmit_if_due(start + Duration::from_secs(29)));
+        // Responses while ejected are ignored
+        assert_eq!(state.record(&POLICY, start + Duration::from_secs(1), true), None);
+
+        let later = start + Duration::from_secs(30);
+        assert!(!state.is_ejected(later));
+        assert!(state.readmit_if_due(later));
+        assert!(!state.readmit_if_due(later));
+        // Evaluation starts over after readmission
+        assert_eq!(run_script(&mut state, later, "555"), None);
+    }
+
+    #[test]
+    fn test_max_ejected() {
+        assert_eq!(POLICY.max_ejected(1), 0);
+        assert_eq!(POLICY.max_ejected(2), 1);
+        assert_eq!(POLICY.max_ejected(30), 3);
+        let all = OutlierPolicy { max_ejected_percent: 100, ..POLICY };
+        assert_eq!(all.max_ejected(4), 3);
+    }
+}
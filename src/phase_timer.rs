@@ -0,0 +1,115 @@
+use std::fmt;
+use tokio::time::Duration;
+
+/// The stages a proxied request goes through, in order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    /// From the client's first byte to the end of its request headers
+    ClientHeaderRead,
+    /// Reading the rest of the request body after the headers
+    ClientBodyRead,
+    /// Opening a connection to an upstream (zero when one is reused)
+    UpstreamConnect,
+    /// Sending the request upstream, retries included
+    UpstreamWrite,
+    /// From the request being sent to having the upstream's response headers
+    UpstreamFirstByte,
+    /// Reading the response body after the headers
+    UpstreamBodyRead,
+    /// Sending the response to the client
+    ClientWrite,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 7] = [
+        Phase::ClientHeaderRead,
+        Phase::ClientBodyRead,
+        Phase::UpstreamConnect,
+        Phase::UpstreamWrite,
+        Phase::UpstreamFirstByte,
+        Phase::UpstreamBodyRead,
+        Phase::ClientWrite,
+    ];
+
+    /// Position in `ALL`.
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Name used in the access log and as the metrics label.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Phase::ClientHeaderRead => "client_header_read",
+            Phase::ClientBodyRead => "client_body_read",
+            Phase::UpstreamConnect => "upstream_connect",
+            Phase::UpstreamWrite => "upstream_write",
+            Phase::UpstreamFirstByte => "upstream_first_byte",
+            Phase::UpstreamBodyRead => "upstream_body_read",
+            Phase::ClientWrite => "client_write",
+        }
+    }
+}
+
+/// How long one request spent in each phase. A timer for a request nobody will look at (no access
+/// log line, no metrics) is disabled, and recording into it does nothing.
+#[derive(Debug)]
+pub struct PhaseTimer {
+    durations: Option<[Option<Duration>; Phase::ALL.len()]>,
+}
+
+impl PhaseTimer {
+    pub fn new(enabled: bool) -> PhaseTimer {
+        PhaseTimer {
+            durations: if enabled {
+                Some([None; Phase::ALL.len()])
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn record(&mut self, phase: Phase, duration: Duration) {
+        if let Some(durations) = &mut self.durations {
+            durations[phase.index()] = Some(duration);
+        }
+    }
+
+    /// The phases recorded so far, with how long each took.
+    pub fn recorded(&self) -> impl Iterator<Item = (Phase, Duration)> + '_ {
+        let durations = self.durations.iter().flatten();
+        Phase::ALL
+            .iter()
+            .zip(durations)
+            .filter_map(|(phase, duration)| duration.map(|duration| (*phase, duration)))
+    }
+}
+
+/// Formats the recorded phases as access log fields, e.g. "client_header_read_ms=0.012
+/// upstream_connect_ms=1.250".
+impl fmt::Display for PhaseTimer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (phase, duration)) in self.recorded().enumerate() {
+            let separator = if i == 0 { "" } else { " " };
+            write!(f, "{}{}_ms={:.3}", separator, phase.as_str(), duration.as_secs_f64() * 1000.0)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phase_timer() {
+        let mut timer = PhaseTimer::new(true);
+        timer.record(Phase::UpstreamWrite, Duration::from_micros(1500));
+        timer.record(Phase::ClientHeaderRead, Duration::from_millis(2));
+        assert_eq!(timer.to_string(), "client_header_read_ms=2.000 upstream_write_ms=1.500");
+
+        let mut disabled = PhaseTimer::new(false);
+        disabled.record(Phase::UpstreamWrite, Duration::from_millis(1));
+        assert_eq!(disabled.recorded().count(), 0);
+        assert_eq!(disabled.to_string(), "");
+    }
+}
@@ -0,0 +1,77 @@
+use std::str::FromStr;
+
+/// A `--upstream-priority` option: requests go to the highest-priority upstreams that are alive.
+/// Upstreams without one have priority 0.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamPriority {
+    pub address: String,
+    pub priority: u8,
+}
+
+impl FromStr for UpstreamPriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, priority) = s
+            .rsplit_once('=')
+            .ok_or_else(|| "expected <address>=<priority>".to_string())?;
+        let priority = priority
+            .trim()
+            .parse()
+            .map_err(|_| format!("priority \"{}\" is not a number from 0 to 255", priority))?;
+        Ok(UpstreamPriority {
+            address: crate::config::normalize_address(address),
+            priority,
+        })
+    }
+}
+
+/// The priority `--upstream-priority` gives an upstream, or 0 if none does.
+pub fn of(address: &str, priorities: &[UpstreamPriority]) -> u8 {
+    priorities
+        .iter()
+        .rev()
+        .find(|priority| priority.address == address)
+        .map_or(0, |priority| priority.priority)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "10.0.0.1:80=5".parse::<UpstreamPriority>(),
+            Ok(UpstreamPriority {
+                address: "10.0.0.1:80".to_string(),
+                priority: 5
+            })
+        );
+        // Matched against upstream addresses, which are lowercased
+        assert_eq!(
+            "Backend:80=1".parse::<UpstreamPriority>().map(|priority| priority.address),
+            Ok("backend:80".to_string())
+        );
+        assert!("10.0.0.1:80".parse::<UpstreamPriority>().is_err());
+        assert!("10.0.0.1:80=256".parse::<UpstreamPriority>().is_err());
+        assert!("10.0.0.1:80=high".parse::<UpstreamPriority>().is_err());
+    }
+
+    #[test]
+    fn test_priority_of() {
+        let priorities = vec![
+            "a:1=2".parse().unwrap(),
+            "c:1=2".parse().unwrap(),
+            "d:1=1".parse().unwrap(),
+            // Unknown addresses are ignored, and later options win
+            "e:1=9".parse().unwrap(),
+            "d:1=3".parse().unwrap(),
+        ];
+        let found: Vec<u8> = ["a:1", "b:1", "c:1", "d:1"]
+            .iter()
+            .map(|address| of(address, &priorities))
+            .collect();
+        assert_eq!(found, vec![2, 0, 2, 3]);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{metrics, request, response, ProxyState};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time::{delay_for, timeout, Duration};
+
+/// Longest we wait between pushes while the Pushgateway is failing
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// How long a single push may take
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where to push metrics, parsed from `--pushgateway-url http://host:port[/prefix]`. Only plain
+/// HTTP is supported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PushgatewayUrl {
+    authority: String,
+    /// Full path metrics are POSTed to
+    path: String,
+}
+
+impl FromStr for PushgatewayUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let uri: http::Uri = s.parse().map_err(|err| format!("invalid URL: {}", err))?;
+        if uri.scheme_str() != Some("http") {
+            return Err("only http:// Pushgateway URLs are supported".to_string());
+        }
+        let authority = uri.authority().ok_or("URL has no host")?;
+        let authority = if authority.port().is_some() {
+            authority.to_string()
+        } else {
+            format!("{}:80", authority)
+        };
+        Ok(PushgatewayUrl {
+            authority,
+            path: format!("{}/metrics/job/balancebeam", uri.path().trim_end_matches('/')),
+        })
+    }
+}
+
+/// Delay before the next push, doubling with each consecutive failure.
+fn next_delay(interval: Duration, failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.min(16));
+    interval
+        .checked_mul(factor)
+        .map(|delay| delay.min(MAX_BACKOFF.max(interval)))
+        .unwrap_or(MAX_BACKOFF)
+}
+
+/// Pushes the current metrics to the Pushgateway every `interval`, backing off while pushes fail.
+pub async fn run(state: Arc<ProxyState>, url: PushgatewayUrl, interval: Duration) {
+    let mut failures = 0;
+    loop {
+        delay_for(next_delay(interval, failures)).await;
+        let body = metrics::render(&state).await.into_bytes();
+        match timeout(PUSH_TIMEOUT, push(&url, body)).await {
+            Ok(Ok(())) => {
+                if failures > 0 {
+                    log::info!("Pushing metrics to {} succeeded again", url.authority);
+                }
+                failures = 0;
+            }
+            Ok(Err(err)) => {
+                failures += 1;
+                log::warn!("Failed to push metrics to {}: {}", url.authority, err);
+            }
+            Err(_) => {
+                failures += 1;
+                log::warn!("Pushing metrics to {} timed out", url.authority);
+            }
+        }
+    }
+}
+
+async fn push(url: &PushgatewayUrl, body: Vec<u8>) -> Result<(), String> {
+    let mut stream = TcpStream::connect(&url.authority)
+        .await
+        .map_err(|err| err.to_string())?;
+    let push_request = http::Request::builder()
+        .method(http::Method::POST)
+        .uri(url.path.as_str())
+        .header("Host", url.authority.as_str())
+        .header("Content-Type", metrics::CONTENT_TYPE)
+        .header("Content-Length", body.len().to_string())
+        .header("Connection", "close")
+        .body(body)
+        .unwrap();
+    request::write_to_stream(&push_request, &mut stream)
+        .await
+        .map_err(|err| err.to_string())?;
+    let push_response = response::read_from_stream(
+        &mut stream,
+        &http::Method::POST,
+        &response::HeaderLimits::default(),
+    )
+    .await
+    .map_err(|err| err.to_string())?;
+    if push_response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("got {}", response::format_response_line(&push_response)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        let url: PushgatewayUrl = "http://push.example.com:9091".parse().unwrap();
+        assert_eq!(url.authority, "push.example.com:9091");
+        assert_eq!(url.path, "/metrics/job/balancebeam");
+        let url: PushgatewayUrl = "http://push.example.com/prefix/".parse().unwrap();
+        assert_eq!(url.authority, "push.example.com:80");
+        assert_eq!(url.path, "/prefix/metrics/job/balancebeam");
+        assert!("https://push.example.com".parse::<PushgatewayUrl>().is_err());
+        assert!("push.example.com:9091".parse::<PushgatewayUrl>().is_err());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let interval = Duration::from_secs(15);
+        assert_eq!(next_delay(interval, 0), interval);
+        assert_eq!(next_delay(interval, 1), Duration::from_secs(30));
+        assert_eq!(next_delay(interval, 3), Duration::from_secs(120));
+        assert_eq!(next_delay(interval, 5), MAX_BACKOFF);
+        assert_eq!(next_delay(interval, 100), MAX_BACKOFF);
+        // An interval longer than the cap is never shortened
+        let interval = Duration::from_secs(600);
+        assert_eq!(next_delay(interval, 2), interval);
+    }
+}
@@ -1,29 +1,31 @@
 use std::collections::HashMap;
-use std::net::IpAddr;
+use async_trait::async_trait;
+use tokio::sync::Mutex;
 use super::RateLimiterAlgorithm;
 
+#[derive(Default)]
 pub struct FixedWindow {
-    limit: usize,
-    requests: HashMap<IpAddr, usize>,
-}
-
-impl FixedWindow {
-    pub fn new(limit: usize) -> Self {
-        FixedWindow {
-            limit: limit,
-            requests: HashMap::new(),
-        }
-    }
+    requests: Mutex<HashMap<String, usize>>,
 }
 
+#[async_trait]
 impl RateLimiterAlgorithm for FixedWindow {
-    fn register_request(&mut self, addr: IpAddr) -> bool {
-        let count = self.requests.entry(addr).or_insert(0);
+    async fn check_and_increment(&self, key: &str, limit: usize) -> bool {
+        let mut requests = self.requests.lock().await;
+        let count = requests.entry(key.to_string()).or_insert(0);
         *count += 1;
-        *count <= self.limit
+        *count <= limit
+    }
+
+    async fn refresh(&self) {
+        self.requests.lock().await.clear();
+    }
+
+    async fn tracked_clients(&self) -> usize {
+        self.requests.lock().await.len()
     }
 
-    fn refresh(&mut self) {
-        self.requests.clear();
+    fn name(&self) -> &'static str {
+        "fixed-window"
     }
-}
\ No newline at end of file
+}
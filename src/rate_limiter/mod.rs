@@ -1,14 +1,79 @@
+use crate::access::Cidr;
+use async_trait::async_trait;
 use std::net::IpAddr;
 
 pub mod fixed_window;
+pub mod offenders;
+pub mod redis;
+pub mod schedule;
 
-#[derive(clap::ArgEnum, Debug, Clone)]
+/// What happens to requests over the rate limit.
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitMode {
+    /// Reject them with 429
+    Enforce,
+    /// Forward them anyway, but log and count them, to see who enforcing would affect
+    Observe,
+}
+
+impl RateLimitMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RateLimitMode::Enforce => "enforce",
+            RateLimitMode::Observe => "observe",
+        }
+    }
+}
+
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq)]
 pub enum ArgRateLimiter {
-    FixedWindow
+    FixedWindow,
+    /// Fixed windows counted in Redis, shared by every balancebeam using the same server
+    Redis,
+}
+
+/// The key a client's requests are counted under. IPv6 clients are counted by the block of
+/// `ipv6_prefix_len` bits their address is in, since anyone given a /64 can use as many of its
+/// addresses as they like. IPv4 clients (including IPv4-mapped IPv6 ones) are counted one by one.
+pub fn client_key(ip: IpAddr, ipv6_prefix_len: u8) -> String {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) if ipv6_prefix_len < 128 => {
+            Cidr::covering(IpAddr::V6(ip), ipv6_prefix_len).to_string()
+        }
+        ip => ip.to_string(),
+    }
 }
 
+/// A rate limiting algorithm. Methods take `&self` so that implementations can be backed by async
+/// stores; each implementation does its own synchronization.
+#[async_trait]
 pub trait RateLimiterAlgorithm: Send + Sync {
-    fn register_request(&mut self, addr: IpAddr) -> bool;
+    /// Counts a request from `key` (usually the client's IP address), returning false if the
+    /// request is over `limit`. The limit is passed on every call rather than fixed at construction
+    /// so that a rate limit schedule can change it while the counts carry on.
+    async fn check_and_increment(&self, key: &str, limit: usize) -> bool;
 
-    fn refresh(&mut self);
-}
\ No newline at end of file
+    async fn refresh(&self);
+
+    /// Number of clients the limiter is currently keeping state for.
+    async fn tracked_clients(&self) -> usize;
+
+    /// The algorithm's name, as given to --rate-limiter.
+    fn name(&self) -> &'static str;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_key() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(client_key(ip("192.0.2.1"), 64), "192.0.2.1");
+        assert_eq!(client_key(ip("::ffff:192.0.2.1"), 64), "192.0.2.1");
+        assert_eq!(client_key(ip("2001:db8::1"), 64), "2001:db8::/64");
+        assert_eq!(client_key(ip("2001:db8::1:0:0:2"), 64), "2001:db8::/64");
+        assert_eq!(client_key(ip("2001:db8:0:1::1"), 64), "2001:db8:0:1::/64");
+        assert_eq!(client_key(ip("2001:db8::1"), 128), "2001:db8::1");
+    }
+}
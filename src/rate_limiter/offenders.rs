@@ -0,0 +1,120 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How far back offenders are remembered, in minutes
+const WINDOW_MINUTES: u64 = 60;
+
+/// Counts requests over the rate limit per client over the last hour, whether they were rejected
+/// or (in observe mode) only would have been, so that the worst offenders can be reported.
+#[derive(Debug, Default)]
+pub struct Offenders {
+    state: Mutex<OffendersState>,
+}
+
+#[derive(Debug, Default)]
+struct OffendersState {
+    /// Per client, (minute, count) for each minute with requests over the limit, oldest first
+    clients: HashMap<String, VecDeque<(u64, u64)>>,
+    /// Minute in which clients were last pruned
+    pruned_at: u64,
+}
+
+impl Offenders {
+    /// Counts a request from `key` that is over the limit. Returns how many there have been from
+    /// `key` this minute, including this one.
+    pub fn record(&self, key: &str) -> u64 {
+        self.record_at(key, current_minute())
+    }
+
+    /// The `n` clients with the most requests over the limit in the last hour, worst first.
+    pub fn top(&self, n: usize) -> Vec<(String, u64)> {
+        self.top_at(n, current_minute())
+    }
+
+    fn record_at(&self, key: &str, now: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        // Once a minute, forget clients that have behaved for an hour so that the map doesn't
+        // grow forever
+        if state.pruned_at != now {
+            state.clients.retain(|_, minutes| {
+                let latest = minutes.back().map_or(0, |(minute, _)| *minute);
+                now.saturating_sub(latest) < WINDOW_MINUTES
+            });
+            state.pruned_at = now;
+        }
+        let minutes = state.clients.entry(key.to_string()).or_default();
+        match minutes.back_mut() {
+            Some((minute, count)) if *minute == now => *count += 1,
+            _ => minutes.push_back((now, 1)),
+        }
+        while let Some((minute, _)) = minutes.front() {
+            if now.saturating_sub(*minute) < WINDOW_MINUTES {
+                break;
+            }
+            minutes.pop_front();
+        }
+        minutes.back().map_or(0, |(_, count)| *count)
+    }
+
+    fn top_at(&self, n: usize, now: u64) -> Vec<(String, u64)> {
+        let state = self.state.lock().unwrap();
+        let mut totals: Vec<(String, u64)> = state
+            .clients
+            .iter()
+            .map(|(key, minutes)| {
+                let total = minutes
+                    .iter()
+                    .filter(|(minute, _)| now.saturating_sub(*minute) < WINDOW_MINUTES)
+                    .map(|(_, count)| count)
+                    .sum();
+                (key.clone(), total)
+            })
+            .filter(|(_, total)| *total > 0)
+            .collect();
+        totals.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        totals.truncate(n);
+        totals
+    }
+}
+
+fn current_minute() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 60
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_top_offenders() {
+        let offenders = Offenders::default();
+        for _ in 0..3 {
+            offenders.record_at("10.0.0.1", 1000);
+        }
+        assert_eq!(offenders.record_at("10.0.0.1", 1001), 1);
+        assert_eq!(offenders.record_at("10.0.0.1", 1001), 2);
+        offenders.record_at("10.0.0.2", 1001);
+        offenders.record_at("10.0.0.3", 1001);
+        assert_eq!(
+            offenders.top_at(2, 1001),
+            vec![("10.0.0.1".to_string(), 5), ("10.0.0.2".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_offenders_are_forgotten_after_an_hour() {
+        let offenders = Offenders::default();
+        offenders.record_at("10.0.0.1", 1000);
+        offenders.record_at("10.0.0.1", 1030);
+        assert_eq!(offenders.top_at(10, 1059), vec![("10.0.0.1".to_string(), 2)]);
+        assert_eq!(offenders.top_at(10, 1060), vec![("10.0.0.1".to_string(), 1)]);
+        assert_eq!(offenders.top_at(10, 1090), vec![]);
+        offenders.record_at("10.0.0.2", 1090);
+        assert_eq!(offenders.state.lock().unwrap().clients.len(), 1);
+    }
+}
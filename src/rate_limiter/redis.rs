@@ -0,0 +1,372 @@
+use std::str::FromStr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::{timeout, Duration};
+use super::fixed_window::FixedWindow;
+use super::RateLimiterAlgorithm;
+
+/// Length of a rate limiting window, matching how often `refresh` is called
+const WINDOW_SECS: u64 = 60;
+/// How long a single Redis command (or connection attempt) may take before we give up on it
+const REDIS_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long to wait before reconnecting after the first failed attempt; doubles on each failure
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(250);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(10);
+/// Increments the counter and makes sure it expires, in one atomic step
+const INCREMENT_SCRIPT: &str = "local count = redis.call('INCR', KEYS[1]) \
+    if count == 1 then redis.call('EXPIRE', KEYS[1], ARGV[1]) end \
+    return count";
+
+/// Where the shared rate limiting counters live, parsed from
+/// `--redis-url redis://[:password@]host[:port][/db]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedisUrl {
+    address: String,
+    password: Option<String>,
+    db: u32,
+}
+
+impl FromStr for RedisUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("redis://")
+            .ok_or("only redis:// URLs are supported")?;
+        let (authority, db) = match rest.split_once('/') {
+            Some((authority, "")) => (authority, 0),
+            Some((authority, db)) => (
+                authority,
+                db.parse()
+                    .map_err(|_| format!("\"{}\" is not a database number", db))?,
+            ),
+            None => (rest, 0),
+        };
+        let (password, host) = match authority.rsplit_once('@') {
+            Some((userinfo, host)) => {
+                // Redis 6 ACLs also take a user name, but the default user is all we support
+                let password = userinfo
+                    .split_once(':')
+                    .map_or(userinfo, |(_, password)| password);
+                (Some(password.to_string()), host)
+            }
+            None => (None, authority),
+        };
+        if host.is_empty() {
+            return Err("URL has no host".to_string());
+        }
+        let has_port = host
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+        let address = if has_port {
+            host.to_string()
+        } else {
+            format!("{}:6379", host)
+        };
+        Ok(RedisUrl { address, password, db })
+    }
+}
+
+/// A reply from Redis, as far as we need to understand them.
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+}
+
+/// Serializes a command in the Redis protocol (an array of bulk strings).
+fn encode_command(args: &[&[u8]]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+async fn read_reply(stream: &mut BufReader<TcpStream>) -> Result<Reply, String> {
+    let mut line = String::new();
+    stream
+        .read_line(&mut line)
+        .await
+        .map_err(|err| err.to_string())?;
+    let line = line
+        .strip_suffix("\r\n")
+        .ok_or("Redis closed the connection")?;
+    let (kind, value) = line.split_at(line.len().min(1));
+    match kind {
+        "+" => Ok(Reply::Status(value.to_string())),
+        "-" => Err(format!("Redis error: {}", value)),
+        ":" => value
+            .parse()
+            .map(Reply::Integer)
+            .map_err(|_| format!("bad integer reply \"{}\"", value)),
+        "$" => {
+            let len: i64 = value
+                .parse()
+                .map_err(|_| format!("bad bulk string length \"{}\"", value))?;
+            if len < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            let mut data = vec![0_u8; len as usize + 2];
+            stream
+                .read_exact(&mut data)
+                .await
+                .map_err(|err| err.to_string())?;
+            data.truncate(len as usize);
+            Ok(Reply::Bulk(Some(data)))
+        }
+        _ => Err(format!("unexpected reply \"{}\"", line)),
+    }
+}
+
+/// Rate limiter whose counters are kept in Redis, so every balancebeam instance using the same
+/// Redis enforces one shared limit. Windows are aligned to wall-clock minutes so that instances
+/// agree on when they start.
+pub struct RedisRateLimiter {
+    url: RedisUrl,
+    connection: Mutex<Option<BufReader<TcpStream>>>,
+    /// When the next connection attempt may be made, and how long to wait after it if it fails.
+    /// Kept outside the connection lock so requests can skip Redis without queueing for it.
+    reconnect: parking_lot::Mutex<(Option<Instant>, Duration)>,
+    /// Used while Redis is unreachable, if enabled; otherwise requests are let through
+    fallback: Option<FixedWindow>,
+}
+
+impl RedisRateLimiter {
+    pub fn new(url: RedisUrl, fallback_local: bool) -> Self {
+        RedisRateLimiter {
+            url,
+            connection: Mutex::new(None),
+            reconnect: parking_lot::Mutex::new((None, RECONNECT_BACKOFF_MIN)),
+            fallback: if fallback_local { Some(FixedWindow::default()) } else { None },
+        }
+    }
+
+    async fn connect(&self) -> Result<BufReader<TcpStream>, String> {
+        let stream = timeout(REDIS_TIMEOUT, TcpStream::connect(&self.url.address))
+            .await
+            .map_err(|_| "timed out connecting".to_string())?
+            .map_err(|err| err.to_string())?;
+        let mut stream = BufReader::new(stream);
+        if let Some(password) = &self.url.password {
+            command(&mut stream, &[b"AUTH", password.as_bytes()]).await?;
+        }
+        if self.url.db != 0 {
+            command(&mut stream, &[b"SELECT", self.url.db.to_string().as_bytes()]).await?;
+        }
+        Ok(stream)
+    }
+
+    /// Returns true while a failed connection attempt's backoff hasn't run out.
+    fn backing_off(&self) -> bool {
+        match self.reconnect.lock().0 {
+            Some(retry_at) => Instant::now() < retry_at,
+            None => false,
+        }
+    }
+
+    /// Increments the counter for `key` in the current window, returning the new count.
+    async fn increment(&self, key: &str) -> Result<i64, String> {
+        let window = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / WINDOW_SECS;
+        let redis_key = format!("balancebeam:ratelimit:{}:{}", key, window);
+        let expiry = WINDOW_SECS.to_string();
+
+        if self.backing_off() {
+            return Err("not reconnecting yet".to_string());
+        }
+        let mut connection = self.connection.lock().await;
+        if connection.is_none() {
+            // Someone else may have failed to connect while we waited for the lock
+            if self.backing_off() {
+                return Err("not reconnecting yet".to_string());
+            }
+            match self.connect().await {
+                Ok(stream) => {
+                    *self.reconnect.lock() = (None, RECONNECT_BACKOFF_MIN);
+                    *connection = Some(stream);
+                }
+                Err(err) => {
+                    let mut reconnect = self.reconnect.lock();
+                    let delay = reconnect.1;
+                    *reconnect =
+                        (Some(Instant::now() + delay), (delay * 2).min(RECONNECT_BACKOFF_MAX));
+                    return Err(err);
+                }
+            }
+        }
+        let args: [&[u8]; 5] = [
+            b"EVAL",
+            INCREMENT_SCRIPT.as_bytes(),
+            b"1",
+            redis_key.as_bytes(),
+            expiry.as_bytes(),
+        ];
+        let result = command(connection.as_mut().unwrap(), &args).await;
+        if result.is_err() {
+            // The connection may be in an unknown state; start afresh next time
+            *connection = None;
+        }
+        match result? {
+            Reply::Integer(count) => Ok(count),
+            reply => Err(format!("unexpected reply {:?}", reply)),
+        }
+    }
+}
+
+/// Sends a command and reads its reply, giving up after REDIS_TIMEOUT.
+async fn command(stream: &mut BufReader<TcpStream>, args: &[&[u8]]) -> Result<Reply, String> {
+    let exchange = async {
+        stream
+            .get_mut()
+            .write_all(&encode_command(args))
+            .await
+            .map_err(|err| err.to_string())?;
+        read_reply(stream).await
+    };
+    timeout(REDIS_TIMEOUT, exchange)
+        .await
+        .map_err(|_| "timed out".to_string())?
+}
+
+#[async_trait]
+impl RateLimiterAlgorithm for RedisRateLimiter {
+    async fn check_and_increment(&self, key: &str, limit: usize) -> bool {
+        match self.increment(key).await {
+            Ok(count) => count <= limit as i64,
+            Err(err) => {
+                log::warn!("Redis rate limiter at {} failed: {}", self.url.address, err);
+                match &self.fallback {
+                    Some(fallback) => fallback.check_and_increment(key, limit).await,
+                    None => true,
+                }
+            }
+        }
+    }
+
+    /// Redis counters expire on their own; only the local fallback needs resetting.
+    async fn refresh(&self) {
+        if let Some(fallback) = &self.fallback {
+            fallback.refresh().await;
+        }
+    }
+
+    /// Only the clients in the local fallback are known; the Redis counters aren't counted.
+    async fn tracked_clients(&self) -> usize {
+        match &self.fallback {
+            Some(fallback) => fallback.tracked_clients().await,
+            None => 0,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            "redis://localhost".parse(),
+            Ok(RedisUrl { address: "localhost:6379".to_string(), password: None, db: 0 })
+        );
+        assert_eq!(
+            "redis://:hunter2@10.0.0.1:6380/3".parse(),
+            Ok(RedisUrl {
+                address: "10.0.0.1:6380".to_string(),
+                password: Some("hunter2".to_string()),
+                db: 3
+            })
+        );
+        assert!("http://localhost".parse::<RedisUrl>().is_err());
+        assert!("redis:///1".parse::<RedisUrl>().is_err());
+        assert!("redis://localhost/db".parse::<RedisUrl>().is_err());
+    }
+
+    #[test]
+    fn test_encode_command() {
+        assert_eq!(
+            encode_command(&[b"SELECT", b"3"]),
+            b"*2\r\n$6\r\nSELECT\r\n$1\r\n3\r\n".to_vec()
+        );
+    }
+
+    /// Starts a fake Redis that answers every command with an incrementing integer.
+    async fn fake_redis() -> String {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0_u8; 1024];
+            let mut count = 0;
+            while let Ok(n) = stream.read(&mut buffer).await {
+                if n == 0 {
+                    break;
+                }
+                count += 1;
+                let _ = stream.write_all(format!(":{}\r\n", count).as_bytes()).await;
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn test_limit_enforced_from_redis_counts() {
+        let url = format!("redis://{}", fake_redis().await).parse().unwrap();
+        let limiter = RedisRateLimiter::new(url, true);
+        assert!(limiter.check_and_increment("10.0.0.1", 2).await);
+        assert!(limiter.check_and_increment("10.0.0.1", 2).await);
+        assert!(!limiter.check_and_increment("10.0.0.1", 2).await);
+        // Nothing went through the fallback
+        assert_eq!(limiter.tracked_clients().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_when_redis_is_down() {
+        // Nothing listens on a port we just released
+        let address = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let url: RedisUrl = format!("redis://{}", address).parse().unwrap();
+
+        let local = RedisRateLimiter::new(url.clone(), true);
+        assert!(local.check_and_increment("10.0.0.1", 1).await);
+        assert!(!local.check_and_increment("10.0.0.1", 1).await);
+        assert_eq!(local.tracked_clients().await, 1);
+
+        let fail_open = RedisRateLimiter::new(url, false);
+        assert!(fail_open.check_and_increment("10.0.0.1", 1).await);
+        assert!(fail_open.check_and_increment("10.0.0.1", 1).await);
+    }
+
+    #[tokio::test]
+    async fn test_backs_off_reconnecting() {
+        let address = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let url: RedisUrl = format!("redis://{}", address).parse().unwrap();
+        let limiter = RedisRateLimiter::new(url, true);
+        assert!(limiter.increment("10.0.0.1").await.is_err());
+        // Redis comes back, but we don't try it again until the backoff is over
+        let mut listener = TcpListener::bind(address).await.unwrap();
+        assert_eq!(limiter.increment("10.0.0.1").await, Err("not reconnecting yet".to_string()));
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0_u8; 1024];
+            let _ = stream.read(&mut buffer).await;
+            let _ = stream.write_all(b":1\r\n").await;
+        });
+        tokio::time::delay_for(RECONNECT_BACKOFF_MIN).await;
+        assert_eq!(limiter.increment("10.0.0.1").await, Ok(1));
+    }
+}
@@ -0,0 +1,255 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECS_PER_DAY: u32 = 24 * 60 * 60;
+
+/// A daily window in which the rate limit is replaced, parsed from
+/// `--rate-limit-schedule "name=nightly-batch,start=02:00,end=04:00,limit=6000"`. Times are UTC,
+/// as HH:MM or HH:MM:SS, and `duration=` ("2h", "90m", "30s") can be given instead of `end=`. A
+/// window that ends before it starts runs over midnight.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledLimit {
+    /// Shown in the admin API and the log; defaults to the window's times
+    pub name: String,
+    /// Second of the UTC day at which the window opens
+    start: u32,
+    /// Length of the window in seconds, at most a day
+    length: u32,
+    /// Requests per IP per minute while the window is open
+    pub limit: usize,
+}
+
+impl ScheduledLimit {
+    fn contains(&self, second_of_day: u32) -> bool {
+        (second_of_day + SECS_PER_DAY - self.start) % SECS_PER_DAY < self.length
+    }
+}
+
+impl FromStr for ScheduledLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut name = None;
+        let mut start = None;
+        let mut end = None;
+        let mut length = None;
+        let mut limit = None;
+        for part in s.split(',') {
+            match part.trim().split_once('=') {
+                Some(("name", value)) => name = Some(value.trim().to_string()),
+                Some(("start", value)) => start = Some(parse_time_of_day(value.trim())?),
+                Some(("end", value)) => end = Some(parse_time_of_day(value.trim())?),
+                Some(("duration", value)) => length = Some(parse_duration(value.trim())?),
+                Some(("limit", value)) => {
+                    limit = Some(
+                        value
+                            .trim()
+                            .parse::<usize>()
+                            .map_err(|_| format!("\"{}\" is not a request count", value))?,
+                    )
+                }
+                Some(("cron", _)) => {
+                    return Err("cron= isn't supported; give the window as start= and end= (or \
+                                duration=)"
+                        .to_string())
+                }
+                _ => return Err(format!("unrecognized schedule component \"{}\"", part)),
+            }
+        }
+        let start = start.ok_or("a scheduled limit needs start=")?;
+        let length = match (end, length) {
+            (Some(end), None) if end != start => (end + SECS_PER_DAY - start) % SECS_PER_DAY,
+            (Some(_), None) => return Err("the window starts and ends at the same time".into()),
+            (None, Some(length)) => length,
+            (Some(_), Some(_)) => return Err("give either end= or duration=, not both".into()),
+            (None, None) => return Err("a scheduled limit needs end= or duration=".into()),
+        };
+        let limit = match limit {
+            Some(limit) if limit > 0 => limit,
+            _ => return Err("a scheduled limit needs a limit= of at least 1".to_string()),
+        };
+        let name = name.unwrap_or_else(|| {
+            format!(
+                "{}-{}",
+                format_time_of_day(start),
+                format_time_of_day((start + length) % SECS_PER_DAY)
+            )
+        });
+        Ok(ScheduledLimit {
+            name,
+            start,
+            length,
+            limit,
+        })
+    }
+}
+
+/// Parses "HH:MM" or "HH:MM:SS" into a second of the day.
+fn parse_time_of_day(value: &str) -> Result<u32, String> {
+    let invalid = || format!("\"{}\" is not a time of day (HH:MM or HH:MM:SS)", value);
+    let parts = value
+        .split(':')
+        .map(|part| part.parse::<u32>().map_err(|_| invalid()))
+        .collect::<Result<Vec<u32>, String>>()?;
+    match parts[..] {
+        [hours, minutes] if hours < 24 && minutes < 60 => Ok(hours * 3600 + minutes * 60),
+        [hours, minutes, seconds] if hours < 24 && minutes < 60 && seconds < 60 => {
+            Ok(hours * 3600 + minutes * 60 + seconds)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses a duration such as "2h", "90m" or "30s", of at most a day.
+fn parse_duration(value: &str) -> Result<u32, String> {
+    let invalid = || format!("\"{}\" is not a duration such as 2h, 90m or 30s", value);
+    let (number, unit) = value.split_at(value.len().saturating_sub(1));
+    let multiplier = match unit {
+        "h" => 3600,
+        "m" => 60,
+        "s" => 1,
+        _ => return Err(invalid()),
+    };
+    let seconds = number
+        .parse::<u32>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(invalid)?;
+    if seconds == 0 || seconds > SECS_PER_DAY {
+        return Err("a scheduled window must last between 1 second and 24 hours".to_string());
+    }
+    Ok(seconds)
+}
+
+fn format_time_of_day(second_of_day: u32) -> String {
+    format!("{:02}:{:02}", second_of_day / 3600, second_of_day % 3600 / 60)
+}
+
+/// The rate limit profiles from `--rate-limit-schedule`, and which of them is in force. A timer
+/// task calls `update` every second; requests only read the result, so switching profiles is a
+/// single atomic store.
+#[derive(Debug, Default)]
+pub struct Schedule {
+    windows: Vec<ScheduledLimit>,
+    /// Index into `windows` of the window in force, plus one, or 0 outside all of them
+    active: AtomicUsize,
+}
+
+impl Schedule {
+    pub fn new(windows: Vec<ScheduledLimit>) -> Schedule {
+        Schedule {
+            windows,
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    /// The window in force, if any.
+    pub fn active(&self) -> Option<&ScheduledLimit> {
+        let active = self.active.load(Ordering::Relaxed);
+        active.checked_sub(1).map(|index| &self.windows[index])
+    }
+
+    /// The limit to enforce: the active window's, or `base` when no window is open.
+    pub fn effective_limit(&self, base: usize) -> usize {
+        self.active().map_or(base, |window| window.limit)
+    }
+
+    /// Works out which window is in force now, logging when that changes.
+    pub fn update(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.update_at((now % SECS_PER_DAY as u64) as u32);
+    }
+
+    /// Puts in force the window open at `second_of_day` with the highest limit (the first such, if
+    /// several tie), or none. Returns whether that changed anything.
+    fn update_at(&self, second_of_day: u32) -> bool {
+        // max_by_key picks the last of equal maxima, so search from the end to get the first
+        let active = self
+            .windows
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, window)| window.contains(second_of_day))
+            .max_by_key(|(_, window)| window.limit)
+            .map_or(0, |(index, _)| index + 1);
+        let previous = self.active.swap(active, Ordering::Relaxed);
+        if previous == active {
+            return false;
+        }
+        match self.active() {
+            Some(window) => log::info!(
+                "Rate limit profile {} is now in force ({} requests per minute)",
+                window.name,
+                window.limit
+            ),
+            None => log::info!(
+                "Rate limit profile {} has ended; back to the configured limit",
+                self.windows[previous - 1].name
+            ),
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> u32 {
+        parse_time_of_day(time).unwrap()
+    }
+
+    #[test]
+    fn test_parse_scheduled_limit() {
+        let nightly: ScheduledLimit =
+            "name=nightly,start=02:00,duration=2h,limit=6000".parse().unwrap();
+        assert_eq!(nightly.name, "nightly");
+        assert_eq!((nightly.start, nightly.length, nightly.limit), (at("02:00"), 7200, 6000));
+
+        let unnamed: ScheduledLimit = "start=23:30,end=00:30,limit=10".parse().unwrap();
+        assert_eq!(unnamed.name, "23:30-00:30");
+        assert_eq!(unnamed.length, 3600);
+        assert!(unnamed.contains(at("23:59:59")) && unnamed.contains(at("00:00")));
+        assert!(!unnamed.contains(at("00:30")) && !unnamed.contains(at("23:29:59")));
+
+        for bad in &[
+            "start=02:00,limit=10",
+            "start=02:00,end=02:00,limit=10",
+            "start=02:00,end=03:00,duration=1h,limit=10",
+            "start=24:00,end=01:00,limit=10",
+            "start=02:00,duration=25h,limit=10",
+            "start=02:00,end=03:00,limit=0",
+            "cron=0 2 * * *,duration=2h,limit=10",
+        ] {
+            assert!(bad.parse::<ScheduledLimit>().is_err(), "{} was accepted", bad);
+        }
+    }
+
+    #[test]
+    fn test_overlapping_windows_take_the_max() {
+        let schedule = Schedule::new(vec![
+            "name=low,start=01:00,end=04:00,limit=100".parse().unwrap(),
+            "name=high,start=02:00,end=03:00,limit=500".parse().unwrap(),
+        ]);
+        assert_eq!(schedule.effective_limit(10), 10);
+
+        assert!(schedule.update_at(at("01:30")));
+        assert_eq!(schedule.active().map(|window| window.name.as_str()), Some("low"));
+        assert!(schedule.update_at(at("02:30")));
+        assert_eq!(schedule.effective_limit(10), 500);
+        assert!(!schedule.update_at(at("02:31")));
+        assert!(schedule.update_at(at("03:30")));
+        assert_eq!(schedule.effective_limit(10), 100);
+        assert!(schedule.update_at(at("04:00")));
+        assert_eq!(schedule.active(), None);
+        assert_eq!(schedule.effective_limit(10), 10);
+    }
+}
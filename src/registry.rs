@@ -0,0 +1,235 @@
+use crate::adaptive_weights::UpstreamStats;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::sync::watch;
+
+/// Identifies an upstream within its pool: the slot its entries have in per-upstream vectors,
+/// and a serial number that no other upstream in the pool ever gets. Once an upstream is removed
+/// its slot goes to the next one added, so an id held across an await (by a request, a health
+/// check or a drain task) may no longer name the upstream in that slot. `UpstreamsState` treats
+/// such ids as removed, and `UpstreamRegistry::get` doesn't find them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct UpstreamId {
+    slot: usize,
+    serial: usize,
+}
+
+impl UpstreamId {
+    /// Position of the upstream's entries in per-upstream vectors such as `UpstreamsState`'s.
+    pub fn index(self) -> usize {
+        self.slot
+    }
+}
+
+impl fmt::Display for UpstreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.slot)
+    }
+}
+
+/// An upstream server and the counters kept about it.
+#[derive(Debug)]
+pub struct Upstream {
+    pub id: UpstreamId,
+    pub address: String,
+    /// Requests go to the highest priority with an alive upstream (`--upstream-priority`)
+    pub priority: u8,
+    pub stats: Arc<UpstreamStats>,
+    /// Becomes true when the connections still open to the upstream are to be closed
+    closing: (watch::Sender<bool>, watch::Receiver<bool>),
+}
+
+impl Upstream {
+    /// Tells every request still talking to the upstream to give up on it and close its
+    /// connection.
+    pub fn close_connections(&self) {
+        let _ = self.closing.0.broadcast(true);
+    }
+
+    /// Completes once `close_connections` has been called.
+    pub async fn closed(&self) {
+        let mut closing = self.closing.1.clone();
+        while closing.recv().await == Some(false) {}
+    }
+}
+
+/// The upstreams a pool has now. Upstreams can be added and removed while requests are being
+/// served. Removing one frees its slot for the next upstream added, while the others keep their
+/// ids (and everything keyed by them stays valid).
+#[derive(Debug, Default)]
+pub struct UpstreamRegistry {
+    /// Indexed by slot; None for a slot that is free
+    upstreams: RwLock<Vec<Option<Arc<Upstream>>>>,
+    /// Upstreams added so far, which gives the next one its serial number
+    added: AtomicUsize,
+}
+
+impl UpstreamRegistry {
+    pub fn new(upstreams: impl IntoIterator<Item = (String, u8)>) -> UpstreamRegistry {
+        let registry = UpstreamRegistry::default();
+        for (address, priority) in upstreams {
+            registry.add(address, priority);
+        }
+        registry
+    }
+
+    /// Adds an upstream in the first free slot, or a new one, returning its id. Per-upstream state
+    /// for it must exist before anyone else can use the id, so callers hold the pool's
+    /// `UpstreamsState` lock across this.
+    pub fn add(&self, address: String, priority: u8) -> UpstreamId {
+        let mut upstreams = self.upstreams.write().unwrap();
+        let slot = upstreams.iter().position(Option::is_none).unwrap_or(upstreams.len());
+        let id = UpstreamId {
+            slot,
+            serial: self.added.fetch_add(1, Ordering::Relaxed),
+        };
+        let upstream = Some(Arc::new(Upstream {
+            id,
+            address,
+            priority,
+            stats: Arc::new(UpstreamStats::default()),
+            closing: watch::channel(false),
+        }));
+        if slot == upstreams.len() {
+            upstreams.push(upstream);
+        } else {
+            upstreams[slot] = upstream;
+        }
+        id
+    }
+
+    /// Frees the slot of a removed upstream. Does nothing if the slot has already gone to another.
+    pub fn remove(&self, id: UpstreamId) {
+        let mut upstreams = self.upstreams.write().unwrap();
+        if upstreams[id.slot].as_ref().map(|upstream| upstream.id) == Some(id) {
+            upstreams[id.slot] = None;
+        }
+    }
+
+    /// Looks up an upstream by id. None if it has been removed.
+    pub fn get(&self, id: UpstreamId) -> Option<Arc<Upstream>> {
+        self.upstreams.read().unwrap()[id.slot].clone().filter(|upstream| upstream.id == id)
+    }
+
+    /// The upstream most recently added with `address`, if the pool still has one. An address that
+    /// is being removed and was added again has another id.
+    pub fn find(&self, address: &str) -> Option<UpstreamId> {
+        self.upstreams
+            .read()
+            .unwrap()
+            .iter()
+            .flatten()
+            .filter(|upstream| upstream.address == address)
+            .map(|upstream| upstream.id)
+            .max_by_key(|id| id.serial)
+    }
+
+    /// Every upstream as of now, in slot order. Later changes don't show up in the returned list.
+    pub fn snapshot(&self) -> Vec<Arc<Upstream>> {
+        self.upstreams.read().unwrap().iter().flatten().cloned().collect()
+    }
+
+    /// Number of slots, free ones included: how long per-upstream vectors need to be.
+    pub fn len(&self) -> usize {
+        self.upstreams.read().unwrap().len()
+    }
+
+    /// Groups upstream ids by priority. Iterate the result in reverse to go from the highest
+    /// priority tier to the lowest.
+    pub fn tiers(&self) -> BTreeMap<u8, Vec<UpstreamId>> {
+        let mut tiers: BTreeMap<u8, Vec<UpstreamId>> = BTreeMap::new();
+        for upstream in self.upstreams.read().unwrap().iter().flatten() {
+            tiers.entry(upstream.priority).or_default().push(upstream.id);
+        }
+        tiers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+
+    #[test]
+    fn test_ids_are_stable() {
+        let registry = UpstreamRegistry::new(vec![("a:1".to_string(), 0), ("b:1".to_string(), 2)]);
+        let a = registry.find("a:1").unwrap();
+        let b = registry.find("b:1").unwrap();
+        let c = registry.add("c:1".to_string(), 2);
+        let a_again = registry.add("a:1".to_string(), 1);
+        assert_eq!(registry.get(b).unwrap().address, "b:1");
+        assert_eq!(registry.get(c).unwrap().address, "c:1");
+        // The newest upstream with an address wins
+        assert_eq!(registry.find("a:1"), Some(a_again));
+        assert_eq!(registry.find("d:1"), None);
+        let ids: Vec<UpstreamId> = registry.snapshot().iter().map(|upstream| upstream.id).collect();
+        assert_eq!(ids, vec![a, b, c, a_again]);
+
+        let tiers: Vec<(u8, Vec<UpstreamId>)> = registry.tiers().into_iter().rev().collect();
+        assert_eq!(tiers, vec![(2, vec![b, c]), (1, vec![a_again]), (0, vec![a])]);
+    }
+
+    #[test]
+    fn test_removed_slots_are_reused() {
+        let registry = UpstreamRegistry::new(vec![("a:1".to_string(), 0), ("b:1".to_string(), 0)]);
+        let a = registry.find("a:1").unwrap();
+        let b = registry.find("b:1").unwrap();
+        registry.remove(a);
+        assert!(registry.get(a).is_none());
+        assert_eq!(registry.find("a:1"), None);
+        assert_eq!(registry.snapshot().len(), 1);
+        assert_eq!(registry.len(), 2);
+
+        // The next upstream takes the free slot, but an id held for the removed one doesn't
+        // find it
+        let c = registry.add("c:1".to_string(), 0);
+        assert_eq!((c.index(), registry.len()), (a.index(), 2));
+        assert_ne!(c, a);
+        assert!(registry.get(a).is_none());
+        registry.remove(a);
+        assert_eq!(registry.get(c).unwrap().address, "c:1");
+        assert_eq!(registry.get(b).unwrap().address, "b:1");
+        assert_eq!(registry.add("a:1".to_string(), 0).index(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_closing_connections() {
+        let registry = UpstreamRegistry::new(vec![("a:1".to_string(), 0)]);
+        let upstream = registry.snapshot()[0].clone();
+        let closed = {
+            let upstream = upstream.clone();
+            tokio::spawn(async move { upstream.closed().await })
+        };
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        upstream.close_connections();
+        tokio::time::timeout(Duration::from_secs(1), closed).await.unwrap().unwrap();
+        // Anything that starts waiting afterwards doesn't wait at all
+        tokio::time::timeout(Duration::from_millis(10), upstream.closed()).await.unwrap();
+    }
+
+    #[test]
+    fn test_counters_follow_ids_while_adding() {
+        let registry = Arc::new(UpstreamRegistry::new(vec![("a:1".to_string(), 0)]));
+        let a = registry.find("a:1").unwrap();
+        let adder = {
+            let registry = registry.clone();
+            std::thread::spawn(move || {
+                for i in 0..100 {
+                    registry.add(format!("new-{}:1", i), 0);
+                }
+            })
+        };
+        for _ in 0..1000 {
+            registry.get(a).unwrap().stats.record_response(Duration::from_millis(1));
+        }
+        adder.join().unwrap();
+
+        assert_eq!(registry.len(), 101);
+        assert_eq!(registry.get(a).unwrap().stats.total_requests(), 1000);
+        for upstream in registry.snapshot().iter().skip(1) {
+            assert_eq!(upstream.stats.total_requests(), 0, "{}", upstream.address);
+        }
+    }
+}
@@ -1,28 +1,103 @@
+use crate::buffer_budget::BufferGuard;
 use std::cmp::min;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::time::Instant;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::time::{delay_for, Duration};
 
 const MAX_HEADERS_SIZE: usize = 8000;
+/// Largest request body read_from_stream accepts
 const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
+/// Longest chunk-size line (chunk extensions included) in a chunked body
+const MAX_CHUNK_LINE: usize = 1024;
+/// The first line of the HTTP/2 connection preface
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n";
+
+/// The header fields of a request as the client sent them: in order, with the names' original case.
+/// parse_request keeps them in the request's extensions, so that the fields nothing changed can be
+/// forwarded as they came in. `HeaderMap` alone can't do that: it lowercases names and groups
+/// fields by name, which breaks signatures computed over the exact headers (e.g. AWS SigV4).
+#[derive(Debug, Clone)]
+pub struct RawHeaders(Vec<(String, http::HeaderValue)>);
+
+/// When the request's headers had all been read, kept in its extensions by
+/// read_from_stream_capturing so that header and body reading can be timed apart.
+#[derive(Debug, Clone, Copy)]
+pub struct HeadersReadAt(pub Instant);
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     /// Client hung up before sending a complete request. IncompleteRequest contains the number of
     /// bytes that were successfully read before the client hung up
     IncompleteRequest(usize),
     /// Client sent an invalid HTTP request. httparse::Error contains more details
     MalformedRequest(httparse::Error),
+    /// The request line names an HTTP version other than 1.0 or 1.1 (or none, as in HTTP/0.9)
+    UnsupportedVersion(String),
+    /// Client opened with the HTTP/2 connection preface, as h2c clients with prior knowledge do
+    Http2Preface,
+    /// An HTTP/1.1 request has no Host header
+    MissingHost,
+    /// The request has more than one Host header
+    DuplicateHost,
     /// The Content-Length header is present, but does not contain a valid numeric value
     InvalidContentLength,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
+    /// The request has a Transfer-Encoding other than chunked
+    UnsupportedTransferEncoding,
+    /// The chunked request body is malformed, or the client hung up partway through it
+    InvalidChunkedBody,
+    /// The request body is bigger than the limit it was read with
     RequestBodyTooLarge,
+    /// Buffering the request body would take more memory than `--max-buffered-bytes` allows
+    BufferLimit,
     /// Encountered an I/O error when reading/writing a TcpStream
     ConnectionError(std::io::Error),
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IncompleteRequest(n) => {
+                write!(f, "client closed connection after sending {} bytes", n)
+            }
+            Error::MalformedRequest(err) => write!(f, "malformed request: {}", err),
+            Error::UnsupportedVersion(version) => write!(f, "unsupported HTTP version {}", version),
+            Error::Http2Preface => write!(f, "client sent the HTTP/2 connection preface"),
+            Error::MissingHost => write!(f, "HTTP/1.1 request has no Host header"),
+            Error::DuplicateHost => write!(f, "request has more than one Host header"),
+            Error::InvalidContentLength => write!(f, "Content-Length header is not a valid integer"),
+            Error::ContentLengthMismatch => {
+                write!(f, "request body length doesn't match Content-Length header")
+            }
+            Error::UnsupportedTransferEncoding => {
+                write!(f, "Transfer-Encoding is something other than chunked")
+            }
+            Error::InvalidChunkedBody => {
+                write!(f, "chunked request body is malformed or incomplete")
+            }
+            Error::RequestBodyTooLarge => write!(f, "request body is larger than the limit"),
+            Error::BufferLimit => write!(f, "no room to buffer the request body"),
+            Error::ConnectionError(err) => write!(f, "error talking to client: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::MalformedRequest(err) => Some(err),
+            Error::ConnectionError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 /// Extracts the Content-Length header value from the provided request. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
@@ -33,11 +108,7 @@ fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>,
     if let Some(header_value) = request.headers().get("content-length") {
         // If it exists, parse it as a usize (or return InvalidContentLength if it can't be parsed as such)
         Ok(Some(
-            header_value
-                .to_str()
-                .or(Err(Error::InvalidContentLength))?
-                .parse::<usize>()
-                .or(Err(Error::InvalidContentLength))?,
+            parse_content_length(header_value.as_bytes()).ok_or(Error::InvalidContentLength)?,
         ))
     } else {
         // If it doesn't exist, return None
@@ -45,25 +116,155 @@ fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>,
     }
 }
 
+/// Parses a Content-Length value, which must be nothing but decimal digits. `str::parse` would also
+/// take a leading "+", and a server reading the value differently from us could be handed a body
+/// that isn't the one we framed.
+pub fn parse_content_length(value: &[u8]) -> Option<usize> {
+    if value.is_empty() || !value.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+/// Caps on a comma-separated list header that we append to, such as X-Forwarded-For or Forwarded.
+#[derive(Debug, Clone, Copy)]
+pub struct ListLimits {
+    /// Longest the whole header value may get
+    pub max_bytes: usize,
+    pub max_entries: usize,
+}
+
 /// This function appends to a header value (adding a new header if the header is not already
 /// present). This is used to add the client's IP address to the end of the X-Forwarded-For list,
 /// or to add a new X-Forwarded-For header if one is not already present.
 ///
-/// You won't need to touch this function.
+/// If the result would exceed `limits`, entries are dropped from the left (the oldest, and least
+/// trustworthy, hops) until it fits; the appended value is always kept. Returns the number of
+/// entries dropped.
 pub fn extend_header_value(
     request: &mut http::Request<Vec<u8>>,
     name: &'static str,
     extend_value: &str,
-) {
+    limits: &ListLimits,
+) -> usize {
+    let existing: Vec<&[u8]> = match request.headers().get(name) {
+        Some(existing_value) => split_list(existing_value.as_bytes()),
+        None => Vec::new(),
+    };
+    let mut dropped = 0;
+    let mut kept_len: usize = existing.iter().map(|entry| entry.len() + 2).sum();
+    while dropped < existing.len()
+        && (existing.len() - dropped + 1 > limits.max_entries
+            || kept_len + extend_value.len() > limits.max_bytes)
+    {
+        kept_len -= existing[dropped].len() + 2;
+        dropped += 1;
+    }
+
     let new_value = match request.headers().get(name) {
-        Some(existing_value) => {
+        Some(existing_value) if dropped == 0 => {
             [existing_value.as_bytes(), b", ", extend_value.as_bytes()].concat()
         }
-        None => extend_value.as_bytes().to_owned(),
+        _ => {
+            let mut entries = existing[dropped..].to_vec();
+            entries.push(extend_value.as_bytes());
+            entries.join(&b", "[..])
+        }
     };
     request
         .headers_mut()
         .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
+    dropped
+}
+
+/// Splits a comma-separated header value into its non-empty entries. Commas inside quoted strings
+/// (which Forwarded elements may contain) don't split.
+pub fn split_list(value: &[u8]) -> Vec<&[u8]> {
+    let mut entries = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, byte) in value.iter().enumerate() {
+        match byte {
+            _ if escaped => escaped = false,
+            b'\\' if quoted => escaped = true,
+            b'"' => quoted = !quoted,
+            b',' if !quoted => {
+                entries.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    entries.push(&value[start..]);
+    entries
+        .into_iter()
+        .map(<[u8]>::trim_ascii)
+        .filter(|entry| !entry.is_empty())
+        .collect()
+}
+
+/// Parses a header given on the command line as "Name: value".
+pub fn parse_header(value: &str) -> Result<(http::header::HeaderName, http::HeaderValue), String> {
+    let (name, value) = value
+        .split_once(':')
+        .ok_or_else(|| "expected \"<name>: <value>\"".to_string())?;
+    let value = http::HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("\"{}\" is not a valid header value", value.trim()))?;
+    Ok((parse_header_name(name)?, value))
+}
+
+/// Parses a header name given on the command line.
+pub fn parse_header_name(name: &str) -> Result<http::header::HeaderName, String> {
+    http::header::HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("\"{}\" is not a valid header name", name.trim()))
+}
+
+/// Builds the element we append to a Forwarded header (RFC 7239), e.g.
+/// `for=192.0.2.1;proto=http;host=example.com;by=10.0.0.1`. IPv6 addresses are bracketed and
+/// quoted as the RFC requires.
+pub fn build_forwarded_header(
+    client_ip: IpAddr,
+    proto: &str,
+    host: Option<&str>,
+    by: IpAddr,
+) -> String {
+    let mut element = format!("for={};proto={}", forwarded_node(client_ip), proto);
+    if let Some(host) = host {
+        element.push_str(";host=");
+        element.push_str(&forwarded_value(host));
+    }
+    element.push_str(";by=");
+    element.push_str(&forwarded_node(by));
+    element
+}
+
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
+
+/// Returns `value` as is if it is a token, or as a quoted string otherwise (e.g. a host with a
+/// port, since ':' can't appear in a token).
+fn forwarded_value(value: &str) -> String {
+    let is_tchar = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if !value.is_empty() && value.chars().all(is_tchar) {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Whether a request's or response's Connection header says the connection closes after it.
+pub fn wants_close(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|option| option.trim().eq_ignore_ascii_case("close"))
 }
 
 /// Attempts to parse the data in the supplied buffer as an HTTP request. Returns one of the
@@ -74,26 +275,100 @@ pub fn extend_header_value(
 /// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
 ///
 /// You won't need to touch this function.
+#[allow(clippy::type_complexity)]
 fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut req = httparse::Request::new(&mut headers);
-    let res = req.parse(buffer).or_else(|err| Err(Error::MalformedRequest(err)))?;
+    let res = req.parse(buffer).map_err(Error::MalformedRequest)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut request = http::Request::builder()
             .method(req.method.unwrap())
             .uri(req.path.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(if req.version == Some(0) {
+                http::Version::HTTP_10
+            } else {
+                http::Version::HTTP_11
+            });
+        let mut raw = Vec::with_capacity(req.headers.len());
         for header in req.headers {
+            // httparse trims whitespace after a value, which would hide a Content-Length of "5 "
+            if header.name.eq_ignore_ascii_case("content-length")
+                && followed_by_whitespace(buffer, header.value)
+            {
+                return Err(Error::InvalidContentLength);
+            }
             request = request.header(header.name, header.value);
+            if let Ok(value) = http::HeaderValue::from_bytes(header.value) {
+                raw.push((header.name.to_string(), value));
+            }
         }
-        let request = request.body(Vec::new()).unwrap();
+        let request = request.extension(RawHeaders(raw)).body(Vec::new()).unwrap();
         Ok(Some((request, len)))
     } else {
         Ok(None)
     }
 }
 
+/// Whether `value`, a slice of `buffer`, has a space or tab right after it in the buffer.
+fn followed_by_whitespace(buffer: &[u8], value: &[u8]) -> bool {
+    let start = (value.as_ptr() as usize).checked_sub(buffer.as_ptr() as usize);
+    let next = start.and_then(|start| buffer.get(start + value.len()));
+    matches!(next, Some(b' ') | Some(b'\t'))
+}
+
+/// Catches requests in HTTP versions other than 1.0 and 1.1 as soon as their request line is in.
+/// httparse would call them malformed, or for HTTP/0.9 (which has no version, and no headers) wait
+/// for headers that are never coming. Lines that aren't a method, target and version separated by
+/// single spaces are left for httparse to judge.
+fn check_version(buffer: &[u8]) -> Result<(), Error> {
+    if buffer.starts_with(HTTP2_PREFACE) {
+        return Err(Error::Http2Preface);
+    }
+    let line = match buffer.iter().position(|&byte| byte == b'\n') {
+        Some(end) => &buffer[..end],
+        None => return Ok(()),
+    };
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let parts: Vec<&[u8]> = line.split(|&byte| byte == b' ').collect();
+    if parts.iter().any(|part| part.is_empty()) {
+        return Ok(());
+    }
+    match parts.as_slice() {
+        [_, _] => Err(Error::UnsupportedVersion("HTTP/0.9".to_string())),
+        [_, _, version] if *version != b"HTTP/1.0" && *version != b"HTTP/1.1" => Err(
+            Error::UnsupportedVersion(String::from_utf8_lossy(version).to_string()),
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Settles which host a request is for, per RFC 7230 §5.4, before anything routes on the Host
+/// header. More than one Host header, or none in an HTTP/1.1 request, is an error. The authority
+/// of an absolute-form target wins over the Host header, which is rewritten to match.
+fn check_host(request: &mut http::Request<Vec<u8>>) -> Result<(), Error> {
+    let hosts = request.headers().get_all(http::header::HOST).iter().count();
+    if hosts > 1 {
+        return Err(Error::DuplicateHost);
+    }
+    let authority = match request.uri().authority() {
+        Some(authority) if request.uri().scheme().is_some() => authority,
+        _ if hosts == 0 && request.version() == http::Version::HTTP_11 => {
+            return Err(Error::MissingHost)
+        }
+        _ => return Ok(()),
+    };
+    // The Host header has no room for the userinfo an authority may have
+    let host = match authority.port() {
+        Some(port) => format!("{}:{}", authority.host(), port),
+        None => authority.host().to_string(),
+    };
+    // Anything http::Uri accepted as a host and port is a valid header value
+    let host = http::HeaderValue::from_str(&host).unwrap();
+    request.headers_mut().insert(http::header::HOST, host);
+    Ok(())
+}
+
 /// Reads an HTTP request from the provided stream, waiting until a complete set of headers is sent.
 /// This function only reads the request line and headers; the read_body function can subsequently
 /// be called in order to read the request body (for a POST request).
@@ -101,7 +376,10 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
 /// Returns Ok(http::Request) if a valid request is received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+async fn read_headers(
+    stream: &mut TcpStream,
+    mut capture: Option<&mut Vec<u8>>,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
@@ -111,12 +389,16 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>,
         // Read bytes from the connection into the buffer, starting at position bytes_read
         let new_bytes = stream
             .read(&mut request_buffer[bytes_read..]).await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete request
             return Err(Error::IncompleteRequest(bytes_read));
         }
+        if let Some(capture) = capture.as_mut() {
+            capture.extend_from_slice(&request_buffer[bytes_read..bytes_read + new_bytes]);
+        }
         bytes_read += new_bytes;
+        check_version(&request_buffer[..bytes_read])?;
 
         // See if we've read a valid request so far
         if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
@@ -141,13 +423,17 @@ async fn read_body(
     stream: &mut TcpStream,
     request: &mut http::Request<Vec<u8>>,
     content_length: usize,
+    mut capture: Option<&mut Vec<u8>>,
 ) -> Result<(), Error> {
     // Keep reading data until we read the full body length, or until we hit an error.
     while request.body().len() < content_length {
         // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
         // space to read that body.)
         let mut buffer = vec![0_u8; min(512, content_length)];
-        let bytes_read = stream.read(&mut buffer).await.or_else(|err| Err(Error::ConnectionError(err)))?;
+        let bytes_read = stream.read(&mut buffer).await.map_err(Error::ConnectionError)?;
+        if let Some(capture) = capture.as_mut() {
+            capture.extend_from_slice(&buffer[..bytes_read]);
+        }
 
         // Make sure the client is still sending us bytes
         if bytes_read == 0 {
@@ -179,19 +465,170 @@ async fn read_body(
 ///
 /// You will need to modify this function in Milestone 2.
 pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+    read_from_stream_capturing(stream, None, None, MAX_BODY_SIZE).await
+}
+
+/// Same as read_from_stream, but if `capture` is provided, every byte read from the stream is also
+/// appended to it (even if the request turns out to be malformed). If `guard` is provided, the body
+/// is only read if the guard can grow to hold it. Bodies bigger than `max_body_size` are refused.
+pub async fn read_from_stream_capturing(
+    stream: &mut TcpStream,
+    mut capture: Option<&mut Vec<u8>>,
+    guard: Option<&mut BufferGuard<'_>>,
+    max_body_size: usize,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Read headers
-    let mut request = read_headers(stream).await?;
-    // Read body if the client supplied the Content-Length header (which it does for POST requests)
-    if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
+    let mut request = read_headers(stream, capture.as_deref_mut()).await?;
+    request.extensions_mut().insert(HeadersReadAt(Instant::now()));
+    check_host(&mut request)?;
+    if is_chunked(&request)? {
+        // Chunked framing takes precedence over any Content-Length, which is replaced
+        read_chunked_body(stream, &mut request, max_body_size, capture, guard).await?;
+    } else if let Some(content_length) = get_content_length(&request)? {
+        // Read body if the client supplied the Content-Length header (which it does for POST
+        // requests). A body declared over the limit is refused without reading any of it, and
+        // read_body only grows the body as its bytes arrive, whatever was declared.
+        if content_length > max_body_size {
             return Err(Error::RequestBodyTooLarge);
+        } else if request.body().len() > content_length {
+            // More came along with the headers than the body can hold (Content-Length: 0 included)
+            return Err(Error::ContentLengthMismatch);
+        } else if guard.is_some_and(|guard| !guard.grow(content_length)) {
+            return Err(Error::BufferLimit);
         } else {
-            read_body(stream, &mut request, content_length).await?;
+            read_body(stream, &mut request, content_length, capture).await?;
         }
+    } else if !request.body().is_empty() {
+        // Without Content-Length or chunked framing a request has no body (RFC 7230 section 3.3.3),
+        // whatever its method, so anything read past the headers isn't part of it
+        log::debug!(
+            "Ignoring {} bytes after the headers of a request without a body",
+            request.body().len()
+        );
+        request.body_mut().clear();
     }
     Ok(request)
 }
 
+/// Whether the request's body is sent with chunked transfer coding. That is the only transfer
+/// coding we decode, so any other is an error.
+fn is_chunked(request: &http::Request<Vec<u8>>) -> Result<bool, Error> {
+    let codings: Vec<&[u8]> = request
+        .headers()
+        .get_all(http::header::TRANSFER_ENCODING)
+        .iter()
+        .flat_map(|value| split_list(value.as_bytes()))
+        .collect();
+    match codings.as_slice() {
+        [] => Ok(false),
+        [coding] if coding.eq_ignore_ascii_case(b"chunked") => Ok(true),
+        _ => Err(Error::UnsupportedTransferEncoding),
+    }
+}
+
+/// Reads a chunked request body, decoding it into the request's body (which starts out holding
+/// whatever of it was read along with the headers) and sending it on with a Content-Length instead.
+/// Trailer fields are dropped. Fails with RequestBodyTooLarge as soon as a chunk would take the
+/// body past `max_size`, without waiting for that chunk to arrive.
+async fn read_chunked_body(
+    stream: &mut TcpStream,
+    request: &mut http::Request<Vec<u8>>,
+    max_size: usize,
+    mut capture: Option<&mut Vec<u8>>,
+    mut guard: Option<&mut BufferGuard<'_>>,
+) -> Result<(), Error> {
+    // Bytes read but not yet decoded
+    let mut raw = std::mem::take(request.body_mut());
+    let mut body = Vec::new();
+    loop {
+        let (line_len, size) = loop {
+            match httparse::parse_chunk_size(&raw) {
+                Ok(httparse::Status::Complete(parsed)) => break parsed,
+                Ok(httparse::Status::Partial) if raw.len() < MAX_CHUNK_LINE => {
+                    read_more(stream, &mut raw, &mut capture).await?
+                }
+                _ => return Err(Error::InvalidChunkedBody),
+            }
+        };
+        raw.drain(..line_len);
+        if size == 0 {
+            break;
+        }
+        if body.len() as u64 + size > max_size as u64 {
+            return Err(Error::RequestBodyTooLarge);
+        }
+        let size = size as usize;
+        if guard.as_mut().is_some_and(|guard| !guard.grow(size)) {
+            return Err(Error::BufferLimit);
+        }
+        // The chunk's data, and the CRLF after it
+        fill(stream, &mut raw, size + 2, &mut capture).await?;
+        if &raw[size..size + 2] != b"\r\n" {
+            return Err(Error::InvalidChunkedBody);
+        }
+        body.extend_from_slice(&raw[..size]);
+        raw.drain(..size + 2);
+    }
+    // Trailer fields, ended by an empty line
+    let mut trailers_len = 0;
+    loop {
+        match raw.windows(2).position(|window| window == b"\r\n") {
+            Some(0) => {
+                raw.drain(..2);
+                break;
+            }
+            Some(end) => {
+                trailers_len += end + 2;
+                raw.drain(..end + 2);
+            }
+            None => read_more(stream, &mut raw, &mut capture).await?,
+        }
+        if trailers_len + raw.len() > MAX_HEADERS_SIZE {
+            return Err(Error::InvalidChunkedBody);
+        }
+    }
+    // Anything more would be a pipelined request, which (as with Content-Length) isn't supported
+    if !raw.is_empty() {
+        return Err(Error::InvalidChunkedBody);
+    }
+    let headers = request.headers_mut();
+    headers.remove(http::header::TRANSFER_ENCODING);
+    headers.insert(http::header::CONTENT_LENGTH, http::HeaderValue::from(body.len()));
+    *request.body_mut() = body;
+    Ok(())
+}
+
+/// Reads from the stream until `buffer` holds at least `len` bytes of a chunked body.
+async fn fill(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    len: usize,
+    capture: &mut Option<&mut Vec<u8>>,
+) -> Result<(), Error> {
+    while buffer.len() < len {
+        read_more(stream, buffer, capture).await?;
+    }
+    Ok(())
+}
+
+/// Reads the next bytes of a chunked body onto the end of `buffer`.
+async fn read_more(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    capture: &mut Option<&mut Vec<u8>>,
+) -> Result<(), Error> {
+    let mut read_buffer = [0_u8; 512];
+    let bytes_read = stream.read(&mut read_buffer).await.map_err(Error::ConnectionError)?;
+    if bytes_read == 0 {
+        return Err(Error::InvalidChunkedBody);
+    }
+    if let Some(capture) = capture.as_mut() {
+        capture.extend_from_slice(&read_buffer[..bytes_read]);
+    }
+    buffer.extend_from_slice(&read_buffer[..bytes_read]);
+    Ok(())
+}
+
 /// This function serializes a request to bytes and writes those bytes to the provided stream.
 ///
 /// You will need to modify this function in Milestone 2.
@@ -199,20 +636,508 @@ pub async fn write_to_stream(
     request: &http::Request<Vec<u8>>,
     stream: &mut TcpStream,
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_request_line(request).into_bytes()).await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    for (header_name, header_value) in request.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(&serialize(request)).await
+}
+
+/// Like write_to_stream, but sends the request as `version` whatever version it has. An HTTP/1.0
+/// request also asks for the connection to be closed, as HTTP/1.0 has no keep-alive to speak of.
+///
+/// Writing stops if the upstream sends its final response before it has taken the whole request,
+/// as upstreams that turn a large upload away early (with a 413 or a 401) do. They usually stop
+/// reading, so writing on would only wait for the write timeout. Interim (1xx) responses, such as
+/// the 100 Continue that answers `Expect: 100-continue`, don't stop the write and are discarded.
+/// Returns whether the whole request was written. If it wasn't, the connection is stuck partway
+/// through a request and can't carry another one.
+pub async fn write_to_stream_as(
+    request: &http::Request<Vec<u8>>,
+    version: http::Version,
+    stream: &mut TcpStream,
+) -> Result<bool, std::io::Error> {
+    let bytes = serialize_as(request, version);
+    let (mut reader, mut writer) = stream.split();
+    let writing = writer.write_all(&bytes);
+    tokio::pin!(writing);
+    let answered = final_response_started(&mut reader);
+    tokio::pin!(answered);
+    let mut watching = true;
+    loop {
+        tokio::select! {
+            written = &mut writing => return written.map(|()| true),
+            answered = &mut answered, if watching => match answered {
+                Ok(true) => return Ok(false),
+                // The upstream hung up or failed; writing on will find out which
+                _ => watching = false,
+            },
+        }
+    }
+}
+
+/// Waits for the upstream to start its final response, reading past any interim (1xx) responses
+/// before it. Returns false if the upstream hangs up first.
+async fn final_response_started(
+    reader: &mut tokio::net::tcp::ReadHalf<'_>,
+) -> std::io::Result<bool> {
+    let mut peeked = [0_u8; 1024];
+    loop {
+        let n = reader.peek(&mut peeked).await?;
+        if n == 0 {
+            return Ok(false);
+        }
+        // "HTTP/1.1 100" is enough to tell an interim response from a final one. 101 Switching
+        // Protocols is final as far as the request is concerned.
+        if n >= 12 && (peeked[9] != b'1' || &peeked[9..12] == b"101") {
+            return Ok(true);
+        }
+        match peeked[..n].windows(4).position(|window| window == b"\r\n\r\n") {
+            Some(end) if n >= 12 => {
+                let mut interim = vec![0_u8; end + 4];
+                reader.read_exact(&mut interim).await?;
+            }
+            // Too long to be an interim response; let whoever reads the response deal with it
+            None if n == peeked.len() => return Ok(true),
+            // Peeking again straight away would find the same bytes, so give the rest a moment
+            _ => delay_for(Duration::from_millis(10)).await,
+        }
+    }
+}
+
+/// Serializes a request to the bytes that write_to_stream sends.
+pub fn serialize(request: &http::Request<Vec<u8>>) -> Vec<u8> {
+    serialize_as(request, request.version())
+}
+
+fn serialize_as(request: &http::Request<Vec<u8>>, version: http::Version) -> Vec<u8> {
+    let http10 = version == http::Version::HTTP_10;
+    let mut bytes =
+        format!("{} {} {:?}\r\n", request.method(), request.uri(), version).into_bytes();
+    let raw = request.extensions().get::<RawHeaders>();
+    for (header_name, header_value) in ordered_fields(request.headers(), raw) {
+        if http10 && header_name.eq_ignore_ascii_case(http::header::CONNECTION.as_str()) {
+            continue;
+        }
+        bytes.extend_from_slice(format!("{}: ", header_name).as_bytes());
+        bytes.extend_from_slice(header_value.as_bytes());
+        bytes.extend_from_slice(b"\r\n");
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
+    if http10 {
+        bytes.extend_from_slice(b"connection: close\r\n");
     }
-    Ok(())
+    bytes.extend_from_slice(b"\r\n");
+    bytes.extend_from_slice(request.body());
+    bytes
+}
+
+/// The header fields to send for `headers`, in order, with their names as they are to be written.
+/// Without `raw`, that is `HeaderMap` order with lowercase names. With it, the fields the client
+/// sent come first, in its order and case: fields whose values are all unchanged each where they
+/// were, and fields that were changed (e.g. X-Forwarded-For, which gets appended to) with every
+/// current value where the first one was. Fields that were added come last, and removed ones are
+/// left out.
+fn ordered_fields<'a>(
+    headers: &'a http::HeaderMap,
+    raw: Option<&'a RawHeaders>,
+) -> Vec<(&'a str, &'a http::HeaderValue)> {
+    let raw = match raw {
+        Some(raw) => raw,
+        None => return headers.iter().map(|(name, value)| (name.as_str(), value)).collect(),
+    };
+    let mut fields = Vec::with_capacity(headers.len());
+    let mut sent = HashSet::new();
+    for (raw_name, raw_value) in &raw.0 {
+        let name = match http::header::HeaderName::from_bytes(raw_name.as_bytes()) {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let current = headers.get_all(&name);
+        let received =
+            raw.0.iter().filter(|(other, _)| other.eq_ignore_ascii_case(raw_name)).map(|(_, v)| v);
+        if current.iter().eq(received) {
+            fields.push((raw_name.as_str(), raw_value));
+        } else if !sent.contains(&name) {
+            fields.extend(current.iter().map(|value| (raw_name.as_str(), value)));
+        }
+        sent.insert(name);
+    }
+    for (name, value) in headers {
+        if !sent.contains(name) {
+            fields.push((name.as_str(), value));
+        }
+    }
+    fields
 }
 
+/// Formats the request line ("GET /path?query HTTP/1.1") for logs and for serializing.
 pub fn format_request_line(request: &http::Request<Vec<u8>>) -> String {
     format!("{} {} {:?}", request.method(), request.uri(), request.version())
-}
\ No newline at end of file
+}
+
+/// The path and query string of a request as the client sent them, still percent-encoded, without
+/// the scheme and authority of an absolute-form target. Anything that matches on or records the
+/// target should use this, so that they all agree on what it is.
+pub fn request_uri(request: &http::Request<Vec<u8>>) -> String {
+    request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str())
+        .to_string()
+}
+
+/// The request method, e.g. "GET".
+pub fn request_method(request: &http::Request<Vec<u8>>) -> &str {
+    request.method().as_str()
+}
+
+/// Decodes %XX escapes in `value`. Malformed escapes are left as they are.
+pub fn percent_decode(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1..i + 3) {
+            Some(hex) if bytes[i] == b'%' && hex.iter().all(u8::is_ascii_hexdigit) => {
+                u8::from_str_radix(std::str::from_utf8(hex).unwrap(), 16).ok()
+            }
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+/// Whether `method` is safe (RFC 7231 section 4.2.1): it only retrieves, so sending it again, or
+/// twice at once, changes nothing on the server.
+pub fn is_safe(method: &http::Method) -> bool {
+    method == http::Method::GET
+        || method == http::Method::HEAD
+        || method == http::Method::OPTIONS
+        || method == http::Method::TRACE
+}
+
+/// Whether `method` is idempotent (RFC 7231 section 4.2.2): sending it several times has the same
+/// effect as sending it once, so it can be sent again after failing partway through.
+pub fn is_idempotent(method: &http::Method) -> bool {
+    is_safe(method) || method == http::Method::PUT || method == http::Method::DELETE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_display() {
+        assert_eq!(
+            Error::IncompleteRequest(0).to_string(),
+            "client closed connection after sending 0 bytes"
+        );
+        assert_eq!(
+            Error::InvalidContentLength.to_string(),
+            "Content-Length header is not a valid integer"
+        );
+        let io_err = std::io::Error::new(std::io::ErrorKind::BrokenPipe, "broken pipe");
+        let err = Error::ConnectionError(io_err);
+        assert!(std::error::Error::source(&err).is_some());
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("/a%20b/%5Fx"), b"/a b/_x");
+        assert_eq!(percent_decode("100%"), b"100%");
+        assert_eq!(percent_decode("%zz%4"), b"%zz%4");
+    }
+
+    #[test]
+    fn test_check_version() {
+        assert!(check_version(b"GET / HTTP/1.1\r\nHost: x\r\n").is_ok());
+        assert!(check_version(b"GET / HTTP/1.0\n").is_ok());
+        // Not enough of the request line to tell yet
+        assert!(check_version(b"GET / HTTP/2").is_ok());
+        // Left for httparse to call malformed
+        assert!(check_version(b"this is not http\r\n").is_ok());
+        assert!(matches!(
+            check_version(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"),
+            Err(Error::Http2Preface)
+        ));
+        assert!(matches!(
+            check_version(b"GET /index.html\r\n"),
+            Err(Error::UnsupportedVersion(version)) if version == "HTTP/0.9"
+        ));
+        assert!(matches!(
+            check_version(b"GET / HTTP/3.0\r\n"),
+            Err(Error::UnsupportedVersion(version)) if version == "HTTP/3.0"
+        ));
+    }
+
+    #[test]
+    fn test_check_host() {
+        let check = |raw: &[u8]| {
+            let (mut request, _) = parse_request(raw).unwrap().expect("incomplete request");
+            check_host(&mut request).map(|()| {
+                let host = request.headers().get(http::header::HOST);
+                host.map(|host| host.to_str().unwrap().to_string())
+            })
+        };
+        assert_eq!(check(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap(), Some("a".into()));
+        assert_eq!(check(b"GET / HTTP/1.0\r\n\r\n").unwrap(), None);
+        assert!(matches!(check(b"GET / HTTP/1.1\r\n\r\n"), Err(Error::MissingHost)));
+        assert!(matches!(
+            check(b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n"),
+            Err(Error::DuplicateHost)
+        ));
+        assert!(matches!(
+            check(b"GET / HTTP/1.1\r\nHost: a\r\nhost: a\r\n\r\n"),
+            Err(Error::DuplicateHost)
+        ));
+        // The absolute-form target's authority wins, whether or not there is a Host header
+        let absolute = b"GET http://user@b:8080/p HTTP/1.1\r\nHost: a\r\n\r\n";
+        assert_eq!(check(absolute).unwrap(), Some("b:8080".into()));
+        let absolute = b"GET http://[::1]/p HTTP/1.1\r\n\r\n";
+        assert_eq!(check(absolute).unwrap(), Some("[::1]".into()));
+        assert!(matches!(
+            check(b"GET http://b/p HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n"),
+            Err(Error::DuplicateHost)
+        ));
+    }
+
+    fn content_length_of(value: &str) -> Result<Option<usize>, Error> {
+        let raw = format!("POST / HTTP/1.1\r\nHost: x\r\nContent-Length:{}\r\n\r\n", value);
+        let (request, _) = parse_request(raw.as_bytes())?.unwrap();
+        get_content_length(&request)
+    }
+
+    #[test]
+    fn test_content_length_values() {
+        for (value, expected) in &[(" 0", 0), (" 5", 5), ("5", 5), (" 007", 7)] {
+            assert!(matches!(content_length_of(value), Ok(Some(n)) if n == *expected), "{}", value);
+        }
+        for value in &[" 5 ", " 5\t", " +5", " -5", " ", " 5,5", " 5 5", " 0x5", " 1e3"] {
+            assert!(
+                matches!(content_length_of(value), Err(Error::InvalidContentLength)),
+                "{:?} was accepted",
+                value
+            );
+        }
+        assert!(matches!(
+            content_length_of(" 99999999999999999999999"),
+            Err(Error::InvalidContentLength)
+        ));
+        // Whitespace after other fields is no concern of ours
+        assert!(parse_request(b"GET / HTTP/1.1\r\nHost: x \r\n\r\n").is_ok());
+    }
+
+    /// Reads a request from a connection `raw` is sent down, the client's side of which is then
+    /// shut down for writing.
+    async fn read_raw(raw: &'static [u8]) -> Result<http::Request<Vec<u8>>, Error> {
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut client = TcpStream::connect(address).await.unwrap();
+            client.write_all(raw).await.unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+            // Keep the connection open until the request has been read
+            let _ = client.read(&mut [0; 1]).await;
+        });
+        let (mut stream, _) = listener.accept().await.unwrap();
+        read_from_stream(&mut stream).await
+    }
+
+    #[tokio::test]
+    async fn test_body_framing() {
+        let zero = read_raw(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\n\r\n").await;
+        assert!(zero.unwrap().body().is_empty());
+        let five = read_raw(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello").await;
+        assert_eq!(five.unwrap().body(), b"hello");
+
+        // Without Content-Length there is no body, whatever the method and whatever follows
+        for raw in &[
+            &b"POST / HTTP/1.1\r\nHost: x\r\n\r\n"[..],
+            b"PUT / HTTP/1.1\r\nHost: x\r\n\r\n",
+            b"POST / HTTP/1.1\r\nHost: x\r\n\r\nhello",
+        ] {
+            let request = read_raw(raw).await.unwrap();
+            assert!(request.body().is_empty(), "{:?}", String::from_utf8_lossy(raw));
+            assert!(request.headers().get("content-length").is_none());
+        }
+
+        // More bytes than the Content-Length says, zero included
+        for raw in &[
+            &b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\n\r\nhello"[..],
+            b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\n\r\nhello",
+        ] {
+            let result = read_raw(raw).await;
+            assert!(matches!(result, Err(Error::ContentLengthMismatch)), "{:?}", result);
+        }
+        // Fewer
+        let short = read_raw(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 9\r\n\r\nhello").await;
+        assert!(matches!(short, Err(Error::ContentLengthMismatch)), "{:?}", short);
+        let signed =
+            read_raw(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: +5\r\n\r\nhello").await;
+        assert!(matches!(signed, Err(Error::InvalidContentLength)), "{:?}", signed);
+    }
+
+    #[test]
+    fn test_request_uri_and_method() {
+        let request = |method: &str, uri: &str| {
+            http::Request::builder().method(method).uri(uri).body(Vec::new()).unwrap()
+        };
+        let get = request("GET", "/a%20b/c?x=1&y=%2F");
+        assert_eq!(request_uri(&get), "/a%20b/c?x=1&y=%2F");
+        assert_eq!(request_method(&get), "GET");
+        assert_eq!(request_uri(&request("GET", "http://example.com/p?q")), "/p?q");
+        assert_eq!(request_uri(&request("OPTIONS", "*")), "*");
+        assert_eq!(request_method(&request("PURGE", "/")), "PURGE");
+    }
+
+    #[test]
+    fn test_method_semantics() {
+        let method = |name: &str| http::Method::from_bytes(name.as_bytes()).unwrap();
+        for name in &["GET", "HEAD", "OPTIONS", "TRACE"] {
+            assert!(is_safe(&method(name)) && is_idempotent(&method(name)), "{}", name);
+        }
+        for name in &["PUT", "DELETE"] {
+            assert!(!is_safe(&method(name)) && is_idempotent(&method(name)), "{}", name);
+        }
+        for name in &["POST", "PATCH", "CONNECT", "PURGE"] {
+            assert!(!is_safe(&method(name)) && !is_idempotent(&method(name)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_serialize_as_http_10() {
+        let request = http::Request::builder()
+            .uri("/path")
+            .header("host", "example.com")
+            .header("connection", "keep-alive")
+            .body(b"body".to_vec())
+            .unwrap();
+        assert_eq!(
+            serialize_as(&request, http::Version::HTTP_10),
+            b"GET /path HTTP/1.0\r\nhost: example.com\r\nconnection: close\r\n\r\nbody".to_vec()
+        );
+        assert_eq!(
+            serialize(&request),
+            b"GET /path HTTP/1.1\r\nhost: example.com\r\nconnection: keep-alive\r\n\r\nbody"
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn test_serialize_keeps_client_header_order() {
+        let raw: &[u8] =
+            b"GET / HTTP/1.1\r\nVia: a\r\nHost: x\r\nConnection: keep-alive\r\nvia: b\r\n\r\n";
+        let (mut request, _) = parse_request(raw).unwrap().unwrap();
+        assert_eq!(serialize(&request), raw.to_vec());
+
+        request.headers_mut().append("via", http::HeaderValue::from_static("c"));
+        request.headers_mut().insert("x-added", http::HeaderValue::from_static("1"));
+        assert_eq!(
+            serialize_as(&request, http::Version::HTTP_10),
+            b"GET / HTTP/1.0\r\nVia: a\r\nVia: b\r\nVia: c\r\nHost: x\r\nx-added: 1\r\n\
+              connection: close\r\n\r\n"
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn test_wants_close() {
+        let mut headers = http::HeaderMap::new();
+        assert!(!wants_close(&headers));
+        headers.insert("connection", http::HeaderValue::from_static("keep-alive"));
+        assert!(!wants_close(&headers));
+        headers.append("connection", http::HeaderValue::from_static("Upgrade, Close"));
+        assert!(wants_close(&headers));
+    }
+
+    fn forwarded_for(existing: Option<&str>, limits: &ListLimits) -> (String, usize) {
+        let mut builder = http::Request::builder().uri("/");
+        if let Some(existing) = existing {
+            builder = builder.header("x-forwarded-for", existing);
+        }
+        let mut request = builder.body(Vec::new()).unwrap();
+        let dropped = extend_header_value(&mut request, "x-forwarded-for", "10.0.0.9", limits);
+        let value = request.headers()["x-forwarded-for"].to_str().unwrap().to_string();
+        (value, dropped)
+    }
+
+    #[test]
+    fn test_extend_header_value_within_limits() {
+        let limits = ListLimits { max_bytes: 1024, max_entries: 10 };
+        assert_eq!(forwarded_for(None, &limits), ("10.0.0.9".to_string(), 0));
+        assert_eq!(
+            forwarded_for(Some("1.1.1.1,2.2.2.2"), &limits),
+            ("1.1.1.1,2.2.2.2, 10.0.0.9".to_string(), 0)
+        );
+    }
+
+    #[test]
+    fn test_extend_header_value_drops_oldest_entries() {
+        // Too many entries: keep the right-most ones
+        let limits = ListLimits { max_bytes: 1024, max_entries: 3 };
+        assert_eq!(
+            forwarded_for(Some("1.1.1.1, 2.2.2.2, 3.3.3.3, 4.4.4.4"), &limits),
+            ("3.3.3.3, 4.4.4.4, 10.0.0.9".to_string(), 2)
+        );
+
+        // Too long: "4.4.4.4, 10.0.0.9" is 17 bytes, one more entry would make it 26
+        let limits = ListLimits { max_bytes: 20, max_entries: 10 };
+        assert_eq!(
+            forwarded_for(Some("1.1.1.1, 2.2.2.2, 3.3.3.3, 4.4.4.4"), &limits),
+            ("4.4.4.4, 10.0.0.9".to_string(), 3)
+        );
+
+        // A single huge fake entry is dropped entirely, but our own entry always stays
+        let junk = "x".repeat(5000);
+        let limits = ListLimits { max_bytes: 1024, max_entries: 10 };
+        assert_eq!(forwarded_for(Some(&junk), &limits), ("10.0.0.9".to_string(), 1));
+        let limits = ListLimits { max_bytes: 4, max_entries: 0 };
+        assert_eq!(forwarded_for(Some("1.1.1.1"), &limits), ("10.0.0.9".to_string(), 1));
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let (name, value) = parse_header("X-Internal-Token:  a:b ").unwrap();
+        assert_eq!((name.as_str(), value.to_str().unwrap()), ("x-internal-token", "a:b"));
+        assert!(parse_header("X-Internal-Token").is_err());
+        assert!(parse_header("Bad Name: value").is_err());
+        assert!(parse_header("X-Token: line\nbreak").is_err());
+        assert_eq!(parse_header_name(" Authorization ").unwrap(), http::header::AUTHORIZATION);
+    }
+
+    #[test]
+    fn test_build_forwarded_header() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(
+            build_forwarded_header(ip("192.0.2.1"), "http", Some("example.com"), ip("10.0.0.1")),
+            "for=192.0.2.1;proto=http;host=example.com;by=10.0.0.1"
+        );
+        assert_eq!(
+            build_forwarded_header(ip("2001:db8::1"), "http", Some("example.com:8080"), ip("::1")),
+            "for=\"[2001:db8::1]\";proto=http;host=\"example.com:8080\";by=\"[::1]\""
+        );
+        assert_eq!(
+            build_forwarded_header(ip("192.0.2.1"), "http", None, ip("10.0.0.1")),
+            "for=192.0.2.1;proto=http;by=10.0.0.1"
+        );
+    }
+
+    #[test]
+    fn test_split_list_respects_quotes() {
+        let value = br#"for="[::1]";host="a,b", for=192.0.2.1 ,, for="x\",y""#;
+        assert_eq!(
+            split_list(value),
+            vec![
+                &br#"for="[::1]";host="a,b""#[..],
+                &b"for=192.0.2.1"[..],
+                &br#"for="x\",y""#[..],
+            ]
+        );
+    }
+}
@@ -1,11 +1,17 @@
+use crate::buffer_budget::BufferGuard;
+use crate::request;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
 const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
+pub const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
+/// Statuses that a cache may store without explicit freshness information (RFC 7231 section 6.1)
+const CACHEABLE_BY_DEFAULT: [u16; 11] = [200, 203, 204, 206, 300, 301, 404, 405, 410, 414, 501];
+
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     /// Client hung up before sending a complete request
     IncompleteResponse,
@@ -17,10 +23,65 @@ pub enum Error {
     ContentLengthMismatch,
     /// The request body is bigger than MAX_BODY_SIZE
     ResponseBodyTooLarge,
+    /// Buffering the response body would take more memory than `--max-buffered-bytes` allows
+    BufferLimit,
+    /// The response headers are bigger than the configured limit
+    HeadersTooLarge,
+    /// The status line has a code that isn't a valid HTTP status
+    InvalidStatusCode(u16),
     /// Encountered an I/O error when reading/writing a TcpStream
     ConnectionError(std::io::Error),
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IncompleteResponse => write!(f, "upstream closed connection mid-response"),
+            Error::MalformedResponse(err) => write!(f, "malformed response: {}", err),
+            Error::InvalidContentLength => write!(f, "Content-Length header is not a valid integer"),
+            Error::ContentLengthMismatch => {
+                write!(f, "response body length doesn't match Content-Length header")
+            }
+            Error::ResponseBodyTooLarge => {
+                write!(f, "response body is larger than {} bytes", MAX_BODY_SIZE)
+            }
+            Error::BufferLimit => write!(f, "no room to buffer the response body"),
+            Error::HeadersTooLarge => write!(f, "response headers exceed the size limit"),
+            Error::InvalidStatusCode(code) => write!(f, "invalid status code {}", code),
+            Error::ConnectionError(err) => write!(f, "error talking to upstream: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::MalformedResponse(err) => Some(err),
+            Error::ConnectionError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Limits on the response headers we accept from upstreams. These are separate from the limits on
+/// client requests, since a trusted-but-buggy backend isn't the same threat as a hostile client.
+#[derive(Debug, Clone, Copy)]
+pub struct HeaderLimits {
+    /// Maximum size of the status line plus headers, in bytes
+    pub max_bytes: usize,
+    /// Maximum number of headers
+    pub max_count: usize,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> Self {
+        HeaderLimits {
+            max_bytes: MAX_HEADERS_SIZE,
+            max_count: MAX_NUM_HEADERS,
+        }
+    }
+}
+
 /// Extracts the Content-Length header value from the provided response. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
@@ -31,11 +92,8 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
     if let Some(header_value) = response.headers().get("content-length") {
         // If it exists, parse it as a usize (or return InvalidResponseFormat if it can't be parsed as such)
         Ok(Some(
-            header_value
-                .to_str()
-                .or(Err(Error::InvalidContentLength))?
-                .parse::<usize>()
-                .or(Err(Error::InvalidContentLength))?,
+            request::parse_content_length(header_value.as_bytes())
+                .ok_or(Error::InvalidContentLength)?,
         ))
     } else {
         // If it doesn't exist, return None
@@ -51,22 +109,39 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
 /// * If there is data in the buffer that is definitely not a valid HTTP response, returns
 ///   Err(Error)
 ///
-/// You won't need to touch this function.
-fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
-    let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
+/// Header lines with names or values that aren't valid HTTP are dropped rather than forwarded.
+#[allow(clippy::type_complexity)]
+fn parse_response(
+    buffer: &[u8],
+    max_headers: usize,
+) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
+    let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
     let mut resp = httparse::Response::new(&mut headers);
-    let res = resp
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedResponse(err)))?;
+    let res = httparse::ParserConfig::default()
+        .ignore_invalid_headers_in_responses(true)
+        .parse_response(&mut resp, buffer)
+        .map_err(Error::MalformedResponse)?;
 
     if let httparse::Status::Complete(len) = res {
+        let code = resp.code.unwrap();
+        let status =
+            http::StatusCode::from_u16(code).map_err(|_| Error::InvalidStatusCode(code))?;
         let mut response = http::Response::builder()
-            .status(resp.code.unwrap())
-            .version(http::Version::HTTP_11);
+            .status(status)
+            .version(http::Version::HTTP_11)
+            .body(Vec::new())
+            .unwrap();
         for header in resp.headers {
-            response = response.header(header.name, header.value);
+            match (
+                http::header::HeaderName::from_bytes(header.name.as_bytes()),
+                http::HeaderValue::from_bytes(header.value),
+            ) {
+                (Ok(name), Ok(value)) => {
+                    response.headers_mut().append(name, value);
+                }
+                _ => log::warn!("Dropping invalid upstream response header {:?}", header.name),
+            }
         }
-        let response = response.body(Vec::new()).unwrap();
         Ok(Some((response, len)))
     } else {
         Ok(None)
@@ -77,20 +152,29 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 /// sent. This function only reads the response line and headers; the read_body function can
 /// subsequently be called in order to read the response body.
 ///
-/// Returns Ok(http::Response) if a valid response is received, or Error if not.
+/// Returns Ok(http::Response) if a valid response is received, or Error if not. Interim (1xx)
+/// responses other than 101 Switching Protocols are skipped, as the client has already been sent
+/// (or doesn't need) whatever they announce.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+pub async fn read_headers(
+    stream: &mut TcpStream,
+    limits: &HeaderLimits,
+) -> Result<http::Response<Vec<u8>>, Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
-    let mut response_buffer = [0_u8; MAX_HEADERS_SIZE];
+    let mut response_buffer = vec![0_u8; limits.max_bytes];
     let mut bytes_read = 0;
     loop {
+        if bytes_read == response_buffer.len() {
+            // The buffer is full and we still haven't seen the end of the headers
+            return Err(Error::HeadersTooLarge);
+        }
         // Read bytes from the connection into the buffer, starting at position bytes_read
         let new_bytes = stream
             .read(&mut response_buffer[bytes_read..]).await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete response
             return Err(Error::IncompleteResponse);
@@ -98,7 +182,14 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
         bytes_read += new_bytes;
 
         // See if we've read a valid response so far
-        if let Some((mut response, headers_len)) = parse_response(&response_buffer[..bytes_read])? {
+        while let Some((mut response, headers_len)) = parse_response(&response_buffer[..bytes_read], limits.max_count)? {
+            if response.status().is_informational()
+                && response.status() != http::StatusCode::SWITCHING_PROTOCOLS
+            {
+                response_buffer.copy_within(headers_len..bytes_read, 0);
+                bytes_read -= headers_len;
+                continue;
+            }
             // We've read a complete set of headers. We may have also read the first part of the
             // response body; take whatever is left over in the response buffer and save that as
             // the start of the response body.
@@ -112,19 +203,42 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
 
 /// This function reads the body for a response from the stream. If the Content-Length header is
 /// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
+/// If `guard` is provided, it is grown to cover the body before the body is buffered.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>>) -> Result<(), Error> {
+pub async fn read_body(
+    stream: &mut TcpStream,
+    response: &mut http::Response<Vec<u8>>,
+    mut guard: Option<&mut BufferGuard<'_>>,
+) -> Result<(), Error> {
     // The response may or may not supply a Content-Length header. If it provides the header, then
     // we want to read that number of bytes; if it does not, we want to keep reading bytes until
     // the connection is closed.
     let content_length = get_content_length(response)?;
+    // A body declared bigger than we would ever hold is refused before any of it is read, rather
+    // than once it has been read up to the limit
+    if let Some(len) = content_length.filter(|len| *len > MAX_BODY_SIZE) {
+        log::warn!("Upstream declared a {} byte body, over the {} byte limit", len, MAX_BODY_SIZE);
+        return Err(Error::ResponseBodyTooLarge);
+    }
+    // Nothing may follow a response but the next one, which can't come unasked, so more than the
+    // declared body arriving with the headers is as wrong as more arriving after them
+    if content_length.is_some_and(|len| response.body().len() > len) {
+        return Err(Error::ContentLengthMismatch);
+    }
+
+    // Account for the whole body up front if we know its length, so that a body that won't fit is
+    // refused before any more of it is read. Otherwise account for it as it arrives. Either way,
+    // the body itself only grows as its bytes arrive.
+    let mut accounted = 0;
+    let expected = content_length.unwrap_or(response.body().len());
+    account(guard.as_deref_mut(), &mut accounted, expected)?;
 
     while content_length.is_none() || response.body().len() < content_length.unwrap() {
         let mut buffer = [0_u8; 512];
         let bytes_read = stream
             .read(&mut buffer).await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if bytes_read == 0 {
             // The server has hung up!
             if content_length.is_none() {
@@ -149,6 +263,7 @@ async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>
         }
 
         // Append received bytes to the response body
+        account(guard.as_deref_mut(), &mut accounted, response.body().len() + bytes_read)?;
         response.body_mut().extend_from_slice(&buffer[..bytes_read]);
     }
     Ok(())
@@ -161,18 +276,66 @@ async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>
 pub async fn read_from_stream(
     stream: &mut TcpStream,
     request_method: &http::Method,
+    limits: &HeaderLimits,
 ) -> Result<http::Response<Vec<u8>>, Error> {
-    let mut response = read_headers(stream).await?;
-    // A response may have a body as long as it is not responding to a HEAD request and as long as
-    // the response status code is not 1xx, 204 (no content), or 304 (not modified).
-    if !(request_method == http::Method::HEAD
+    let mut response = read_headers(stream, limits).await?;
+    if has_body(request_method, &response) {
+        read_body(stream, &mut response, None).await?;
+    }
+    Ok(response)
+}
+
+/// Grows `guard`, if there is one, from covering `accounted` bytes of body to covering `total`.
+fn account(
+    guard: Option<&mut BufferGuard<'_>>,
+    accounted: &mut usize,
+    total: usize,
+) -> Result<(), Error> {
+    if let Some(guard) = guard {
+        if total > *accounted {
+            if !guard.grow(total - *accounted) {
+                return Err(Error::BufferLimit);
+            }
+            *accounted = total;
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether a body follows the headers of this response. A response may have a body as long
+/// as it is not responding to a HEAD request and as long as the response status code is not 1xx,
+/// 204 (no content), or 304 (not modified).
+pub fn has_body(request_method: &http::Method, response: &http::Response<Vec<u8>>) -> bool {
+    !(request_method == http::Method::HEAD
         || response.status().as_u16() < 200
         || response.status() == http::StatusCode::NO_CONTENT
         || response.status() == http::StatusCode::NOT_MODIFIED)
+}
+
+/// Gives a response that has content but no Content-Type the `default` one, so that browsers don't
+/// sniff the body to guess it. Returns whether the response needed it.
+pub fn default_content_type(
+    response: &mut http::Response<Vec<u8>>,
+    default: &http::HeaderValue,
+) -> bool {
+    // A response to HEAD describes the content a GET would get, so it still needs one
+    if !has_body(&http::Method::GET, response)
+        || response.headers().contains_key(http::header::CONTENT_TYPE)
     {
-        read_body(stream, &mut response).await?;
+        return false;
     }
-    Ok(response)
+    response.headers_mut().insert(http::header::CONTENT_TYPE, default.clone());
+    true
+}
+
+/// Parses a Content-Type given on the command line, e.g. "text/plain; charset=utf-8".
+pub fn parse_content_type(value: &str) -> Result<http::HeaderValue, String> {
+    let value = value.trim();
+    if !value.contains('/') {
+        return Err(format!("\"{}\" is not a media type", value));
+    }
+    http::HeaderValue::from_str(value)
+        .map_err(|_| format!("\"{}\" is not a valid header value", value))
 }
 
 /// This function serializes a response to bytes and writes those bytes to the provided stream.
@@ -182,18 +345,21 @@ pub async fn write_to_stream(
     response: &http::Response<Vec<u8>>,
     stream: &mut TcpStream,
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_response_line(response).into_bytes()).await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(&serialize(response)).await
+}
+
+/// Serializes a response to the bytes that write_to_stream sends.
+pub fn serialize(response: &http::Response<Vec<u8>>) -> Vec<u8> {
+    let mut bytes = format_response_line(response).into_bytes();
+    bytes.extend_from_slice(b"\r\n");
     for (header_name, header_value) in response.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+        bytes.extend_from_slice(format!("{}: ", header_name).as_bytes());
+        bytes.extend_from_slice(header_value.as_bytes());
+        bytes.extend_from_slice(b"\r\n");
     }
-    Ok(())
+    bytes.extend_from_slice(b"\r\n");
+    bytes.extend_from_slice(response.body());
+    bytes
 }
 
 pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
@@ -201,10 +367,29 @@ pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
         "{:?} {} {}",
         response.version(),
         response.status().as_str(),
-        response.status().canonical_reason().unwrap_or("")
+        reason_phrase(response.status())
     )
 }
 
+fn reason_phrase(status: http::StatusCode) -> &'static str {
+    match status.as_u16() {
+        // Too new for the http crate to know
+        103 => "Early Hints",
+        _ => status.canonical_reason().unwrap_or(""),
+    }
+}
+
+/// Creates a 103 Early Hints interim response carrying `links` as Link headers, sent ahead of the
+/// real response so that clients can start preloading.
+pub fn make_early_hints(links: &[&http::HeaderValue]) -> http::Response<Vec<u8>> {
+    let mut response = http::Response::new(Vec::new());
+    *response.status_mut() = http::StatusCode::from_u16(103).unwrap();
+    for link in links {
+        response.headers_mut().append(http::header::LINK, (*link).clone());
+    }
+    response
+}
+
 /// This is a helper function that creates an http::Response containing an HTTP error that can be
 /// sent to a client.
 pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
@@ -214,11 +399,244 @@ pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
         status.canonical_reason().unwrap_or("")
     )
     .into_bytes();
+    make_response(status, "text/plain", body)
+}
+
+/// Creates a redirect to `location`: 301 Moved Permanently if `permanent`, otherwise 302 Found.
+///
+/// Panics if `location` is not a valid header value.
+pub fn make_redirect(location: &str, permanent: bool) -> http::Response<Vec<u8>> {
+    let status = if permanent {
+        http::StatusCode::MOVED_PERMANENTLY
+    } else {
+        http::StatusCode::FOUND
+    };
+    let mut response = make_http_error(status);
+    response.headers_mut().insert(
+        "Location",
+        http::HeaderValue::from_str(location).expect("invalid redirect location"),
+    );
+    response
+}
+
+/// Parses the Cache-Control headers into (directive, argument) pairs, with directive names
+/// lowercased and quotes taken off arguments: "no-cache, max-age=\"60\"" gives ("no-cache", None)
+/// and ("max-age", Some("60")).
+pub fn cache_control(headers: &http::HeaderMap) -> Vec<(String, Option<String>)> {
+    headers
+        .get_all(http::header::CACHE_CONTROL)
+        .iter()
+        .flat_map(|value| request::split_list(value.as_bytes()))
+        .map(|directive| {
+            let directive = String::from_utf8_lossy(directive);
+            match directive.split_once('=') {
+                Some((name, argument)) => (
+                    name.trim().to_ascii_lowercase(),
+                    Some(argument.trim().trim_matches('"').to_string()),
+                ),
+                None => (directive.trim().to_ascii_lowercase(), None),
+            }
+        })
+        .collect()
+}
+
+/// Whether a shared cache (one that serves many clients, as a cache in balancebeam would) may store
+/// `response` to `request`, following RFC 7234 section 3. The response must be to a GET or HEAD,
+/// and must not be marked no-store or private (or Pragma: no-cache, for HTTP/1.0 upstreams), nor
+/// vary on everything. A response to a request with credentials is only stored if it says that's
+/// fine. Beyond that the status has to be cacheable by default, or the response has to say how
+/// long it stays fresh.
+pub fn is_cacheable(request: &http::Request<Vec<u8>>, response: &http::Response<Vec<u8>>) -> bool {
+    if request.method() != http::Method::GET && request.method() != http::Method::HEAD {
+        return false;
+    }
+    let directives = cache_control(response.headers());
+    let has = |name: &str| directives.iter().any(|(directive, _)| directive == name);
+    if has("no-store") || has("private") {
+        return false;
+    }
+    let pragma_no_cache = response
+        .headers()
+        .get_all(http::header::PRAGMA)
+        .iter()
+        .flat_map(|value| request::split_list(value.as_bytes()))
+        .any(|directive| directive.eq_ignore_ascii_case(b"no-cache"));
+    if pragma_no_cache {
+        return false;
+    }
+    let vary_all = response
+        .headers()
+        .get_all(http::header::VARY)
+        .iter()
+        .flat_map(|value| request::split_list(value.as_bytes()))
+        .any(|field| field == b"*");
+    if vary_all {
+        return false;
+    }
+    let shareable = has("public") || has("must-revalidate") || has("s-maxage");
+    if request.headers().contains_key(http::header::AUTHORIZATION) && !shareable {
+        return false;
+    }
+    let explicitly_fresh = has("public")
+        || has("max-age")
+        || has("s-maxage")
+        || response.headers().contains_key(http::header::EXPIRES);
+    CACHEABLE_BY_DEFAULT.contains(&response.status().as_u16()) || explicitly_fresh
+}
+
+/// Creates an http::Response with the given body, for responses that balancebeam generates itself.
+pub fn make_response(
+    status: http::StatusCode,
+    content_type: &str,
+    body: Vec<u8>,
+) -> http::Response<Vec<u8>> {
     http::Response::builder()
         .status(status)
-        .header("Content-Type", "text/plain")
+        .header("Content-Type", content_type)
         .header("Content-Length", body.len().to_string())
         .version(http::Version::HTTP_11)
         .body(body)
         .unwrap()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_make_redirect() {
+        let response = make_redirect("https://example.com/a?b=1", true);
+        assert_eq!(response.status(), http::StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()["location"], "https://example.com/a?b=1");
+        assert_eq!(make_redirect("/elsewhere", false).status(), http::StatusCode::FOUND);
+    }
+
+    #[test]
+    fn test_parse_valid_response() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nX-A: 1\r\n\r\nhi";
+        let (response, len) = parse_response(raw, MAX_NUM_HEADERS).unwrap().unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(response.headers().len(), 2);
+        assert_eq!(len, raw.len() - 2);
+    }
+
+    #[test]
+    fn test_parse_incomplete_response() {
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\nX-A:", MAX_NUM_HEADERS)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_parse_malformed_status_line() {
+        for raw in &[
+            &b"HTTP/1.1 abc OK\r\n\r\n"[..],
+            b"HTTP/1.1 20 OK\r\n\r\n",
+            b"garbage garbage\r\n\r\n",
+        ] {
+            assert!(
+                matches!(parse_response(raw, MAX_NUM_HEADERS), Err(Error::MalformedResponse(_))),
+                "{:?}",
+                String::from_utf8_lossy(raw)
+            );
+        }
+        assert!(matches!(
+            parse_response(b"HTTP/1.1 042 Nope\r\n\r\n", MAX_NUM_HEADERS),
+            Err(Error::InvalidStatusCode(42))
+        ));
+    }
+
+    #[test]
+    fn test_parse_too_many_headers() {
+        let mut raw = b"HTTP/1.1 200 OK\r\n".to_vec();
+        for i in 0..5 {
+            raw.extend_from_slice(format!("X-Header-{}: {}\r\n", i, i).as_bytes());
+        }
+        raw.extend_from_slice(b"\r\n");
+        assert!(parse_response(&raw, 5).unwrap().is_some());
+        assert!(matches!(
+            parse_response(&raw, 4),
+            Err(Error::MalformedResponse(httparse::Error::TooManyHeaders))
+        ));
+    }
+
+    #[test]
+    fn test_cache_control() {
+        let mut headers = http::HeaderMap::new();
+        headers.append("cache-control", "No-Cache, max-age=\"60\"".parse().unwrap());
+        headers.append("cache-control", "private=\"set-cookie, x-user\"".parse().unwrap());
+        assert_eq!(
+            cache_control(&headers),
+            vec![
+                ("no-cache".to_string(), None),
+                ("max-age".to_string(), Some("60".to_string())),
+                ("private".to_string(), Some("set-cookie, x-user".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_cacheable() {
+        let get = http::Request::builder().uri("/").body(Vec::new()).unwrap();
+        let response = |status: u16, headers: &[(&str, &str)]| {
+            let mut response = http::Response::builder().status(status);
+            for (name, value) in headers {
+                response = response.header(*name, *value);
+            }
+            response.body(Vec::new()).unwrap()
+        };
+        assert!(is_cacheable(&get, &response(200, &[])));
+        assert!(is_cacheable(&get, &response(404, &[])));
+        assert!(!is_cacheable(&get, &response(302, &[])));
+        assert!(is_cacheable(&get, &response(302, &[("cache-control", "max-age=60")])));
+        assert!(!is_cacheable(&get, &response(500, &[])));
+        for (name, value) in &[
+            ("cache-control", "max-age=60, no-store"),
+            ("cache-control", "private"),
+            ("pragma", "no-cache"),
+            ("vary", "accept-encoding, *"),
+        ] {
+            assert!(!is_cacheable(&get, &response(200, &[(name, value)])), "{}: {}", name, value);
+        }
+        assert!(is_cacheable(&get, &response(200, &[("vary", "accept-encoding")])));
+
+        let post = http::Request::builder().method("POST").uri("/").body(Vec::new()).unwrap();
+        assert!(!is_cacheable(&post, &response(200, &[])));
+        let authorized = http::Request::builder()
+            .uri("/")
+            .header("authorization", "Bearer x")
+            .body(Vec::new())
+            .unwrap();
+        assert!(!is_cacheable(&authorized, &response(200, &[])));
+        assert!(is_cacheable(&authorized, &response(200, &[("cache-control", "public")])));
+        assert!(is_cacheable(&authorized, &response(200, &[("cache-control", "s-maxage=5")])));
+    }
+
+    #[test]
+    fn test_default_content_type() {
+        let default = http::HeaderValue::from_static("application/octet-stream");
+        let mut untyped = http::Response::new(b"\x89PNG".to_vec());
+        assert!(default_content_type(&mut untyped, &default));
+        assert_eq!(untyped.headers()["content-type"], "application/octet-stream");
+
+        let mut typed = make_response(http::StatusCode::OK, "text/html", b"<p>".to_vec());
+        assert!(!default_content_type(&mut typed, &default));
+        assert_eq!(typed.headers()["content-type"], "text/html");
+
+        let mut not_modified = http::Response::new(Vec::new());
+        *not_modified.status_mut() = http::StatusCode::NOT_MODIFIED;
+        assert!(!default_content_type(&mut not_modified, &default));
+        assert!(!not_modified.headers().contains_key("content-type"));
+
+        assert!(parse_content_type("text/plain; charset=utf-8").is_ok());
+        assert!(parse_content_type("plain").is_err());
+    }
+
+    #[test]
+    fn test_invalid_header_value_dropped() {
+        let raw = b"HTTP/1.1 200 OK\r\nX-Good: 1\r\nX-Bad: a\x7fb\r\nBad Name: 1\r\n\r\n";
+        let (response, _) = parse_response(raw, MAX_NUM_HEADERS).unwrap().unwrap();
+        assert_eq!(response.headers().len(), 1);
+        assert!(response.headers().contains_key("x-good"));
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// How far back the budget looks when comparing retries to requests
+const WINDOW_SECS: u64 = 10;
+
+/// Caps retries at a percentage of recent original requests, so that retrying during an incident
+/// can't multiply the load on upstreams that are already struggling. Every retry site asks the
+/// same budget before retrying. A small per-second floor is always available on top, so a quiet
+/// proxy can still fail over away from a dead upstream.
+#[derive(Debug)]
+pub struct RetryBudget {
+    percent: u64,
+    /// Retries per second allowed regardless of traffic
+    min_per_sec: u64,
+    started_at: Instant,
+    /// Per-second (second, requests, retries) counts, oldest first
+    window: Mutex<VecDeque<(u64, u64, u64)>>,
+    /// Retries granted since startup
+    retries: AtomicU64,
+    /// Retries refused since startup
+    suppressed: AtomicU64,
+}
+
+impl RetryBudget {
+    pub fn new(percent: u64, min_per_sec: u64) -> RetryBudget {
+        RetryBudget {
+            percent,
+            min_per_sec,
+            started_at: Instant::now(),
+            window: Mutex::new(VecDeque::new()),
+            retries: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts an original (non-retry) request towards the budget.
+    pub fn record_request(&self) {
+        self.record_request_at(self.now());
+    }
+
+    /// Returns true if a retry is allowed, consuming budget if so.
+    pub fn try_retry(&self) -> bool {
+        self.try_retry_at(self.now())
+    }
+
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed.load(Ordering::Relaxed)
+    }
+
+    /// Fraction of the budget used in the current window (can exceed 1.0 only transiently).
+    pub fn utilization(&self) -> f64 {
+        let (requests, retries) = self.totals_at(self.now());
+        let allowed = self.allowed(requests);
+        if allowed == 0 {
+            0.0
+        } else {
+            (retries * 100) as f64 / allowed as f64
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.started_at.elapsed().as_secs()
+    }
+
+    fn record_request_at(&self, now: u64) {
+        let mut window = self.window.lock().unwrap();
+        Self::bucket(&mut window, now).1 += 1;
+    }
+
+    fn try_retry_at(&self, now: u64) -> bool {
+        let mut window = self.window.lock().unwrap();
+        let (requests, retries) = Self::totals(&mut window, now);
+        if (retries + 1) * 100 <= self.allowed(requests) {
+            Self::bucket(&mut window, now).2 += 1;
+            self.retries.fetch_add(1, Ordering::Relaxed);
+            true
+        } else {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+
+    /// Retries allowed in the window, scaled by 100 to keep the percentage exact.
+    fn allowed(&self, requests: u64) -> u64 {
+        requests * self.percent + self.min_per_sec * WINDOW_SECS * 100
+    }
+
+    fn totals_at(&self, now: u64) -> (u64, u64) {
+        Self::totals(&mut self.window.lock().unwrap(), now)
+    }
+
+    /// Drops buckets that have left the window and sums the rest.
+    fn totals(window: &mut VecDeque<(u64, u64, u64)>, now: u64) -> (u64, u64) {
+        while window.front().map(|b| b.0 + WINDOW_SECS <= now).unwrap_or(false) {
+            window.pop_front();
+        }
+        window
+            .iter()
+            .fold((0, 0), |(requests, retries), b| (requests + b.1, retries + b.2))
+    }
+
+    fn bucket(window: &mut VecDeque<(u64, u64, u64)>, now: u64) -> &mut (u64, u64, u64) {
+        if window.back().map(|b| b.0 != now).unwrap_or(true) {
+            window.push_back((now, 0, 0));
+        }
+        window.back_mut().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retries_limited_to_percentage() {
+        let budget = RetryBudget::new(20, 0);
+        for _ in 0..10 {
+            budget.record_request_at(0);
+        }
+        assert!(budget.try_retry_at(0));
+        assert!(budget.try_retry_at(0));
+        assert!(!budget.try_retry_at(0));
+        assert_eq!(budget.retries(), 2);
+        assert_eq!(budget.suppressed(), 1);
+    }
+
+    #[test]
+    fn test_no_requests_no_retries() {
+        let budget = RetryBudget::new(20, 0);
+        assert!(!budget.try_retry_at(0));
+    }
+
+    #[test]
+    fn test_floor_allows_retries_without_traffic() {
+        let budget = RetryBudget::new(20, 1);
+        for _ in 0..WINDOW_SECS {
+            assert!(budget.try_retry_at(0));
+        }
+        assert!(!budget.try_retry_at(0));
+        // The floor's retries age out with the window
+        assert!(budget.try_retry_at(WINDOW_SECS));
+    }
+
+    #[test]
+    fn test_window_slides() {
+        let budget = RetryBudget::new(50, 0);
+        budget.record_request_at(0);
+        budget.record_request_at(0);
+        assert!(budget.try_retry_at(0));
+        assert!(!budget.try_retry_at(5));
+        // The old requests and retries have aged out together
+        assert!(!budget.try_retry_at(WINDOW_SECS));
+        budget.record_request_at(WINDOW_SECS);
+        budget.record_request_at(WINDOW_SECS);
+        assert!(budget.try_retry_at(WINDOW_SECS));
+    }
+}
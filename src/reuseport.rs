@@ -0,0 +1,113 @@
+use crate::upgrade::nix_error;
+use nix::sys::socket::{self, sockopt, AddressFamily, InetAddr, SockAddr, SockFlag, SockType};
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::FromRawFd;
+use tokio::net::TcpListener;
+
+/// How many connections a listener socket may queue before they are accepted (as tokio's bind)
+const BACKLOG: usize = 1024;
+
+/// Whether the kernel spreads connections across listeners bound to one address with
+/// SO_REUSEPORT. Other platforms may have the option, but hand every connection to one listener.
+pub const SUPPORTED: bool = cfg!(any(target_os = "linux", target_os = "android"));
+
+/// Binds a listener to `address` with SO_REUSEPORT set, so that more listeners can join it on the
+/// same address (see `group`).
+pub async fn bind(address: &str) -> io::Result<TcpListener> {
+    let mut last_error = None;
+    for address in tokio::net::lookup_host(address).await? {
+        match bind_to(address) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_error = Some(err),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} did not resolve to any address", address),
+        )
+    }))
+}
+
+/// `existing` (which must not be empty) and as many more listeners bound to their address as make
+/// `count`, for as many accept loops, with the kernel sharing out incoming connections among
+/// them. The existing listeners must have SO_REUSEPORT set; inherited sockets may not, and then
+/// binding the others fails. More existing listeners than `count` are all kept, as closing one
+/// would reset the connections queued on it.
+pub async fn group(existing: Vec<TcpListener>, count: usize) -> io::Result<Vec<TcpListener>> {
+    let address = existing[0].local_addr()?;
+    let mut group = existing;
+    while group.len() < count {
+        let listener = bind_to(address).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!(
+                    "{} (binding acceptor {} of {}, alongside a socket that needs SO_REUSEPORT)",
+                    err,
+                    group.len() + 1,
+                    count
+                ),
+            )
+        })?;
+        group.push(listener);
+    }
+    Ok(group)
+}
+
+fn bind_to(address: SocketAddr) -> io::Result<TcpListener> {
+    let family = if address.is_ipv4() {
+        AddressFamily::Inet
+    } else {
+        AddressFamily::Inet6
+    };
+    let fd = socket::socket(family, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)
+        .map_err(nix_error)?;
+    // Safety: socket just opened the fd, for us alone
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    socket::setsockopt(fd, sockopt::ReuseAddr, &true)
+        .and_then(|_| socket::setsockopt(fd, sockopt::ReusePort, &true))
+        .and_then(|_| socket::bind(fd, &SockAddr::new_inet(InetAddr::from_std(&address))))
+        .and_then(|_| socket::listen(fd, BACKLOG))
+        .map_err(nix_error)?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connections_are_spread_across_the_group() {
+        if !SUPPORTED {
+            return;
+        }
+        let first = bind("127.0.0.1:0").await.unwrap();
+        let address = first.local_addr().unwrap();
+        let group = group(vec![first], 4).await.unwrap();
+        assert!(group.iter().all(|listener| listener.local_addr().unwrap() == address));
+        // Without SO_REUSEPORT, nothing else may join
+        assert_eq!(
+            TcpListener::bind(address).await.unwrap_err().kind(),
+            io::ErrorKind::AddrInUse
+        );
+
+        let clients: Vec<std::net::TcpStream> =
+            (0..200).map(|_| std::net::TcpStream::connect(address).unwrap()).collect();
+        let mut accepted = Vec::new();
+        for mut listener in group {
+            let mut count = 0;
+            while let Ok(Ok(_)) =
+                tokio::time::timeout(tokio::time::Duration::from_millis(50), listener.accept())
+                    .await
+            {
+                count += 1;
+            }
+            accepted.push(count);
+        }
+        assert_eq!(accepted.iter().sum::<usize>(), clients.len());
+        // Connections are hashed across the listeners; with this many, each gets some
+        assert!(accepted.iter().all(|&count| count > 0), "accepted {:?}", accepted);
+    }
+}
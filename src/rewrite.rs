@@ -0,0 +1,229 @@
+use std::borrow::Cow;
+use std::str::FromStr;
+
+/// A single path prefix substitution, parsed from a `--rewrite-path "match=/api/v1,replace=/"`
+/// option.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathRewrite {
+    /// Prefix of the client-facing path
+    from: String,
+    /// Prefix the upstream expects in its place
+    to: String,
+}
+
+impl FromStr for PathRewrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut from = None;
+        let mut to = None;
+        for part in s.split(',') {
+            match part.trim().split_once('=') {
+                Some(("match", value)) => from = Some(value.trim().to_string()),
+                Some(("replace", value)) => to = Some(value.trim().to_string()),
+                _ => return Err(format!("unrecognized rewrite component \"{}\"", part)),
+            }
+        }
+        let (from, to) = match (from, to) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return Err("a rewrite needs both match= and replace=".to_string()),
+        };
+        if !from.starts_with('/') || !to.starts_with('/') {
+            return Err("rewrite prefixes must start with '/'".to_string());
+        }
+        Ok(PathRewrite { from, to })
+    }
+}
+
+/// If `path` begins with `prefix` on a segment boundary, returns the remainder of the path
+/// (either empty or starting with '/'). "/api/v1" is a prefix of "/api/v1/users" but not of
+/// "/api/v10".
+pub fn strip_segment_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let prefix = prefix.trim_end_matches('/');
+    let rest = path.strip_prefix(prefix)?;
+    if rest.is_empty() || rest.starts_with('/') {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+/// Joins a replacement prefix with the remainder of a path, avoiding doubled or missing slashes.
+fn join_prefix(prefix: &str, rest: &str) -> String {
+    let joined = format!("{}{}", prefix.trim_end_matches('/'), rest);
+    if joined.is_empty() {
+        "/".to_string()
+    } else {
+        joined
+    }
+}
+
+impl PathRewrite {
+    /// Maps a client-facing path to the path the upstream expects, if this rule applies.
+    fn forward(&self, path: &str) -> Option<String> {
+        strip_segment_prefix(path, &self.from).map(|rest| join_prefix(&self.to, rest))
+    }
+
+    /// Maps an upstream path (e.g. from a Location header) back to the client-facing path.
+    fn reverse(&self, path: &str) -> Option<String> {
+        strip_segment_prefix(path, &self.to).map(|rest| join_prefix(&self.from, rest))
+    }
+
+    /// Rewrites a Location header value so that redirects issued by the upstream point back at
+    /// the client-facing path. Path-absolute locations are always mapped; absolute URLs are only
+    /// mapped when they point at `host` (the Host the client asked for). Relative references
+    /// resolve against the client's URL already and are left alone.
+    pub fn reverse_location(&self, location: &str, host: Option<&str>) -> Option<String> {
+        if location.starts_with('/') && !location.starts_with("//") {
+            let (path, suffix) = split_path(location);
+            return self.reverse(path).map(|path| format!("{}{}", path, suffix));
+        }
+        let uri = location.parse::<http::Uri>().ok()?;
+        let (scheme, authority) = (uri.scheme_str()?, uri.authority()?);
+        if host.map(|host| !authority.as_str().eq_ignore_ascii_case(host)).unwrap_or(true) {
+            return None;
+        }
+        let authority_end = location.find(authority.as_str())? + authority.as_str().len();
+        let after_authority = &location[authority_end..];
+        let (path, suffix) = split_path(after_authority);
+        let path = self.reverse(if path.is_empty() { "/" } else { path })?;
+        Some(format!("{}://{}{}{}", scheme, authority, path, suffix))
+    }
+}
+
+/// Splits a path-and-query (possibly with a fragment) into the path and everything after it.
+fn split_path(path_and_rest: &str) -> (&str, &str) {
+    let end = path_and_rest.find(['?', '#']).unwrap_or(path_and_rest.len());
+    path_and_rest.split_at(end)
+}
+
+/// The configured set of path rewrites, ordered so that the longest matching prefix wins.
+#[derive(Debug, Default)]
+pub struct PathRewriter {
+    rules: Vec<PathRewrite>,
+}
+
+impl PathRewriter {
+    pub fn new(mut rules: Vec<PathRewrite>) -> PathRewriter {
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.from.trim_end_matches('/').len()));
+        PathRewriter { rules }
+    }
+
+    /// Rewrites the request's path (leaving the query string untouched) using the longest
+    /// matching rule. Returns the rule that was applied so the response can be mapped back.
+    pub fn rewrite_request(&self, request: &mut http::Request<Vec<u8>>) -> Option<&PathRewrite> {
+        let (rule, path) = self.forward(request.uri().path())?;
+        let path_and_query = match request.uri().query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        let mut parts = request.uri().clone().into_parts();
+        parts.path_and_query = Some(path_and_query.parse().ok()?);
+        *request.uri_mut() = http::Uri::from_parts(parts).ok()?;
+        Some(rule)
+    }
+
+    /// The path a request for `path` is sent upstream with.
+    pub fn upstream_path<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        match self.forward(path) {
+            Some((_, path)) => Cow::Owned(path),
+            None => Cow::Borrowed(path),
+        }
+    }
+
+    fn forward(&self, path: &str) -> Option<(&PathRewrite, String)> {
+        self.rules.iter().find_map(|rule| rule.forward(path).map(|path| (rule, path)))
+    }
+}
+
+/// Maps the Location header of an upstream response back through the rule that rewrote the
+/// request, so that redirects keep working for the client.
+pub fn rewrite_response(
+    rule: &PathRewrite,
+    response: &mut http::Response<Vec<u8>>,
+    host: Option<&str>,
+) {
+    let location = match response.headers().get("location").and_then(|v| v.to_str().ok()) {
+        Some(location) => location,
+        None => return,
+    };
+    if let Some(new_location) = rule.reverse_location(location, host) {
+        if let Ok(value) = http::HeaderValue::from_str(&new_location) {
+            response.headers_mut().insert("location", value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewriter(rules: &[&str]) -> PathRewriter {
+        PathRewriter::new(rules.iter().map(|r| r.parse().unwrap()).collect())
+    }
+
+    fn rewrite(rewriter: &PathRewriter, uri: &str) -> String {
+        let mut request = http::Request::builder().uri(uri).body(Vec::new()).unwrap();
+        rewriter.rewrite_request(&mut request);
+        request.uri().to_string()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!("match=/api/v1,replace=/".parse::<PathRewrite>().is_ok());
+        assert!("match=/api/v1".parse::<PathRewrite>().is_err());
+        assert!("match=api,replace=/".parse::<PathRewrite>().is_err());
+        assert!("match=/a,replace=/b,extra=1".parse::<PathRewrite>().is_err());
+    }
+
+    #[test]
+    fn test_root_replacement() {
+        let r = rewriter(&["match=/api/v1,replace=/"]);
+        assert_eq!(rewrite(&r, "/api/v1/users"), "/users");
+        assert_eq!(rewrite(&r, "/api/v1"), "/");
+        assert_eq!(rewrite(&r, "/api/v1/"), "/");
+        assert_eq!(rewrite(&r, "/api/v1/users?id=3&x=/api/v1"), "/users?id=3&x=/api/v1");
+        assert_eq!(rewrite(&r, "/api/v10/users"), "/api/v10/users");
+        assert_eq!(rewrite(&r, "/other"), "/other");
+    }
+
+    #[test]
+    fn test_nested_prefixes_longest_wins() {
+        let r = rewriter(&["match=/api,replace=/legacy", "match=/api/v2,replace=/v2/"]);
+        assert_eq!(rewrite(&r, "/api/v2/items"), "/v2/items");
+        assert_eq!(rewrite(&r, "/api/v1/items"), "/legacy/v1/items");
+        assert_eq!(rewrite(&r, "/api"), "/legacy");
+    }
+
+    #[test]
+    fn test_location_relative() {
+        let rule: PathRewrite = "match=/api/v1,replace=/".parse().unwrap();
+        assert_eq!(rule.reverse_location("/users/1", None).unwrap(), "/api/v1/users/1");
+        assert_eq!(rule.reverse_location("/", None).unwrap(), "/api/v1/");
+        assert_eq!(rule.reverse_location("/a?next=/b#frag", None).unwrap(), "/api/v1/a?next=/b#frag");
+        // Relative references and protocol-relative URLs aren't touched
+        assert_eq!(rule.reverse_location("users/1", None), None);
+        assert_eq!(rule.reverse_location("//cdn.example.com/x", None), None);
+
+        let rule: PathRewrite = "match=/app,replace=/internal".parse().unwrap();
+        assert_eq!(rule.reverse_location("/internal/login", None).unwrap(), "/app/login");
+        assert_eq!(rule.reverse_location("/internalx", None), None);
+        assert_eq!(rule.reverse_location("/elsewhere", None), None);
+    }
+
+    #[test]
+    fn test_location_absolute() {
+        let rule: PathRewrite = "match=/api/v1,replace=/".parse().unwrap();
+        assert_eq!(
+            rule.reverse_location("http://example.com/users?x=1", Some("example.com")).unwrap(),
+            "http://example.com/api/v1/users?x=1"
+        );
+        assert_eq!(
+            rule.reverse_location("https://Example.com:8443", Some("example.com:8443")).unwrap(),
+            "https://Example.com:8443/api/v1/"
+        );
+        // Redirects to other sites are left alone
+        assert_eq!(rule.reverse_location("http://other.com/users", Some("example.com")), None);
+        assert_eq!(rule.reverse_location("http://example.com/users", None), None);
+    }
+}
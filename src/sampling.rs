@@ -0,0 +1,136 @@
+use std::str::FromStr;
+
+/// Decides which requests get verbose (debug-level) logging. Sampling is a pure function of the
+/// request ID, so every log line for a sampled request is emitted and a request is never half
+/// traced.
+#[derive(Debug, Clone, Copy)]
+pub struct LogSampler {
+    /// One in this many requests is sampled (0 means none are)
+    one_in: u64,
+}
+
+/// Parses a `--log-sample-rate` value: either a fraction of requests ("0.01") or a whole number N
+/// meaning one in N requests ("100"). "1" means every request either way.
+pub fn parse_sample_rate(value: &str) -> Result<u64, String> {
+    if let Ok(one_in) = value.parse::<u64>() {
+        return Ok(one_in);
+    }
+    match value.parse::<f64>() {
+        Ok(fraction) if fraction > 0.0 && fraction <= 1.0 => Ok((1.0 / fraction).round() as u64),
+        _ => Err("expected a fraction between 0 and 1, or a whole number".to_string()),
+    }
+}
+
+impl LogSampler {
+    pub fn new(one_in: u64) -> LogSampler {
+        LogSampler { one_in }
+    }
+
+    /// Returns true if the request with this ID should be logged verbosely.
+    pub fn is_verbose(&self, request_id: u64) -> bool {
+        // Request IDs are sequential, so mix them first; otherwise a client that always sends N
+        // requests per connection could end up always (or never) sampled.
+        self.one_in != 0 && mix(request_id) % self.one_in == 0
+    }
+}
+
+/// A `--access-log-exclude-path` option: an exact path ("/healthz"), or a prefix if it ends with
+/// `*` ("/internal/*").
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExcludedPath {
+    path: String,
+    prefix: bool,
+}
+
+impl FromStr for ExcludedPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.starts_with('/') {
+            return Err("path must start with /".to_string());
+        }
+        Ok(match s.strip_suffix('*') {
+            Some(prefix) => ExcludedPath { path: prefix.to_string(), prefix: true },
+            None => ExcludedPath { path: s.to_string(), prefix: false },
+        })
+    }
+}
+
+impl ExcludedPath {
+    pub fn matches(&self, path: &str) -> bool {
+        if self.prefix {
+            path.starts_with(&self.path)
+        } else {
+            path == self.path
+        }
+    }
+}
+
+/// The splitmix64 finalizer: a cheap bijection that scatters sequential integers.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_everything_or_nothing() {
+        let sampler = LogSampler::new(1);
+        assert!((0..1000).all(|id| sampler.is_verbose(id)));
+        let sampler = LogSampler::new(0);
+        assert!(!(0..1000).any(|id| sampler.is_verbose(id)));
+    }
+
+    #[test]
+    fn test_excluded_paths() {
+        let exact: ExcludedPath = "/healthz".parse().unwrap();
+        assert!(exact.matches("/healthz"));
+        assert!(!exact.matches("/healthz/deep"));
+        let prefix: ExcludedPath = "/internal/*".parse().unwrap();
+        assert!(prefix.matches("/internal/"));
+        assert!(prefix.matches("/internal/metrics"));
+        assert!(!prefix.matches("/internal"));
+        assert!("healthz".parse::<ExcludedPath>().is_err());
+    }
+
+    #[test]
+    fn test_parse_sample_rate() {
+        assert_eq!(parse_sample_rate("100"), Ok(100));
+        assert_eq!(parse_sample_rate("0.01"), Ok(100));
+        assert_eq!(parse_sample_rate("1"), Ok(1));
+        assert_eq!(parse_sample_rate("1.0"), Ok(1));
+        assert_eq!(parse_sample_rate("0"), Ok(0));
+        assert!(parse_sample_rate("0.0").is_err());
+        assert!(parse_sample_rate("1.5").is_err());
+        assert!(parse_sample_rate("often").is_err());
+    }
+
+    #[test]
+    fn test_sample_ratio() {
+        for one_in in &[2, 10, 100] {
+            let sampler = LogSampler::new(*one_in);
+            let n = 5000;
+            let sampled = (0..n).filter(|id| sampler.is_verbose(*id)).count() as f64;
+            let expected = n as f64 / *one_in as f64;
+            assert!(
+                (sampled - expected).abs() < expected * 0.3 + 3.0,
+                "sampled {} of {} with one_in={}",
+                sampled,
+                n,
+                one_in
+            );
+        }
+    }
+
+    #[test]
+    fn test_sampling_is_deterministic() {
+        let sampler = LogSampler::new(7);
+        let first: Vec<bool> = (0..100).map(|id| sampler.is_verbose(id)).collect();
+        let second: Vec<bool> = (0..100).map(|id| sampler.is_verbose(id)).collect();
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::{request, response, Listener, ProxyState};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration, Instant};
+
+/// How long the request sent through a listener may take, upstream included
+const PROXY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The parts of starting up that `--self-test` checks, in order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    /// Binding the listeners
+    Bind,
+    /// Finding a healthy upstream in every pool
+    UpstreamHealth,
+    /// Sending a request through each listener and getting a 2xx back
+    ProxyPath,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stage::Bind => write!(f, "bind"),
+            Stage::UpstreamHealth => write!(f, "upstream health"),
+            Stage::ProxyPath => write!(f, "proxy path"),
+        }
+    }
+}
+
+/// Why the self-test failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    pub stage: Stage,
+    pub reason: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Self-test failed at the {} stage: {}", self.stage, self.reason)
+    }
+}
+
+/// Health checks every upstream once, marking the ones that fail dead, and then sends a GET for
+/// `path` (or the pool's health check path) through each listener. Returns the slowest of those
+/// requests' round trips.
+pub async fn run(
+    state: &Arc<ProxyState>,
+    listeners: &[Arc<Listener>],
+    path: Option<&str>,
+) -> Result<Duration, Failure> {
+    for pool in &state.pools {
+        let check = crate::health_check(state, pool);
+        let mut healthy = 0;
+        for upstream in pool.upstreams.snapshot() {
+            if state.health_check_disabled.contains(&upstream.address) {
+                healthy += 1;
+                continue;
+            }
+            match check.probe(&upstream.address).await {
+                Ok(()) => healthy += 1,
+                Err(failure) => {
+                    log::warn!("Self-test: upstream {} failed: {}", upstream.address, failure);
+                    pool.upstreams_state.write().await.set_dead(upstream.id);
+                }
+            }
+        }
+        if healthy == 0 {
+            return Err(Failure {
+                stage: Stage::UpstreamHealth,
+                reason: format!("no upstream in pool {} passed its health check", pool.name),
+            });
+        }
+    }
+
+    let mut slowest = Duration::default();
+    for listener in listeners {
+        let path = path.unwrap_or(&listener.pool.active_health_check_path);
+        let started = Instant::now();
+        let result = timeout(PROXY_TIMEOUT, send(state, listener, path)).await;
+        let status = match result {
+            Ok(Ok(status)) => status,
+            Ok(Err(err)) => {
+                return Err(Failure {
+                    stage: Stage::ProxyPath,
+                    reason: format!("GET {} through {}: {}", path, listener.bind, err),
+                })
+            }
+            Err(_) => {
+                return Err(Failure {
+                    stage: Stage::ProxyPath,
+                    reason: format!("GET {} through {} timed out", path, listener.bind),
+                })
+            }
+        };
+        if !status.is_success() {
+            return Err(Failure {
+                stage: Stage::ProxyPath,
+                reason: format!("GET {} through {} got {}", path, listener.bind, status),
+            });
+        }
+        let latency = started.elapsed();
+        log::info!(
+            "Self-test: GET {} through {} got {} in {:?}",
+            path,
+            listener.bind,
+            status,
+            latency
+        );
+        slowest = slowest.max(latency);
+    }
+    Ok(slowest)
+}
+
+/// Sends a GET for `path` through `listener` on a loopback connection of its own: the listener's
+/// sockets aren't accepted from until the self-test has passed.
+async fn send(
+    state: &Arc<ProxyState>,
+    listener: &Arc<Listener>,
+    path: &str,
+) -> Result<http::StatusCode, String> {
+    let mut loopback =
+        TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.map_err(|err| err.to_string())?;
+    let address = loopback.local_addr().map_err(|err| err.to_string())?;
+    let (mut stream, (client_conn, _)) =
+        tokio::try_join!(TcpStream::connect(address), loopback.accept())
+            .map_err(|err| err.to_string())?;
+    tokio::spawn(crate::handle_connection(client_conn, state.clone(), listener.clone()));
+    let request = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(path)
+        .header("Host", listener.bind.as_str())
+        .header("Connection", "close")
+        .body(Vec::new())
+        .unwrap();
+    request::write_to_stream(&request, &mut stream).await.map_err(|err| err.to_string())?;
+    let limits = &state.response_limits;
+    let response = response::read_from_stream(&mut stream, &http::Method::GET, limits)
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok(response.status())
+}
@@ -0,0 +1,140 @@
+use crate::error_reason::ErrorReason;
+use crate::ProxyState;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use std::sync::atomic::Ordering;
+use tokio::time::{delay_for, Duration, Instant};
+
+/// What was left when balancebeam shut down.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct ShutdownResult {
+    /// Requests read from clients since balancebeam started
+    pub requests_processed: u64,
+    /// Upstreams alive across every pool
+    pub upstreams_alive: usize,
+    /// Client connections still open when the shutdown timeout ran out
+    pub connections_abandoned: usize,
+}
+
+/// Waits for SIGTERM or SIGINT, returning the name of the one that arrived. Never returns if the
+/// signals can't be listened for.
+pub async fn signalled() -> &'static str {
+    let signals = (signal(SignalKind::terminate()), signal(SignalKind::interrupt()));
+    let (mut terminate, mut interrupt) = match signals {
+        (Ok(terminate), Ok(interrupt)) => (terminate, interrupt),
+        (Err(err), _) | (_, Err(err)) => {
+            log::warn!("Could not listen for SIGTERM and SIGINT; graceful shutdown is off: {}", err);
+            return std::future::pending().await;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    }
+}
+
+/// Stops balancebeam: the accept loops, health checks and rate limiter updates stop, and open
+/// client connections get the shutdown timeout to finish what they are doing. Also what tests call
+/// to tear down a `ProxyState` they started.
+pub async fn shutdown(state: Arc<ProxyState>) -> ShutdownResult {
+    let _ = state.shutdown.send(());
+    let connections_abandoned = drain(&state, state.shutdown_timeout).await;
+    let mut upstreams_alive = 0;
+    for pool in &state.pools {
+        let upstreams_state = pool.upstreams_state.read().await;
+        upstreams_alive += pool
+            .upstreams
+            .snapshot()
+            .iter()
+            .filter(|upstream| upstreams_state.is_alive(upstream.id))
+            .count();
+    }
+    ShutdownResult {
+        requests_processed: state.next_request_id.load(Ordering::Relaxed),
+        upstreams_alive,
+        connections_abandoned,
+    }
+}
+
+/// Asks every client connection to close once it has sent the response it is working on, and
+/// waits up to `timeout` for them to go. Idle keep-alive connections close straight away. Returns
+/// the number of connections still open.
+pub async fn drain(state: &ProxyState, timeout: Duration) -> usize {
+    let deadline = Instant::now() + timeout;
+    loop {
+        // Asked again each time round, for connections accepted just before the listeners closed
+        for connection in state.connections.snapshot() {
+            state.connections.close(connection.id);
+        }
+        let open = state.connections.len();
+        if open == 0 || Instant::now() >= deadline {
+            return open;
+        }
+        delay_for(Duration::from_millis(50)).await;
+    }
+}
+
+/// A summary of everything balancebeam did since it started, for postmortems. Only takes locks
+/// that can't be held across an await, so that it can be built from a panic hook.
+pub fn report(state: &ProxyState, reason: &str) -> serde_json::Value {
+    let mut responses = serde_json::Map::new();
+    for (class, count) in state.metrics.responses_by_class().iter().enumerate() {
+        responses.insert(format!("{}xx", class + 1), (*count).into());
+    }
+    let mut upstreams = Vec::new();
+    for pool in &state.pools {
+        for upstream in pool.upstreams.snapshot() {
+            upstreams.push(serde_json::json!({
+                "pool": pool.name,
+                "address": upstream.address,
+                "requests": upstream.stats.total_requests(),
+                "errors": upstream.stats.errors(),
+            }));
+        }
+    }
+    serde_json::json!({
+        "shutdown_reason": reason,
+        "version": env!("CARGO_PKG_VERSION"),
+        "uptime_secs": state.started_at.elapsed().as_secs_f64(),
+        "responses": responses,
+        "upstreams": upstreams,
+        "peak_connections": state.connections.peak(),
+        "peak_buffered_bytes": state.buffer_budget.peak(),
+        "rate_limited": state.metrics.errors(ErrorReason::RateLimited),
+    })
+}
+
+/// Writes the report as one line of JSON, appended to `path` if given or logged otherwise.
+pub fn write_report(state: &ProxyState, reason: &str, path: Option<&Path>) {
+    let report = report(state, reason);
+    if let Some(path) = path {
+        let written = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", report));
+        match written {
+            Ok(()) => {
+                log::info!("Wrote the shutdown report to {:?}", path);
+                return;
+            }
+            Err(err) => log::error!("Could not write the shutdown report to {:?}: {}", path, err),
+        }
+    }
+    log::info!("Shutdown report: {}", report);
+}
+
+/// Writes a best-effort report when balancebeam panics on its main thread, which takes the whole
+/// process down. A panic in a connection's task only ends that task, so it gets no report.
+pub fn install_panic_hook(state: Arc<ProxyState>, path: Option<PathBuf>) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        if std::thread::current().name() == Some("main") {
+            write_report(&state, "panic", path.as_deref());
+        }
+    }));
+}
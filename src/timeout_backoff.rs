@@ -0,0 +1,106 @@
+use tokio::time::Duration;
+
+/// How an upstream's response timeout adapts to its latency. An upstream that keeps answering
+/// right at the timeout is probably overloaded rather than dead, so each near-timeout response
+/// stretches its timeout by `factor` (up to `max`), and a run of fast responses resets it.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeoutPolicy {
+    pub base: Duration,
+    pub factor: f64,
+    pub max: Duration,
+    /// Number of consecutive fast responses after which the timeout goes back to `base`
+    pub recovery_requests: usize,
+}
+
+/// Per-upstream state for TimeoutPolicy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveTimeout {
+    /// The timeout currently applied to this upstream's responses
+    pub effective: Duration,
+    fast_streak: usize,
+}
+
+impl TimeoutPolicy {
+    /// Returns false if timeouts never change, so callers can skip recording response times. A
+    /// zero base means there is no timeout to adapt.
+    pub fn is_adaptive(&self) -> bool {
+        self.factor > 1.0 && self.base > Duration::from_secs(0)
+    }
+
+    pub fn initial(&self) -> AdaptiveTimeout {
+        AdaptiveTimeout {
+            effective: self.base,
+            fast_streak: 0,
+        }
+    }
+
+    /// Updates an upstream's timeout after a response took `elapsed` (or after it timed out, in
+    /// which case `elapsed` is the timeout itself).
+    pub fn record(&self, timeout: &mut AdaptiveTimeout, elapsed: Duration) {
+        if elapsed.as_secs_f64() > timeout.effective.as_secs_f64() * 0.9 {
+            timeout.fast_streak = 0;
+            let stretched = timeout.effective.as_secs_f64() * self.factor;
+            timeout.effective = Duration::from_secs_f64(stretched.min(self.max.as_secs_f64()))
+                .max(self.base);
+        } else if timeout.effective != self.base {
+            timeout.fast_streak += 1;
+            if timeout.fast_streak >= self.recovery_requests {
+                *timeout = self.initial();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(factor: f64) -> TimeoutPolicy {
+        TimeoutPolicy {
+            base: Duration::from_millis(1000),
+            factor,
+            max: Duration::from_millis(3000),
+            recovery_requests: 3,
+        }
+    }
+
+    #[test]
+    fn test_no_backoff_by_default() {
+        let policy = policy(1.0);
+        let mut timeout = policy.initial();
+        policy.record(&mut timeout, Duration::from_millis(1000));
+        assert_eq!(timeout.effective, Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_backoff_up_to_max() {
+        let policy = policy(2.0);
+        let mut timeout = policy.initial();
+        policy.record(&mut timeout, Duration::from_millis(500));
+        assert_eq!(timeout.effective, Duration::from_millis(1000));
+        policy.record(&mut timeout, Duration::from_millis(950));
+        assert_eq!(timeout.effective, Duration::from_millis(2000));
+        policy.record(&mut timeout, Duration::from_millis(1900));
+        assert_eq!(timeout.effective, Duration::from_millis(3000));
+        policy.record(&mut timeout, Duration::from_millis(3000));
+        assert_eq!(timeout.effective, Duration::from_millis(3000));
+    }
+
+    #[test]
+    fn test_recovery_needs_consecutive_fast_responses() {
+        let policy = policy(2.0);
+        let mut timeout = policy.initial();
+        policy.record(&mut timeout, Duration::from_millis(1000));
+        policy.record(&mut timeout, Duration::from_millis(10));
+        policy.record(&mut timeout, Duration::from_millis(10));
+        // A slow response breaks the streak
+        policy.record(&mut timeout, Duration::from_millis(1900));
+        assert_eq!(timeout.effective, Duration::from_millis(3000));
+        for _ in 0..2 {
+            policy.record(&mut timeout, Duration::from_millis(10));
+        }
+        assert_eq!(timeout.effective, Duration::from_millis(3000));
+        policy.record(&mut timeout, Duration::from_millis(10));
+        assert_eq!(timeout, policy.initial());
+    }
+}
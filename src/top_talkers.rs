@@ -0,0 +1,322 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::Duration;
+
+/// Length of the windows traffic is counted in, in seconds
+const WINDOW_SECS: u64 = 300;
+/// Number of independently locked parts each window's counts are split into (by key), so that
+/// requests from different clients rarely wait for each other
+const SHARDS: usize = 8;
+
+/// Estimated traffic for one client or path prefix. Counts are never below the true ones, and
+/// never above them by more than `error`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Counter {
+    /// Request and response body bytes
+    pub bytes: u64,
+    pub requests: u64,
+    /// How many of the bytes may belong to keys this one took the place of
+    pub error: u64,
+}
+
+/// Keys and their counts, most bytes first
+pub type Ranking = Vec<(String, Counter)>;
+
+/// A weighted space-saving sketch: counts bytes for at most `capacity` keys. A key that isn't
+/// counted yet takes the place of the one with the fewest bytes when the sketch is full, and
+/// inherits its count, which makes every count an overestimate by at most the total counted
+/// divided by the capacity. Any key with more than that share is always among those counted.
+///
+/// The counted keys are kept in a min-heap on bytes, indexed by key, so that recording takes
+/// O(log capacity) time whether or not the key is already counted.
+#[derive(Debug)]
+pub struct SpaceSaving {
+    capacity: usize,
+    /// Keys and their counts, the one with the fewest bytes first
+    heap: Vec<(String, Counter)>,
+    /// Where each key is in `heap`
+    positions: HashMap<String, usize>,
+}
+
+impl SpaceSaving {
+    pub fn new(capacity: usize) -> SpaceSaving {
+        SpaceSaving {
+            capacity: capacity.max(1),
+            heap: Vec::new(),
+            positions: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, key: &str, bytes: u64) {
+        if let Some(&position) = self.positions.get(key) {
+            let counter = &mut self.heap[position].1;
+            counter.bytes += bytes;
+            counter.requests += 1;
+            self.sift_down(position);
+            return;
+        }
+        let counter = Counter {
+            bytes,
+            requests: 1,
+            error: 0,
+        };
+        if self.heap.len() < self.capacity {
+            self.positions.insert(key.to_string(), self.heap.len());
+            self.heap.push((key.to_string(), counter));
+            self.sift_up(self.heap.len() - 1);
+            return;
+        }
+        // Take the place of the key with the fewest bytes
+        let (replaced_key, replaced) =
+            std::mem::replace(&mut self.heap[0], (key.to_string(), counter));
+        self.positions.remove(&replaced_key);
+        self.positions.insert(key.to_string(), 0);
+        self.heap[0].1 = Counter {
+            bytes: replaced.bytes + bytes,
+            requests: replaced.requests + 1,
+            error: replaced.bytes,
+        };
+        self.sift_down(0);
+    }
+
+    pub fn clear(&mut self) {
+        self.heap.clear();
+        self.positions.clear();
+    }
+
+    pub fn counters(&self) -> impl Iterator<Item = (&String, &Counter)> {
+        self.heap.iter().map(|(key, counter)| (key, counter))
+    }
+
+    fn sift_up(&mut self, mut position: usize) {
+        while position > 0 {
+            let parent = (position - 1) / 2;
+            if self.heap[parent].1.bytes <= self.heap[position].1.bytes {
+                break;
+            }
+            self.swap(parent, position);
+            position = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut position: usize) {
+        loop {
+            let mut smallest = position;
+            for child in &[2 * position + 1, 2 * position + 2] {
+                match self.heap.get(*child) {
+                    Some((_, counter)) if counter.bytes < self.heap[smallest].1.bytes => {
+                        smallest = *child;
+                    }
+                    _ => {}
+                }
+            }
+            if smallest == position {
+                break;
+            }
+            self.swap(smallest, position);
+            position = smallest;
+        }
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.heap.swap(a, b);
+        *self.positions.get_mut(&self.heap[a].0).unwrap() = a;
+        *self.positions.get_mut(&self.heap[b].0).unwrap() = b;
+    }
+}
+
+/// One kind of key's sketches for one window, split into shards by key.
+#[derive(Debug)]
+struct Sharded {
+    /// The window each shard's counts are for, and the counts
+    shards: Vec<Mutex<(u64, SpaceSaving)>>,
+}
+
+impl Sharded {
+    fn new(capacity: usize) -> Sharded {
+        let per_shard = capacity.div_ceil(SHARDS);
+        Sharded {
+            shards: (0..SHARDS).map(|_| Mutex::new((0, SpaceSaving::new(per_shard)))).collect(),
+        }
+    }
+
+    fn record(&self, window: u64, key: &str, bytes: u64) {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let mut shard = self.shards[hasher.finish() as usize % SHARDS].lock().unwrap();
+        // Whatever is here is from a window that has rolled out of the retention period
+        if shard.0 != window {
+            shard.1.clear();
+            shard.0 = window;
+        }
+        shard.1.record(key, bytes);
+    }
+
+    /// Adds this window's counts to `totals`, if they are from a window still retained.
+    fn add_to(&self, totals: &mut HashMap<String, Counter>, now: u64, windows: u64) {
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            if now.saturating_sub(shard.0) >= windows {
+                continue;
+            }
+            for (key, counter) in shard.1.counters() {
+                let total = totals.entry(key.clone()).or_default();
+                total.bytes += counter.bytes;
+                total.requests += counter.requests;
+                total.error += counter.error;
+            }
+        }
+    }
+}
+
+/// Which clients and which paths made the most traffic recently (`--top-k-entries`), for capacity
+/// planning. Traffic is counted in five-minute windows, kept for `--top-k-retention`; each window
+/// counts at most `--top-k-entries` clients and as many path prefixes, so memory is bounded
+/// whatever the traffic looks like, at the cost of some overcounting.
+#[derive(Debug)]
+pub struct TopTalkers {
+    /// (clients, paths) per window, reused round-robin
+    windows: Vec<(Sharded, Sharded)>,
+}
+
+impl TopTalkers {
+    pub fn new(entries: usize, retention: Duration) -> TopTalkers {
+        let windows = retention.as_secs().div_ceil(WINDOW_SECS).max(1);
+        TopTalkers {
+            windows: (0..windows).map(|_| (Sharded::new(entries), Sharded::new(entries))).collect(),
+        }
+    }
+
+    /// Counts a request from `client` for `path`, and the `bytes` of body it and its response had.
+    pub fn record(&self, client: &str, path: &str, bytes: u64) {
+        self.record_at(client, path, bytes, current_window());
+    }
+
+    /// The `n` clients and the `n` path prefixes with the most bytes over the retention period,
+    /// most first.
+    pub fn top(&self, n: usize) -> (Ranking, Ranking) {
+        self.top_at(n, current_window())
+    }
+
+    fn record_at(&self, client: &str, path: &str, bytes: u64, window: u64) {
+        let (clients, paths) = &self.windows[window as usize % self.windows.len()];
+        clients.record(window, client, bytes);
+        paths.record(window, path_prefix(path), bytes);
+    }
+
+    fn top_at(&self, n: usize, now: u64) -> (Ranking, Ranking) {
+        let windows = self.windows.len() as u64;
+        let mut clients = HashMap::new();
+        let mut paths = HashMap::new();
+        for (window_clients, window_paths) in &self.windows {
+            window_clients.add_to(&mut clients, now, windows);
+            window_paths.add_to(&mut paths, now, windows);
+        }
+        (ranked(clients, n), ranked(paths, n))
+    }
+}
+
+fn ranked(totals: HashMap<String, Counter>, n: usize) -> Ranking {
+    let mut totals: Ranking = totals.into_iter().collect();
+    totals.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then_with(|| a.0.cmp(&b.0)));
+    totals.truncate(n);
+    totals
+}
+
+/// The first segment of a path ("/api" for "/api/users/7"), which paths are counted by.
+pub fn path_prefix(path: &str) -> &str {
+    match path.get(1..).and_then(|rest| rest.find('/')) {
+        Some(end) => &path[..end + 1],
+        None => path,
+    }
+}
+
+fn current_window() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / WINDOW_SECS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_space_saving_error_bounds() {
+        let capacity = 50;
+        let mut sketch = SpaceSaving::new(capacity);
+        let mut truth: HashMap<String, u64> = HashMap::new();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(198);
+        let mut total = 0;
+        for _ in 0..100_000 {
+            // A few heavy keys among a long tail of light ones
+            let key = if rng.gen_bool(0.3) {
+                format!("heavy-{}", rng.gen_range(0, 5))
+            } else {
+                format!("light-{}", rng.gen_range(0, 5000))
+            };
+            let bytes = rng.gen_range(1, 1000);
+            sketch.record(&key, bytes);
+            *truth.entry(key).or_default() += bytes;
+            total += bytes;
+        }
+        let bound = total / capacity as u64;
+        assert!(sketch.counters().count() <= capacity);
+        assert_eq!(sketch.positions.len(), sketch.heap.len());
+        for (position, (key, counter)) in sketch.heap.iter().enumerate() {
+            assert_eq!(sketch.positions[key], position);
+            assert!(position == 0 || sketch.heap[(position - 1) / 2].1.bytes <= counter.bytes);
+        }
+        for (key, counter) in sketch.counters() {
+            let true_bytes = truth[key];
+            assert!(counter.bytes >= true_bytes, "{} undercounted", key);
+            assert!(counter.bytes - true_bytes <= counter.error, "{} overcounted", key);
+            assert!(counter.error <= bound, "{} has error {} over {}", key, counter.error, bound);
+        }
+        // Every key with more than its share is counted
+        for (key, true_bytes) in &truth {
+            if *true_bytes > bound {
+                assert!(sketch.positions.contains_key(key), "{} not counted", key);
+            }
+        }
+    }
+
+    #[test]
+    fn test_windows_expire() {
+        let talkers = TopTalkers::new(100, Duration::from_secs(600));
+        talkers.record_at("10.0.0.1", "/api/a", 500, 1000);
+        talkers.record_at("10.0.0.2", "/static/x.png", 100, 1000);
+        talkers.record_at("10.0.0.2", "/api/b", 200, 1001);
+
+        let (clients, paths) = talkers.top_at(10, 1001);
+        assert_eq!(clients[0].0, "10.0.0.1");
+        let counter = |bytes, requests| Counter {
+            bytes,
+            requests,
+            error: 0,
+        };
+        assert_eq!(clients[1], ("10.0.0.2".to_string(), counter(300, 2)));
+        assert_eq!(paths[0], ("/api".to_string(), counter(700, 2)));
+        assert_eq!(paths[1].0, "/static");
+
+        // Window 1000 has aged out, and window 1002 reuses its slot
+        talkers.record_at("10.0.0.3", "/", 1, 1002);
+        let (clients, _) = talkers.top_at(10, 1002);
+        let keys: Vec<&str> = clients.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["10.0.0.2", "10.0.0.3"]);
+    }
+
+    #[test]
+    fn test_path_prefix() {
+        assert_eq!(path_prefix("/api/users/7"), "/api");
+        assert_eq!(path_prefix("/favicon.ico"), "/favicon.ico");
+        assert_eq!(path_prefix("/"), "/");
+        assert_eq!(path_prefix("*"), "*");
+    }
+}
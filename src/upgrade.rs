@@ -0,0 +1,239 @@
+use crate::admin::Api;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::str::FromStr;
+use tokio::net::TcpListener;
+use tokio::time::{timeout, Duration};
+
+/// How long a new binary gets to say it is ready before the upgrade is abandoned
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What a new binary writes to its readiness pipe once it is serving
+const READY_MESSAGE: &[u8] = b"ready\n";
+
+/// Options an upgrade sets on the new process, which aren't passed on again from there
+const UPGRADE_OPTIONS: [&str; 2] = ["--inherit-listener-fd", "--upgrade-ready-fd"];
+
+/// The first file descriptor systemd passes listener sockets in (SD_LISTEN_FDS_START)
+const SYSTEMD_FIRST_FD: RawFd = 3;
+
+/// Already-bound listener sockets handed over by whatever started balancebeam, parsed from
+/// `--inherit-listener-fd 3` (the next proxy listener, in order) or
+/// `--inherit-listener-fd admin=5`. A proxy listener accepting on several SO_REUSEPORT sockets
+/// hands over all of them, as `--inherit-listener-fd 3,4,5`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InheritedFd {
+    /// The internal listener it serves, or None for a proxy listener
+    pub api: Option<Api>,
+    pub fds: Vec<RawFd>,
+}
+
+impl FromStr for InheritedFd {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (api, fd) = match s.trim().split_once('=') {
+            Some(("admin", fd)) => (Some(Api::Admin), fd),
+            Some(("metrics", fd)) => (Some(Api::Metrics), fd),
+            Some((name, _)) => return Err(format!("unknown listener \"{}\"", name)),
+            None => (None, s.trim()),
+        };
+        let fds = fd
+            .split(',')
+            .map(|fd| match fd.trim().parse() {
+                Ok(fd) if fd >= 0 => Ok(fd),
+                _ => Err(format!("\"{}\" is not a file descriptor", fd)),
+            })
+            .collect::<Result<Vec<RawFd>, String>>()?;
+        match api {
+            Some(api) if fds.len() > 1 => {
+                Err(format!("the {} listener takes a single socket", api.name()))
+            }
+            _ => Ok(InheritedFd { api, fds }),
+        }
+    }
+}
+
+impl fmt::Display for InheritedFd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fds: Vec<String> = self.fds.iter().map(RawFd::to_string).collect();
+        match self.api {
+            Some(api) => write!(f, "{}={}", api.name(), fds.join(",")),
+            None => write!(f, "{}", fds.join(",")),
+        }
+    }
+}
+
+/// The listener sockets passed by systemd socket activation: LISTEN_FDS of them starting at fd 3,
+/// if LISTEN_PID says they are meant for this process. They stand in for the proxy listeners.
+pub fn systemd_listeners() -> Vec<InheritedFd> {
+    let pid = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<i32>().ok());
+    if pid != Some(nix::unistd::getpid().as_raw()) {
+        return Vec::new();
+    }
+    let count = std::env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<RawFd>().ok());
+    (SYSTEMD_FIRST_FD..SYSTEMD_FIRST_FD + count.unwrap_or(0))
+        .map(|fd| InheritedFd { api: None, fds: vec![fd] })
+        .collect()
+}
+
+/// Takes ownership of an inherited listener socket. It is marked close-on-exec again, so that it
+/// is only passed on by another upgrade.
+pub fn listener(fd: RawFd) -> io::Result<TcpListener> {
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(nix_error)?;
+    // Safety: the fd was handed to this process to own, and nothing else here uses it
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    // Fails if the fd isn't a socket
+    listener.local_addr()?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+/// Why an upgrade didn't happen.
+#[derive(Debug)]
+pub enum Error {
+    /// The new binary couldn't be started
+    Spawn(io::Error),
+    /// The new binary exited, or took too long, before saying it was ready
+    NotReady(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Spawn(err) => write!(f, "could not start the new binary: {}", err),
+            Error::NotReady(reason) => write!(f, "the new binary did not become ready: {}", reason),
+        }
+    }
+}
+
+/// Starts `binary` with this process's arguments, handing it `listeners`, and waits for it to say
+/// it is ready. Returns its pid: from then on it accepts connections alongside this process, which
+/// should stop accepting straight away and drain.
+pub async fn spawn(binary: &Path, listeners: &[InheritedFd]) -> Result<u32, Error> {
+    let (ready_read, ready_write) =
+        nix::unistd::pipe2(OFlag::O_CLOEXEC).map_err(|err| Error::Spawn(nix_error(err)))?;
+    // Safety: pipe2 just opened the read end for us alone
+    let mut ready = unsafe { File::from_raw_fd(ready_read) };
+
+    let mut command = std::process::Command::new(binary);
+    command.args(passed_on(std::env::args_os().skip(1)));
+    for listener in listeners {
+        command.arg("--inherit-listener-fd").arg(listener.to_string());
+    }
+    command.arg("--upgrade-ready-fd").arg(ready_write.to_string());
+    let inherited: Vec<RawFd> = listeners
+        .iter()
+        .flat_map(|listener| listener.fds.iter().copied())
+        .chain(Some(ready_write))
+        .collect();
+    // Safety: between fork and exec, only fcntl (which is async-signal-safe) is called
+    unsafe {
+        command.pre_exec(move || {
+            for fd in &inherited {
+                fcntl(*fd, FcntlArg::F_SETFD(FdFlag::empty()))
+                    .map_err(|_| io::Error::last_os_error())?;
+            }
+            Ok(())
+        });
+    }
+    let spawned = command.spawn();
+    // The child has its own copy; ours must go so that reading sees the child exit
+    let _ = nix::unistd::close(ready_write);
+    let mut child = spawned.map_err(Error::Spawn)?;
+    log::info!("Upgrade: started {:?} as pid {}", binary, child.id());
+
+    let reading = tokio::task::spawn_blocking(move || {
+        let mut message = Vec::new();
+        ready.read_to_end(&mut message).map(|_| message)
+    });
+    let reason = match timeout(READY_TIMEOUT, reading).await {
+        Ok(Ok(Ok(message))) if message == READY_MESSAGE => return Ok(child.id()),
+        Ok(Ok(Ok(_))) => "it exited".to_string(),
+        Ok(Ok(Err(err))) => err.to_string(),
+        Ok(Err(err)) => err.to_string(),
+        Err(_) => format!("no word after {:?}", READY_TIMEOUT),
+    };
+    let _ = child.kill();
+    let _ = tokio::task::spawn_blocking(move || child.wait()).await;
+    Err(Error::NotReady(reason))
+}
+
+/// Tells the process that started this one for an upgrade that it is serving.
+pub fn signal_ready(fd: RawFd) {
+    // Safety: the fd is the write end of a pipe opened for us by the process upgrading to this one
+    let mut pipe = unsafe { File::from_raw_fd(fd) };
+    if let Err(err) = pipe.write_all(READY_MESSAGE) {
+        log::warn!("Could not tell the old process we are ready: {}", err);
+    }
+}
+
+/// The arguments to start the new binary with: ours, less any that a previous upgrade added.
+fn passed_on(args: impl Iterator<Item = OsString>) -> Vec<OsString> {
+    let mut passed = Vec::new();
+    let mut skip_value = false;
+    for arg in args {
+        if skip_value {
+            skip_value = false;
+            continue;
+        }
+        let text = arg.to_string_lossy();
+        if UPGRADE_OPTIONS.contains(&&*text) {
+            skip_value = true;
+        } else if !UPGRADE_OPTIONS.iter().any(|option| text.starts_with(&format!("{}=", option))) {
+            passed.push(arg);
+        }
+    }
+    passed
+}
+
+/// The error a nix call failed with, as std reports OS errors. Other code may have changed errno
+/// since, so it is taken from the nix error rather than `io::Error::last_os_error`.
+pub fn nix_error(err: nix::Error) -> io::Error {
+    match err.as_errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32),
+        None => io::Error::other(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_inherited_fd() {
+        assert_eq!("3".parse(), Ok(InheritedFd { api: None, fds: vec![3] }));
+        assert_eq!("3,4, 5".parse(), Ok(InheritedFd { api: None, fds: vec![3, 4, 5] }));
+        assert_eq!("admin=5".parse(), Ok(InheritedFd { api: Some(Api::Admin), fds: vec![5] }));
+        assert_eq!("metrics=6".parse(), Ok(InheritedFd { api: Some(Api::Metrics), fds: vec![6] }));
+        assert!("proxy=3".parse::<InheritedFd>().is_err());
+        assert!("-1".parse::<InheritedFd>().is_err());
+        assert!("3,".parse::<InheritedFd>().is_err());
+        assert!("admin=5,6".parse::<InheritedFd>().is_err());
+        assert_eq!(InheritedFd { api: Some(Api::Admin), fds: vec![5] }.to_string(), "admin=5");
+        assert_eq!(InheritedFd { api: None, fds: vec![3, 4] }.to_string(), "3,4");
+    }
+
+    #[test]
+    fn test_upgrade_options_not_passed_on() {
+        let args = [
+            "--bind",
+            "0.0.0.0:80",
+            "--inherit-listener-fd",
+            "3",
+            "--inherit-listener-fd=admin=4",
+            "--upgrade-ready-fd",
+            "7",
+            "--upstream",
+            "10.0.0.1:80",
+        ];
+        let passed = passed_on(args.iter().map(OsString::from));
+        assert_eq!(passed, ["--bind", "0.0.0.0:80", "--upstream", "10.0.0.1:80"]);
+    }
+}
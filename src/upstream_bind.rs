@@ -0,0 +1,112 @@
+use crate::upgrade::nix_error;
+use nix::sys::socket::{bind, socket, AddressFamily, InetAddr, SockAddr, SockFlag, SockType};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::FromRawFd;
+use std::str::FromStr;
+use tokio::net::TcpStream;
+
+/// A `--upstream-bind-addr` option: the local address connections to upstreams are made from, for
+/// hosts whose backends only accept connections from one of their interfaces. Given for a single
+/// upstream as `<upstream>=<ip>`, or for every upstream as a bare `<ip>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamBindAddr {
+    /// The upstream it applies to, or None for all of them
+    pub upstream: Option<String>,
+    pub ip: IpAddr,
+}
+
+impl FromStr for UpstreamBindAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (upstream, ip) = match s.rsplit_once('=') {
+            Some((upstream, ip)) => (Some(crate::config::normalize_address(upstream)), ip.trim()),
+            None => (None, s.trim()),
+        };
+        let ip = ip.parse().map_err(|_| format!("\"{}\" is not an IP address", ip))?;
+        Ok(UpstreamBindAddr { upstream, ip })
+    }
+}
+
+/// The local address connections to the upstream at `address` are made from: the last
+/// `--upstream-bind-addr` for that upstream, or else the last one for every upstream.
+pub fn of(address: &str, binds: &[UpstreamBindAddr]) -> Option<IpAddr> {
+    let for_upstream = binds.iter().rev().find(|bind| bind.upstream.as_deref() == Some(address));
+    for_upstream
+        .or_else(|| binds.iter().rev().find(|bind| bind.upstream.is_none()))
+        .map(|bind| bind.ip)
+}
+
+/// Checks that `ip` belongs to one of this host's interfaces, so that a typo is caught at startup
+/// rather than on every connection.
+pub fn check_local(ip: IpAddr) -> Result<(), String> {
+    std::net::TcpListener::bind((ip, 0))
+        .map(|_| ())
+        .map_err(|err| format!("{} is not an address of any interface on this host ({})", ip, err))
+}
+
+/// Connects to `address`, from `source` if given. Only the addresses `address` resolves to in the
+/// same family as `source` are tried.
+pub async fn connect(address: &str, source: Option<IpAddr>) -> io::Result<TcpStream> {
+    let source = match source {
+        Some(source) => source,
+        None => return TcpStream::connect(address).await,
+    };
+    let mut last_error = None;
+    for target in tokio::net::lookup_host(address).await? {
+        if target.is_ipv4() != source.is_ipv4() {
+            continue;
+        }
+        match connect_from(target, source).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_error = Some(err),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("{} has no address to reach from {}", address, source),
+        )
+    }))
+}
+
+async fn connect_from(target: SocketAddr, source: IpAddr) -> io::Result<TcpStream> {
+    let family = if source.is_ipv4() {
+        AddressFamily::Inet
+    } else {
+        AddressFamily::Inet6
+    };
+    let fd = socket(family, SockType::Stream, SockFlag::SOCK_CLOEXEC, None).map_err(nix_error)?;
+    // Safety: socket just opened the fd, for us alone
+    let socket = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+    let local = SockAddr::new_inet(InetAddr::from_std(&SocketAddr::new(source, 0)));
+    if let Err(err) = bind(fd, &local) {
+        let err = nix_error(err);
+        return Err(io::Error::new(
+            err.kind(),
+            format!("could not use {} as the source address: {}", source, err),
+        ));
+    }
+    TcpStream::connect_std(socket, &target).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_lookup() {
+        let binds: Vec<UpstreamBindAddr> = ["10.0.0.5", "Backend:80=10.0.1.5", "[::1]:80=::1"]
+            .iter()
+            .map(|bind| bind.parse().unwrap())
+            .collect();
+        assert_eq!(binds[2].upstream.as_deref(), Some("[::1]:80"));
+        assert_eq!(of("backend:80", &binds), Some("10.0.1.5".parse().unwrap()));
+        assert_eq!(of("other:80", &binds), Some("10.0.0.5".parse().unwrap()));
+        assert_eq!(of("other:80", &binds[1..]), None);
+        assert!("backend:80=eth0".parse::<UpstreamBindAddr>().is_err());
+        assert!(check_local("127.0.0.1".parse().unwrap()).is_ok());
+        assert!(check_local("192.0.2.1".parse().unwrap()).is_err());
+    }
+}
@@ -1,7 +1,10 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, Server};
+use common::{init_logging, BalanceBeam, EchoServer, RawServer, Server};
 use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
 
 async fn setup() -> (BalanceBeam, EchoServer) {
     init_logging();
@@ -98,4 +101,1089 @@ async fn test_multiple_requests_per_connection() {
     );
 
     log::info!("All done :)");
-}
\ No newline at end of file
+}
+
+/// With --upstream-http-version 1.0, every request should reach the upstream as a single-request
+/// HTTP/1.0 exchange, even when the client reuses its connection.
+#[tokio::test]
+async fn test_upstream_http_10() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--upstream-http-version", "1.0"])
+            .await;
+
+    let client = reqwest::Client::new();
+    for i in 0..3 {
+        let path = format!("/req-{}", i);
+        let response = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.version(), reqwest::Version::HTTP_11);
+        let response_text = response.text().await.unwrap();
+        assert!(response_text.contains(&format!("GET {} HTTP/1.0", path)));
+        assert!(response_text.contains("connection: close"));
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+
+    log::info!("All done :)");
+}
+
+/// With --log-sample-rate, only about one in N requests should get verbose logging, but every
+/// request still gets an access log line and errors are never sampled away.
+#[tokio::test]
+async fn test_log_sampling() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--log-sample-rate", "10"]).await;
+
+    let n_requests = 2000;
+    let client = reqwest::Client::new();
+    for i in 0..n_requests {
+        client
+            .get(&format!("http://{}/sampled-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    let response = balancebeam.send_raw(b"this is not http\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 400"), "got {:?}", response);
+    assert!(balancebeam.output_contains("reason=client_parse").await);
+
+    let sampled = balancebeam.output_count("timings:");
+    log::info!("{} of {} requests were sampled", sampled, n_requests);
+    assert!((100..=300).contains(&sampled), "sampled {} requests", sampled);
+    assert_eq!(balancebeam.output_count("/sampled-"), n_requests);
+
+    assert_eq!(Box::new(upstream).stop().await, n_requests);
+    log::info!("All done :)");
+}
+
+/// With --redirect-http-to-https, requests are answered with a redirect and never reach the
+/// upstream.
+#[tokio::test]
+async fn test_redirect_http_to_https() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--redirect-http-to-https"]).await;
+
+    let response = balancebeam
+        .send_raw(b"GET /a/b?c=1 HTTP/1.1\r\nHost: example.com:8080\r\n\r\n")
+        .await;
+    assert!(response.starts_with("HTTP/1.1 301"), "got {:?}", response);
+    assert!(
+        response.contains("location: https://example.com:8080/a/b?c=1\r\n"),
+        "got {:?}",
+        response
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// The runtime can be sized explicitly.
+#[tokio::test]
+async fn test_worker_threads() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--worker-threads", "1", "--blocking-threads", "4"],
+    )
+    .await;
+    assert!(
+        balancebeam
+            .output_contains("Using 1 worker threads and up to 4 blocking threads")
+            .await
+    );
+    let response_text = balancebeam.get("/threads").await.expect("Error sending request");
+    assert!(response_text.contains("GET /threads HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// TRACE is refused and `OPTIONS *` is answered by balancebeam itself; neither reaches the upstream.
+/// Origin-form OPTIONS is still forwarded.
+#[tokio::test]
+async fn test_trace_and_asterisk_options() {
+    let (balancebeam, upstream) = setup().await;
+
+    let response = balancebeam
+        .send_raw(b"TRACE / HTTP/1.1\r\nHost: x\r\nCookie: secret=1\r\n\r\n")
+        .await;
+    assert!(response.starts_with("HTTP/1.1 405"), "got {:?}", response);
+    assert!(!response.contains("secret"), "got {:?}", response);
+    assert!(
+        response.contains("allow: GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS\r\n"),
+        "got {:?}",
+        response
+    );
+
+    let response = balancebeam.send_raw(b"OPTIONS * HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 204"), "got {:?}", response);
+    assert!(
+        response.contains("allow: GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS\r\n"),
+        "got {:?}",
+        response
+    );
+
+    let response = balancebeam.send_raw(b"OPTIONS /thing HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+    assert!(response.contains("OPTIONS /thing HTTP/1.1"), "got {:?}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// With --cors-allowed-origin, preflights are answered by balancebeam for allowed origins and
+/// refused (by leaving out Access-Control-Allow-Origin) for others. Other OPTIONS requests are
+/// still forwarded.
+#[tokio::test]
+async fn test_cors_preflight() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--cors-allowed-origin", "https://app.example.com"],
+    )
+    .await;
+    let preflight = |origin: &str| {
+        format!(
+            "OPTIONS /api HTTP/1.1\r\nHost: x\r\nOrigin: {}\r\n\
+             Access-Control-Request-Method: PUT\r\n\
+             Access-Control-Request-Headers: content-type\r\n\r\n",
+            origin
+        )
+    };
+
+    let response = balancebeam.send_raw(preflight("https://app.example.com").as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 204"), "got {:?}", response);
+    assert!(
+        response.contains("access-control-allow-origin: https://app.example.com\r\n"),
+        "got {:?}",
+        response
+    );
+    assert!(response.contains("access-control-allow-headers: content-type\r\n"));
+    let response = balancebeam.send_raw(preflight("https://other.example.com").as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 204"), "got {:?}", response);
+    assert!(!response.contains("access-control-allow-origin"), "got {:?}", response);
+
+    let response = balancebeam
+        .send_raw(b"OPTIONS /api HTTP/1.1\r\nHost: x\r\nOrigin: https://app.example.com\r\n\r\n")
+        .await;
+    assert!(response.contains("OPTIONS /api HTTP/1.1"), "got {:?}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// With --allow-trace, TRACE is forwarded like any other method.
+#[tokio::test]
+async fn test_allow_trace() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &["--allow-trace"]).await;
+
+    let response = balancebeam.send_raw(b"TRACE /t HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+    let response = balancebeam.send_raw(b"OPTIONS * HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(
+        response.contains("allow: GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS, TRACE\r\n"),
+        "got {:?}",
+        response
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// The remaining deadline should reach the upstream, with client-requested timeouts clamped to the
+/// configured maximums and not forwarded themselves.
+#[tokio::test]
+async fn test_deadline_propagation() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--request-deadline-secs", "5"]).await;
+
+    let deadline_sent = |response_text: &str| -> u64 {
+        response_text
+            .lines()
+            .find_map(|line| line.strip_prefix("x-deadline-ms: "))
+            .expect("No deadline header reached the upstream")
+            .parse()
+            .unwrap()
+    };
+
+    let response_text = balancebeam.get("/").await.expect("Error sending request");
+    let deadline = deadline_sent(&response_text);
+    assert!((4000..=5000).contains(&deadline), "deadline was {}", deadline);
+
+    let client = reqwest::Client::new();
+    for (requested, max) in &[("60", 5000), ("1.5", 1500)] {
+        let response_text = client
+            .get(&format!("http://{}/", balancebeam.address))
+            .header("x-request-timeout", *requested)
+            .header("x-deadline-ms", "999999")
+            .send()
+            .await
+            .expect("Error sending request")
+            .text()
+            .await
+            .unwrap();
+        let deadline = deadline_sent(&response_text);
+        assert!(deadline <= *max && deadline > max - 1000, "deadline was {}", deadline);
+        assert!(!response_text.contains("x-request-timeout"), "{}", response_text);
+    }
+
+    // Without a configured deadline, what clients ask for is still capped
+    let uncapped = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--max-request-timeout-secs", "2"],
+    )
+    .await;
+    let response_text = client
+        .get(&format!("http://{}/", uncapped.address))
+        .header("x-request-timeout", "3600")
+        .send()
+        .await
+        .expect("Error sending request")
+        .text()
+        .await
+        .unwrap();
+    let deadline = deadline_sent(&response_text);
+    assert!(deadline <= 2000 && deadline > 1000, "deadline was {}", deadline);
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// Requests for excluded paths are proxied but left out of the access log.
+#[tokio::test]
+async fn test_access_log_exclude_path() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--access-log-exclude-path", "/healthz", "--access-log-exclude-path", "/internal/*"],
+    )
+    .await;
+
+    for path in &["/healthz", "/internal/stats", "/healthz-not", "/logged"] {
+        let response_text = balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert!(balancebeam.output_contains("GET /logged HTTP/1.1").await);
+    assert_eq!(balancebeam.output_count("GET /healthz HTTP/1.1"), 0);
+    assert_eq!(balancebeam.output_count("GET /internal/stats HTTP/1.1"), 0);
+    assert!(balancebeam.output_count("GET /healthz-not HTTP/1.1") > 0);
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// With --log-upstream-errors-only, successful requests shouldn't show up in the logs (apart from
+/// the sampled ones), but errors still should.
+#[tokio::test]
+async fn test_log_upstream_errors_only() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let quiet =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--log-upstream-errors-only"]).await;
+    let sampled = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--log-upstream-errors-only", "--log-sample-rate", "0.1"],
+    )
+    .await;
+
+    let n_requests = 200;
+    let client = reqwest::Client::new();
+    for i in 0..n_requests {
+        for (balancebeam, path) in &[(&quiet, "quiet"), (&sampled, "sampled")] {
+            client
+                .get(&format!("http://{}/{}-{}", balancebeam.address, path, i))
+                .send()
+                .await
+                .expect("Error sending request to balancebeam");
+        }
+    }
+    let response = quiet.send_raw(b"this is not http\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 400"), "got {:?}", response);
+    assert!(quiet.output_contains("reason=client_parse").await);
+
+    assert_eq!(quiet.output_count("/quiet-"), 0);
+    let sampled_count = sampled.output_count("/sampled-");
+    assert!((5..=50).contains(&sampled_count), "{} requests logged", sampled_count);
+
+    assert_eq!(Box::new(upstream).stop().await, 2 * n_requests);
+    log::info!("All done :)");
+}
+
+/// --upstream-http-host-override replaces whatever Host the client sent.
+#[tokio::test]
+async fn test_upstream_http_host_override() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--upstream-http-host-override", "backend-service.internal"],
+    )
+    .await;
+
+    let response_text = balancebeam
+        .get("/host")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("host: backend-service.internal\n"), "{}", response_text);
+    assert!(!response_text.contains(&format!("host: {}", balancebeam.address)));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// --upstream-remove-request-header strips headers from forwarded requests, and
+/// --upstream-add-request-header adds ours in place of any the client sent, without logging them.
+#[tokio::test]
+async fn test_upstream_request_header_options() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--upstream-remove-request-header",
+            "Authorization",
+            "--upstream-remove-request-header",
+            "X-Debug",
+            "--upstream-add-request-header",
+            "X-Internal-Token: s3cret",
+            "--upstream-add-request-header",
+            "X-Debug: off",
+        ],
+    )
+    .await;
+
+    let response_text = balancebeam
+        .send_raw(
+            b"GET /headers HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer client\r\n\
+              X-Internal-Token: forged\r\nX-Debug: on\r\n\r\n",
+        )
+        .await;
+    assert!(response_text.contains("x-internal-token: s3cret\n"), "{}", response_text);
+    assert!(response_text.contains("x-debug: off\n"), "{}", response_text);
+    for removed in &["authorization", "forged", "x-debug: on"] {
+        assert!(!response_text.contains(removed), "{}", response_text);
+    }
+    assert!(balancebeam.output_contains("[REDACTED]").await);
+    assert_eq!(balancebeam.output_count("s3cret"), 0);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// --strip-request-header drops every copy of a header clients could spoof, and headers balancebeam
+/// sets itself are always dropped, while the values we set still reach the upstream.
+#[tokio::test]
+async fn test_strip_request_header() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--strip-request-header",
+            "x-internal-auth",
+            "--upstream-add-request-header",
+            "X-Internal-Auth: from-proxy",
+            "--request-deadline-secs",
+            "30",
+        ],
+    )
+    .await;
+
+    let response_text = balancebeam
+        .send_raw(
+            b"GET /headers HTTP/1.1\r\nHost: x\r\nX-Internal-Auth: spoofed, admin\r\n\
+              x-internal-auth: spoofed-again\r\nX-Deadline-Ms: 99999999\r\nX-Other: kept\r\n\r\n",
+        )
+        .await;
+    assert!(response_text.contains("x-internal-auth: from-proxy\n"), "{}", response_text);
+    assert_eq!(response_text.matches("x-internal-auth").count(), 1, "{}", response_text);
+    assert!(!response_text.contains("spoofed"), "{}", response_text);
+    assert!(!response_text.contains("99999999"), "{}", response_text);
+    // Ours, which can't be more than the 30 seconds configured
+    let deadline = response_text.lines().find_map(|line| line.strip_prefix("x-deadline-ms: "));
+    assert!(deadline.unwrap().parse::<u64>().unwrap() <= 30_000, "{}", response_text);
+    assert!(response_text.contains("x-other: kept\n"), "{}", response_text);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// --upstream-remove-response-header strips fingerprinting headers from upstream responses, and
+/// --upstream-add-response-header adds ours in place of any the upstream sent.
+#[tokio::test]
+async fn test_upstream_response_header_options() {
+    init_logging();
+    let upstream = RawServer::new(
+        b"HTTP/1.1 200 OK\r\nServer: nginx/1.2.3\r\nX-Powered-By: PHP/5.6\r\n\
+          X-Served-By: backend-7\r\nContent-Length: 2\r\n\r\nok",
+    )
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--upstream-remove-response-header",
+            "Server",
+            "--upstream-remove-response-header",
+            "x-powered-by",
+            "--upstream-add-response-header",
+            "X-Served-By: balancebeam",
+        ],
+    )
+    .await;
+
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+    assert!(response.contains("x-served-by: balancebeam\r\n"), "got {:?}", response);
+    for removed in &["server:", "x-powered-by:", "backend-7"] {
+        assert!(!response.contains(removed), "got {:?}", response);
+    }
+    assert!(response.ends_with("\r\n\r\nok"), "got {:?}", response);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// --upstream-bind-addr makes connections to the upstream, health checks included, from the given
+/// local address, and an address that isn't this host's stops balancebeam at startup.
+#[tokio::test]
+async fn test_upstream_bind_addr() {
+    init_logging();
+    let upstream = RawServer::new(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+    let args = [
+        "--upstream-bind-addr",
+        "127.0.0.3",
+        "--upstream-bind-addr",
+        &format!("{}=127.0.0.2", upstream.address),
+        "--active-health-check-interval",
+        "1",
+    ];
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &args).await;
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+    // Long enough for a health check too
+    tokio::time::delay_for(Duration::from_secs(2)).await;
+    let peers = upstream.peers();
+    assert!(peers.len() >= 2, "only saw {:?}", peers);
+    assert!(peers.iter().all(|peer| peer.ip().to_string() == "127.0.0.2"), "got {:?}", peers);
+
+    let mut balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--upstream-bind-addr", "192.0.2.1"])
+            .await;
+    let status = balancebeam.wait_for_exit().await.expect("balancebeam didn't exit");
+    assert_eq!(status.code(), Some(1));
+    assert!(balancebeam.output_contains("192.0.2.1 is not an address of any interface").await);
+    Box::new(upstream).stop().await;
+}
+
+/// A chunked request body is decoded and forwarded with a Content-Length.
+#[tokio::test]
+async fn test_chunked_request_body() {
+    let (balancebeam, upstream) = setup().await;
+    let response = balancebeam
+        .send_raw(
+            b"POST /upload HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5;name=first\r\nhello\r\n7\r\n, world\r\n0\r\nX-Checksum: 1\r\n\r\n",
+        )
+        .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+    assert!(response.contains("content-length: 12\n"), "got {:?}", response);
+    assert!(!response.contains("transfer-encoding"), "got {:?}", response);
+    assert!(!response.contains("x-checksum"), "got {:?}", response);
+    assert!(response.ends_with("hello, world"), "got {:?}", response);
+
+    let response = balancebeam
+        .send_raw(b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: gzip\r\n\r\n")
+        .await;
+    assert!(response.starts_with("HTTP/1.1 400"), "got {:?}", response);
+    Box::new(upstream).stop().await;
+}
+
+/// --security-headers gives responses without a Content-Type the --default-content-type, and marks
+/// every response nosniff.
+#[tokio::test]
+async fn test_security_headers_default_content_type() {
+    init_logging();
+    let upstream = RawServer::new(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--security-headers"]).await;
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.contains("content-type: application/octet-stream\r\n"), "got {:?}", response);
+    assert!(response.contains("x-content-type-options: nosniff\r\n"), "got {:?}", response);
+    assert!(balancebeam.output_contains("the upstream sent no Content-Type").await);
+
+    let args = ["--security-headers", "--default-content-type", "text/plain; charset=utf-8"];
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &args).await;
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.contains("content-type: text/plain; charset=utf-8\r\n"), "got {:?}", response);
+
+    // Off by default
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(!response.contains("content-type"), "got {:?}", response);
+    Box::new(upstream).stop().await;
+}
+
+/// --rewrite-body replaces the backend's own URLs in HTML bodies and fixes up Content-Length, but
+/// leaves other content types alone.
+#[tokio::test]
+async fn test_rewrite_body() {
+    init_logging();
+    let body = r#"<a href="http://internal-host:8080/a"><a href="http://internal-host:8080/b">"#;
+    let page = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let json = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let args = ["--rewrite-body", "from=http://internal-host:8080,to=https://www.example.com"];
+
+    let upstream = RawServer::new(page.as_bytes()).await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &args).await;
+    assert!(balancebeam.output_contains("Rewriting \"http://internal-host:8080\"").await);
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    let rewritten = r#"<a href="https://www.example.com/a"><a href="https://www.example.com/b">"#;
+    assert!(response.ends_with(&format!("\r\n\r\n{}", rewritten)), "got {:?}", response);
+    assert!(response.contains(&format!("content-length: {}\r\n", rewritten.len())));
+    Box::new(upstream).stop().await;
+
+    let upstream = RawServer::new(json.as_bytes()).await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &args).await;
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.ends_with(body), "got {:?}", response);
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// With --decompress-upstream, a gzipped response is decoded for a client without a matching
+/// Accept-Encoding, and relayed as it is to one with.
+#[tokio::test]
+async fn test_decompress_upstream() {
+    init_logging();
+    // "hello hello hello hello, balancebeam! hello hello hello hello\n", gzipped
+    let gzipped: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48, 0xcd, 0xc9, 0xc9,
+        0x57, 0xc8, 0x40, 0x27, 0x75, 0x14, 0x92, 0x12, 0x73, 0x12, 0xf3, 0x92, 0x53, 0x93, 0x52,
+        0x13, 0x73, 0x15, 0x31, 0xe5, 0x21, 0x24, 0x17, 0x00, 0x61, 0x34, 0x20, 0x38, 0x3e, 0x00,
+        0x00, 0x00,
+    ];
+    let text = "hello hello hello hello, balancebeam! hello hello hello hello\n";
+    let mut raw = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Encoding: gzip\r\n\
+         Content-Length: {}\r\n\r\n",
+        gzipped.len()
+    )
+    .into_bytes();
+    raw.extend_from_slice(gzipped);
+    let upstream = RawServer::new(&raw).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--decompress-upstream"]).await;
+
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.ends_with(&format!("\r\n\r\n{}", text)), "got {:?}", response);
+    assert!(response.contains(&format!("content-length: {}\r\n", text.len())));
+    assert!(!response.contains("content-encoding"), "got {:?}", response);
+    assert!(response.contains("vary: Accept-Encoding\r\n"), "got {:?}", response);
+
+    let response = balancebeam
+        .send_raw(b"GET / HTTP/1.1\r\nHost: x\r\nAccept-Encoding: gzip, br\r\n\r\n")
+        .await;
+    assert!(response.contains("content-encoding: gzip\r\n"), "got {:?}", response);
+    assert!(response.contains(&format!("content-length: {}\r\n", gzipped.len())));
+    assert!(!response.contains("balancebeam"), "got {:?}", response);
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// Sends requests on one connection without closing our side, returning everything received
+/// until balancebeam closes the connection (or a timeout passes, if it keeps it open).
+async fn exchange_until_closed(address: &str, requests: &[u8]) -> (String, bool) {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream.write_all(requests).await.unwrap();
+    let mut received = Vec::new();
+    let closed = timeout(Duration::from_secs(2), stream.read_to_end(&mut received)).await.is_ok();
+    (String::from_utf8_lossy(&received).to_string(), closed)
+}
+
+/// `Connection: close` from either the client or the upstream ends both connections after the
+/// response, and the client is told so.
+#[tokio::test]
+async fn test_connection_close() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let (received, closed) = exchange_until_closed(
+        &balancebeam.address,
+        b"GET /last HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(closed, "connection left open after {:?}", received);
+    assert!(received.starts_with("HTTP/1.1 200"), "got {:?}", received);
+    assert!(received.to_lowercase().contains("connection: close\r\n"), "got {:?}", received);
+    let (received, closed) =
+        exchange_until_closed(&balancebeam.address, b"GET /kept HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(!closed, "connection closed after {:?}", received);
+    Box::new(upstream).stop().await;
+
+    // This upstream closes every connection after one response. Without passing that on, the
+    // second request on the client's connection would be written to a dead upstream connection.
+    let closing = RawServer::new(
+        b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\nok",
+    )
+    .await;
+    let balancebeam = BalanceBeam::new(&[&closing.address], None, None).await;
+    let (received, closed) = exchange_until_closed(
+        &balancebeam.address,
+        b"GET /1 HTTP/1.1\r\nHost: x\r\n\r\nGET /2 HTTP/1.1\r\nHost: x\r\n\r\n",
+    )
+    .await;
+    assert!(closed, "connection left open after {:?}", received);
+    assert_eq!(received.matches("HTTP/1.1 200").count(), 1, "got {:?}", received);
+    let response_text = balancebeam.get("/again").await.expect("Error sending request");
+    assert_eq!(response_text, "ok");
+
+    assert_eq!(Box::new(closing).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// --forwarded-header-style rfc7239 appends to the Forwarded chain and drops X-Forwarded-*; both
+/// adds to both chains.
+#[tokio::test]
+async fn test_forwarded_header_style() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let request = b"GET / HTTP/1.1\r\nHost: example.com:8080\r\nX-Forwarded-For: 192.0.2.7\r\n\
+                    X-Forwarded-Proto: https\r\nForwarded: for=192.0.2.7;proto=https\r\n\r\n";
+    let ours = "for=127.0.0.1;proto=http;host=\"example.com:8080\";by=127.0.0.1";
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--forwarded-header-style", "rfc7239"],
+    )
+    .await;
+    let response = balancebeam.send_raw(request).await;
+    assert!(
+        response.contains(&format!("forwarded: for=192.0.2.7;proto=https, {}\n", ours)),
+        "got {:?}",
+        response
+    );
+    assert!(!response.contains("x-forwarded-"), "got {:?}", response);
+
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--forwarded-header-style", "both"])
+            .await;
+    let response = balancebeam.send_raw(request).await;
+    assert!(response.contains(&format!("forwarded: for=192.0.2.7;proto=https, {}\n", ours)));
+    assert!(response.contains("x-forwarded-for: 192.0.2.7, 127.0.0.1\n"), "got {:?}", response);
+    assert!(response.contains("x-forwarded-proto: https\n"));
+
+    log::info!("All done :)");
+}
+
+/// --early-hints sends a 103 to matching HTTP/1.1 GETs while the upstream is still working, and
+/// nothing extra to HTTP/1.0 clients or other paths.
+#[tokio::test]
+async fn test_early_hints() {
+    init_logging();
+    let upstream = RawServer::new_with_delay(
+        b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\npage",
+        Duration::from_secs(1),
+    )
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--early-hints",
+            "prefix=/app,link=</static/app.css>; rel=preload; as=style",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream.write_all(b"GET /app/page HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+    let mut buffer = [0_u8; 1024];
+    let n = timeout(Duration::from_millis(500), stream.read(&mut buffer))
+        .await
+        .expect("no early hints before the upstream answered")
+        .unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&buffer[..n]),
+        "HTTP/1.1 103 Early Hints\r\nlink: </static/app.css>; rel=preload; as=style\r\n\r\n"
+    );
+    let mut rest = Vec::new();
+    timeout(Duration::from_secs(3), stream.read_to_end(&mut rest)).await.unwrap().unwrap();
+    let rest = String::from_utf8_lossy(&rest);
+    assert!(rest.starts_with("HTTP/1.1 200 OK\r\n"), "got {:?}", rest);
+    assert!(rest.ends_with("page"), "got {:?}", rest);
+
+    for request in &[
+        &b"GET /app/page HTTP/1.0\r\nHost: x\r\n\r\n"[..],
+        &b"GET /other HTTP/1.1\r\nHost: x\r\n\r\n"[..],
+    ] {
+        let response = balancebeam.send_raw(request).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "got {:?}", response);
+    }
+
+    log::info!("All done :)");
+}
+
+/// Requests under the internal prefix are answered by balancebeam, and are exempt from the rate
+/// limit unless --rate-limit-internal-paths is given. Turning off the legacy aliases hands their
+/// paths back to the upstream.
+#[tokio::test]
+async fn test_internal_paths() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--max-requests-per-minute", "2", "--active-health-check-interval", "60"],
+    )
+    .await;
+    for _ in 0..4 {
+        assert_eq!(balancebeam.get("/_balancebeam/healthz").await.unwrap(), "ok\n");
+    }
+    assert_eq!(balancebeam.get("/balancebeam/healthz").await.unwrap(), "ok\n");
+    assert_eq!(balancebeam.get("/_balancebeam/nothing").await.unwrap(), "HTTP 404 Not Found");
+    drop(balancebeam);
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--internal-prefix",
+            "/_lb",
+            "--no-legacy-internal-paths",
+            "--rate-limit-internal-paths",
+            "--max-requests-per-minute",
+            "2",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    let response_text = balancebeam.get("/balancebeam/healthz").await.unwrap();
+    assert!(response_text.contains("GET /balancebeam/healthz HTTP/1.1"), "got {:?}", response_text);
+    assert_eq!(balancebeam.get("/_lb/healthz").await.unwrap(), "ok\n");
+    let response = balancebeam.send_raw(b"GET /_lb/healthz HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 429"), "got {:?}", response);
+
+    // Only the request the second instance forwarded (and no health checks) reached the upstream
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+}
+
+/// Sends each request to balancebeam (started with `args`) in turn, and returns exactly what the
+/// upstream received for each.
+async fn forwarded_bytes(args: &[&str], requests: &[&[u8]]) -> Vec<String> {
+    let upstream = RawServer::new(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let mut args = args.to_vec();
+    args.extend_from_slice(&["--active-health-check-interval", "60"]);
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &args).await;
+    for request in requests {
+        let response = balancebeam.send_raw(request).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+    }
+    let forwarded = upstream.requests();
+    assert_eq!(Box::new(upstream).stop().await, requests.len());
+    forwarded
+}
+
+/// Golden tests for the bytes upstreams receive: headers balancebeam doesn't touch keep the
+/// client's order and case, and only the intentional changes differ.
+#[tokio::test]
+async fn test_forwarded_header_bytes() {
+    init_logging();
+    let signed: &[u8] = b"GET /bucket/key HTTP/1.1\r\n\
+        Host: s3.example.com\r\n\
+        X-Amz-Date: 20260101T000000Z\r\n\
+        accept: text/html\r\n\
+        X-Amz-Content-Sha256: UNSIGNED-PAYLOAD\r\n\
+        Accept: */*\r\n\
+        Authorization: AWS4-HMAC-SHA256 Credential=AKID/20260101/us-east-1/s3/aws4_request\r\n\
+        \r\n";
+    let proxied: &[u8] = b"POST /form HTTP/1.1\r\n\
+        Host: app.example.com\r\n\
+        X-Forwarded-For: 10.0.0.1\r\n\
+        X-Internal-Auth: forged\r\n\
+        Content-Length: 3\r\n\
+        \r\n\
+        a=b";
+    let forwarded =
+        forwarded_bytes(&["--strip-request-header", "X-Internal-Auth"], &[signed, proxied]).await;
+    assert_eq!(
+        forwarded[0],
+        "GET /bucket/key HTTP/1.1\r\n\
+         Host: s3.example.com\r\n\
+         X-Amz-Date: 20260101T000000Z\r\n\
+         accept: text/html\r\n\
+         X-Amz-Content-Sha256: UNSIGNED-PAYLOAD\r\n\
+         Accept: */*\r\n\
+         Authorization: AWS4-HMAC-SHA256 Credential=AKID/20260101/us-east-1/s3/aws4_request\r\n\
+         x-forwarded-for: 127.0.0.1\r\n\
+         \r\n"
+    );
+    assert_eq!(
+        forwarded[1],
+        "POST /form HTTP/1.1\r\n\
+         Host: app.example.com\r\n\
+         X-Forwarded-For: 10.0.0.1, 127.0.0.1\r\n\
+         Content-Length: 3\r\n\
+         \r\n\
+         a=b"
+    );
+
+    log::info!("Without --preserve-header-order, headers are lowercased and grouped by name");
+    let forwarded = forwarded_bytes(&["--preserve-header-order", "false"], &[signed]).await;
+    assert_eq!(
+        forwarded[0],
+        "GET /bucket/key HTTP/1.1\r\n\
+         host: s3.example.com\r\n\
+         x-amz-date: 20260101T000000Z\r\n\
+         accept: text/html\r\n\
+         accept: */*\r\n\
+         x-amz-content-sha256: UNSIGNED-PAYLOAD\r\n\
+         authorization: AWS4-HMAC-SHA256 Credential=AKID/20260101/us-east-1/s3/aws4_request\r\n\
+         x-forwarded-for: 127.0.0.1\r\n\
+         \r\n"
+    );
+
+    log::info!("All done :)");
+}
+
+/// An upstream that turns an upload away before reading all of it gets its response relayed
+/// straight away, rather than balancebeam waiting to finish writing a body nobody is reading.
+#[tokio::test]
+async fn test_early_upstream_response() {
+    init_logging();
+    let upstream_address = common::free_address();
+    let mut listener = tokio::net::TcpListener::bind(&upstream_address).await.unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                // Read the headers and a KiB of the body, then refuse the rest without reading it
+                let mut received = Vec::new();
+                let mut buffer = [0_u8; 1024];
+                while received.len() < 2048 {
+                    match stream.read(&mut buffer).await {
+                        Ok(n) if n > 0 => received.extend_from_slice(&buffer[..n]),
+                        _ => return,
+                    }
+                }
+                let refusal = b"HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\n\r\n";
+                let _ = stream.write_all(refusal).await;
+                tokio::time::delay_for(Duration::from_secs(30)).await;
+            });
+        }
+    });
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let body = vec![b'x'; 10_000_000];
+    let mut request =
+        format!("POST /upload HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\n", body.len())
+            .into_bytes();
+    request.extend_from_slice(&body);
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream.write_all(&request).await.unwrap();
+    let started = std::time::Instant::now();
+    let mut response = vec![0_u8; 1024];
+    let read = timeout(Duration::from_secs(5), stream.read(&mut response))
+        .await
+        .expect("the early response wasn't relayed")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response[..read]);
+    assert!(response.starts_with("HTTP/1.1 413"), "got {:?}", response);
+    assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed());
+    assert!(balancebeam.output_contains("answered before taking the whole request").await);
+
+    log::info!("All done :)");
+}
+
+/// The X-Upstream-Latency-Ms value in a raw response.
+fn upstream_latency(response: &str) -> Option<u64> {
+    let line = response.lines().find(|line| line.starts_with("x-upstream-latency-ms: "))?;
+    line["x-upstream-latency-ms: ".len()..].trim().parse().ok()
+}
+
+/// --expose-upstream-header says which upstream served a response and how long it took, next to
+/// any such header from the upstream; with a token, only for requests that carry it.
+#[tokio::test]
+async fn test_expose_upstream_header() {
+    init_logging();
+    let upstream = RawServer::new_with_delay(
+        b"HTTP/1.1 200 OK\r\nX-Served-By: backend-7\r\nContent-Length: 2\r\n\r\nok",
+        Duration::from_millis(300),
+    )
+    .await;
+    let request = b"GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+
+    // Off by default, leaving the upstream's header alone
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+    let response = balancebeam.send_raw(request).await;
+    assert!(response.contains("x-served-by: backend-7\r\n"), "got {:?}", response);
+    assert_eq!(response.matches("x-served-by").count(), 1, "got {:?}", response);
+    assert_eq!(upstream_latency(&response), None, "got {:?}", response);
+    drop(balancebeam);
+
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--expose-upstream-header"]).await;
+    let response = balancebeam.send_raw(request).await;
+    assert!(response.contains("x-served-by: backend-7\r\n"), "got {:?}", response);
+    let ours = format!("x-served-by: {}\r\n", upstream.address);
+    assert!(response.contains(&ours), "got {:?}", response);
+    let latency = upstream_latency(&response).expect("no latency");
+    assert!((300..2000).contains(&latency), "latency {}", latency);
+    drop(balancebeam);
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--expose-upstream-header", "--expose-upstream-header-token", "s3cret"],
+    )
+    .await;
+    for (debug, exposed) in &[("", false), ("X-Balancebeam-Debug: wrong\r\n", false)] {
+        let request = format!("GET / HTTP/1.1\r\nHost: x\r\n{}\r\n", debug);
+        let response = balancebeam.send_raw(request.as_bytes()).await;
+        assert_eq!(response.contains(&ours), *exposed, "got {:?}", response);
+        assert_eq!(upstream_latency(&response).is_some(), *exposed, "got {:?}", response);
+    }
+    let response = balancebeam
+        .send_raw(b"GET / HTTP/1.1\r\nHost: x\r\nX-Balancebeam-Debug: s3cret\r\n\r\n")
+        .await;
+    assert!(response.contains(&ours), "got {:?}", response);
+    assert!(upstream_latency(&response).is_some(), "got {:?}", response);
+    // The secret is never passed on
+    for request in upstream.requests() {
+        assert!(!request.to_lowercase().contains("x-balancebeam-debug"), "sent {:?}", request);
+    }
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// POSTs with an empty body, whether or not they say so with Content-Length: 0, are forwarded with
+/// nothing after their headers, and bytes a client sends after a request without a body never reach
+/// the upstream as one.
+#[tokio::test]
+async fn test_empty_post_bodies() {
+    init_logging();
+    let upstream = RawServer::new(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    for request in &[
+        &b"POST /zero HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\n\r\n"[..],
+        b"POST /missing HTTP/1.1\r\nHost: x\r\n\r\n",
+        b"POST /trailing HTTP/1.1\r\nHost: x\r\n\r\nGET /smuggled HTTP/1.1\r\nHost: x\r\n\r\n",
+    ] {
+        let response = balancebeam.send_raw(request).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+        assert!(response.ends_with("\r\n\r\nok"), "got {:?}", response);
+    }
+
+    let forwarded = upstream.requests();
+    assert_eq!(forwarded.len(), 3, "got {:?}", forwarded);
+    assert!(forwarded[0].starts_with("POST /zero "), "got {:?}", forwarded[0]);
+    assert!(forwarded[0].to_lowercase().contains("\r\ncontent-length: 0\r\n"));
+    for request in &forwarded[1..] {
+        assert!(!request.to_lowercase().contains("content-length"), "got {:?}", request);
+    }
+    for request in &forwarded {
+        assert!(request.ends_with("\r\n\r\n"), "body forwarded in {:?}", request);
+        assert!(!request.contains("smuggled"), "got {:?}", request);
+    }
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// An upstream that answers `Expect: 100-continue` with 100 Continue still gets the whole upload,
+/// and the client gets its final response rather than the interim one.
+#[tokio::test]
+async fn test_upload_with_expect_continue() {
+    init_logging();
+    let upstream_address = common::free_address();
+    let mut listener = tokio::net::TcpListener::bind(&upstream_address).await.unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut received = Vec::new();
+                let mut buffer = [0_u8; 65536];
+                let headers_end = loop {
+                    match stream.read(&mut buffer).await {
+                        Ok(n) if n > 0 => received.extend_from_slice(&buffer[..n]),
+                        _ => return,
+                    }
+                    let end = received.windows(4).position(|window| window == b"\r\n\r\n");
+                    if let Some(end) = end {
+                        break end + 4;
+                    }
+                };
+                let _ = stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await;
+                while received.len() < headers_end + 10_000_000 {
+                    match stream.read(&mut buffer).await {
+                        Ok(n) if n > 0 => received.extend_from_slice(&buffer[..n]),
+                        _ => return,
+                    }
+                }
+                let body = format!("got {} bytes", received.len() - headers_end);
+                let response =
+                    format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let body = vec![b'x'; 10_000_000];
+    let mut request = format!(
+        "POST /upload HTTP/1.1\r\nHost: x\r\nExpect: 100-continue\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(&body);
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream.write_all(&request).await.unwrap();
+    let mut response = vec![0_u8; 1024];
+    let read = timeout(Duration::from_secs(5), stream.read(&mut response))
+        .await
+        .expect("no response to the upload")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response[..read]);
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+    assert!(response.ends_with("got 10000000 bytes"), "got {:?}", response);
+    assert_eq!(balancebeam.output_count("answered before taking the whole request"), 0);
+
+    log::info!("All done :)");
+}
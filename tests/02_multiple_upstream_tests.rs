@@ -1,8 +1,11 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use common::{free_address, init_logging, BalanceBeam, EchoServer, ErrorServer, RawServer, Server};
 
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
 use tokio::time::delay_for;
 
 async fn setup_with_params(
@@ -190,6 +193,147 @@ async fn test_active_health_checks_check_http_status() {
     }
 }
 
+/// Health checks accept whatever statuses --active-health-check-success-codes lists.
+#[tokio::test]
+async fn test_active_health_check_success_codes() {
+    init_logging();
+    let no_content = b"HTTP/1.1 204 No Content\r\n\r\n";
+
+    let upstream = RawServer::new(no_content).await;
+    let strict =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--active-health-check-interval", "1"])
+            .await;
+    log::info!("Waiting for health checks to decide a 204 means dead...");
+    delay_for(Duration::from_secs(3)).await;
+    let response = strict.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 503"), "got {:?}", response);
+    Box::new(upstream).stop().await;
+
+    let upstream = RawServer::new(no_content).await;
+    let lenient = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-success-codes",
+            "200,204",
+        ],
+    )
+    .await;
+    log::info!("Waiting for a few health checks that should all pass...");
+    delay_for(Duration::from_secs(3)).await;
+    let response = lenient.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 204"), "got {:?}", response);
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// An upstream that accepts connections but answers everything with 500 should be taken out of
+/// rotation once it has sent enough of them, while the healthy upstreams keep serving.
+#[tokio::test]
+async fn test_outlier_ejection() {
+    init_logging();
+    let healthy = vec![EchoServer::new().await, EchoServer::new().await];
+    let failing = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&healthy[0].address, &healthy[1].address, &failing.address],
+        &[
+            "--outlier-5xx-threshold",
+            "0.5",
+            "--outlier-min-requests",
+            "3",
+            "--outlier-max-ejected-percent",
+            "50",
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests until the failing upstream has sent enough 500s...");
+    let mut errors = 0;
+    for i in 0..60 {
+        let request = format!("GET /before-{} HTTP/1.1\r\nHost: x\r\n\r\n", i);
+        if balancebeam.send_raw(request.as_bytes()).await.starts_with("HTTP/1.1 500") {
+            errors += 1;
+        }
+        if errors == 3 {
+            break;
+        }
+    }
+    assert_eq!(errors, 3, "the failing upstream should have been picked at least 3 times");
+    assert!(balancebeam.output_contains("ejected for").await);
+
+    log::info!("Checking that the ejected upstream gets no more traffic");
+    for i in 0..20 {
+        let request = format!("GET /after-{} HTTP/1.1\r\nHost: x\r\n\r\n", i);
+        let response = balancebeam.send_raw(request.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+    }
+    assert_eq!(Box::new(failing).stop().await, 3);
+    for upstream in healthy {
+        Box::new(upstream).stop().await;
+    }
+
+    log::info!("All done :)");
+}
+
+/// A request that is already in flight when its upstream fails a health check should still get
+/// its response; only once it has finished is the upstream marked dead.
+#[tokio::test]
+async fn test_failed_upstream_drains() {
+    init_logging();
+    // An upstream that fails its health checks straight away but takes 2 seconds to answer
+    // anything else
+    let address = common::free_address();
+    let mut upstream = TcpListener::bind(&address).await.unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = upstream.accept().await {
+            tokio::spawn(async move {
+                let mut buffer = [0_u8; 1024];
+                let n = stream.read(&mut buffer).await.unwrap_or(0);
+                let response: &[u8] = if buffer[..n].starts_with(b"GET /healthz ") {
+                    b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n"
+                } else {
+                    delay_for(Duration::from_secs(2)).await;
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow"
+                };
+                let _ = stream.write_all(response).await;
+            });
+        }
+    });
+    let balancebeam = Arc::new(
+        BalanceBeam::new_with_args(
+            &[&address],
+            &[
+                "--active-health-check-interval",
+                "1",
+                "--active-health-check-path",
+                "/healthz",
+                "--upstream-drain-timeout-secs",
+                "10",
+            ],
+        )
+        .await,
+    );
+
+    log::info!("Sending a slow request that the first health check will catch in flight");
+    let in_flight = {
+        let balancebeam = balancebeam.clone();
+        tokio::spawn(async move { balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await })
+    };
+    let response = in_flight.await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+    assert!(response.ends_with("slow"), "got {:?}", response);
+    assert!(balancebeam.output_contains("is now draining").await);
+    assert!(balancebeam.output_contains("drained in").await);
+
+    log::info!("Checking that the drained upstream gets no new requests");
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 503"), "got {:?}", response);
+
+    log::info!("All done :)");
+}
+
 /// Make sure active health checks restore upstreams that were previously failed but are now
 /// working again:
 ///
@@ -240,6 +384,69 @@ async fn test_active_health_checks_restore_failed_upstream() {
     log::info!("All done :)");
 }
 
+/// An upstream that comes back from the dead is sent all of its warm-up requests before any client
+/// request reaches it.
+#[tokio::test]
+async fn test_warmup_before_readmission() {
+    init_logging();
+    let address = common::free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--warmup-requests",
+            "path=/warm,count=3",
+        ],
+    )
+    .await;
+
+    log::info!("Sending a request while nothing is listening, so that the upstream is marked dead");
+    let response = balancebeam.send_raw(b"GET /client HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 502"), "got {:?}", response);
+
+    log::info!("Starting an upstream that records the requests it gets, and is slow to warm up");
+    let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut upstream = TcpListener::bind(&address).await.unwrap();
+    let upstream_received = received.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = upstream.accept().await {
+            let received = upstream_received.clone();
+            tokio::spawn(async move {
+                let mut buffer = [0_u8; 1024];
+                let n = stream.read(&mut buffer).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buffer[..n]).to_string();
+                let path = request.split(' ').nth(1).unwrap_or("").to_string();
+                received.lock().unwrap().push(path.clone());
+                if path == "/warm" {
+                    delay_for(Duration::from_millis(300)).await;
+                }
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+            });
+        }
+    });
+
+    log::info!("Sending client requests until the upstream is back");
+    let mut readmitted = false;
+    for _ in 0..50 {
+        let response = balancebeam.send_raw(b"GET /client HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        if response.starts_with("HTTP/1.1 200") {
+            readmitted = true;
+            break;
+        }
+        delay_for(Duration::from_millis(100)).await;
+    }
+    assert!(readmitted, "the upstream was never let back in");
+    assert!(balancebeam.output_contains("warmed up in").await);
+
+    let received = received.lock().unwrap().clone();
+    let first_client = received.iter().position(|path| path == "/client").unwrap();
+    let warmups = received[..first_client].iter().filter(|path| *path == "/warm").count();
+    assert_eq!(warmups, 3, "a client request arrived before the warm-up finished: {:?}", received);
+
+    log::info!("All done :)");
+}
+
 /// Enable rate limiting and ensure that requests fail after sending more than the threshold
 #[tokio::test]
 async fn test_rate_limiting() {
@@ -293,4 +500,294 @@ async fn test_rate_limiting() {
     assert_eq!(total_request_count, rate_limit_threshold);
 
     log::info!("All done :)");
-}
\ No newline at end of file
+}
+
+const ROUTING_SECRET: &str = "debug-routing-tests";
+
+fn routing_token(secret: &str, upstream: &str) -> String {
+    use hmac::Mac;
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(upstream.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Sends a request asking to be routed to `upstream`, returning the raw response.
+async fn send_with_override(balancebeam: &BalanceBeam, upstream: &str, token: &str) -> String {
+    let request = format!(
+        "GET /pinned HTTP/1.1\r\nHost: x\r\nX-Balancebeam-Upstream: {}\r\n\
+         X-Balancebeam-Token: {}\r\n\r\n",
+        upstream, token
+    );
+    let response = balancebeam.send_raw(request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+    assert!(!response.contains("x-balancebeam"), "override headers were forwarded: {:?}", response);
+    response
+}
+
+/// Requests carrying a valid token go to the upstream they name; anything else is balanced as
+/// usual. The override headers never reach an upstream.
+#[tokio::test]
+async fn test_debug_routing_override() {
+    init_logging();
+    let upstreams = vec![
+        EchoServer::new().await,
+        EchoServer::new().await,
+        EchoServer::new().await,
+    ];
+    let addresses: Vec<&str> = upstreams.iter().map(|upstream| upstream.address.as_str()).collect();
+    let balancebeam =
+        BalanceBeam::new_with_args(&addresses, &["--debug-routing-secret", ROUTING_SECRET]).await;
+
+    let target = addresses[2];
+    for _ in 0..10 {
+        send_with_override(&balancebeam, target, &routing_token(ROUTING_SECRET, target)).await;
+    }
+    assert!(balancebeam.output_contains("by debug override").await);
+
+    log::info!("Trying overrides with a bad token and an unknown upstream");
+    send_with_override(&balancebeam, target, &routing_token("wrong secret", target)).await;
+    send_with_override(&balancebeam, target, "not even hex").await;
+    let unknown = "127.0.0.1:1";
+    send_with_override(&balancebeam, unknown, &routing_token(ROUTING_SECRET, unknown)).await;
+    delay_for(Duration::from_millis(500)).await;
+    assert_eq!(balancebeam.output_count("by debug override"), 10);
+
+    let mut counts = Vec::new();
+    for upstream in upstreams {
+        counts.push(Box::new(upstream).stop().await);
+    }
+    assert!(counts[2] >= 10, "counts: {:?}", counts);
+    assert_eq!(counts.iter().sum::<usize>(), 13);
+
+    log::info!("All done :)");
+}
+
+/// An override naming a dead upstream is ignored and the request is served by a live one.
+#[tokio::test]
+async fn test_debug_routing_to_dead_upstream() {
+    init_logging();
+    let alive = EchoServer::new().await;
+    let dead = EchoServer::new().await;
+    let dead_address = dead.address.clone();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&alive.address, &dead_address],
+        &["--debug-routing-secret", ROUTING_SECRET],
+    )
+    .await;
+    Box::new(dead).stop().await;
+
+    let token = routing_token(ROUTING_SECRET, &dead_address);
+    for _ in 0..3 {
+        send_with_override(&balancebeam, &dead_address, &token).await;
+    }
+    delay_for(Duration::from_millis(500)).await;
+    assert_eq!(balancebeam.output_count("by debug override"), 0);
+    assert_eq!(Box::new(alive).stop().await, 3);
+
+    log::info!("All done :)");
+}
+
+/// Requests go to the highest-priority tier with a live upstream, and only fall through to a lower
+/// tier once every upstream above it is dead.
+#[tokio::test]
+async fn test_upstream_priority_tiers() {
+    init_logging();
+    let primary = EchoServer::new().await;
+    let secondary = EchoServer::new().await;
+    let last_resort = EchoServer::new().await;
+    let primary_priority = format!("{}=2", primary.address);
+    let secondary_priority = format!("{}=1", secondary.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&last_resort.address, &secondary.address, &primary.address],
+        &[
+            "--upstream-priority",
+            &primary_priority,
+            "--upstream-priority",
+            &secondary_priority,
+        ],
+    )
+    .await;
+
+    for i in 0..10 {
+        let path = format!("/primary-{}", i);
+        let response_text = balancebeam.get(&path).await.expect("Error sending request");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert_eq!(Box::new(primary).stop().await, 10);
+
+    log::info!("Primary is down; requests should go to the secondary tier");
+    for i in 0..10 {
+        let path = format!("/secondary-{}", i);
+        let response_text = balancebeam.get(&path).await.expect("Error sending request");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert_eq!(Box::new(secondary).stop().await, 10);
+    assert_eq!(Box::new(last_resort).stop().await, 0);
+
+    log::info!("All done :)");
+}
+
+/// --upstream-proto picks the HTTP version per upstream, so a pool can mix upstreams that only
+/// speak HTTP/1.0 with ones that speak HTTP/1.1.
+#[tokio::test]
+async fn test_upstream_proto_per_upstream() {
+    init_logging();
+    let legacy = EchoServer::new().await;
+    let modern = EchoServer::new().await;
+    let legacy_proto = format!("{}=http1.0", legacy.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&legacy.address, &modern.address],
+        &["--upstream-proto", &legacy_proto],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let mut http10_responses = 0;
+    for i in 0..20 {
+        let path = format!("/req-{}", i);
+        let response_text = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        if response_text.contains(&format!("GET {} HTTP/1.0", path)) {
+            assert!(response_text.contains("connection: close"));
+            http10_responses += 1;
+        } else {
+            assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+            assert!(!response_text.contains("connection: close"));
+        }
+    }
+
+    assert_eq!(Box::new(legacy).stop().await, http10_responses);
+    assert_eq!(Box::new(modern).stop().await, 20 - http10_responses);
+
+    log::info!("All done :)");
+}
+
+/// No more than --health-check-concurrency health checks are in flight at once, however many
+/// upstreams there are to check.
+#[tokio::test]
+async fn test_health_check_concurrency() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    init_logging();
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let most_in_flight = Arc::new(AtomicUsize::new(0));
+    let probes = Arc::new(AtomicUsize::new(0));
+    let mut addresses = Vec::new();
+    for _ in 0..6 {
+        // An upstream that takes a while to answer its health checks
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        addresses.push(listener.local_addr().unwrap().to_string());
+        let (in_flight, most_in_flight, probes) =
+            (in_flight.clone(), most_in_flight.clone(), probes.clone());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let (in_flight, most_in_flight, probes) =
+                    (in_flight.clone(), most_in_flight.clone(), probes.clone());
+                tokio::spawn(async move {
+                    let mut buffer = [0_u8; 1024];
+                    let _ = stream.read(&mut buffer).await;
+                    let now_in_flight = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_in_flight.fetch_max(now_in_flight, Ordering::SeqCst);
+                    delay_for(Duration::from_millis(300)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    probes.fetch_add(1, Ordering::SeqCst);
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                        .await;
+                });
+            }
+        });
+    }
+    let upstreams: Vec<&str> = addresses.iter().map(String::as_str).collect();
+    let _balancebeam = BalanceBeam::new_with_args(
+        &upstreams,
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-timeout-ms",
+            "2000",
+            "--health-check-concurrency",
+            "2",
+        ],
+    )
+    .await;
+
+    delay_for(Duration::from_millis(2500)).await;
+    assert!(probes.load(Ordering::SeqCst) >= 6, "Not every upstream was health checked");
+    assert_eq!(most_in_flight.load(Ordering::SeqCst), 2);
+
+    log::info!("All done :)");
+}
+
+/// With consistent hashing, every request from the same client goes to the same upstream.
+#[tokio::test]
+async fn test_consistent_hashing_sticks_to_one_upstream() {
+    init_logging();
+    let mut upstreams = Vec::new();
+    for _ in 0..3 {
+        upstreams.push(Box::new(EchoServer::new().await));
+    }
+    let addresses: Vec<&str> = upstreams.iter().map(|upstream| upstream.address.as_str()).collect();
+    let balancebeam =
+        BalanceBeam::new_with_args(&addresses, &["--load-balancing", "consistent-hashing"]).await;
+
+    for i in 0..12 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam.get(&path).await.expect("Error sending request");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let mut counts = Vec::new();
+    for upstream in upstreams {
+        counts.push(upstream.stop().await);
+    }
+    counts.sort_unstable();
+    assert_eq!(counts, vec![0, 0, 12]);
+
+    log::info!("All done :)");
+}
+
+/// A request whose upstream refuses the connection is retried on another one, never the same one
+/// again, even though the refusing upstream keeps being put back in rotation (its health checks are
+/// disabled, so every health check round revives it).
+#[tokio::test]
+async fn test_retry_excludes_failed_upstream() {
+    init_logging();
+    let healthy = EchoServer::new().await;
+    let refusing = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&refusing, &healthy.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--upstream-healthcheck-disable-for",
+            &refusing,
+        ],
+    )
+    .await;
+
+    let n_requests = 30;
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam.get(&path).await.expect("Error sending request");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)), "{}", response_text);
+        delay_for(Duration::from_millis(100)).await;
+    }
+
+    // Every request got through, and the refusing upstream was dialed at most once each time it
+    // was put back (or at the start), by whichever request drew it
+    let failed_dials = balancebeam.output_count("Failed to connect to upstream");
+    let revivals = balancebeam.output_count("(not health checked) is back in rotation");
+    log::info!("{} failed dials, {} revivals", failed_dials, revivals);
+    assert!(failed_dials >= 1, "the refusing upstream was never tried");
+    assert!(failed_dials <= revivals + 1, "{} failed dials", failed_dials);
+    Box::new(healthy).stop().await;
+
+    log::info!("All done :)");
+}
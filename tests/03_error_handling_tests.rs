@@ -0,0 +1,807 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, EchoServer, ErrorServer, RawServer, Server};
+use rand::Rng;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration, Instant};
+
+/// An address nothing is listening on
+fn unused_address() -> String {
+    free_address()
+}
+
+/// Malformed requests and oversized bodies are the client's fault, and should be logged as such.
+#[tokio::test]
+async fn test_client_error_reasons() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    log::info!("Sending a garbage request");
+    let response = balancebeam.send_raw(b"this is not http\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 400"), "got {:?}", response);
+    assert!(balancebeam.output_contains("reason=client_parse").await);
+
+    log::info!("Sending a request with an enormous Content-Length");
+    let response = balancebeam
+        .send_raw(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 99999999999\r\n\r\n")
+        .await;
+    assert!(response.starts_with("HTTP/1.1 413"), "got {:?}", response);
+    assert!(balancebeam.output_contains("reason=body_too_large").await);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// A chunked body is refused with a 413 as soon as it crosses --max-request-body-bytes, without
+/// waiting for the rest of it, and nothing reaches the upstream.
+#[tokio::test]
+async fn test_chunked_body_limit() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-request-body-bytes", "1000"])
+            .await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let chunk = format!("190\r\n{}\r\n", "x".repeat(400));
+    let mut request =
+        b"POST /upload HTTP/1.1\r\nHost: x\r\nContent-Type: multipart/form-data; boundary=b\r\n\
+          Transfer-Encoding: chunked\r\n\r\n"
+            .to_vec();
+    // 1200 bytes, but the terminating chunk never comes
+    for _ in 0..3 {
+        request.extend_from_slice(chunk.as_bytes());
+    }
+    stream.write_all(&request).await.unwrap();
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam waited for the rest of the body")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 413"), "got {:?}", response);
+    assert!(response.contains("connection: close\r\n"), "got {:?}", response);
+    assert!(balancebeam.output_contains("reason=body_too_large").await);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Requests in HTTP versions we don't speak get a 505 straight away and the connection is closed,
+/// even when the client waits for an answer rather than hanging up.
+#[tokio::test]
+async fn test_unsupported_http_versions() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let requests: [&[u8]; 3] = [
+        b"GET /index.html\r\n",
+        b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n",
+        b"GET / SPDY/3.1\r\nHost: x\r\n\r\n",
+    ];
+    for request in requests.iter() {
+        log::info!("Sending {:?}", String::from_utf8_lossy(request));
+        let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+            .await
+            .expect("balancebeam left the connection open")
+            .unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 505"), "got {:?}", response);
+        assert!(response.contains("connection: close\r\n"), "got {:?}", response);
+    }
+    // The last log line may not have been read from balancebeam's output yet
+    tokio::time::delay_for(Duration::from_millis(500)).await;
+    assert_eq!(balancebeam.output_count("reason=unsupported_version"), 3);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Content-Length values that other servers might read differently, or that don't match the body
+/// sent, are refused before anything reaches the upstream.
+#[tokio::test]
+async fn test_bad_content_lengths() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    for (content_length, body) in &[("+5", "hello"), ("5 ", "hello"), ("-1", ""), ("0", "hello")] {
+        log::info!("Sending Content-Length {:?} with a body of {:?}", content_length, body);
+        let request = format!(
+            "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\n{}",
+            content_length, body
+        );
+        let response = balancebeam.send_raw(request.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 400"), "got {:?}", response);
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Bodies declared bigger than the limits are refused on sight, in either direction, without
+/// waiting for any of the body; bodies bigger than declared are refused too.
+#[tokio::test]
+async fn test_absurd_content_lengths() {
+    init_logging();
+    let huge = RawServer::new_with_stall(
+        b"HTTP/1.1 200 OK\r\nContent-Length: 999999999999\r\n\r\nshort",
+        Duration::from_secs(4),
+        b"never mind",
+    )
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&huge.address],
+        &["--max-request-body-bytes", "1000", "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    log::info!("Declaring a huge request body and sending a tiny one");
+    let started = Instant::now();
+    let response = balancebeam
+        .send_raw(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 999999999999\r\n\r\nhi")
+        .await;
+    assert!(response.starts_with("HTTP/1.1 413"), "got {:?}", response);
+
+    log::info!("Declaring a tiny request body and sending a bigger one");
+    let body = "x".repeat(900);
+    let request = format!("POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\n\r\n{}", body);
+    let response = balancebeam.send_raw(request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 400"), "got {:?}", response);
+
+    log::info!("Getting a response that declares a huge body but stalls after a tiny one");
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 502"), "got {:?}", response);
+    assert!(started.elapsed() < Duration::from_secs(3), "took {:?}", started.elapsed());
+    assert!(balancebeam.output_contains("declared a 999999999999 byte body").await);
+    assert_eq!(Box::new(huge).stop().await, 1);
+
+    log::info!("Getting a response with more body than it declares");
+    let mut response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n".to_vec();
+    response.extend_from_slice(&[b'x'; 100_000]);
+    let long = RawServer::new(&response).await;
+    let balancebeam = BalanceBeam::new(&[&long.address], Some(60), None).await;
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 502"), "got {:?}", response);
+
+    assert_eq!(Box::new(long).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Requests that are ambiguous about their host are refused before anything routes them.
+#[tokio::test]
+async fn test_host_header_enforcement() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    log::info!("Sending a request with two Host headers");
+    let response = balancebeam
+        .send_raw(b"GET / HTTP/1.1\r\nHost: a.example\r\nHost: b.example\r\n\r\n")
+        .await;
+    assert!(response.starts_with("HTTP/1.1 400"), "got {:?}", response);
+
+    log::info!("Sending an HTTP/1.1 request without a Host header");
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 400"), "got {:?}", response);
+
+    log::info!("Sending an absolute-form request with a different Host header");
+    let response = balancebeam
+        .send_raw(b"GET http://b.example/p HTTP/1.1\r\nHost: a.example\r\n\r\n")
+        .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+    assert!(response.contains("host: b.example\n"), "got {:?}", response);
+    assert!(!response.contains("a.example"), "got {:?}", response);
+
+    // Only the last request got through
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_rate_limited_reason() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, Some(1)).await;
+
+    balancebeam.get("/ok").await.expect("Error sending request");
+    let response = balancebeam.send_raw(b"GET /limited HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 429"), "got {:?}", response);
+    assert!(balancebeam.output_contains("reason=rate_limited").await);
+    // The refused request never got as far as an upstream connection
+    assert_eq!(balancebeam.output_count("-> 127.0.0.1: GET /ok"), 1);
+    assert_eq!(balancebeam.output_count("-> 127.0.0.1: GET /limited"), 0);
+
+    log::info!("All done :)");
+}
+
+/// A failed connection attempt and an already-dead pool are distinct failures: the first is a
+/// broken upstream (502), the second has nothing to serve the request until an upstream comes back
+/// (503, with a hint of when to try again).
+#[tokio::test]
+async fn test_upstream_connect_and_all_dead_reasons() {
+    init_logging();
+    let balancebeam = BalanceBeam::new(&[&unused_address()], Some(3), None).await;
+
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 502"), "got {:?}", response);
+    assert!(!response.contains("retry-after"), "got {:?}", response);
+    assert!(balancebeam.output_contains("reason=upstream_connect").await);
+
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 503"), "got {:?}", response);
+    assert!(response.contains("retry-after: 3\r\n"), "got {:?}", response);
+    assert!(balancebeam.output_contains("reason=all_dead").await);
+
+    log::info!("All done :)");
+}
+
+/// When every upstream is failing, an exhausted retry budget should stop a single request from
+/// being tried against every upstream in turn.
+#[tokio::test]
+async fn test_retry_budget_suppresses_failover() {
+    init_logging();
+    let dead_upstreams = [unused_address(), unused_address(), unused_address()];
+    let dead_upstreams: Vec<&str> = dead_upstreams.iter().map(String::as_str).collect();
+
+    let generous =
+        BalanceBeam::new_with_args(&dead_upstreams, &["--retry-budget-percent", "1000"]).await;
+    let response = generous.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 502"), "got {:?}", response);
+    assert!(generous.output_contains("reason=upstream_connect").await);
+    assert_eq!(generous.output_count("Failed to connect to upstream"), 3);
+
+    let stingy = BalanceBeam::new_with_args(
+        &dead_upstreams,
+        &["--retry-budget-percent", "0", "--retry-budget-min-per-sec", "0"],
+    )
+    .await;
+    let response = stingy.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 502"), "got {:?}", response);
+    assert!(stingy.output_contains("reason=upstream_connect").await);
+    assert!(stingy.output_contains("Retry budget exhausted").await);
+    assert_eq!(stingy.output_count("Failed to connect to upstream"), 1);
+
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_upstream_response_reasons() {
+    init_logging();
+
+    log::info!("Testing an upstream that sends garbage");
+    let upstream = RawServer::new(b"garbage garbage garbage\r\n\r\n").await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 502"), "got {:?}", response);
+    assert!(balancebeam.output_contains("reason=upstream_parse").await);
+    Box::new(upstream).stop().await;
+
+    log::info!("Testing an upstream that hangs up without responding");
+    let upstream = RawServer::new(b"").await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 502"), "got {:?}", response);
+    assert!(balancebeam.output_contains("reason=upstream_read").await);
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// Upstreams that answer every request with a 500 stay alive, but once each of them has failed
+/// --failure-streak-alert requests in a row an error is logged (once), and the longest streak is
+/// exported.
+#[tokio::test]
+async fn test_failure_streak_alert() {
+    init_logging();
+    let upstreams = [ErrorServer::new().await, ErrorServer::new().await];
+    let admin_address = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0].address, &upstreams[1].address],
+        &["--failure-streak-alert", "2", "--admin-bind", &admin_address],
+    )
+    .await;
+
+    // With random selection each upstream gets at least two of these, barring extreme bad luck
+    for _ in 0..20 {
+        let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 500"), "got {:?}", response);
+    }
+    assert!(balancebeam.output_contains("All upstreams may be failing").await);
+    assert_eq!(balancebeam.output_count("All upstreams may be failing"), 1);
+
+    let metrics = reqwest::get(&format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error fetching metrics")
+        .text()
+        .await
+        .unwrap();
+    let streak: u64 = metrics
+        .lines()
+        .find_map(|line| line.strip_prefix("balancebeam_max_upstream_consecutive_failures "))
+        .expect("no consecutive failures gauge")
+        .parse()
+        .unwrap();
+    assert!((10..=18).contains(&streak), "got {}", streak);
+
+    log::info!("All done :)");
+}
+
+/// A backend that sends enormous headers gets a 502 rather than having them relayed, unless the
+/// limit is raised.
+#[tokio::test]
+async fn test_oversized_upstream_headers() {
+    init_logging();
+    let mut raw_response = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nSet-Cookie: ".to_vec();
+    raw_response.extend_from_slice(&[b'a'; 20000]);
+    raw_response.extend_from_slice(b"\r\n\r\n");
+
+    let upstream = RawServer::new(&raw_response).await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 502"), "got {:?}", response);
+    assert!(balancebeam.output_contains("reason=upstream_parse").await);
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--max-response-header-bytes", "32768"],
+    )
+    .await;
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", &response[..40]);
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_upstream_timeout() {
+    init_logging();
+    let upstream = RawServer::new_with_delay(
+        b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n",
+        Duration::from_secs(3),
+    )
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--upstream-response-header-timeout-ms", "500"],
+    )
+    .await;
+
+    let start = Instant::now();
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 504"), "got {:?}", response);
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert!(balancebeam.output_contains("reason=upstream_timeout").await);
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that accepts connections but never reads from them, returning its address.
+async fn start_unread_upstream() -> String {
+    let address = free_address();
+    let mut listener = TcpListener::bind(&address).await.unwrap();
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            held.push(stream);
+        }
+    });
+    address
+}
+
+/// A request bigger than the socket buffers can only be written to an upstream that reads it. If
+/// the upstream doesn't, we give up after --upstream-write-timeout and try another one, or answer
+/// 504 if there isn't one. Requests that aren't idempotent aren't tried again.
+#[tokio::test]
+async fn test_upstream_write_timeout() {
+    init_logging();
+    let mut request = b"POST /big HTTP/1.1\r\nHost: x\r\nContent-Length: 9000000\r\n\r\n".to_vec();
+    request.resize(request.len() + 9_000_000, b'x');
+
+    let stuck = start_unread_upstream().await;
+    let alone = BalanceBeam::new_with_args(&[&stuck], &["--upstream-write-timeout", "1"]).await;
+    let start = Instant::now();
+    let response = alone.send_raw(&request).await;
+    assert!(response.starts_with("HTTP/1.1 504"), "got {:?}", &response[..response.len().min(100)]);
+    assert!(start.elapsed() < Duration::from_secs(3));
+    assert!(alone.output_contains("reason=upstream_write_timeout").await);
+
+    let stuck = start_unread_upstream().await;
+    let healthy = EchoServer::new().await;
+    let priority = format!("{}=1", stuck);
+    let with_failover = BalanceBeam::new_with_args(
+        &[&stuck, &healthy.address],
+        &[
+            "--upstream-write-timeout",
+            "1",
+            // Make sure the stuck upstream is tried first
+            "--upstream-priority",
+            &priority,
+        ],
+    )
+    .await;
+    let response = with_failover.send_raw(&request).await;
+    assert!(response.starts_with("HTTP/1.1 504"), "got {:?}", &response[..response.len().min(100)]);
+    assert!(with_failover.output_contains("not retrying POST on another upstream").await);
+
+    // The stuck upstream was marked dead by the POST, so bring in a fresh one for the PUT
+    let stuck = start_unread_upstream().await;
+    let priority = format!("{}=1", stuck);
+    let with_failover = BalanceBeam::new_with_args(
+        &[&stuck, &healthy.address],
+        &[
+            "--upstream-write-timeout",
+            "1",
+            "--upstream-priority",
+            &priority,
+        ],
+    )
+    .await;
+    request.splice(..4, b"PUT".iter().copied());
+    let response = with_failover.send_raw(&request).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", &response[..response.len().min(100)]);
+    assert!(response.contains("PUT /big HTTP/1.1"));
+    assert!(with_failover.output_contains("retrying on upstream").await);
+    assert_eq!(Box::new(healthy).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// An upstream that closed a kept-alive connection fails the next request written to it. That
+/// request never reached the upstream, so it is sent to another one, even if it is a POST.
+#[tokio::test]
+async fn test_upstream_write_error_fails_over() {
+    init_logging();
+    let hangs_up = RawServer::new(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let healthy = EchoServer::new().await;
+    let priority = format!("{}=1", hangs_up.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&hangs_up.address, &healthy.address],
+        &["--upstream-priority", &priority, "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client.write_all(b"GET /first HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+    let mut first = [0_u8; 1024];
+    let n = client.read(&mut first).await.unwrap();
+    let response = String::from_utf8_lossy(&first[..n]);
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+
+    // The POST is too big for the socket buffers, so writing it runs into the hangup
+    let mut request = b"POST /big HTTP/1.1\r\nHost: x\r\nContent-Length: 9000000\r\n".to_vec();
+    request.extend_from_slice(b"Connection: close\r\n\r\n");
+    request.resize(request.len() + 9_000_000, b'x');
+    client.write_all(&request).await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", &response[..response.len().min(100)]);
+    assert!(response.contains("POST /big HTTP/1.1"));
+    assert!(balancebeam.output_contains("Failed to send request to upstream").await);
+    assert!(balancebeam.output_contains("retrying on upstream").await);
+    assert_eq!(Box::new(hangs_up).stop().await, 1);
+    assert_eq!(Box::new(healthy).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// The header timeout stops applying once headers arrive; from then on the body timeout does.
+#[tokio::test]
+async fn test_upstream_body_timeout() {
+    init_logging();
+    let head = b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nab";
+    let slow_body = RawServer::new_with_stall(head, Duration::from_secs(1), b"cd").await;
+    let patient = BalanceBeam::new_with_args(
+        &[&slow_body.address],
+        &[
+            "--upstream-response-header-timeout-ms",
+            "300",
+            "--upstream-response-body-timeout-ms",
+            "3000",
+        ],
+    )
+    .await;
+    let response = patient.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+    assert!(response.ends_with("abcd"), "got {:?}", response);
+    Box::new(slow_body).stop().await;
+
+    let slow_body = RawServer::new_with_stall(head, Duration::from_secs(3), b"cd").await;
+    let impatient = BalanceBeam::new_with_args(
+        &[&slow_body.address],
+        &["--upstream-response-body-timeout-ms", "300"],
+    )
+    .await;
+    let start = Instant::now();
+    let response = impatient.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 504"), "got {:?}", response);
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert!(impatient.output_contains("didn't send the response body").await);
+    Box::new(slow_body).stop().await;
+
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_preflight_check_marks_unreachable_upstreams_dead() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let dead_address = unused_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address, &dead_address],
+        &["--preflight-check"],
+    )
+    .await;
+    assert!(balancebeam.output_contains(&format!("{}: SUCCESS", upstream.address)).await);
+    assert!(balancebeam.output_contains(&format!("{}: FAILED", dead_address)).await);
+
+    // The unreachable upstream should never be dialed for client requests
+    for _ in 0..10 {
+        balancebeam.get("/").await.expect("Error sending request to balancebeam");
+    }
+    assert_eq!(balancebeam.output_count("Failed to connect to upstream"), 0);
+    assert_eq!(Box::new(upstream).stop().await, 10);
+
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_preflight_check_can_require_all_upstreams() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address, &unused_address()],
+        &["--preflight-check", "--require-all-upstreams-reachable"],
+    )
+    .await;
+    let status = balancebeam.wait_for_exit().await.expect("balancebeam didn't exit");
+    assert_eq!(status.code(), Some(1));
+
+    log::info!("All done :)");
+}
+
+/// A request that fails to parse should be dumped to the capture directory, with its credentials
+/// redacted.
+#[tokio::test]
+async fn test_debug_capture_of_parse_error() {
+    init_logging();
+    let capture_dir = std::env::temp_dir().join(format!(
+        "balancebeam-test-capture-{}",
+        rand::thread_rng().gen::<u32>()
+    ));
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--debug-capture-dir", capture_dir.to_str().unwrap()],
+    )
+    .await;
+
+    balancebeam.get("/fine").await.expect("Error sending request to balancebeam");
+    let response = balancebeam
+        .send_raw(b"GET /broken HTTP/1.1\r\nAuthorization: Bearer hunter2\r\nBad Header\r\n\r\n")
+        .await;
+    assert!(response.starts_with("HTTP/1.1 400"), "got {:?}", response);
+
+    let files: Vec<_> = std::fs::read_dir(&capture_dir)
+        .expect("Capture directory wasn't created")
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    assert_eq!(files.len(), 1, "expected exactly one capture file: {:?}", files);
+    let contents = String::from_utf8(std::fs::read(&files[0]).unwrap()).unwrap();
+    assert!(contents.contains("GET /broken HTTP/1.1"));
+    assert!(contents.contains("Authorization: [REDACTED]"));
+    assert!(!contents.contains("hunter2"));
+    assert!(contents.contains("HTTP/1.1 400 Bad Request"));
+
+    std::fs::remove_dir_all(&capture_dir).unwrap();
+    log::info!("All done :)");
+}
+
+/// Fault injection fails or delays requests on purpose, without the failures reaching upstreams.
+#[tokio::test]
+async fn test_fault_injection() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let failing =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--fault-inject-error-rate", "1"])
+            .await;
+    let response = failing.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 500"), "got {:?}", response);
+    assert!(failing.output_contains("reason=fault_injected").await);
+
+    let unreachable = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--fault-inject-upstream-failure-rate", "1"],
+    )
+    .await;
+    let response = unreachable.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 502"), "got {:?}", response);
+    assert!(unreachable.output_contains("reason=upstream_connect").await);
+
+    let admin_address = free_address();
+    let slow = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--fault-inject-delay-ms",
+            "500",
+            "--fault-inject-delay-rate",
+            "1",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+    let start = Instant::now();
+    let response_text = slow.get("/delayed").await.expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /delayed HTTP/1.1"));
+    assert!(start.elapsed() >= Duration::from_millis(500));
+    let metrics = reqwest::get(&format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("balancebeam_faults_injected_total{fault=\"delay\"} 1\n"));
+    assert!(metrics.contains("balancebeam_faults_injected_total{fault=\"error\"} 0\n"));
+
+    // Only the delayed request got through
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// With --max-buffered-bytes, requests whose bodies won't fit in what's left get a 503 rather than
+/// growing memory without bound, and the proxy carries on serving once buffers are freed.
+#[tokio::test]
+async fn test_max_buffered_bytes() {
+    init_logging();
+    let upstream = RawServer::new_with_delay(
+        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+        Duration::from_millis(500),
+    )
+    .await;
+    let admin_address = free_address();
+    let balancebeam = Arc::new(
+        BalanceBeam::new_with_args(
+            &[&upstream.address],
+            &[
+                "--max-buffered-bytes",
+                "100000",
+                "--admin-bind",
+                &admin_address,
+                "--active-health-check-interval",
+                "60",
+            ],
+        )
+        .await,
+    );
+    let body = "x".repeat(30000);
+    let request = format!(
+        "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+
+    log::info!("Sending 5 concurrent POSTs, of which only 3 fit");
+    let posts: Vec<_> = (0..5)
+        .map(|_| {
+            let balancebeam = balancebeam.clone();
+            let request = request.clone();
+            tokio::spawn(async move { balancebeam.send_raw(request.as_bytes()).await })
+        })
+        .collect();
+    let mut responses = Vec::new();
+    for post in posts {
+        responses.push(post.await.unwrap());
+    }
+    let ok = responses.iter().filter(|r| r.starts_with("HTTP/1.1 200")).count();
+    let unavailable: Vec<&String> =
+        responses.iter().filter(|r| r.starts_with("HTTP/1.1 503")).collect();
+    assert_eq!((ok, unavailable.len()), (3, 2), "got {:?}", responses);
+    assert!(unavailable.iter().all(|r| r.contains("retry-after: 1\r\n")));
+    assert!(balancebeam.output_contains("reason=buffer_limit").await);
+
+    log::info!("Checking that requests fit again once the buffers are freed");
+    let response = balancebeam.send_raw(request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+    let metrics = reqwest::get(&format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("balancebeam_buffered_bytes 0\n"), "{}", metrics);
+    assert!(metrics.contains("balancebeam_proxy_errors_total{reason=\"buffer_limit\"} 2\n"));
+    let peak: usize = metrics
+        .lines()
+        .find_map(|line| line.strip_prefix("balancebeam_buffered_bytes_peak "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((90000..=100000).contains(&peak), "peak {}", peak);
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// --dry-upstream-connect runs requests through everything up to the upstream's response without
+/// contacting any upstream, so each one ends in a 502.
+#[tokio::test]
+async fn test_dry_upstream_connect() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--dry-upstream-connect", "--max-requests-per-minute", "2"],
+    )
+    .await;
+    for _ in 0..2 {
+        let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 502"), "got {:?}", response);
+    }
+    // The last log line may not have been read from balancebeam's output yet
+    tokio::time::delay_for(Duration::from_millis(500)).await;
+    assert_eq!(balancebeam.output_count("reason=upstream_read"), 2);
+    // Rate limiting still applies
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 429"), "got {:?}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// A client that hangs up in the middle of a large response is counted as an abort rather than as
+/// a response, its upstream connection isn't reused, and the next client is served as usual.
+#[tokio::test]
+async fn test_client_aborts_mid_response() {
+    init_logging();
+    let body = vec![b'x'; 8_000_000];
+    let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+    let mut response = response.into_bytes();
+    response.extend_from_slice(&body);
+    let upstream = RawServer::new(&response).await;
+    let admin_address = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--admin-bind", &admin_address, "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    log::info!("Reading the start of a large response, then hanging up");
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+    let mut start = [0_u8; 1024];
+    client.read_exact(&mut start).await.unwrap();
+    assert!(start.starts_with(b"HTTP/1.1 200"));
+    drop(client);
+    assert!(balancebeam.output_contains("aborted by the client").await);
+
+    log::info!("Checking that a fresh client is served in full");
+    let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", &response[..300]);
+    assert!(response.ends_with(&"x".repeat(1000)));
+    assert_eq!(response.len(), response.find("\r\n\r\n").unwrap() + 4 + body.len());
+    // Each request had a connection of its own
+    assert_eq!(upstream.peers().len(), 2);
+
+    let metrics = reqwest::get(&format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("balancebeam_client_aborts_total 1\n"), "{}", metrics);
+    assert!(metrics.contains("balancebeam_responses_total{class=\"2xx\"} 1\n"), "{}", metrics);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
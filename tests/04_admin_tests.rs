@@ -0,0 +1,905 @@
+mod common;
+
+use common::{
+    free_address, init_logging, BalanceBeam, EchoServer, ErrorServer, RawServer, Server,
+};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{delay_for, timeout, Duration};
+
+async fn setup() -> (BalanceBeam, EchoServer, String) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = free_address();
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--admin-bind", &admin_address]).await;
+    (balancebeam, upstream, admin_address)
+}
+
+async fn admin_get(admin_address: &str, path: &str) -> reqwest::Response {
+    reqwest::get(&format!("http://{}{}", admin_address, path))
+        .await
+        .expect("Error sending request to admin API")
+}
+
+#[tokio::test]
+async fn test_status_reports_uptime() {
+    let (_balancebeam, upstream, admin_address) = setup().await;
+
+    let response = admin_get(&admin_address, "/status").await;
+    assert_eq!(response.status().as_u16(), 200);
+    let status: serde_json::Value =
+        serde_json::from_str(&response.text().await.unwrap()).expect("/status is not JSON");
+    assert!(status["uptime_secs"].as_u64().is_some(), "no uptime in {}", status);
+    assert_eq!(status["upstreams"][0]["address"], upstream.address.as_str());
+    assert_eq!(status["upstreams"][0]["alive"], true);
+
+    log::info!("All done :)");
+}
+
+/// Upstreams listed in --upstream-healthcheck-disable-for are never probed, so they stay alive
+/// even though their health checks would fail.
+#[tokio::test]
+async fn test_health_check_disabled() {
+    init_logging();
+    let unchecked = ErrorServer::new().await;
+    let checked = ErrorServer::new().await;
+    let admin_address = free_address();
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&unchecked.address, &checked.address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "1",
+            "--upstream-healthcheck-disable-for",
+            &unchecked.address,
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for a health check");
+    delay_for(Duration::from_secs(2)).await;
+    let status: serde_json::Value =
+        serde_json::from_str(&admin_get(&admin_address, "/status").await.text().await.unwrap())
+            .expect("/status is not JSON");
+    assert_eq!(status["upstreams"][0]["address"], unchecked.address.as_str());
+    assert_eq!(status["upstreams"][0]["alive"], true);
+    assert_eq!(status["upstreams"][0]["state"], "health_check_disabled");
+    assert_eq!(status["upstreams"][1]["alive"], false);
+    assert_eq!(status["upstreams"][1]["state"], "dead");
+    assert_eq!(Box::new(unchecked).stop().await, 0, "the excluded upstream was health checked");
+
+    log::info!("All done :)");
+}
+
+/// Clients asking for the health check path get a 404 unless they are allowed to, while the health
+/// checks themselves still reach the upstream and keep it alive.
+#[tokio::test]
+async fn test_health_path_blocked_from_clients() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = free_address();
+    let health_args = ["--active-health-check-path", "/healthz", "--active-health-check-interval"];
+    let mut args = health_args.to_vec();
+    args.extend_from_slice(&["1", "--admin-bind", &admin_address]);
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &args).await;
+    let get = |path: &str| format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path);
+
+    for path in &["/healthz", "/HealthZ/", "/%68ealthz?verbose=1"] {
+        let response = balancebeam.send_raw(get(path).as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{} got {:?}", path, response);
+    }
+    let response = balancebeam.send_raw(get("/healthz/deeper").as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+
+    log::info!("Waiting for health checks");
+    delay_for(Duration::from_secs(2)).await;
+    let status: serde_json::Value =
+        serde_json::from_str(&admin_get(&admin_address, "/status").await.text().await.unwrap())
+            .expect("/status is not JSON");
+    assert_eq!(status["upstreams"][0]["alive"], true);
+    assert_eq!(balancebeam.output_count("reason=health_path_blocked"), 3);
+    // One request from a client, the rest health checks
+    let received = Box::new(upstream).stop().await;
+    assert!(received >= 2, "only {} requests reached the upstream", received);
+
+    log::info!("Checking that allowed clients and disabled blocking get through");
+    let upstream = EchoServer::new().await;
+    let allowed = ["--health-path-allow-cidr", "127.0.0.0/8"];
+    let unblocked = ["--block-health-path-from-clients", "false"];
+    for extra in &[allowed, unblocked] {
+        let mut args = health_args.to_vec();
+        args.push("60");
+        args.extend_from_slice(extra);
+        let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &args).await;
+        let response = balancebeam.send_raw(get("/healthz").as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+    }
+
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_upstreams_snapshot() {
+    let (balancebeam, upstream, admin_address) = setup().await;
+    for path in &["/one", "/two"] {
+        balancebeam.get(path).await.expect("Error sending request to balancebeam");
+    }
+
+    let response = admin_get(&admin_address, "/upstreams").await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["content-type"], "application/json");
+    let upstreams: serde_json::Value =
+        serde_json::from_str(&response.text().await.unwrap()).expect("/upstreams is not JSON");
+    let upstream_json = &upstreams[0];
+    assert_eq!(upstreams.as_array().map(Vec::len), Some(1));
+    assert_eq!(upstream_json["address"], upstream.address.as_str());
+    assert_eq!(upstream_json["alive"], true);
+    assert_eq!(upstream_json["circuit_state"], "alive");
+    assert_eq!(upstream_json["total_requests"], 2);
+    assert_eq!(upstream_json["error_rate_1m"], 0.0);
+    assert!(upstream_json["p99_latency_ms"].as_u64().is_some(), "{}", upstream_json);
+    assert_eq!(upstream_json["weight"], 1.0);
+    assert_eq!(upstream_json["health_weight"], 1.0);
+
+    log::info!("All done :)");
+}
+
+/// Removing an upstream stops new requests going to it, but lets the one it is serving finish.
+#[tokio::test]
+async fn test_remove_upstream() {
+    init_logging();
+    let slow = RawServer::new_with_delay(
+        b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow",
+        Duration::from_secs(2),
+    )
+    .await;
+    let fast = EchoServer::new().await;
+    let admin_address = free_address();
+    let priority = format!("{}=1", slow.address);
+    let balancebeam = Arc::new(
+        BalanceBeam::new_with_args(
+            &[&slow.address, &fast.address],
+            &["--admin-bind", &admin_address, "--upstream-priority", &priority],
+        )
+        .await,
+    );
+
+    let in_flight = {
+        let balancebeam = balancebeam.clone();
+        tokio::spawn(async move { balancebeam.get("/slow").await })
+    };
+    delay_for(Duration::from_millis(500)).await;
+    let upstream_url = format!("http://{}/upstreams/{}", admin_address, slow.address);
+    let response = reqwest::Client::new()
+        .delete(&upstream_url)
+        .send()
+        .await
+        .expect("Error sending request to admin API");
+    assert_eq!(response.status().as_u16(), 200);
+    let removed: serde_json::Value =
+        serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(removed[0]["circuit_state"], "removing");
+    assert_eq!(removed[0]["in_flight"], 1);
+
+    let response_text = balancebeam.get("/after-removal").await.unwrap();
+    assert!(response_text.contains("GET /after-removal HTTP/1.1"), "got {:?}", response_text);
+    assert_eq!(in_flight.await.unwrap().unwrap(), "slow");
+
+    // Once it has drained it is gone
+    delay_for(Duration::from_millis(300)).await;
+    let response = admin_get(&admin_address, &format!("/upstreams/{}", slow.address)).await;
+    assert_eq!(response.status().as_u16(), 404);
+    assert_eq!(admin_get(&admin_address, "/upstreams/10.0.0.1:1").await.status().as_u16(), 404);
+
+    assert_eq!(Box::new(slow).stop().await, 1);
+    assert_eq!(Box::new(fast).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Requests a removed upstream hasn't answered by the end of the grace period have their
+/// connections closed, and the next upstream added takes its slot.
+#[tokio::test]
+async fn test_removal_grace_period() {
+    init_logging();
+    let slow = RawServer::new_with_delay(
+        b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow",
+        Duration::from_secs(5),
+    )
+    .await;
+    let fast = EchoServer::new().await;
+    let admin_address = free_address();
+    let priority = format!("{}=1", slow.address);
+    let balancebeam = Arc::new(
+        BalanceBeam::new_with_args(
+            &[&slow.address, &fast.address],
+            &[
+                "--admin-bind",
+                &admin_address,
+                "--upstream-priority",
+                &priority,
+                "--removal-grace-period-secs",
+                "1",
+            ],
+        )
+        .await,
+    );
+
+    let in_flight = {
+        let balancebeam = balancebeam.clone();
+        tokio::spawn(async move {
+            balancebeam.send_raw(b"GET /slow HTTP/1.1\r\nHost: x\r\n\r\n").await
+        })
+    };
+    delay_for(Duration::from_millis(500)).await;
+    let upstream_url = format!("http://{}/upstreams/{}", admin_address, slow.address);
+    let client = reqwest::Client::new();
+    let response = client.delete(&upstream_url).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let response = timeout(Duration::from_secs(3), in_flight)
+        .await
+        .expect("the removed upstream's connection wasn't closed")
+        .unwrap();
+    assert!(response.starts_with("HTTP/1.1 502"), "got {:?}", response);
+    assert!(balancebeam.output_contains("closing their connections").await);
+
+    delay_for(Duration::from_millis(300)).await;
+    let response = admin_get(&admin_address, &format!("/upstreams/{}", slow.address)).await;
+    assert_eq!(response.status().as_u16(), 404);
+    let response = client.put(&upstream_url).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let added: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(added[0]["id"], 0);
+    assert_eq!(added[0]["total_requests"], 0);
+    assert_eq!(slow.requests().len(), 1);
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_metrics_exposition() {
+    let (balancebeam, _upstream, admin_address) = setup().await;
+    balancebeam.send_raw(b"garbage\r\n\r\n").await;
+    balancebeam.get("/cacheable").await.unwrap();
+    balancebeam.post("/not-cacheable", "body").await.unwrap();
+
+    let response = admin_get(&admin_address, "/metrics").await;
+    assert_eq!(response.status().as_u16(), 200);
+    let metrics = response.text().await.unwrap();
+    assert!(metrics.contains("# TYPE balancebeam_uptime_seconds counter"));
+    assert!(metrics.contains(&format!(
+        "balancebeam_build_info{{version=\"{}\"",
+        env!("CARGO_PKG_VERSION")
+    )));
+    assert!(metrics.contains("balancebeam_proxy_errors_total{reason=\"client_parse\"} 1"));
+    assert!(metrics.contains("balancebeam_retries_suppressed_total 0"));
+    assert!(metrics.contains("balancebeam_cacheable_responses_total 1\n"));
+
+    let response = admin_get(&admin_address, "/nope").await;
+    assert_eq!(response.status().as_u16(), 404);
+
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_pushgateway() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let pushgateway = RawServer::new(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+    let pushgateway_url = format!("http://{}/gateway", pushgateway.address);
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--pushgateway-url", &pushgateway_url, "--pushgateway-interval-secs", "1"],
+    )
+    .await;
+
+    delay_for(Duration::from_secs(2)).await;
+    let pushes = pushgateway.requests();
+    assert!(pushes.len() >= 2, "only {} pushes", pushes.len());
+    assert!(pushes[0].starts_with("POST /gateway/metrics/job/balancebeam HTTP/1.1\r\n"));
+    assert!(pushes[0].contains("balancebeam_uptime_seconds"), "{:?}", pushes[0]);
+    Box::new(pushgateway).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// Upstreams can be added and removed while requests are in flight, and every request is counted
+/// against the upstream that actually served it, even when an address is removed and added again
+/// in the slot it left.
+#[tokio::test]
+async fn test_add_and_remove_upstreams_under_traffic() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let admin_address = free_address();
+    // Health checks would add requests the upstreams count but balancebeam doesn't
+    let balancebeam = Arc::new(
+        BalanceBeam::new_with_args(
+            &[&first.address],
+            &["--admin-bind", &admin_address, "--active-health-check-interval", "60"],
+        )
+        .await,
+    );
+
+    let traffic: Vec<_> = (0..4)
+        .map(|task| {
+            let balancebeam = balancebeam.clone();
+            tokio::spawn(async move {
+                for i in 0..25 {
+                    let path = format!("/task-{}-{}", task, i);
+                    let response_text = balancebeam.get(&path).await.unwrap();
+                    assert!(response_text.contains(&path), "got {:?}", response_text);
+                    delay_for(Duration::from_millis(10)).await;
+                }
+            })
+        })
+        .collect();
+    let client = reqwest::Client::new();
+    for (method, address) in &[
+        (reqwest::Method::PUT, &second.address),
+        (reqwest::Method::DELETE, &first.address),
+        (reqwest::Method::PUT, &first.address),
+    ] {
+        delay_for(Duration::from_millis(60)).await;
+        let url = format!("http://{}/upstreams/{}", admin_address, address);
+        if *method == reqwest::Method::PUT && *address == &first.address {
+            // Wait for the removal to finish, so that the address comes back in its old slot
+            let path = format!("/upstreams/{}", address);
+            while admin_get(&admin_address, &path).await.status().as_u16() != 404 {
+                delay_for(Duration::from_millis(50)).await;
+            }
+        }
+        let response = client.request(method.clone(), &url).send().await.unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    for task in traffic {
+        task.await.unwrap();
+    }
+
+    let response = admin_get(&admin_address, "/upstreams").await;
+    let upstreams: serde_json::Value =
+        serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    let upstreams = upstreams.as_array().unwrap();
+    let ids: Vec<u64> = upstreams.iter().map(|upstream| upstream["id"].as_u64().unwrap()).collect();
+    assert_eq!(ids, vec![0, 1]);
+    assert_eq!(upstreams[0]["address"], first.address.as_str());
+    assert_eq!(upstreams[0]["circuit_state"], "alive");
+    let served: Vec<u64> = upstreams
+        .iter()
+        .map(|upstream| upstream["total_requests"].as_u64().unwrap())
+        .collect();
+    assert!(served[1] > 0, "added upstream got no traffic: {:?}", served);
+    assert_eq!(balancebeam.output_count("removed after serving"), 1);
+
+    // The removed upstream's counters went with it; the upstream now in its slot started afresh
+    let first_served = Box::new(first).stop().await as u64;
+    let second_served = Box::new(second).stop().await as u64;
+    assert_eq!(first_served + second_served, 100);
+    assert!(served[0] < first_served, "{:?} of {}", served, first_served);
+    assert_eq!(second_served, served[1]);
+    log::info!("All done :)");
+}
+
+/// In observe mode requests over the rate limit are forwarded but reported; switching to enforce
+/// through the admin API starts rejecting them without resetting the counts.
+#[tokio::test]
+async fn test_rate_limit_observe_mode() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--max-requests-per-minute",
+            "2",
+            "--rate-limit-mode",
+            "observe",
+        ],
+    )
+    .await;
+
+    for i in 0..5 {
+        let response = balancebeam.send_raw(b"GET /burst HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "request {} got {:?}", i, response);
+    }
+    assert!(balancebeam.output_contains("over its rate limit; forwarding anyway").await);
+    let status: serde_json::Value =
+        serde_json::from_str(&admin_get(&admin_address, "/status").await.text().await.unwrap())
+            .unwrap();
+    assert_eq!(status["rate_limit"]["mode"], "observe");
+    assert_eq!(
+        status["rate_limit"]["top_offenders"],
+        serde_json::json!([{"client": "127.0.0.1", "over_limit": 3}])
+    );
+
+    let response = reqwest::Client::new()
+        .put(&format!("http://{}/rate-limit-mode", admin_address))
+        .body("enforce")
+        .send()
+        .await
+        .expect("Error sending request to admin API");
+    assert_eq!(response.status().as_u16(), 200);
+    let response = balancebeam.send_raw(b"GET /burst HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 429"), "got {:?}", response);
+    let status: serde_json::Value =
+        serde_json::from_str(&admin_get(&admin_address, "/status").await.text().await.unwrap())
+            .unwrap();
+    assert_eq!(status["rate_limit"]["mode"], "enforce");
+    assert_eq!(status["rate_limit"]["top_offenders"][0]["over_limit"], 4);
+
+    assert_eq!(Box::new(upstream).stop().await, 5);
+    log::info!("All done :)");
+}
+
+/// A scheduled profile replaces the rate limit while its window is open, shows up in /status,
+/// and hands back to the configured limit when the window closes.
+#[tokio::test]
+async fn test_rate_limit_schedule() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = free_address();
+    // A window opening a few seconds from now, long enough to start balancebeam before it
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let opens = now.as_secs() + 4;
+    let schedule = format!(
+        "name=batch,start={:02}:{:02}:{:02},duration=4s,limit=10",
+        opens % 86400 / 3600,
+        opens % 3600 / 60,
+        opens % 60
+    );
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--max-requests-per-minute",
+            "1",
+            "--rate-limit-schedule",
+            &schedule,
+        ],
+    )
+    .await;
+    let profile = || async {
+        let status: serde_json::Value =
+            serde_json::from_str(&admin_get(&admin_address, "/status").await.text().await.unwrap())
+                .unwrap();
+        status["rate_limit"]["profile"].clone()
+    };
+    let burst = || balancebeam.send_raw(b"GET /burst HTTP/1.1\r\nHost: x\r\n\r\n");
+    let sleep_until = |secs: u64| {
+        let deadline = Duration::from_secs(secs) + Duration::from_millis(500);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        delay_for(deadline.checked_sub(now).unwrap_or_default())
+    };
+
+    assert!(burst().await.starts_with("HTTP/1.1 200"));
+    assert!(burst().await.starts_with("HTTP/1.1 429"));
+    assert_eq!(profile().await, serde_json::Value::Null);
+
+    // The schedule is checked every second, so give it one to notice the window
+    sleep_until(opens + 1).await;
+    assert_eq!(profile().await, serde_json::json!({"name": "batch", "limit": 10}));
+    let response = burst().await;
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+    assert!(balancebeam.output_contains("Rate limit profile batch is now in force").await);
+
+    sleep_until(opens + 5).await;
+    assert_eq!(profile().await, serde_json::Value::Null);
+    let response = burst().await;
+    assert!(response.starts_with("HTTP/1.1 429"), "got {:?}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+async fn get_with_token(address: &str, path: &str, token: Option<&str>) -> reqwest::Response {
+    let mut request = reqwest::Client::new().get(&format!("http://{}{}", address, path));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    request.send().await.expect("Error sending request to admin API")
+}
+
+/// The admin API only answers peers in its allow list that present its token, if it has one.
+#[tokio::test]
+async fn test_admin_access_control() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--admin-bind", &admin_address, "--admin-token", "s3cret"],
+    )
+    .await;
+    let response = get_with_token(&admin_address, "/status", Some("s3cret")).await;
+    assert_eq!(response.status().as_u16(), 200);
+    for token in &[None, Some("wrong")] {
+        let response = get_with_token(&admin_address, "/status", *token).await;
+        assert_eq!(response.status().as_u16(), 401);
+        assert_eq!(response.headers()["www-authenticate"], "Bearer");
+    }
+    assert!(
+        balancebeam
+            .output_contains("admin: refused GET /status HTTP/1.1 from 127.0.0.1 with 401")
+            .await
+    );
+
+    log::info!("Checking that peers outside the allow list are refused, token or not");
+    let admin_address = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--admin-allow-cidr",
+            "10.0.0.0/8",
+            "--admin-allow-cidr",
+            "192.0.2.0/24",
+        ],
+    )
+    .await;
+    // The connection is closed before the request is read
+    assert!(reqwest::get(&format!("http://{}/status", admin_address)).await.is_err());
+    assert!(balancebeam.output_contains("admin: refused connection from 127.0.0.1").await);
+
+    log::info!("All done :)");
+}
+
+/// --metrics-bind serves only /metrics, with access control of its own.
+#[tokio::test]
+async fn test_metrics_listener() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = free_address();
+    let metrics_address = free_address();
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--admin-token",
+            "admin-token",
+            "--metrics-bind",
+            &metrics_address,
+            "--metrics-token",
+            "scraper-token",
+        ],
+    )
+    .await;
+    let response = get_with_token(&metrics_address, "/metrics", Some("scraper-token")).await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.text().await.unwrap().contains("balancebeam_uptime_seconds"));
+    let response = get_with_token(&metrics_address, "/status", Some("scraper-token")).await;
+    assert_eq!(response.status().as_u16(), 404);
+
+    // Each listener only takes its own token
+    let response = get_with_token(&metrics_address, "/metrics", Some("admin-token")).await;
+    assert_eq!(response.status().as_u16(), 401);
+    let response = get_with_token(&admin_address, "/metrics", Some("scraper-token")).await;
+    assert_eq!(response.status().as_u16(), 401);
+
+    log::info!("All done :)");
+}
+
+/// Request body sizes land in the size buckets, and their percentiles show in /status and /metrics.
+#[tokio::test]
+async fn test_traffic_percentiles() {
+    let (balancebeam, _upstream, admin_address) = setup().await;
+    for size in [100; 8].iter().chain([5000; 2].iter()) {
+        balancebeam
+            .post("/sized", &"x".repeat(*size))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let response = admin_get(&admin_address, "/status").await;
+    let status: serde_json::Value =
+        serde_json::from_str(&response.text().await.unwrap()).expect("/status is not JSON");
+    let request_sizes = &status["traffic"]["request_body_bytes"];
+    assert_eq!(request_sizes["count"], 10, "{}", status);
+    assert_eq!(request_sizes["p50"], 256);
+    assert_eq!(request_sizes["p90"], 16384);
+    assert_eq!(request_sizes["p99"], 16384);
+    // The echoed responses are a little bigger than the requests, but not by a bucket
+    assert_eq!(status["traffic"]["response_body_bytes"]["p50"], 256);
+    assert_eq!(status["traffic"]["response_body_bytes"]["p99"], 16384);
+    assert!(status["traffic"]["connection_lifetime_ms"].is_object());
+
+    let metrics = admin_get(&admin_address, "/metrics").await.text().await.unwrap();
+    assert!(metrics.contains("# TYPE balancebeam_request_body_bytes histogram\n"));
+    assert!(metrics.contains("balancebeam_request_body_bytes_bucket{le=\"256\"} 8\n"));
+    assert!(metrics.contains("balancebeam_request_body_bytes_bucket{le=\"16384\"} 10\n"));
+    assert!(metrics.contains("balancebeam_request_body_bytes_sum 10800\n"));
+    assert!(metrics.contains("balancebeam_connection_lifetime_seconds_bucket{le=\"0.01\"} "));
+
+    log::info!("All done :)");
+}
+
+async fn admin_connections(admin_address: &str) -> Vec<serde_json::Value> {
+    let response = admin_get(admin_address, "/connections").await;
+    assert_eq!(response.status().as_u16(), 200);
+    let connections: serde_json::Value =
+        serde_json::from_str(&response.text().await.unwrap()).expect("/connections is not JSON");
+    connections.as_array().unwrap().clone()
+}
+
+async fn close_connection(admin_address: &str, id: u64) -> reqwest::Response {
+    reqwest::Client::new()
+        .delete(&format!("http://{}/connections/{}", admin_address, id))
+        .send()
+        .await
+        .expect("Error sending request to admin API")
+}
+
+/// Open client connections are listed, and closing one lets its current response finish before the
+/// connection is closed cleanly. Idle connections are closed straight away.
+#[tokio::test]
+async fn test_list_and_close_connections() {
+    init_logging();
+    let upstream = RawServer::new_with_delay(
+        b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow",
+        Duration::from_secs(2),
+    )
+    .await;
+    let admin_address = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--admin-bind", &admin_address, "--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut busy = TcpStream::connect(&balancebeam.address).await.unwrap();
+    busy.write_all(b"GET /slow HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+    delay_for(Duration::from_millis(500)).await;
+    let connections = admin_connections(&admin_address).await;
+    assert_eq!(connections.len(), 1, "got {:?}", connections);
+    let connection = &connections[0];
+    assert_eq!(connection["peer"], busy.local_addr().unwrap().to_string().as_str());
+    assert_eq!(connection["state"], "waiting-upstream");
+    assert_eq!(connection["requests"], 1);
+    let id = connection["id"].as_u64().unwrap();
+
+    let response = close_connection(&admin_address, id).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let closed: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(closed["closing"], true);
+    assert_eq!(close_connection(&admin_address, id + 100).await.status().as_u16(), 404);
+
+    // The response still arrives in full, followed by a clean end of stream rather than a reset
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), busy.read_to_end(&mut response))
+        .await
+        .expect("connection not closed")
+        .expect("connection not closed cleanly");
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "got {:?}", response);
+    assert!(response.contains("connection: close\r\n"), "got {:?}", response);
+    assert!(response.ends_with("slow"), "got {:?}", response);
+    let connections = admin_connections(&admin_address).await;
+    assert!(connections.is_empty(), "got {:?}", connections);
+
+    let mut idle = TcpStream::connect(&balancebeam.address).await.unwrap();
+    delay_for(Duration::from_millis(200)).await;
+    let connections = admin_connections(&admin_address).await;
+    assert_eq!(connections[0]["state"], "idle");
+    let id = connections[0]["id"].as_u64().unwrap();
+    assert_eq!(close_connection(&admin_address, id).await.status().as_u16(), 200);
+    let mut rest = Vec::new();
+    let read = timeout(Duration::from_secs(2), idle.read_to_end(&mut rest)).await;
+    assert_eq!(read.expect("idle connection not closed").unwrap(), 0);
+    assert!(admin_connections(&admin_address).await.is_empty());
+
+    log::info!("All done :)");
+}
+
+/// Sends `request` to `address` over a connection from `source` (any loopback address will do on
+/// Linux), returning the response.
+async fn send_from(source: &str, address: &str, request: &[u8]) -> String {
+    use nix::sys::socket::{bind, socket, AddressFamily, InetAddr, SockAddr, SockFlag, SockType};
+    use std::os::unix::io::FromRawFd;
+
+    let fd = socket(AddressFamily::Inet, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)
+        .expect("Could not open a socket");
+    // Safety: socket just opened the fd, for us alone
+    let socket = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+    let local = format!("{}:0", source).parse().unwrap();
+    bind(fd, &SockAddr::new_inet(InetAddr::from_std(&local))).expect("Could not bind");
+    let mut stream = TcpStream::connect_std(socket, &address.parse().unwrap())
+        .await
+        .expect("Could not connect to balancebeam");
+    stream.write_all(request).await.expect("Could not send request to balancebeam");
+    stream
+        .shutdown(std::net::Shutdown::Write)
+        .expect("Could not shut down write half");
+    let mut response = Vec::new();
+    let _ = timeout(Duration::from_secs(5), stream.read_to_end(&mut response)).await;
+    String::from_utf8_lossy(&response).to_string()
+}
+
+/// A client moving far more bytes than the others tops the /status ranking, as does the path
+/// prefix it uses.
+#[tokio::test]
+async fn test_top_talkers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--admin-bind", &admin_address, "--top-k-entries", "16"],
+    )
+    .await;
+
+    let body = "x".repeat(10000);
+    let big = format!(
+        "POST /upload/file HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    for _ in 0..3 {
+        let response = send_from("127.0.0.2", &balancebeam.address, big.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+    }
+    for _ in 0..10 {
+        let response = send_from(
+            "127.0.0.3",
+            &balancebeam.address,
+            b"GET /small HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+    }
+
+    let response = admin_get(&admin_address, "/status").await;
+    let status: serde_json::Value =
+        serde_json::from_str(&response.text().await.unwrap()).expect("/status is not JSON");
+    let clients = status["top_talkers"]["clients"].as_array().expect("no clients");
+    assert_eq!(clients.len(), 2, "{}", status);
+    assert_eq!(clients[0]["client"], "127.0.0.2");
+    assert_eq!(clients[0]["requests"], 3);
+    assert!(clients[0]["bytes"].as_u64().unwrap() > 60000, "{}", status);
+    assert_eq!(clients[0]["max_overcount_bytes"], 0);
+    assert_eq!(clients[1]["client"], "127.0.0.3");
+    assert_eq!(clients[1]["requests"], 10);
+    let paths = status["top_talkers"]["paths"].as_array().expect("no paths");
+    assert_eq!(paths[0]["path"], "/upload");
+    assert_eq!(paths[1]["path"], "/small");
+
+    // Off unless asked for
+    let (_balancebeam, _upstream, admin_address) = setup().await;
+    let response = admin_get(&admin_address, "/status").await;
+    let status: serde_json::Value =
+        serde_json::from_str(&response.text().await.unwrap()).expect("/status is not JSON");
+    assert!(status["top_talkers"].is_null());
+
+    log::info!("All done :)");
+}
+
+/// The milliseconds an access log line gives `phase`.
+fn phase_ms(line: &str, phase: &str) -> f64 {
+    let field = format!("{}_ms=", phase);
+    let start = line.find(&field).unwrap_or_else(|| panic!("no {} in {:?}", phase, line));
+    let value = line[start + field.len()..].split(' ').next().unwrap();
+    value.parse().unwrap()
+}
+
+/// Time spent waiting on the client and on the upstream is attributed to the right phases, both in
+/// the access log and in the per-phase histograms.
+#[tokio::test]
+async fn test_request_phase_timings() {
+    init_logging();
+    let delay = Duration::from_millis(300);
+    let slow_phases = [
+        "client_header_read",
+        "client_body_read",
+        "upstream_first_byte",
+        "upstream_body_read",
+    ];
+    let upstream = RawServer::new_with_chunks(vec![
+        (delay, b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n".to_vec()),
+        (delay, b"done".to_vec()),
+    ])
+    .await;
+    let admin_address = free_address();
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--admin-bind", &admin_address]).await;
+
+    // The client dawdles over both its headers and its body
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client.write_all(b"POST /slow HTTP/1.1\r\nHost: x\r\n").await.unwrap();
+    delay_for(delay).await;
+    client.write_all(b"Content-Length: 4\r\n\r\n").await.unwrap();
+    delay_for(delay).await;
+    client.write_all(b"body").await.unwrap();
+    let mut response = vec![0; 1024];
+    let n = timeout(Duration::from_secs(5), client.read(&mut response)).await.unwrap().unwrap();
+    let response = String::from_utf8_lossy(&response[..n]).to_string();
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+
+    // The timings go on the end of the access log line
+    let access_line = "<- HTTP/1.1 200 OK client_header_read_ms=";
+    assert!(balancebeam.output_contains(access_line).await);
+    let line = balancebeam.output_lines(access_line).pop().unwrap();
+    assert_eq!(balancebeam.output_count("phases:"), 0);
+    for slow in &slow_phases {
+        let ms = phase_ms(&line, slow);
+        assert!((290.0..1000.0).contains(&ms), "{} took {} in {:?}", slow, ms, line);
+    }
+    for fast in &["upstream_connect", "upstream_write", "client_write"] {
+        let ms = phase_ms(&line, fast);
+        assert!(ms < 100.0, "{} took {} in {:?}", fast, ms, line);
+    }
+
+    let metrics = admin_get(&admin_address, "/metrics").await.text().await.unwrap();
+    assert!(metrics.contains("# TYPE balancebeam_request_phase_seconds histogram\n"));
+    let bucket = |phase: &str, le: &str, count: u64| {
+        format!(
+            "balancebeam_request_phase_seconds_bucket{{phase=\"{}\",le=\"{}\"}} {}\n",
+            phase, le, count
+        )
+    };
+    for slow in &slow_phases {
+        let below = bucket(slow, "0.2", 0);
+        let above = bucket(slow, "1", 1);
+        assert!(metrics.contains(&below) && metrics.contains(&above), "{} in {}", slow, metrics);
+    }
+    assert!(metrics.contains(&bucket("upstream_connect", "0.1", 1)), "{}", metrics);
+    let count = "balancebeam_request_phase_seconds_count{phase=\"client_write\"} 1\n";
+    assert!(metrics.contains(count), "{}", metrics);
+
+    log::info!("All done :)");
+}
+
+/// The effective configuration is logged at startup and served at /config, with every secret
+/// replaced by a fingerprint and everything else as it was given.
+#[tokio::test]
+async fn test_effective_config() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--admin-token",
+            "admin-secret",
+            "--metrics-token",
+            "metrics-secret",
+            "--upstream-add-request-header",
+            "X-Internal-Token: header-secret",
+            "--max-requests-per-minute",
+            "50",
+        ],
+    )
+    .await;
+
+    let response = get_with_token(&admin_address, "/config", Some("admin-secret")).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let text = response.text().await.unwrap();
+    let served: serde_json::Value = serde_json::from_str(&text).expect("/config is not JSON");
+    let logged = balancebeam.output_lines("Effective configuration: ");
+    assert_eq!(logged.len(), 1);
+    let logged = &logged[0][logged[0].find('{').unwrap()..];
+    assert_eq!(serde_json::from_str::<serde_json::Value>(logged).unwrap(), served);
+    for secret in &["admin-secret", "metrics-secret", "header-secret"] {
+        assert!(!text.contains(secret), "{} leaked into /config", secret);
+        assert_eq!(balancebeam.output_count(secret), 0, "{} leaked into the log", secret);
+    }
+
+    let options = &served["options"];
+    assert!(options["admin-token"]["value"].as_str().unwrap().starts_with("<redacted hmac:"));
+    let header = options["upstream-add-request-header"]["value"][0].as_str().unwrap();
+    assert!(header.starts_with("X-Internal-Token: <redacted"), "{}", header);
+    let given = serde_json::json!({ "value": "50", "source": "command line" });
+    assert_eq!(options["max-requests-per-minute"], given);
+    assert_eq!(options["bind"]["value"], balancebeam.address.as_str());
+    assert_eq!(options["active-health-check-path"]["source"], "default");
+    assert_eq!(served["pools"][0]["upstreams"][0]["address"], upstream.address.as_str());
+    assert_eq!(served["listeners"][0]["max_requests_per_minute"], 50);
+
+    log::info!("All done :)");
+}
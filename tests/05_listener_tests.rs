@@ -0,0 +1,430 @@
+mod common;
+
+use common::{
+    free_address, free_address_v6, init_logging, BalanceBeam, EchoServer, ErrorServer, RawServer,
+    Server,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{delay_for, Duration};
+
+/// Two listeners, each routing to its own pool, with a rate limit on only one of them. Requests
+/// should never cross between pools, and hitting the public listener's rate limit shouldn't affect
+/// the internal listener.
+#[tokio::test]
+async fn test_listeners_are_isolated() {
+    init_logging();
+    let internal_upstream = EchoServer::new().await;
+    let public_upstream = EchoServer::new().await;
+    let internal_address = free_address();
+    let public_address = free_address();
+    let config = serde_json::json!({
+        "pools": {
+            "internal": {"upstreams": [internal_upstream.address]},
+            "public": {"upstreams": [public_upstream.address]},
+        },
+        "listeners": [
+            {"bind": internal_address, "pool": "internal"},
+            {"bind": public_address, "pool": "public", "max_requests_per_minute": 2},
+        ],
+    });
+    let _balancebeam = BalanceBeam::new_with_config(&config.to_string(), &internal_address).await;
+
+    let client = reqwest::Client::new();
+    let get = |address: &str, path: &str| {
+        client
+            .get(&format!("http://{}{}", address, path))
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+    };
+
+    for i in 0..3 {
+        let response = get(&public_address, &format!("/public-{}", i))
+            .await
+            .expect("Error sending request to public listener");
+        let expected = if i < 2 { 200 } else { 429 };
+        assert_eq!(response.status().as_u16(), expected);
+    }
+    for i in 0..5 {
+        let response = get(&internal_address, &format!("/internal-{}", i))
+            .await
+            .expect("Error sending request to internal listener");
+        assert_eq!(response.status().as_u16(), 200);
+        let text = response.text().await.unwrap();
+        assert!(text.contains(&format!("GET /internal-{} HTTP/1.1", i)));
+    }
+
+    assert_eq!(Box::new(public_upstream).stop().await, 2);
+    assert_eq!(Box::new(internal_upstream).stop().await, 5);
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_invalid_config_exits() {
+    init_logging();
+    let address = free_address();
+    let config = serde_json::json!({
+        "pools": {"web": {"upstreams": ["127.0.0.1:1"]}},
+        "listeners": [{"bind": address, "pool": "missing"}],
+    });
+    let mut balancebeam = BalanceBeam::new_with_config(&config.to_string(), &address).await;
+    let status = balancebeam.wait_for_exit().await.expect("balancebeam didn't exit");
+    assert_eq!(status.code(), Some(1));
+    assert!(balancebeam.output_contains("unknown pool").await);
+
+    log::info!("All done :)");
+}
+
+/// A pool with no live upstreams tells clients to come back after its own health check interval,
+/// not whichever pool checks soonest.
+#[tokio::test]
+async fn test_retry_after_follows_the_routed_pool() {
+    init_logging();
+    let fast_address = free_address();
+    let slow_address = free_address();
+    let config = serde_json::json!({
+        "pools": {
+            "fast": {"upstreams": [free_address()], "active_health_check_interval": 2},
+            "slow": {"upstreams": [free_address()], "active_health_check_interval": 7},
+        },
+        "listeners": [
+            {"bind": fast_address, "pool": "fast"},
+            {"bind": slow_address, "pool": "slow"},
+        ],
+    });
+    let _balancebeam = BalanceBeam::new_with_config(&config.to_string(), &fast_address).await;
+
+    let send = |address: String| async move {
+        let mut stream = TcpStream::connect(&address).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        String::from_utf8_lossy(&response).to_string()
+    };
+    for (address, interval) in &[(&slow_address, 7), (&fast_address, 2)] {
+        // The first request finds the upstream dead; the next has nothing left to try
+        let response = send(address.to_string()).await;
+        assert!(response.starts_with("HTTP/1.1 502"), "got {:?}", response);
+        let response = send(address.to_string()).await;
+        assert!(response.starts_with("HTTP/1.1 503"), "got {:?}", response);
+        let expected = format!("retry-after: {}\r\n", interval);
+        assert!(response.contains(&expected), "got {:?}", response);
+    }
+
+    log::info!("All done :)");
+}
+
+/// A listener address that is still in use (the admin API's included) is retried until it frees
+/// up, and once the retries run out, the fallback address is used rather than exiting.
+#[tokio::test]
+async fn test_bind_retry_and_fallback() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let address = free_address();
+    let admin_address = free_address();
+    let held = std::net::TcpListener::bind(&address).unwrap();
+    let admin_held = std::net::TcpListener::bind(&admin_address).unwrap();
+    let balancebeam = BalanceBeam::new_at_address(
+        &address,
+        &[&upstream.address],
+        &["--bind-retry", "attempts=10,interval=0.5", "--admin-bind", &admin_address],
+    )
+    .await;
+    assert!(balancebeam.output_contains("is in use; trying again").await);
+    log::info!("Releasing the address");
+    drop(held);
+    assert!(balancebeam.output_contains(&format!("Listening for requests on {}", address)).await);
+    // The admin listener waits for its address just the same
+    assert!(balancebeam.output_contains(&format!("{} is in use", admin_address)).await);
+    drop(admin_held);
+    assert!(balancebeam.output_contains(&format!("Serving admin API on {}", admin_address)).await);
+    let response = balancebeam.get("/retried").await.expect("Error sending request to balancebeam");
+    assert!(response.starts_with("GET /retried HTTP/1.1"));
+
+    log::info!("Holding the address for good, with a fallback to use");
+    let address = free_address();
+    let fallback = free_address();
+    let held = std::net::TcpListener::bind(&address).unwrap();
+    let balancebeam = BalanceBeam::new_at_address(
+        &address,
+        &[&upstream.address],
+        &["--bind-retry", "attempts=1,interval=0.1", "--bind-fallback", &fallback],
+    )
+    .await;
+    assert!(balancebeam.output_contains("listening on the fallback address").await);
+    let response = reqwest::get(&format!("http://{}/fallback", fallback))
+        .await
+        .expect("Error sending request to the fallback address")
+        .text()
+        .await
+        .unwrap();
+    assert!(response.starts_with("GET /fallback HTTP/1.1"));
+    drop(held);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Makes `count` short-lived connections to `address`, `concurrency` at a time, each sending one
+/// request and reading the response to the end. Returns how long they all took.
+async fn short_connections(address: &str, count: usize, concurrency: usize) -> Duration {
+    let start = std::time::Instant::now();
+    let clients: Vec<_> = (0..concurrency)
+        .map(|_| {
+            let address = address.to_string();
+            tokio::spawn(async move {
+                for _ in 0..count / concurrency {
+                    let mut stream = TcpStream::connect(&address).await.unwrap();
+                    let request = b"GET /short HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n";
+                    stream.write_all(request).await.unwrap();
+                    let mut response = Vec::new();
+                    stream.read_to_end(&mut response).await.unwrap();
+                    assert!(response.starts_with(b"HTTP/1.1 200"));
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.await.expect("Client failed");
+    }
+    start.elapsed()
+}
+
+/// With --reuseport-acceptors, several sockets share one address and all of them serve. Under a
+/// stream of short connections they should keep up at least as well as a single listener, and
+/// shutting down should close every one of them straight away, while requests are still draining.
+#[tokio::test]
+async fn test_reuseport_acceptors() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (connections, concurrency) = (2000, 50);
+
+    let single = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+    let single_elapsed = short_connections(&single.address, connections, concurrency).await;
+    log::info!("{} connections to one acceptor took {:?}", connections, single_elapsed);
+
+    let mut balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--reuseport-acceptors", "4"]).await;
+    assert!(balancebeam.output_contains("Accepting on 4 sockets with SO_REUSEPORT").await);
+    let elapsed = short_connections(&balancebeam.address, connections, concurrency).await;
+    log::info!("{} connections to four acceptors took {:?}", connections, elapsed);
+    // Coarse, to allow for noisy machines: only a clear slowdown fails
+    assert!(
+        elapsed < single_elapsed * 2,
+        "four acceptors took {:?}, one took {:?}",
+        elapsed,
+        single_elapsed
+    );
+
+    balancebeam.signal(nix::sys::signal::Signal::SIGTERM);
+    balancebeam.wait_for_exit().await.expect("balancebeam didn't exit");
+    assert_eq!(Box::new(upstream).stop().await, 2 * connections);
+
+    // While a slow request keeps it draining, no socket in the group takes new connections
+    let slow_upstream = RawServer::new_with_delay(
+        b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n",
+        Duration::from_secs(3),
+    )
+    .await;
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&slow_upstream.address],
+        &["--reuseport-acceptors", "4", "--active-health-check-interval", "60"],
+    )
+    .await;
+    let in_flight = {
+        let address = balancebeam.address.clone();
+        tokio::spawn(async move {
+            let mut stream = TcpStream::connect(&address).await.unwrap();
+            stream.write_all(b"GET /slow HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+            let mut response = vec![0_u8; 1024];
+            let read = stream.read(&mut response).await.unwrap();
+            String::from_utf8_lossy(&response[..read]).into_owned()
+        })
+    };
+    delay_for(Duration::from_millis(500)).await;
+    balancebeam.signal(nix::sys::signal::Signal::SIGTERM);
+    assert!(balancebeam.output_contains("Shutting down (SIGTERM)").await);
+    delay_for(Duration::from_millis(200)).await;
+    for _ in 0..20 {
+        assert!(TcpStream::connect(&balancebeam.address).await.is_err());
+    }
+    let response = in_flight.await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "got {:?}", response);
+    balancebeam.wait_for_exit().await.expect("balancebeam didn't exit");
+    assert_eq!(Box::new(slow_upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Clients on an IPv6 listener are identified properly everywhere: in the forwarding headers, in
+/// the logs, by the admin API's allow list, and by the rate limiter.
+#[tokio::test]
+async fn test_ipv6_listener() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let address = free_address_v6();
+    let admin_address = free_address_v6();
+    let balancebeam = BalanceBeam::new_at_address(
+        &address,
+        &[&upstream.address],
+        &[
+            "--forwarded-header-style",
+            "both",
+            "--max-requests-per-minute",
+            "2",
+            "--admin-bind",
+            &admin_address,
+            "--admin-allow-cidr",
+            "::1/128",
+        ],
+    )
+    .await;
+
+    let response = balancebeam.send_raw(b"GET /first HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.contains("x-forwarded-for: ::1\n"), "got {:?}", response);
+    let forwarded = "forwarded: for=\"[::1]\";proto=http;host=x;by=\"[::1]\"\n";
+    assert!(response.contains(forwarded), "got {:?}", response);
+    assert!(balancebeam.output_contains("Connection received from ::1").await);
+
+    // The loopback client's /64 is ::/64, and it is counted under that
+    balancebeam.send_raw(b"GET /second HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    let response = balancebeam.send_raw(b"GET /third HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 429"), "got {:?}", response);
+    let status = reqwest::get(&format!("http://{}/status", admin_address))
+        .await
+        .expect("Error querying the admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(status.contains(r#""client":"::/64""#), "got {:?}", status);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// --self-test sends a request through the listener before reporting ready, and exits naming the
+/// stage that failed if that doesn't work.
+#[tokio::test]
+async fn test_self_test() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--self-test", "--self-test-path", "/self-test"],
+    )
+    .await;
+    assert!(balancebeam.output_contains("Self-test: GET /self-test through").await);
+    assert!(balancebeam.output_contains("Self-test passed").await);
+    assert_eq!(balancebeam.get("/_balancebeam/healthz").await.unwrap(), "ok\n");
+    balancebeam.signal(nix::sys::signal::Signal::SIGTERM);
+    balancebeam.wait_for_exit().await.expect("balancebeam didn't exit");
+
+    // A header rule that breaks every forwarded request, though not the health checks
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--self-test", "--upstream-add-request-header", "Content-Length: none"],
+    )
+    .await;
+    let status = balancebeam.wait_for_exit().await.expect("balancebeam didn't exit");
+    assert_eq!(status.code(), Some(1));
+    assert!(balancebeam.output_contains("Self-test failed at the proxy path stage").await);
+    assert!(balancebeam.output_contains("got 400 Bad Request").await);
+    Box::new(upstream).stop().await;
+
+    let upstream = ErrorServer::new().await;
+    let mut balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &["--self-test"]).await;
+    let status = balancebeam.wait_for_exit().await.expect("balancebeam didn't exit");
+    assert_eq!(status.code(), Some(1));
+    assert!(balancebeam.output_contains("Self-test failed at the upstream health stage").await);
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// POST /upgrade hands the listeners to a new balancebeam. Requests on fresh connections keep
+/// working throughout, without a single one refused, while the old process drains and exits.
+#[tokio::test]
+async fn test_upgrade() {
+    init_logging();
+    upgrade_without_refusals(&[]).await;
+    log::info!("All done :)");
+}
+
+/// With several SO_REUSEPORT sockets per listener, every one of them is handed over, so that no
+/// connection queued on one is reset when the old process closes its copy.
+#[tokio::test]
+async fn test_upgrade_with_reuseport_acceptors() {
+    init_logging();
+    let balancebeam = upgrade_without_refusals(&["--reuseport-acceptors", "4"]).await;
+    let adopted = balancebeam.output_lines("Listening for requests on");
+    assert_eq!(adopted.len(), 2, "{:?}", adopted);
+    assert!(adopted[1].contains("(fd ["), "{:?}", adopted);
+    assert_eq!(adopted[1].matches(',').count(), 3, "{:?}", adopted);
+    log::info!("All done :)");
+}
+
+/// Upgrades a balancebeam started with `extra_args` while clients keep connecting, checking that
+/// none of them fail. Returns the old balancebeam, whose output includes the new one's.
+async fn upgrade_without_refusals(extra_args: &[&str]) -> BalanceBeam {
+    let upstream = EchoServer::new().await;
+    let admin_address = free_address();
+    let binary = BalanceBeam::target_bin_path();
+    let binary = binary.to_str().unwrap();
+    let mut args = vec!["--admin-bind", &admin_address, "--upgrade-binary", binary];
+    args.extend_from_slice(extra_args);
+    let mut balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &args).await;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let sender = {
+        let (address, stop) = (balancebeam.address.clone(), stop.clone());
+        tokio::spawn(async move {
+            let (mut sent, mut failures) = (0, Vec::new());
+            while !stop.load(Ordering::Relaxed) {
+                let result = async {
+                    let mut stream = TcpStream::connect(&address).await?;
+                    let request = b"GET /a HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n";
+                    stream.write_all(request).await?;
+                    let mut response = Vec::new();
+                    stream.read_to_end(&mut response).await?;
+                    Ok::<_, std::io::Error>(String::from_utf8_lossy(&response).into_owned())
+                };
+                match result.await {
+                    Ok(response) if response.starts_with("HTTP/1.1 200") => {}
+                    Ok(response) => failures.push(response),
+                    Err(err) => failures.push(err.to_string()),
+                }
+                sent += 1;
+            }
+            (sent, failures)
+        })
+    };
+
+    delay_for(Duration::from_millis(300)).await;
+    let response = reqwest::Client::new()
+        .post(&format!("http://{}/upgrade", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to admin API");
+    assert_eq!(response.status().as_u16(), 200);
+    let upgraded: serde_json::Value =
+        serde_json::from_str(&response.text().await.unwrap()).expect("/upgrade is not JSON");
+    let pid = nix::unistd::Pid::from_raw(upgraded["pid"].as_i64().expect("no pid") as i32);
+    assert!(balancebeam.output_contains("Shutting down (upgrade)").await);
+    let status = balancebeam.wait_for_exit().await.expect("the old balancebeam didn't exit");
+    assert!(status.success());
+
+    // The new process serves the listener and the admin API alone now
+    delay_for(Duration::from_millis(500)).await;
+    stop.store(true, Ordering::Relaxed);
+    let (sent, failures) = sender.await.unwrap();
+    let admin_status = reqwest::get(&format!("http://{}/status", admin_address)).await;
+    nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGTERM).expect("no new balancebeam");
+
+    assert!(failures.is_empty(), "{} of {} requests failed: {:?}", failures.len(), sent, failures);
+    assert_eq!(admin_status.unwrap().status().as_u16(), 200);
+    assert_eq!(balancebeam.output_count("Ready"), 2);
+    Box::new(upstream).stop().await;
+    balancebeam
+}
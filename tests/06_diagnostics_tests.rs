@@ -0,0 +1,174 @@
+mod common;
+
+use common::{free_address, init_logging, BalanceBeam, EchoServer, Server};
+use nix::sys::signal::Signal;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{delay_for, Duration};
+
+/// SIGUSR2 dumps a report that includes open client connections, even idle keep-alive ones.
+#[tokio::test]
+async fn test_sigusr2_dumps_diagnostics() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    // Leave a keep-alive connection open after one request
+    let mut idle = TcpStream::connect(&balancebeam.address).await.unwrap();
+    idle.write_all(b"GET /idle HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+    let mut buffer = [0_u8; 1024];
+    assert!(idle.read(&mut buffer).await.unwrap() > 0);
+    let idle_addr = idle.local_addr().unwrap().to_string();
+
+    let traffic = {
+        let address = balancebeam.address.clone();
+        tokio::spawn(async move {
+            let client = reqwest::Client::new();
+            for i in 0..20 {
+                let _ = client.get(&format!("http://{}/busy-{}", address, i)).send().await;
+            }
+        })
+    };
+    balancebeam.signal(Signal::SIGUSR2);
+    assert!(balancebeam.output_contains("=== end of diagnostics ===").await);
+    traffic.await.unwrap();
+
+    for section in &["--- listeners ---", "--- upstreams ---", "--- connections ("] {
+        assert_eq!(balancebeam.output_count(section), 1, "missing {}", section);
+    }
+    assert_eq!(balancebeam.output_count(&format!("{}: alive, ", upstream.address)), 1);
+    let idle_line = format!("{} on {}", idle_addr, balancebeam.address);
+    assert_eq!(balancebeam.output_count(&idle_line), 1);
+
+    drop(idle);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --diag-file the report goes to a file instead.
+#[tokio::test]
+async fn test_diag_file() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = std::env::temp_dir().join(format!("balancebeam-diag-{}.txt", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--diag-file", path.to_str().unwrap()])
+            .await;
+
+    balancebeam.signal(Signal::SIGUSR2);
+    assert!(balancebeam.output_contains("Wrote diagnostics to").await);
+    let report = std::fs::read_to_string(&path).expect("No diagnostics file");
+    assert!(report.starts_with("=== balancebeam diagnostics ==="), "{}", report);
+    assert!(report.contains("--- connections (0) ---"), "{}", report);
+    assert_eq!(balancebeam.output_count("=== end of diagnostics ==="), 0);
+
+    let _ = std::fs::remove_file(&path);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --debug-connection-tracking, open connections are counted in /metrics, and ones older than
+/// --max-connection-age-secs are reported along with their latest request.
+#[tokio::test]
+async fn test_debug_connection_tracking() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = free_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--debug-connection-tracking",
+            "--max-connection-age-secs",
+            "1",
+        ],
+    )
+    .await;
+
+    let mut lingering = TcpStream::connect(&balancebeam.address).await.unwrap();
+    lingering.write_all(b"GET /lingering HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+    let mut buffer = [0_u8; 1024];
+    assert!(lingering.read(&mut buffer).await.unwrap() > 0);
+
+    let metrics = reqwest::get(&format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("balancebeam_active_connections 1\n"), "{}", metrics);
+
+    delay_for(Duration::from_secs(2)).await;
+    assert!(
+        balancebeam
+            .output_contains("last request: GET /lingering HTTP/1.1")
+            .await
+    );
+    assert_eq!(balancebeam.output_count("has been open for"), 1);
+
+    drop(lingering);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// SIGTERM closes idle connections, and the report written on the way out agrees with the traffic
+/// the test sent.
+#[tokio::test]
+async fn test_shutdown_report() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = std::env::temp_dir().join(format!("balancebeam-report-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "5",
+            "--active-health-check-interval",
+            "60",
+            "--shutdown-report-file",
+            path.to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    // An idle keep-alive connection, which shouldn't hold up the shutdown
+    let mut idle = TcpStream::connect(&balancebeam.address).await.unwrap();
+    idle.write_all(b"GET /idle HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+    let mut buffer = [0_u8; 1024];
+    assert!(idle.read(&mut buffer).await.unwrap() > 0);
+    let (mut ok, mut limited) = (1, 0);
+    for _ in 0..6 {
+        let response = balancebeam.send_raw(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        if response.starts_with("HTTP/1.1 200") {
+            ok += 1;
+        } else if response.starts_with("HTTP/1.1 429") {
+            limited += 1;
+        }
+    }
+    assert_eq!((ok, limited), (5, 2));
+
+    balancebeam.signal(Signal::SIGTERM);
+    let status = balancebeam.wait_for_exit().await.expect("balancebeam didn't exit");
+    assert!(status.success(), "exited with {}", status);
+    assert_eq!(idle.read(&mut buffer).await.unwrap(), 0, "the idle connection wasn't closed");
+    assert!(balancebeam.output_contains("Shutting down (SIGTERM)").await);
+
+    let report = std::fs::read_to_string(&path).expect("No shutdown report");
+    let report: serde_json::Value = serde_json::from_str(&report).expect("report isn't JSON");
+    assert_eq!(report["shutdown_reason"], "SIGTERM");
+    assert_eq!(report["responses"]["2xx"], ok);
+    assert_eq!(report["responses"]["4xx"], limited);
+    assert_eq!(report["responses"]["5xx"], 0);
+    assert_eq!(report["rate_limited"], limited);
+    assert_eq!(report["upstreams"][0]["address"], upstream.address.as_str());
+    assert_eq!(report["upstreams"][0]["requests"], ok);
+    assert!(report["peak_connections"].as_u64().unwrap() >= 2, "{}", report);
+    assert!(report["peak_buffered_bytes"].as_u64().unwrap() > 0, "{}", report);
+
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(Box::new(upstream).stop().await, ok);
+    log::info!("All done :)");
+}
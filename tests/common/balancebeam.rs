@@ -1,17 +1,18 @@
-use rand::Rng;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
 use tokio::process::{Child, Command};
-use tokio::time::delay_for;
+use tokio::time::{delay_for, timeout};
 
 pub struct BalanceBeam {
-    #[allow(dead_code)]
     child: Child, // process is killed when dropped (Command::kill_on_drop)
     pub address: String,
+    output: Arc<Mutex<Vec<String>>>,
 }
 
 impl BalanceBeam {
-    fn target_bin_path() -> std::path::PathBuf {
+    pub fn target_bin_path() -> std::path::PathBuf {
         let mut path = std::env::current_exe().expect("Could not get current test executable path");
         path.pop();
         path.pop();
@@ -24,36 +25,74 @@ impl BalanceBeam {
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
     ) -> BalanceBeam {
-        let mut rng = rand::thread_rng();
-        let address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
-        let mut cmd = Command::new(BalanceBeam::target_bin_path());
-        cmd.arg("--bind").arg(&address);
-        for upstream in upstreams {
-            cmd.arg("--upstream").arg(upstream);
-        }
+        let mut args = Vec::new();
         if let Some(active_health_check_interval) = active_health_check_interval {
-            cmd.arg("--active-health-check-interval")
-                .arg(active_health_check_interval.to_string());
+            args.push("--active-health-check-interval".to_string());
+            args.push(active_health_check_interval.to_string());
         }
         if let Some(max_requests_per_minute) = max_requests_per_minute {
-            cmd.arg("--max-requests-per-minute")
-                .arg(max_requests_per_minute.to_string());
+            args.push("--max-requests-per-minute".to_string());
+            args.push(max_requests_per_minute.to_string());
+        }
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+        BalanceBeam::new_with_args(upstreams, &args).await
+    }
+
+    /// Starts balancebeam with the given upstreams plus any extra command-line arguments.
+    pub async fn new_with_args(upstreams: &[&str], extra_args: &[&str]) -> BalanceBeam {
+        let address = crate::common::free_address();
+        BalanceBeam::new_at_address(&address, upstreams, extra_args).await
+    }
+
+    /// Like `new_with_args`, but listening on `address` (e.g. one from `free_address_v6`).
+    pub async fn new_at_address(
+        address: &str,
+        upstreams: &[&str],
+        extra_args: &[&str],
+    ) -> BalanceBeam {
+        let mut args = vec!["--bind", address];
+        for upstream in upstreams {
+            args.push("--upstream");
+            args.push(upstream);
         }
+        args.extend_from_slice(extra_args);
+        BalanceBeam::spawn(&args, address.to_string()).await
+    }
+
+    /// Starts balancebeam with a --config file containing `config`. `address` should be one of the
+    /// listeners in the config; it's the one `get` and `post` send requests to.
+    pub async fn new_with_config(config: &str, address: &str) -> BalanceBeam {
+        let path = std::env::temp_dir().join(format!(
+            "balancebeam-config-{}.json",
+            address.replace(':', "-")
+        ));
+        std::fs::write(&path, config).expect("Could not write config file");
+        BalanceBeam::spawn(&["--config", path.to_str().unwrap()], address.to_string()).await
+    }
+
+    async fn spawn(args: &[&str], address: String) -> BalanceBeam {
+        let mut cmd = Command::new(BalanceBeam::target_bin_path());
+        cmd.args(args);
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
-        let mut child = cmd.spawn().expect(&format!(
-            "Could not execute balancebeam binary {}",
-            BalanceBeam::target_bin_path().to_str().unwrap()
-        ));
+        let mut child = cmd.spawn().unwrap_or_else(|_| {
+            panic!(
+                "Could not execute balancebeam binary {}",
+                BalanceBeam::target_bin_path().to_str().unwrap()
+            )
+        });
 
         // Print output from the child. We want to intercept and log this output (instead of letting
         // the child inherit stderr and print directly to the terminal) so that the output can be
-        // suppressed if the test passes and displayed if it fails.
+        // suppressed if the test passes and displayed if it fails. Lines are also kept around so
+        // that tests can make assertions about what was logged.
+        let output = Arc::new(Mutex::new(Vec::new()));
         let stdout = child
             .stdout
             .take()
             .expect("Child process somehow missing stdout pipe!");
+        let stdout_output = output.clone();
         tokio::spawn(async move {
             let mut stdout_reader = BufReader::new(stdout).lines();
             while let Some(line) = stdout_reader
@@ -62,12 +101,14 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stdout")
             {
                 println!("Balancebeam output: {}", line);
+                stdout_output.lock().unwrap().push(line);
             }
         });
         let stderr = child
             .stderr
             .take()
             .expect("Child process somehow missing stderr pipe!");
+        let stderr_output = output.clone();
         tokio::spawn(async move {
             let mut stderr_reader = BufReader::new(stderr).lines();
             while let Some(line) = stderr_reader
@@ -76,12 +117,69 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stderr")
             {
                 println!("Balancebeam output: {}", line);
+                stderr_output.lock().unwrap().push(line);
             }
         });
 
         // Hack: wait for executable to start running
         delay_for(Duration::from_secs(1)).await;
-        BalanceBeam { child, address }
+        BalanceBeam {
+            child,
+            address,
+            output,
+        }
+    }
+
+    /// Returns true if any line balancebeam prints contains `needle`. Output is relayed
+    /// asynchronously, so this waits up to a couple of seconds for the line to show up.
+    #[allow(dead_code)]
+    pub async fn output_contains(&self, needle: &str) -> bool {
+        for _ in 0..20 {
+            if self.output_count(needle) > 0 {
+                return true;
+            }
+            delay_for(Duration::from_millis(100)).await;
+        }
+        false
+    }
+
+    /// Sends a signal to the balancebeam process.
+    #[allow(dead_code)]
+    pub fn signal(&self, signal: nix::sys::signal::Signal) {
+        let pid = nix::unistd::Pid::from_raw(self.child.id() as i32);
+        nix::sys::signal::kill(pid, signal).expect("Could not signal balancebeam");
+    }
+
+    /// Waits briefly for the balancebeam process to exit, returning its exit status if it did.
+    #[allow(dead_code)]
+    pub async fn wait_for_exit(&mut self) -> Option<std::process::ExitStatus> {
+        timeout(Duration::from_secs(5), &mut self.child)
+            .await
+            .ok()
+            .map(|status| status.expect("Error waiting for balancebeam to exit"))
+    }
+
+    /// Returns the number of lines balancebeam has printed so far that contain `needle`.
+    #[allow(dead_code)]
+    pub fn output_count(&self, needle: &str) -> usize {
+        self.output
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|line| line.contains(needle))
+            .count()
+    }
+
+    /// Returns the lines balancebeam has printed so far that contain `needle`.
+    #[allow(dead_code)]
+    pub fn output_lines(&self, needle: &str) -> Vec<String> {
+        self.output
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|line| line.contains(needle))
+            .cloned()
+            .collect()
     }
 
     #[allow(dead_code)]
@@ -108,4 +206,24 @@ impl BalanceBeam {
             .text()
             .await
     }
-}
\ No newline at end of file
+
+    /// Sends raw bytes to balancebeam on a fresh connection, closes our write half, and returns
+    /// everything balancebeam sends back before closing the connection (or going quiet for a few
+    /// seconds). Useful for requests that HTTP client libraries refuse to send.
+    #[allow(dead_code)]
+    pub async fn send_raw(&self, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect(&self.address)
+            .await
+            .expect("Could not connect to balancebeam");
+        stream
+            .write_all(request)
+            .await
+            .expect("Could not send request to balancebeam");
+        stream
+            .shutdown(std::net::Shutdown::Write)
+            .expect("Could not shut down write half");
+        let mut response = Vec::new();
+        let _ = timeout(Duration::from_secs(5), stream.read_to_end(&mut response)).await;
+        String::from_utf8_lossy(&response).to_string()
+    }
+}
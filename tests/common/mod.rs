@@ -1,6 +1,10 @@
+// Each integration test crate uses a different subset of these helpers
+#![allow(dead_code, unused_imports)]
+
 mod balancebeam;
 mod echo_server;
 mod error_server;
+mod raw_server;
 mod server;
 
 use std::sync;
@@ -8,10 +12,24 @@ use std::sync;
 pub use balancebeam::BalanceBeam;
 pub use echo_server::EchoServer;
 pub use error_server::ErrorServer;
+pub use raw_server::RawServer;
 pub use server::Server;
 
 static INIT_TESTS: sync::Once = sync::Once::new();
 
+/// Returns a localhost address with a port that was free a moment ago. Asking the OS for the port
+/// (rather than picking one at random) avoids colliding with ports other tests are using.
+pub fn free_address() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Could not find a free port");
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Like `free_address`, but on the IPv6 loopback address, e.g. "[::1]:40123".
+pub fn free_address_v6() -> String {
+    let listener = std::net::TcpListener::bind("[::1]:0").expect("Could not find a free port");
+    listener.local_addr().unwrap().to_string()
+}
+
 pub fn init_logging() {
     INIT_TESTS.call_once(|| {
         pretty_env_logger::formatted_builder()
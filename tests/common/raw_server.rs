@@ -0,0 +1,131 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::sync::{atomic, Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::time::{delay_for, Duration};
+
+#[derive(Debug)]
+struct ServerState {
+    pub requests_received: atomic::AtomicUsize,
+    /// Everything read from each connection (at least the request headers)
+    pub requests: Mutex<Vec<String>>,
+    /// Where each connection came from
+    pub peers: Mutex<Vec<SocketAddr>>,
+}
+
+/// A fake upstream that answers every connection with a fixed sequence of bytes (which need not be
+/// valid HTTP) and then hangs up. Useful for feeding balancebeam malformed or unusual responses.
+/// Connections are handled one at a time.
+pub struct RawServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl RawServer {
+    pub async fn new(response: &[u8]) -> RawServer {
+        RawServer::new_with_delay(response, Duration::from_secs(0)).await
+    }
+
+    /// Like `new`, but waits `delay` after reading each request before answering it.
+    pub async fn new_with_delay(response: &[u8], delay: Duration) -> RawServer {
+        RawServer::new_with_chunks(vec![(delay, response.to_vec())]).await
+    }
+
+    /// Sends `head` straight away, then waits `stall` before sending `rest`. Useful for upstreams
+    /// that answer quickly but are slow to finish the body.
+    pub async fn new_with_stall(head: &[u8], stall: Duration, rest: &[u8]) -> RawServer {
+        RawServer::new_with_chunks(vec![
+            (Duration::from_secs(0), head.to_vec()),
+            (stall, rest.to_vec()),
+        ])
+        .await
+    }
+
+    /// Answers each request by writing each chunk in turn, after waiting that chunk's delay.
+    pub async fn new_with_chunks(chunks: Vec<(Duration, Vec<u8>)>) -> RawServer {
+        let address = crate::common::free_address();
+        let mut listener = TcpListener::bind(&address)
+            .await
+            .expect("RawServer could not bind");
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+            requests: Mutex::new(Vec::new()),
+            peers: Mutex::new(Vec::new()),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                let mut stream = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, peer)) => {
+                            server_task_state.peers.lock().unwrap().push(peer);
+                            stream
+                        }
+                        Err(_) => break,
+                    },
+                    _ = &mut shutdown_rx => break,
+                };
+                // Wait for the request headers before answering
+                let mut request = Vec::new();
+                let mut buffer = [0_u8; 8192];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    match stream.read(&mut buffer).await {
+                        Ok(n) if n > 0 => request.extend_from_slice(&buffer[..n]),
+                        _ => break,
+                    }
+                }
+                if !request.is_empty() {
+                    server_task_state
+                        .requests_received
+                        .fetch_add(1, atomic::Ordering::SeqCst);
+                    server_task_state
+                        .requests
+                        .lock()
+                        .unwrap()
+                        .push(String::from_utf8_lossy(&request).to_string());
+                }
+                for (delay, chunk) in &chunks {
+                    delay_for(*delay).await;
+                    let _ = stream.write_all(chunk).await;
+                }
+            }
+        });
+        RawServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            address,
+            state: server_state,
+        }
+    }
+
+    /// The raw requests received so far.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    /// The addresses connections came from so far.
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.state.peers.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Server for RawServer {
+    async fn stop(self: Box<Self>) -> usize {
+        let _ = self.shutdown_signal_sender.send(());
+        self.server_task
+            .await
+            .expect("RawServer server task panicked");
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}